@@ -0,0 +1,25 @@
+// Embeds the git commit as OPENAI_PROXY_BUILD_HASH for GET /status, unless
+// the build environment already sets it, e.g. in CI without a checkout
+
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=OPENAI_PROXY_BUILD_HASH");
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    if std::env::var("OPENAI_PROXY_BUILD_HASH").is_ok() {
+        return;
+    }
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=OPENAI_PROXY_BUILD_HASH={}", hash);
+}
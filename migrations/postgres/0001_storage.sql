@@ -0,0 +1,27 @@
+-- IF NOT EXISTS adopts the tables of databases the proxy migrated itself
+-- before it used sqlx; openai_proxy_migrations is left from then
+CREATE TABLE IF NOT EXISTS openai_proxy_values (
+    key TEXT PRIMARY KEY,
+    value BYTEA NOT NULL,
+    expires_at BIGINT
+);
+CREATE INDEX IF NOT EXISTS openai_proxy_values_expiry ON openai_proxy_values (expires_at)
+    WHERE expires_at IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS openai_proxy_counters (
+    key TEXT NOT NULL,
+    field TEXT NOT NULL,
+    value BIGINT NOT NULL,
+    PRIMARY KEY (key, field)
+);
+ALTER TABLE openai_proxy_counters ADD COLUMN IF NOT EXISTS expires_at BIGINT;
+CREATE INDEX IF NOT EXISTS openai_proxy_counters_expiry ON openai_proxy_counters (expires_at)
+    WHERE expires_at IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS openai_proxy_logs (
+    seq BIGSERIAL PRIMARY KEY,
+    key TEXT NOT NULL,
+    entry BYTEA NOT NULL,
+    expires_at BIGINT
+);
+CREATE INDEX IF NOT EXISTS openai_proxy_logs_key ON openai_proxy_logs (key, seq);
@@ -0,0 +1,23 @@
+CREATE TABLE openai_proxy_values (
+    key TEXT PRIMARY KEY,
+    value BLOB NOT NULL,
+    expires_at INTEGER
+);
+CREATE INDEX openai_proxy_values_expiry ON openai_proxy_values (expires_at);
+
+CREATE TABLE openai_proxy_counters (
+    key TEXT NOT NULL,
+    field TEXT NOT NULL,
+    value INTEGER NOT NULL,
+    expires_at INTEGER,
+    PRIMARY KEY (key, field)
+);
+CREATE INDEX openai_proxy_counters_expiry ON openai_proxy_counters (expires_at);
+
+CREATE TABLE openai_proxy_logs (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    key TEXT NOT NULL,
+    entry BLOB NOT NULL,
+    expires_at INTEGER
+);
+CREATE INDEX openai_proxy_logs_key ON openai_proxy_logs (key, seq);
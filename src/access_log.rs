@@ -0,0 +1,188 @@
+// Access log file with rotation, compression and retention
+
+use crate::config::AccessLogConfig;
+use crate::time::{format_rotation_suffix, unix_now};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+#[derive(Clone)]
+pub(crate) struct AccessLog {
+    pub(crate) config: AccessLogConfig,
+    pub(crate) file: Arc<Mutex<AccessLogFile>>,
+    // Held while rotated files are compressed and pruned, so a pruner never
+    // sees a file another rotation is still compressing
+    housekeeping: Arc<Mutex<()>>,
+}
+
+pub(crate) struct AccessLogFile {
+    pub(crate) file: File,
+    pub(crate) size: u64,
+    pub(crate) period: u64,
+}
+
+impl AccessLog {
+    pub(crate) fn open(config: AccessLogConfig) -> std::io::Result<Self> {
+        let path = Path::new(&config.path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // Continue the current period if the existing file was written in it
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_else(unix_now);
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(AccessLogFile {
+                file,
+                size: metadata.len(),
+                period: rotation_period(&config.rotation, modified),
+            })),
+            housekeeping: Arc::new(Mutex::new(())),
+            config,
+        })
+    }
+
+    pub(crate) fn write(&self, line: &str) {
+        let mut current = self.file.lock().unwrap();
+        let now = unix_now();
+        let period = rotation_period(&self.config.rotation, now);
+        let max_size = self.config.max_size_mb * 1024 * 1024;
+
+        let size_exceeded = max_size > 0 && current.size + line.len() as u64 + 1 > max_size;
+        if current.size > 0 && (period != current.period || size_exceeded) {
+            if let Err(err) = self.rotate(&mut current, now) {
+                eprintln!(
+                    "⚠️  Failed to rotate access log {}: {}",
+                    self.config.path, err
+                );
+            }
+        }
+        current.period = period;
+
+        match writeln!(current.file, "{}", line) {
+            Ok(()) => current.size += line.len() as u64 + 1,
+            Err(err) => eprintln!("⚠️  Failed to write access log: {}", err),
+        }
+    }
+
+    pub(crate) fn rotate(&self, current: &mut AccessLogFile, now: u64) -> std::io::Result<()> {
+        current.file.flush()?;
+
+        let path = PathBuf::from(&self.config.path);
+        let mut rotated = PathBuf::from(format!(
+            "{}.{}",
+            self.config.path,
+            format_rotation_suffix(now)
+        ));
+        // Several size-based rotations may happen within the same second
+        let mut counter = 1;
+        while rotated.exists() || Path::new(&format!("{}.gz", rotated.display())).exists() {
+            rotated = PathBuf::from(format!(
+                "{}.{}-{}",
+                self.config.path,
+                format_rotation_suffix(now),
+                counter
+            ));
+            counter += 1;
+        }
+
+        fs::rename(&path, &rotated)?;
+        current.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        current.size = 0;
+        println!("🗂️  Rotated access log to {}", rotated.display());
+
+        // Compress and prune off the request path
+        let compress = self.config.compress;
+        let max_files = self.config.max_files;
+        let housekeeping = self.housekeeping.clone();
+        std::thread::spawn(move || {
+            let _housekeeping = housekeeping.lock().unwrap_or_else(|e| e.into_inner());
+            if compress {
+                if let Err(err) = compress_file(&rotated) {
+                    eprintln!("⚠️  Failed to compress {}: {}", rotated.display(), err);
+                }
+            }
+            if let Err(err) = prune_rotated_logs(&path, max_files) {
+                eprintln!("⚠️  Failed to prune rotated access logs: {}", err);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+pub(crate) fn compress_file(path: &Path) -> std::io::Result<()> {
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let mut input = File::open(path)?;
+    let mut encoder =
+        flate2::write::GzEncoder::new(File::create(&gz_path)?, flate2::Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+pub(crate) fn prune_rotated_logs(path: &Path, max_files: usize) -> std::io::Result<()> {
+    let dir = match path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from("."),
+    };
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+
+    // Only names the rotator generates, oldest first
+    let mut rotated: Vec<((String, u64), PathBuf)> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter_map(|p| {
+            let order = rotated_order(name, p.file_name()?.to_str()?)?;
+            Some((order, p))
+        })
+        .collect();
+    rotated.sort();
+
+    let mut failed = None;
+    if rotated.len() > max_files {
+        for (_, old) in &rotated[..rotated.len() - max_files] {
+            // One file that cannot be removed does not keep the others
+            if let Err(err) = fs::remove_file(old) {
+                failed = Some(err);
+            }
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+// The timestamp and collision counter of a rotated file's name,
+// "<name>.YYYYMMDD-HHMMSS[-N][.gz]"; None for other files
+fn rotated_order(name: &str, file: &str) -> Option<(String, u64)> {
+    let suffix = file.strip_prefix(name)?.strip_prefix('.')?;
+    let suffix = suffix.strip_suffix(".gz").unwrap_or(suffix);
+    let (stamp, counter) = match suffix.get(15..) {
+        Some("") => (suffix, 0),
+        Some(rest) => (&suffix[..15], rest.strip_prefix('-')?.parse().ok()?),
+        None => return None,
+    };
+    let valid = stamp.char_indices().all(|(i, c)| match i {
+        8 => c == '-',
+        _ => c.is_ascii_digit(),
+    });
+    valid.then(|| (stamp.to_string(), counter))
+}
+
+pub(crate) fn rotation_period(rotation: &str, secs: u64) -> u64 {
+    match rotation {
+        "hourly" => secs / 3600,
+        "never" => 0,
+        _ => secs / 86400,
+    }
+}
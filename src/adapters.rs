@@ -0,0 +1,531 @@
+// Request shaping for upstreams whose parameters and content parts differ
+// from OpenAI's
+
+use crate::cohere;
+use crate::config::{AdapterKind, ProviderConfig};
+use crate::models::ReasoningEffort;
+use crate::secrets::hex_encode;
+use crate::transform::UpstreamStream;
+use crate::vertex;
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use futures_util::{ready, Stream};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Known renames per upstream type; a dot in the target nests the value
+fn preset(kind: AdapterKind) -> &'static [(&'static str, &'static str)] {
+    match kind {
+        AdapterKind::Openai => &[],
+        AdapterKind::Anthropic => &[
+            ("max_completion_tokens", "max_tokens"),
+            ("stop", "stop_sequences"),
+            ("user", "metadata.user_id"),
+        ],
+        AdapterKind::Mistral => &[
+            ("max_completion_tokens", "max_tokens"),
+            ("seed", "random_seed"),
+        ],
+        AdapterKind::Cohere => &[
+            ("max_completion_tokens", "max_tokens"),
+            ("top_p", "p"),
+            ("top_k", "k"),
+            ("stop", "stop_sequences"),
+        ],
+        AdapterKind::Gemini | AdapterKind::Vertex => &[
+            ("max_tokens", "generationConfig.maxOutputTokens"),
+            ("max_completion_tokens", "generationConfig.maxOutputTokens"),
+            ("temperature", "generationConfig.temperature"),
+            ("top_p", "generationConfig.topP"),
+            ("stop", "generationConfig.stopSequences"),
+            ("n", "generationConfig.candidateCount"),
+            ("seed", "generationConfig.seed"),
+            ("presence_penalty", "generationConfig.presencePenalty"),
+            ("frequency_penalty", "generationConfig.frequencyPenalty"),
+        ],
+    }
+}
+
+// Request rewrites of one provider: content parts in its adapter's schema and
+// parameter renames, the adapter's preset overridden by the provider's own
+pub(crate) struct RequestShaper {
+    kind: AdapterKind,
+    renames: Vec<(String, Vec<String>)>,
+}
+
+impl RequestShaper {
+    pub(crate) fn new(config: &ProviderConfig) -> Self {
+        let mut renames: Vec<(String, Vec<String>)> = Vec::new();
+        let custom = config.renames.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        for (from, to) in preset(config.adapter).iter().copied().chain(custom) {
+            let path = to.split('.').map(|s| s.to_string()).collect();
+            match renames.iter_mut().find(|(f, _)| f == from) {
+                Some(entry) => entry.1 = path,
+                None => renames.push((from.to_string(), path)),
+            }
+        }
+        Self {
+            kind: config.adapter,
+            renames,
+        }
+    }
+
+    pub(crate) fn is_openai(&self) -> bool {
+        self.kind == AdapterKind::Openai
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.kind == AdapterKind::Openai && self.renames.is_empty()
+    }
+
+    // Gemini has no image URLs, remote images are fetched and inlined first
+    pub(crate) fn inlines_images(&self) -> bool {
+        self.is_gemini()
+    }
+
+    fn is_gemini(&self) -> bool {
+        matches!(self.kind, AdapterKind::Gemini | AdapterKind::Vertex)
+    }
+
+    // Converts content parts, then moves each known parameter to its upstream
+    // name. A value already at the target wins; the OpenAI name is dropped
+    // either way.
+    pub(crate) fn apply(&self, obj: &mut Map<String, Value>) {
+        if self.kind != AdapterKind::Openai {
+            self.convert_content_parts(obj);
+            self.convert_thinking(obj);
+        }
+        for (from, path) in &self.renames {
+            if path.len() == 1 && &path[0] == from {
+                continue;
+            }
+            let Some(mut value) = obj.remove(from) else {
+                continue;
+            };
+            // OpenAI takes a single stop string, the renamed fields want lists
+            if from == "stop" && value.is_string() {
+                value = Value::Array(vec![value]);
+            }
+            insert_at(obj, path, value);
+        }
+        match self.kind {
+            AdapterKind::Gemini | AdapterKind::Vertex => vertex::translate_request(obj),
+            AdapterKind::Cohere => cohere::translate_request(obj),
+            AdapterKind::Mistral => mistral_quirks(obj),
+            // Mistral's moderation switch means nothing to the others
+            AdapterKind::Anthropic => {
+                obj.remove("safe_prompt");
+            }
+            AdapterKind::Openai => {}
+        }
+    }
+
+    // Requests and responses in a schema other than chat completions
+    pub(crate) fn translates(&self) -> bool {
+        matches!(
+            self.kind,
+            AdapterKind::Gemini | AdapterKind::Vertex | AdapterKind::Cohere
+        )
+    }
+
+    // Where a translated chat completion goes; None forwards the path as is
+    pub(crate) fn endpoint(&self, api_base: &str, json: &Value) -> Option<String> {
+        match self.kind {
+            AdapterKind::Gemini => Some(vertex::gemini_url(
+                api_base,
+                json["model"].as_str()?,
+                json["stream"] == true,
+            )),
+            AdapterKind::Vertex => Some(vertex::model_url(
+                api_base,
+                json["model"].as_str()?,
+                json["stream"] == true,
+            )),
+            AdapterKind::Cohere => Some(format!("{}/chat", api_base.trim_end_matches('/'))),
+            _ => None,
+        }
+    }
+
+    // A successful upstream response as a chat completion
+    pub(crate) fn translate_response(&self, body: &[u8], id: &str, model: &str) -> Option<Bytes> {
+        match self.kind {
+            AdapterKind::Gemini | AdapterKind::Vertex => {
+                vertex::translate_response(body, id, model)
+            }
+            AdapterKind::Cohere => cohere::translate_response(body, id, model),
+            _ => None,
+        }
+    }
+
+    // The upstream stream as chat.completion.chunk events
+    pub(crate) fn translate_stream(
+        &self,
+        inner: UpstreamStream,
+        id: &str,
+        model: &str,
+    ) -> UpstreamStream {
+        match self.kind {
+            AdapterKind::Gemini | AdapterKind::Vertex => Box::pin(TranslatedStream::new(
+                inner,
+                vertex::VertexChunks::new(id, model),
+            )),
+            AdapterKind::Cohere => Box::pin(TranslatedStream::new(
+                inner,
+                cohere::CohereChunks::new(id, model),
+            )),
+            _ => inner,
+        }
+    }
+
+    // Replaces reasoning_effort and OpenAI-style thinking with the upstream's
+    // token budget
+    fn convert_thinking(&self, obj: &mut Map<String, Value>) {
+        let Some(budget) = thinking_budget(obj) else {
+            return;
+        };
+        match self.kind {
+            AdapterKind::Anthropic if budget == 0 => {
+                obj.remove("thinking");
+            }
+            AdapterKind::Anthropic => {
+                obj.insert(
+                    "thinking".to_string(),
+                    serde_json::json!({"type": "enabled", "budget_tokens": budget}),
+                );
+            }
+            AdapterKind::Gemini | AdapterKind::Vertex => {
+                obj.remove("thinking");
+                let path = ["generationConfig", "thinkingConfig", "thinkingBudget"];
+                let path: Vec<String> = path.iter().map(|s| s.to_string()).collect();
+                insert_at(obj, &path, budget.into());
+            }
+            // No thinking budget to set
+            AdapterKind::Mistral | AdapterKind::Cohere => {
+                obj.remove("thinking");
+            }
+            AdapterKind::Openai => {}
+        }
+    }
+
+    // Rewrites image_url parts, and for Gemini also text parts, in the
+    // upstream's content schema
+    fn convert_content_parts(&self, obj: &mut Map<String, Value>) {
+        let Some(messages) = obj.get_mut("messages").and_then(|m| m.as_array_mut()) else {
+            return;
+        };
+        let parts = messages
+            .iter_mut()
+            .filter_map(|m| m.get_mut("content").and_then(|c| c.as_array_mut()))
+            .flatten();
+        for part in parts {
+            let converted = match (self.kind, part["type"].as_str()) {
+                (_, Some("image_url")) => {
+                    let url = part["image_url"]["url"]
+                        .as_str()
+                        .or(part["image_url"].as_str())
+                        .unwrap_or_default();
+                    self.image_part(url)
+                }
+                (_, Some("text")) if self.is_gemini() => {
+                    Some(serde_json::json!({"text": part["text"]}))
+                }
+                _ => None,
+            };
+            if let Some(converted) = converted {
+                *part = converted;
+            }
+        }
+    }
+
+    fn image_part(&self, url: &str) -> Option<Value> {
+        let inline = parse_data_url(url);
+        match (self.kind, inline) {
+            (AdapterKind::Anthropic, Some((media_type, data))) => Some(serde_json::json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": media_type, "data": data},
+            })),
+            (AdapterKind::Anthropic, None) => Some(serde_json::json!({
+                "type": "image",
+                "source": {"type": "url", "url": url},
+            })),
+            (AdapterKind::Gemini | AdapterKind::Vertex, Some((mime_type, data))) => {
+                Some(serde_json::json!({
+                    "inline_data": {"mime_type": mime_type, "data": data},
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Mistral wants "any" for a required tool call, tool call ids of nine letters
+// and digits, and no fields it does not know
+fn mistral_quirks(obj: &mut Map<String, Value>) {
+    const UNSUPPORTED: &[&str] = &[
+        "user",
+        "logit_bias",
+        "logprobs",
+        "top_logprobs",
+        "service_tier",
+        "store",
+        "metadata",
+        "stream_options",
+    ];
+    obj.retain(|field, _| !UNSUPPORTED.contains(&field.as_str()));
+    if obj.get("tool_choice").is_some_and(|c| c == "required") {
+        obj.insert("tool_choice".to_string(), "any".into());
+    }
+    let short_id = |id: &mut Value| {
+        if let Some(text) = id.as_str() {
+            let valid = text.len() == 9 && text.chars().all(|c| c.is_ascii_alphanumeric());
+            if !valid {
+                // The same id always maps to the same short one
+                *id = hex_encode(&Sha256::digest(text.as_bytes()))[..9].into();
+            }
+        }
+    };
+    for message in obj
+        .get_mut("messages")
+        .and_then(|m| m.as_array_mut())
+        .into_iter()
+        .flatten()
+    {
+        if let Some(id) = message.get_mut("tool_call_id") {
+            short_id(id);
+        }
+        for call in message
+            .get_mut("tool_calls")
+            .and_then(|c| c.as_array_mut())
+            .into_iter()
+            .flatten()
+        {
+            if let Some(id) = call.get_mut("id") {
+                short_id(id);
+            }
+        }
+    }
+}
+
+// Rewrites an upstream stream event by event for a translated adapter
+pub(crate) trait StreamTranslator: Send + 'static {
+    // Position and length of the separator after the first complete event
+    fn event_end(&self, buffer: &[u8]) -> Option<(usize, usize)>;
+    fn translate(&mut self, event: &[u8], out: &mut Vec<u8>);
+}
+
+// Ends with [DONE], which the translated upstreams do not send
+pub(crate) struct TranslatedStream<T> {
+    inner: UpstreamStream,
+    pending: Vec<u8>,
+    translator: T,
+    done: bool,
+}
+
+impl<T: StreamTranslator> TranslatedStream<T> {
+    fn new(inner: UpstreamStream, translator: T) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            translator,
+            done: false,
+        }
+    }
+}
+
+impl<T: StreamTranslator + Unpin> Stream for TranslatedStream<T> {
+    type Item = reqwest::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(Ok(bytes)) => {
+                    this.pending.extend_from_slice(&bytes);
+                    let mut out = Vec::new();
+                    while let Some((end, separator)) = this.translator.event_end(&this.pending) {
+                        let event: Vec<u8> = this.pending.drain(..end + separator).collect();
+                        this.translator.translate(&event[..end], &mut out);
+                    }
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(out.into())));
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => {
+                    this.done = true;
+                    let event = std::mem::take(&mut this.pending);
+                    let mut out = Vec::new();
+                    if !event.is_empty() {
+                        this.translator.translate(&event, &mut out);
+                    }
+                    out.extend_from_slice(b"data: [DONE]\n\n");
+                    return Poll::Ready(Some(Ok(out.into())));
+                }
+            }
+        }
+    }
+}
+
+// Thinking tokens asked for by reasoning_effort and thinking, which is
+// removed; 0 turns thinking off. None when the request asks for neither.
+fn thinking_budget(obj: &mut Map<String, Value>) -> Option<u64> {
+    let effort = obj
+        .remove("reasoning_effort")
+        .and_then(|e| e.as_str().and_then(ReasoningEffort::from_name));
+    let thinking = obj.get("thinking");
+    if thinking.is_none() && effort.is_none() {
+        return None;
+    }
+    if thinking.is_some_and(|t| t["type"] == "disabled") {
+        return Some(0);
+    }
+    let budget = thinking.and_then(|t| t["budget_tokens"].as_u64());
+    Some(budget.unwrap_or_else(|| effort.unwrap_or_default().budget_tokens()))
+}
+
+// Replaces a client's Anthropic-style thinking with reasoning_effort, which
+// OpenAI-compatible upstreams take instead. An effort sent along wins.
+pub(crate) fn thinking_to_effort(obj: &mut Map<String, Value>) {
+    let Some(thinking) = obj.remove("thinking") else {
+        return;
+    };
+    let budget = match thinking["type"].as_str() {
+        Some("disabled") => 0,
+        Some("enabled") => thinking["budget_tokens"]
+            .as_u64()
+            .unwrap_or(ReasoningEffort::default().budget_tokens()),
+        _ => return,
+    };
+    obj.entry("reasoning_effort")
+        .or_insert_with(|| ReasoningEffort::from_budget(budget).as_str().into());
+}
+
+// "data:image/png;base64,..." as (media type, base64 data)
+fn parse_data_url(url: &str) -> Option<(&str, &str)> {
+    let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let media_type = header.strip_suffix(";base64")?;
+    Some((media_type, data))
+}
+
+fn insert_at(obj: &mut Map<String, Value>, path: &[String], value: Value) {
+    let (last, parents) = path.split_last().expect("rename targets are not empty");
+    let mut current = obj;
+    for key in parents {
+        let next = current
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        match next.as_object_mut() {
+            Some(next) => current = next,
+            None => return,
+        }
+    }
+    current.entry(last.clone()).or_insert(value);
+}
+
+// Headers carrying provider beta flags
+const BETA_HEADERS: [&str; 2] = ["openai-beta", "anthropic-beta"];
+
+// Which of the beta headers an upstream type understands
+fn beta_header(kind: AdapterKind) -> Option<&'static str> {
+    match kind {
+        AdapterKind::Openai => Some("openai-beta"),
+        AdapterKind::Anthropic => Some("anthropic-beta"),
+        AdapterKind::Gemini | AdapterKind::Vertex | AdapterKind::Mistral | AdapterKind::Cohere => {
+            None
+        }
+    }
+}
+
+// Flags an upstream type needs on some endpoints: (header, value, paths)
+fn beta_preset(
+    kind: AdapterKind,
+) -> &'static [(&'static str, &'static str, &'static [&'static str])] {
+    match kind {
+        AdapterKind::Openai => &[(
+            "openai-beta",
+            "assistants=v2",
+            &["assistants", "threads", "vector_stores"],
+        )],
+        _ => &[],
+    }
+}
+
+// Whether a path has one of the segments; any path when there are none
+fn on_paths<'a>(path: &str, paths: impl IntoIterator<Item = &'a str>) -> bool {
+    let mut paths = paths.into_iter().peekable();
+    paths.peek().is_none() || paths.any(|p| path.split('/').any(|s| s == p))
+}
+
+pub(crate) fn is_beta_header(name: &str) -> bool {
+    BETA_HEADERS.contains(&name)
+}
+
+// The beta headers sent to one upstream, replacing the client's: the client's
+// flags for headers the provider understands, merged with the flags the
+// adapter and the provider's beta_headers require for this request. The
+// default upstream (no provider) gets every client flag and OpenAI's preset.
+pub(crate) fn beta_headers(
+    provider: Option<&ProviderConfig>,
+    client: &HeaderMap,
+    path: &str,
+    body: Option<&Value>,
+    extra: &[(&str, &str)],
+) -> Vec<(String, String)> {
+    let kind = provider.map_or(AdapterKind::Openai, |p| p.adapter);
+    let mut required: Vec<(String, &str)> = beta_preset(kind)
+        .iter()
+        .filter(|(_, _, paths)| on_paths(path, paths.iter().copied()))
+        .map(|(header, value, _)| (header.to_string(), *value))
+        .collect();
+    for rule in provider.map_or(&[][..], |p| &p.beta_headers[..]) {
+        let Some(header) = rule.header.as_deref().or(beta_header(kind)) else {
+            continue;
+        };
+        let requested = rule.fields.is_empty()
+            || body.is_some_and(|b| rule.fields.iter().any(|f| !b[f.as_str()].is_null()));
+        if requested && on_paths(path, rule.paths.iter().map(|p| p.as_str())) {
+            required.push((header.to_lowercase(), rule.value.as_str()));
+        }
+    }
+    required.extend(extra.iter().map(|(h, v)| (h.to_string(), *v)));
+
+    let understood = |name: &str| provider.is_none() || beta_header(kind) == Some(name);
+    let mut names: Vec<String> = BETA_HEADERS
+        .iter()
+        .filter(|name| understood(name))
+        .map(|name| name.to_string())
+        .collect();
+    for (header, _) in &required {
+        if !names.contains(header) {
+            names.push(header.clone());
+        }
+    }
+    let mut out = Vec::new();
+    for name in names {
+        let mut values: Vec<&str> = Vec::new();
+        let from_client = client
+            .get_all(name.as_str())
+            .iter()
+            .filter(|_| understood(&name))
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        let from_config = required
+            .iter()
+            .filter(|(header, _)| *header == name)
+            .map(|(_, value)| *value);
+        for value in from_client.chain(from_config).map(str::trim) {
+            if !value.is_empty() && !values.contains(&value) {
+                values.push(value);
+            }
+        }
+        if !values.is_empty() {
+            out.push((name, values.join(",")));
+        }
+    }
+    out
+}
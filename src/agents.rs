@@ -0,0 +1,326 @@
+// Composite models: a bounded agent loop that runs declared HTTP tools locally
+
+use crate::config::AgentToolConfig;
+use crate::error::ProxyError;
+use crate::proxy::proxy_handler;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header::HeaderValue, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Tool output beyond this is cut before it is fed back to the model
+const MAX_TOOL_OUTPUT_CHARS: usize = 16_000;
+
+pub(crate) async fn agent_handler(
+    state: State<Arc<AppState>>,
+    connect_info: ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    req: Request,
+) -> Response {
+    run_agent(state, connect_info, headers, None, req)
+        .await
+        .unwrap_or_else(|err| err.into_response())
+}
+
+pub(crate) async fn tenant_agent_handler(
+    state: State<Arc<AppState>>,
+    connect_info: ConnectInfo<SocketAddr>,
+    Path(tenant): Path<String>,
+    headers: HeaderMap,
+    req: Request,
+) -> Response {
+    run_agent(state, connect_info, headers, Some(tenant), req)
+        .await
+        .unwrap_or_else(|err| err.into_response())
+}
+
+async fn run_agent(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    tenant: Option<String>,
+    req: Request,
+) -> Result<Response, ProxyError> {
+    let body = axum::body::to_bytes(req.into_body(), usize::MAX)
+        .await
+        .map_err(|e| ProxyError::BodyReadError(e.to_string()))?;
+    let request: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid JSON body: {}", e)))?;
+    let run = AgentRun::new(&state, tenant, request)?;
+
+    // x-proxy-async: 1 runs the agent as a journaled background job
+    let async_run = headers
+        .get("x-proxy-async")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| !matches!(v.trim().to_lowercase().as_str(), "" | "0" | "false" | "no"));
+    if async_run {
+        let jobs = state.agent_jobs.as_ref().ok_or_else(|| {
+            ProxyError::InvalidRequest("Asynchronous agent runs need [agent_jobs]".to_string())
+        })?;
+        let job = jobs.submit(&state, run, step_headers(&headers), peer);
+        let mut response = crate::router::json_response(&job);
+        *response.status_mut() = StatusCode::ACCEPTED;
+        return Ok(response);
+    }
+    run.execute(&state, peer, &headers, None).await
+}
+
+// A composite model run: the request without its messages, the conversation
+// so far and the steps it took, enough to resume it after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AgentRun {
+    pub(crate) model: String,
+    pub(crate) tenant: Option<String>,
+    request: serde_json::Map<String, serde_json::Value>,
+    messages: Vec<serde_json::Value>,
+    // Model calls completed
+    pub(crate) steps: usize,
+    // Prompt and completion tokens of those calls
+    usage: (u64, u64),
+}
+
+impl AgentRun {
+    // The user field of the request
+    pub(crate) fn user(&self) -> Option<&str> {
+        self.request.get("user")?.as_str()
+    }
+
+    fn new(
+        state: &AppState,
+        tenant: Option<String>,
+        mut request: serde_json::Value,
+    ) -> Result<Self, ProxyError> {
+        let id = request["model"].as_str().unwrap_or_default().to_string();
+        let composite = state
+            .composite_models
+            .iter()
+            .find(|m| m.id == id)
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest(format!("Unknown composite model: {}", id))
+            })?;
+        if request["stream"].as_bool() == Some(true) {
+            return Err(ProxyError::InvalidRequest(
+                "Composite models do not support streaming".to_string(),
+            ));
+        }
+
+        let obj = request
+            .as_object_mut()
+            .ok_or_else(|| ProxyError::InvalidRequest("Expected a JSON object".to_string()))?;
+        let mut messages = match obj.remove("messages") {
+            Some(serde_json::Value::Array(messages)) => messages,
+            _ => {
+                return Err(ProxyError::InvalidRequest(
+                    "messages must be an array".to_string(),
+                ))
+            }
+        };
+        if let Some(prompt) = &composite.system_prompt {
+            messages.insert(0, serde_json::json!({"role": "system", "content": prompt}));
+        }
+        // The composite model's tools replace any the client sent
+        obj.insert("model".to_string(), composite.model.clone().into());
+        obj.remove("tool_choice");
+        if composite.tools.is_empty() {
+            obj.remove("tools");
+        } else {
+            let tools: Vec<serde_json::Value> =
+                composite.tools.iter().map(tool_definition).collect();
+            obj.insert("tools".to_string(), tools.into());
+        }
+        Ok(Self {
+            model: id,
+            tenant,
+            request: obj.clone(),
+            messages,
+            steps: 0,
+            usage: (0, 0),
+        })
+    }
+
+    // Runs the remaining steps. A job's progress is journaled after each one.
+    pub(crate) async fn execute(
+        mut self,
+        state: &Arc<AppState>,
+        peer: SocketAddr,
+        headers: &HeaderMap,
+        job: Option<&str>,
+    ) -> Result<Response, ProxyError> {
+        let composite = state
+            .composite_models
+            .iter()
+            .find(|m| m.id == self.model)
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest(format!("Unknown composite model: {}", self.model))
+            })?;
+        let upstream_path = match &self.tenant {
+            Some(tenant) => format!("/t/{}/v3/chat/completions", tenant),
+            None => "/v3/chat/completions".to_string(),
+        };
+        let max_steps = composite.max_steps.max(1);
+        for step in self.steps + 1..=max_steps {
+            let mut step_request = serde_json::Value::Object(self.request.clone());
+            step_request["messages"] = self.messages.clone().into();
+            // The last step has to answer instead of calling more tools
+            if step == max_steps && !composite.tools.is_empty() {
+                step_request["tool_choice"] = "none".into();
+            }
+
+            // Each step goes through the regular proxy path, with its auth, limits and logging
+            let inner = Request::builder()
+                .method(Method::POST)
+                .uri(&upstream_path)
+                .body(Body::from(step_request.to_string()))
+                .map_err(|e| ProxyError::RequestError(e.to_string()))?;
+            let response = proxy_handler(
+                State(state.clone()),
+                ConnectInfo(peer),
+                step_headers(headers),
+                inner,
+            )
+            .await;
+            if !response.status().is_success() {
+                return Ok(response);
+            }
+            let response_body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .map_err(|e| ProxyError::ResponseError(e.to_string()))?;
+            let mut completion: serde_json::Value = serde_json::from_slice(&response_body)
+                .map_err(|e| ProxyError::ResponseError(format!("Invalid upstream JSON: {}", e)))?;
+            if let Some((prompt_tokens, completion_tokens)) =
+                crate::transform::usage_from_json(&completion)
+            {
+                self.usage.0 += prompt_tokens;
+                self.usage.1 += completion_tokens;
+            }
+
+            let message = completion["choices"][0]["message"].clone();
+            let tool_calls = message["tool_calls"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            if tool_calls.is_empty() || step == max_steps {
+                println!("🤖 Agent {} answered after {} step(s)", composite.id, step);
+                completion["model"] = composite.id.clone().into();
+                completion["usage"] = serde_json::json!({
+                    "prompt_tokens": self.usage.0,
+                    "completion_tokens": self.usage.1,
+                    "total_tokens": self.usage.0 + self.usage.1,
+                });
+                let mut response = crate::router::json_response(&completion);
+                response
+                    .headers_mut()
+                    .insert("x-proxy-agent-steps", HeaderValue::from(step));
+                return Ok(response);
+            }
+
+            self.messages.push(message);
+            for call in &tool_calls {
+                let name = call["function"]["name"].as_str().unwrap_or_default();
+                println!(
+                    "🤖 Agent {} step {} calls tool {}",
+                    composite.id, step, name
+                );
+                let output = match composite.tools.iter().find(|t| t.name == name) {
+                    Some(tool) => call_tool(state, tool, &call["function"]["arguments"]).await,
+                    None => format!("Error: unknown tool {}", name),
+                };
+                self.messages.push(serde_json::json!({
+                    "role": "tool",
+                    "tool_call_id": call["id"],
+                    "content": output,
+                }));
+            }
+            self.steps = step;
+            if let (Some(id), Some(jobs)) = (job, &state.agent_jobs) {
+                jobs.record_step(id, &self);
+            }
+        }
+        unreachable!("the last step always answers")
+    }
+}
+
+// Client headers for an agent step. HMAC signatures cover the original body
+// only, so agent requests authenticate with bearer keys.
+pub(crate) fn step_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut step_headers = headers.clone();
+    step_headers.remove("content-length");
+    step_headers.insert("content-type", HeaderValue::from_static("application/json"));
+    step_headers
+}
+
+fn tool_definition(tool: &AgentToolConfig) -> serde_json::Value {
+    serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": tool.name,
+            "description": tool.description,
+            "parameters": tool.parameters,
+        },
+    })
+}
+
+// Runs a tool and returns its output, or the error, as text for the model
+async fn call_tool(
+    state: &AppState,
+    tool: &AgentToolConfig,
+    arguments: &serde_json::Value,
+) -> String {
+    // Arguments arrive as a JSON-encoded string
+    let arguments: serde_json::Value = match arguments {
+        serde_json::Value::String(text) => match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) => return format!("Error: arguments are not valid JSON: {}", e),
+        },
+        value => value.clone(),
+    };
+
+    let mut request = if tool.method.eq_ignore_ascii_case("GET") {
+        let query: HashMap<String, String> = arguments
+            .as_object()
+            .map(|args| {
+                args.iter()
+                    .map(|(k, v)| {
+                        (
+                            k.clone(),
+                            v.as_str()
+                                .map(str::to_string)
+                                .unwrap_or_else(|| v.to_string()),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        state.client.get(&tool.url).query(&query)
+    } else {
+        state.client.post(&tool.url).json(&arguments)
+    };
+    for (name, value) in &tool.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    let response = match request
+        .timeout(Duration::from_millis(tool.timeout_ms))
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => return format!("Error: tool request failed: {}", e),
+    };
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    let text: String = text.chars().take(MAX_TOOL_OUTPUT_CHARS).collect();
+    if status.is_success() {
+        text
+    } else {
+        format!("Error: tool returned {}: {}", status.as_u16(), text)
+    }
+}
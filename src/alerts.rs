@@ -0,0 +1,173 @@
+// Sliding-window degradation alerts
+
+use crate::config::AlertsConfig;
+use crate::leader::Leadership;
+use crate::proxy::RequestLog;
+use crate::time::{format_utc, unix_now};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub(crate) struct AlertSample {
+    pub(crate) at: Instant,
+    pub(crate) latency_ms: u64,
+    pub(crate) error: bool,
+    // None when the response carried no completion usage, e.g. embeddings
+    pub(crate) zero_tokens: Option<bool>,
+}
+
+// Keeps recent request outcomes per provider and model and warns when a
+// threshold is exceeded
+pub(crate) struct AlertMonitor {
+    pub(crate) config: AlertsConfig,
+    pub(crate) client: reqwest::Client,
+    // Keyed by (provider, model)
+    pub(crate) samples: Mutex<HashMap<(String, String), VecDeque<AlertSample>>>,
+    // Keyed by (provider, model, alert)
+    pub(crate) last_fired: Mutex<HashMap<(String, String, &'static str), Instant>>,
+    // Replicas claim each webhook, so an alert seen by several is sent once
+    pub(crate) leadership: Arc<Leadership>,
+}
+
+// Oldest samples are dropped beyond this many per provider and model
+pub(crate) const MAX_ALERT_SAMPLES: usize = 10_000;
+
+impl AlertMonitor {
+    pub(crate) fn new(
+        config: AlertsConfig,
+        client: reqwest::Client,
+        leadership: Arc<Leadership>,
+    ) -> Self {
+        Self {
+            config,
+            client,
+            leadership,
+            samples: Mutex::new(HashMap::new()),
+            last_fired: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        log: &RequestLog,
+        status: u16,
+        latency_ms: u64,
+        completion_tokens: Option<u64>,
+    ) {
+        let model = log.model.clone().unwrap_or_else(|| "unknown".to_string());
+        let key = (log.provider.clone(), model);
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_secs);
+        let success = (200..300).contains(&status);
+
+        let mut samples = self.samples.lock().unwrap();
+        let window_samples = samples.entry(key.clone()).or_default();
+        window_samples.push_back(AlertSample {
+            at: now,
+            latency_ms,
+            error: status >= 500,
+            zero_tokens: completion_tokens
+                .filter(|_| success)
+                .map(|tokens| tokens == 0),
+        });
+        while window_samples.len() > MAX_ALERT_SAMPLES
+            || window_samples
+                .front()
+                .is_some_and(|s| now.duration_since(s.at) > window)
+        {
+            window_samples.pop_front();
+        }
+
+        let count = window_samples.len();
+        if count < self.config.min_requests {
+            return;
+        }
+
+        let mut latencies: Vec<u64> = window_samples.iter().map(|s| s.latency_ms).collect();
+        latencies.sort_unstable();
+        let p99 = latencies[(count * 99).div_ceil(100) - 1];
+        let errors = window_samples.iter().filter(|s| s.error).count();
+        let completions = window_samples.iter().filter_map(|s| s.zero_tokens);
+        let (completion_count, zero_count) = completions.fold((0, 0), |(total, zero), is_zero| {
+            (total + 1, zero + usize::from(is_zero))
+        });
+        drop(samples);
+
+        if let Some(threshold) = self.config.p99_latency_ms {
+            if p99 > threshold {
+                self.fire(&key, "p99_latency_ms", p99 as f64, threshold as f64, count);
+            }
+        }
+        if let Some(threshold) = self.config.error_rate {
+            let rate = errors as f64 / count as f64;
+            if rate > threshold {
+                self.fire(&key, "error_rate", rate, threshold, count);
+            }
+        }
+        if let Some(threshold) = self.config.zero_token_rate {
+            if completion_count > 0 {
+                let rate = zero_count as f64 / completion_count as f64;
+                if rate > threshold {
+                    self.fire(&key, "zero_token_rate", rate, threshold, count);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn fire(
+        &self,
+        key: &(String, String),
+        alert: &'static str,
+        value: f64,
+        threshold: f64,
+        requests: usize,
+    ) {
+        let (provider, model) = key;
+        {
+            let mut last_fired = self.last_fired.lock().unwrap();
+            let cooldown = Duration::from_secs(self.config.cooldown_secs);
+            let fired_key = (provider.clone(), model.clone(), alert);
+            if last_fired
+                .get(&fired_key)
+                .is_some_and(|at| at.elapsed() < cooldown)
+            {
+                return;
+            }
+            last_fired.insert(fired_key, Instant::now());
+        }
+
+        eprintln!(
+            "🚨 Alert {} for {} on {}: {:.3} exceeds {} over the last {}s ({} requests)",
+            alert, model, provider, value, threshold, self.config.window_secs, requests
+        );
+
+        if let Some(url) = &self.config.webhook_url {
+            let payload = serde_json::json!({
+                "alert": alert,
+                "provider": provider,
+                "model": model,
+                "value": value,
+                "threshold": threshold,
+                "window_secs": self.config.window_secs,
+                "requests": requests,
+                "timestamp": format_utc(unix_now()),
+            });
+            let request = self.client.post(url).json(&payload);
+            let leadership = self.leadership.clone();
+            let claim = format!("alert:{}:{}:{}", provider, model, alert);
+            let cooldown_secs = self.config.cooldown_secs;
+            tokio::spawn(async move {
+                if !leadership.claim(&claim, cooldown_secs).await {
+                    return;
+                }
+                match request.send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        eprintln!("⚠️  Alert webhook returned {}", response.status());
+                    }
+                    Err(err) => eprintln!("⚠️  Alert webhook failed: {}", err),
+                    Ok(_) => {}
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Audit trail of admin changes: every authorized admin request other than a
+// read is recorded with its outcome. With [storage] the trail is shared, so
+// /admin/audit lists the changes made through any replica.
+
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::storage::{MemoryStorage, Storage, StorageResult, Store};
+use crate::time::{format_utc, unix_now};
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, Method},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// The trail is kept this long after its latest entry
+const AUDIT_TTL_SECS: u64 = 90 * 24 * 3600;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) at: String,
+    // The replica that handled it
+    pub(crate) instance: String,
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) status: u16,
+}
+
+pub(crate) struct AuditLog {
+    store: Option<Arc<Store>>,
+    // Without storage the trail lasts as long as the process
+    local: MemoryStorage,
+}
+
+impl AuditLog {
+    pub(crate) fn new(store: Option<Arc<Store>>) -> Self {
+        Self {
+            store,
+            local: MemoryStorage::default(),
+        }
+    }
+
+    fn log(&self) -> (&dyn Storage, String) {
+        match &self.store {
+            Some(store) => (store.backend.as_ref(), store.key("audit")),
+            None => (&self.local, "audit".to_string()),
+        }
+    }
+
+    pub(crate) async fn record(&self, entry: &AuditEntry) -> StorageResult<()> {
+        let (backend, key) = self.log();
+        let entry = serde_json::to_vec(entry).map_err(|e| e.to_string())?;
+        backend.append(&key, entry, Some(AUDIT_TTL_SECS)).await
+    }
+
+    // Oldest first; entries that no longer parse are skipped
+    pub(crate) async fn entries(&self) -> StorageResult<Vec<AuditEntry>> {
+        let (backend, key) = self.log();
+        Ok(backend
+            .range(&key, 0)
+            .await?
+            .iter()
+            .filter_map(|entry| serde_json::from_slice(entry).ok())
+            .collect())
+    }
+}
+
+// Middleware recording admin changes once they are answered. Requests without
+// the admin key are not recorded, so they cannot flood the trail.
+pub(crate) async fn audit_admin_changes(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let audited = request.uri().path().starts_with("/admin/")
+        && request.method() != Method::GET
+        && state.check_admin(request.headers()).is_ok();
+    if !audited {
+        return next.run(request).await;
+    }
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+    let entry = AuditEntry {
+        at: format_utc(unix_now()),
+        instance: state.leadership.instance.clone(),
+        method,
+        path,
+        status: response.status().as_u16(),
+    };
+    if let Err(err) = state.audit.record(&entry).await {
+        eprintln!("⚠️  Failed to record admin change {:?}: {}", entry, err);
+    }
+    response
+}
+
+pub(crate) async fn audit_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let entries = state
+        .audit
+        .entries()
+        .await
+        .map_err(|err| ProxyError::Unavailable(format!("Audit trail unavailable: {}", err)))?;
+    Ok(json_response(
+        &serde_json::json!({"object": "list", "data": entries}),
+    ))
+}
@@ -0,0 +1,316 @@
+// `openai_proxy bench`: synthetic chat traffic against the proxy or straight
+// at upstreams, for capacity planning and comparing providers
+
+use crate::config::Settings;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// An OpenAI-compatible base URL, e.g. http://127.0.0.1:8080/v3
+#[derive(Debug, Clone)]
+pub struct BenchTarget {
+    pub name: String,
+    pub api_base: String,
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub model: String,
+    pub requests: usize,
+    pub concurrency: usize,
+    // Length of the synthetic user message
+    pub prompt_chars: usize,
+    pub max_tokens: u64,
+    pub stream: bool,
+    pub timeout: Duration,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            model: "gpt-4o-mini".to_string(),
+            requests: 100,
+            concurrency: 10,
+            prompt_chars: 1000,
+            max_tokens: 128,
+            stream: true,
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+impl BenchTarget {
+    // The proxy the settings describe, or the named providers' upstreams
+    pub fn from_settings(settings: &Settings, providers: &[String]) -> Result<Vec<Self>, String> {
+        if providers.is_empty() {
+            let address = match settings.server_ip() {
+                Some(IpAddr::V4(ip)) if ip.is_unspecified() => {
+                    format!("127.0.0.1:{}", settings.server_port)
+                }
+                Some(IpAddr::V6(ip)) if ip.is_unspecified() => {
+                    format!("[::1]:{}", settings.server_port)
+                }
+                _ => settings.server_address(),
+            };
+            return Ok(vec![Self {
+                name: "proxy".to_string(),
+                api_base: format!("http://{}/v3", address),
+                api_key: None,
+            }]);
+        }
+        providers
+            .iter()
+            .map(|name| {
+                let provider = settings
+                    .providers
+                    .iter()
+                    .find(|p| &p.name == name)
+                    .ok_or_else(|| format!("Unknown provider {}", name))?;
+                Ok(Self {
+                    name: name.clone(),
+                    api_base: provider.api_base.clone(),
+                    api_key: Some(
+                        provider
+                            .api_key
+                            .clone()
+                            .unwrap_or_else(|| settings.openai_api_key.clone()),
+                    ),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetReport {
+    pub target: String,
+    pub requests: usize,
+    pub errors: usize,
+    pub error_rate: f64,
+    pub duration_secs: f64,
+    pub requests_per_second: f64,
+    // Time to the first content delta; the whole response without streaming
+    pub ttft_ms_p50: Option<u64>,
+    pub ttft_ms_p95: Option<u64>,
+    pub latency_ms_p50: Option<u64>,
+    pub latency_ms_p95: Option<u64>,
+    // Completion tokens per second of generation, averaged over requests
+    pub tokens_per_second: Option<f64>,
+    pub completion_tokens: u64,
+    // One of the errors, to see what went wrong
+    pub sample_error: Option<String>,
+}
+
+impl TargetReport {
+    // One line per target for the terminal
+    pub fn summary(&self) -> String {
+        let ms = |v: Option<u64>| v.map_or("-".to_string(), |v| format!("{}ms", v));
+        format!(
+            "{}: {} requests, {:.1}% errors, {:.1} req/s, TTFT p50 {} p95 {}, latency p50 {} p95 {}, {} tok/s",
+            self.target,
+            self.requests,
+            self.error_rate * 100.0,
+            self.requests_per_second,
+            ms(self.ttft_ms_p50),
+            ms(self.ttft_ms_p95),
+            ms(self.latency_ms_p50),
+            ms(self.latency_ms_p95),
+            self.tokens_per_second
+                .map_or("-".to_string(), |v| format!("{:.1}", v)),
+        )
+    }
+}
+
+// Measurements of one successful request
+struct Sample {
+    ttft: Duration,
+    latency: Duration,
+    completion_tokens: u64,
+}
+
+// Runs the targets one after another, so they do not compete for bandwidth
+pub async fn run(targets: &[BenchTarget], options: &BenchOptions) -> Vec<TargetReport> {
+    let client = reqwest::Client::builder()
+        .timeout(options.timeout)
+        .build()
+        .unwrap_or_default();
+    let mut reports = Vec::new();
+    for target in targets {
+        reports.push(run_target(&client, target, options).await);
+    }
+    reports
+}
+
+async fn run_target(
+    client: &reqwest::Client,
+    target: &BenchTarget,
+    options: &BenchOptions,
+) -> TargetReport {
+    let next = Arc::new(AtomicUsize::new(0));
+    let samples = Arc::new(Mutex::new(Vec::new()));
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let started = Instant::now();
+    let mut workers = Vec::new();
+    for _ in 0..options.concurrency.clamp(1, options.requests.max(1)) {
+        let (client, target, options) = (client.clone(), target.clone(), options.clone());
+        let (next, samples, errors) = (next.clone(), samples.clone(), errors.clone());
+        workers.push(tokio::spawn(async move {
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= options.requests {
+                    break;
+                }
+                match send(&client, &target, &options, i).await {
+                    Ok(sample) => samples.lock().unwrap().push(sample),
+                    Err(err) => errors.lock().unwrap().push(err),
+                }
+            }
+        }));
+    }
+    for worker in workers {
+        let _ = worker.await;
+    }
+    let duration = started.elapsed();
+
+    let samples = std::mem::take(&mut *samples.lock().unwrap());
+    let errors = std::mem::take(&mut *errors.lock().unwrap());
+    let requests = samples.len() + errors.len();
+    let mut ttfts: Vec<u64> = samples.iter().map(|s| s.ttft.as_millis() as u64).collect();
+    let mut latencies: Vec<u64> = samples
+        .iter()
+        .map(|s| s.latency.as_millis() as u64)
+        .collect();
+    let speeds: Vec<f64> = samples
+        .iter()
+        .filter(|s| s.completion_tokens > 0)
+        .map(|s| {
+            // Without streaming the whole response counts as generation
+            let generation = match s.latency.saturating_sub(s.ttft) {
+                d if d.is_zero() => s.latency,
+                d => d,
+            };
+            s.completion_tokens as f64 / generation.as_secs_f64().max(0.001)
+        })
+        .collect();
+    TargetReport {
+        target: target.name.clone(),
+        requests,
+        errors: errors.len(),
+        error_rate: match requests {
+            0 => 0.0,
+            n => errors.len() as f64 / n as f64,
+        },
+        duration_secs: duration.as_secs_f64(),
+        requests_per_second: requests as f64 / duration.as_secs_f64().max(0.001),
+        ttft_ms_p50: percentile(&mut ttfts, 0.50),
+        ttft_ms_p95: percentile(&mut ttfts, 0.95),
+        latency_ms_p50: percentile(&mut latencies, 0.50),
+        latency_ms_p95: percentile(&mut latencies, 0.95),
+        tokens_per_second: match speeds.len() {
+            0 => None,
+            n => Some(speeds.iter().sum::<f64>() / n as f64),
+        },
+        completion_tokens: samples.iter().map(|s| s.completion_tokens).sum(),
+        sample_error: errors.into_iter().next(),
+    }
+}
+
+pub(crate) fn percentile(values: &mut [u64], p: f64) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let rank = ((values.len() as f64 * p).ceil() as usize).clamp(1, values.len());
+    Some(values[rank - 1])
+}
+
+// Filler text; the request number up front keeps response caches out of it
+fn prompt(i: usize, chars: usize) -> String {
+    const WORDS: &str = "the quick brown fox jumps over the lazy dog while ";
+    let mut text = format!("Request {}. Summarize: ", i);
+    while text.len() < chars {
+        text.push_str(WORDS);
+    }
+    text.truncate(chars.max(1));
+    text
+}
+
+async fn send(
+    client: &reqwest::Client,
+    target: &BenchTarget,
+    options: &BenchOptions,
+    i: usize,
+) -> Result<Sample, String> {
+    let mut body = serde_json::json!({
+        "model": options.model,
+        "messages": [{"role": "user", "content": prompt(i, options.prompt_chars)}],
+        "max_tokens": options.max_tokens,
+    });
+    if options.stream {
+        body["stream"] = true.into();
+        body["stream_options"] = serde_json::json!({"include_usage": true});
+    }
+    let url = format!("{}/chat/completions", target.api_base.trim_end_matches('/'));
+    let mut request = client.post(url).json(&body);
+    if let Some(key) = &target.api_key {
+        request = request.bearer_auth(key);
+    }
+
+    let sent = Instant::now();
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!(
+            "{}: {}",
+            status,
+            text.chars().take(200).collect::<String>()
+        ));
+    }
+    if !options.stream {
+        let json: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+        let latency = sent.elapsed();
+        return Ok(Sample {
+            ttft: latency,
+            latency,
+            completion_tokens: json["usage"]["completion_tokens"].as_u64().unwrap_or(0),
+        });
+    }
+
+    let mut stream = response.bytes_stream();
+    let mut buffer = Vec::new();
+    let mut ttft = None;
+    let mut deltas = 0;
+    let mut usage_tokens = None;
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let Ok(event) = serde_json::from_str::<serde_json::Value>(data.trim()) else {
+                continue;
+            };
+            let content = &event["choices"][0]["delta"]["content"];
+            if content.as_str().is_some_and(|c| !c.is_empty()) {
+                ttft.get_or_insert_with(|| sent.elapsed());
+                deltas += 1;
+            }
+            if let Some(tokens) = event["usage"]["completion_tokens"].as_u64() {
+                usage_tokens = Some(tokens);
+            }
+        }
+    }
+    let latency = sent.elapsed();
+    Ok(Sample {
+        ttft: ttft.unwrap_or(latency),
+        latency,
+        completion_tokens: usage_tokens.unwrap_or(deltas),
+    })
+}
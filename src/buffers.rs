@@ -0,0 +1,101 @@
+// Reusable body buffers, so MB-scale embedding and vision payloads don't cost
+// fresh allocations on every request. A body is split off a pooled buffer and
+// frozen; the buffer goes back to the pool, and once every body split off it
+// has been dropped, its next reserve() reuses the whole allocation.
+
+use axum::body::Bytes;
+use bytes::{BufMut, BytesMut};
+use futures_util::{Stream, StreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Capacity idle buffers may hold in total, and how many are kept
+const MAX_POOLED_BYTES: usize = 64 << 20;
+const MAX_POOLED_BUFFERS: usize = 64;
+// Content-Length is only trusted this far before the body has arrived
+const MAX_PRESIZE: usize = 16 << 20;
+
+#[derive(Default)]
+pub(crate) struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    reused: AtomicU64,
+    allocated: AtomicU64,
+}
+
+impl BufferPool {
+    fn take(&self, capacity: usize) -> BytesMut {
+        match self.free.lock().unwrap().pop() {
+            Some(mut buf) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buf.reserve(capacity);
+                buf
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(capacity)
+            }
+        }
+    }
+
+    fn give(&self, mut buf: BytesMut) {
+        buf.clear();
+        if buf.capacity() == 0 {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        let pooled: usize = free.iter().map(|b| b.capacity()).sum();
+        if free.len() < MAX_POOLED_BUFFERS && pooled + buf.capacity() <= MAX_POOLED_BYTES {
+            free.push(buf);
+        }
+    }
+
+    // A whole request or response body in one piece, sized from its
+    // Content-Length up front
+    pub(crate) async fn read<S, E>(&self, stream: S, length: Option<u64>) -> Result<Bytes, E>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let length = length.unwrap_or(0).min(MAX_PRESIZE as u64) as usize;
+        let mut buf = self.take(length);
+        let mut stream = std::pin::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        let bytes = buf.split().freeze();
+        self.give(buf);
+        Ok(bytes)
+    }
+
+    pub(crate) fn serialize(&self, json: &serde_json::Value) -> Bytes {
+        let buf = self.take(0);
+        let mut writer = buf.writer();
+        // Writing a Value into memory cannot fail
+        let _ = serde_json::to_writer(&mut writer, json);
+        let mut buf = writer.into_inner();
+        let bytes = buf.split().freeze();
+        self.give(buf);
+        bytes
+    }
+
+    // Appended to the Prometheus output
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            (
+                "body_buffers_reused_total",
+                "Body buffers taken from the pool.",
+                self.reused.load(Ordering::Relaxed),
+            ),
+            (
+                "body_buffers_allocated_total",
+                "Body buffers allocated because the pool was empty.",
+                self.allocated.load(Ordering::Relaxed),
+            ),
+        ] {
+            out.push_str(&format!("# HELP openai_proxy_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE openai_proxy_{} counter\n", name));
+            out.push_str(&format!("openai_proxy_{} {}\n", name, value));
+        }
+        out
+    }
+}
@@ -0,0 +1,648 @@
+// Response cache: a bounded in-memory LRU in front of an optional disk store
+// and an optional shared storage tier
+
+use crate::config::{DiskCacheConfig, ResponseCacheConfig};
+use crate::secrets::hex_encode;
+use crate::storage::{StorageResult, Store};
+use crate::time::unix_now;
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct CachedResponse {
+    pub(crate) status: u16,
+    pub(crate) content_type: String,
+    // Unix seconds
+    pub(crate) expires: u64,
+    // The upstream's, for revalidating the entry once it expires
+    #[serde(default)]
+    pub(crate) etag: Option<String>,
+    // The user field of the request that stored it, for erasure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+    #[serde(skip)]
+    pub(crate) body: Bytes,
+}
+
+impl CachedResponse {
+    pub(crate) fn new(
+        status: u16,
+        content_type: &str,
+        etag: Option<&str>,
+        user: Option<&str>,
+        body: Bytes,
+    ) -> Self {
+        Self {
+            status,
+            content_type: content_type.to_string(),
+            expires: 0,
+            etag: etag.map(str::to_string),
+            user: user.map(str::to_string),
+            body,
+        }
+    }
+
+    // A JSON header line, then the body
+    fn encode(&self) -> Vec<u8> {
+        let mut data = serde_json::to_vec(self).unwrap_or_default();
+        data.push(b'\n');
+        data.extend_from_slice(&self.body);
+        data
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Self> {
+        let split = data
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No entry header"))?;
+        let mut entry: CachedResponse = serde_json::from_slice(&data[..split])?;
+        entry.body = Bytes::copy_from_slice(&data[split + 1..]);
+        Ok(entry)
+    }
+
+    // Expired entries with an ETag are kept until the upstream confirms them
+    fn revalidatable(&self) -> bool {
+        self.etag.is_some()
+    }
+
+    // The entry as served, or 304 when the client already has it
+    pub(crate) fn into_response(
+        self,
+        outcome: &'static str,
+        if_none_match: Option<&str>,
+    ) -> Response {
+        let not_modified = if_none_match.is_some_and(|tags| self.matches(tags));
+        let mut resp = if not_modified {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_MODIFIED;
+            resp
+        } else {
+            let mut resp = Response::new(Body::from(self.body));
+            *resp.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+            if let Ok(value) = HeaderValue::from_str(&self.content_type) {
+                resp.headers_mut().insert("content-type", value);
+            }
+            resp
+        };
+        if let Some(value) = self.etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+            resp.headers_mut().insert("etag", value);
+        }
+        resp.headers_mut()
+            .insert("x-proxy-cache", HeaderValue::from_static(outcome));
+        resp
+    }
+
+    // Whether an If-None-Match header lists this entry's ETag
+    pub(crate) fn matches(&self, if_none_match: &str) -> bool {
+        let Some(etag) = &self.etag else {
+            return false;
+        };
+        let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+    }
+}
+
+pub(crate) struct ResponseCache {
+    pub(crate) config: ResponseCacheConfig,
+    memory: Mutex<Lru<CachedResponse>>,
+    disk: Option<DiskStore>,
+    // Entries seen by every replica
+    shared: Option<Arc<Store>>,
+    // The retention of response bodies, which caps every TTL
+    max_age_secs: Option<u64>,
+    // Misses being fetched, by key
+    inflight: Mutex<HashMap<String, watch::Receiver<Option<CachedResponse>>>>,
+}
+
+pub(crate) enum Lookup<'a> {
+    Hit(CachedResponse),
+    // Answered by an identical request that was already fetching it
+    Shared(CachedResponse),
+    // Fetch it; identical requests wait for the flight's response
+    Miss(Option<Flight<'a>>),
+}
+
+// The one request fetching a missed key. Dropping it without a response lets
+// the waiting requests fetch for themselves.
+pub(crate) struct Flight<'a> {
+    cache: &'a ResponseCache,
+    key: String,
+    sender: watch::Sender<Option<CachedResponse>>,
+}
+
+impl Flight<'_> {
+    pub(crate) fn finish(self, entry: CachedResponse) {
+        self.sender.send_replace(Some(entry));
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        self.cache.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn open(
+        config: ResponseCacheConfig,
+        shared: Option<Arc<Store>>,
+        max_age_secs: Option<u64>,
+    ) -> io::Result<Self> {
+        let disk = config.disk.clone().map(DiskStore::open).transpose()?;
+        Ok(Self {
+            memory: Mutex::new(Lru::default()),
+            disk,
+            shared,
+            max_age_secs,
+            config,
+            inflight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Requests are identical when their tenant, path and forwarded body are,
+    // apart from the ignored fields and, optionally, whitespace
+    pub(crate) fn key(&self, tenant: Option<&str>, path: &str, body: &Value) -> String {
+        use sha2::Digest;
+        let mut body = body.clone();
+        for field in &self.config.ignore_fields {
+            remove_path(&mut body, field);
+        }
+        if self.config.normalize_whitespace {
+            if let Some(messages) = body.get_mut("messages").and_then(|m| m.as_array_mut()) {
+                messages.iter_mut().for_each(normalize_content);
+            }
+        }
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(tenant.unwrap_or_default().as_bytes());
+        hasher.update(b"\n");
+        hasher.update(path.as_bytes());
+        hasher.update(b"\n");
+        // Object keys are sorted, so field order does not matter
+        hasher.update(body.to_string().as_bytes());
+        hex_encode(&hasher.finalize())
+    }
+
+    pub(crate) fn caches_path(&self, path: &str) -> bool {
+        self.config
+            .paths
+            .iter()
+            .any(|suffix| path.ends_with(suffix))
+    }
+
+    pub(crate) fn caches_get(&self, path: &str) -> bool {
+        path.split('/')
+            .any(|segment| self.config.get_paths.iter().any(|p| p == segment))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<CachedResponse> {
+        self.get_stale(key)
+            .filter(|entry| entry.expires > unix_now())
+    }
+
+    // The entry for a key, including an expired one that can be revalidated
+    pub(crate) fn get_stale(&self, key: &str) -> Option<CachedResponse> {
+        let now = unix_now();
+        {
+            let mut memory = self.memory.lock().unwrap();
+            match memory.get(key) {
+                Some(entry) if entry.expires > now || entry.revalidatable() => {
+                    return Some(entry.clone())
+                }
+                Some(_) => {
+                    memory.remove(key);
+                }
+                None => {}
+            }
+        }
+        let entry = self.disk.as_ref()?.get(key, now)?;
+        self.remember(key, entry.clone());
+        Some(entry)
+    }
+
+    // A cached response, or one shared by the request already fetching it
+    pub(crate) async fn lookup(&self, key: &str) -> Lookup<'_> {
+        loop {
+            if let Some(entry) = self.get(key) {
+                return Lookup::Hit(entry);
+            }
+            if let Some(entry) = self.get_shared(key).await {
+                self.remember(key, entry.clone());
+                return Lookup::Hit(entry);
+            }
+            if !self.config.coalesce {
+                return Lookup::Miss(None);
+            }
+            let mut receiver = {
+                let mut inflight = self.inflight.lock().unwrap();
+                match inflight.get(key) {
+                    Some(receiver) => receiver.clone(),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        inflight.insert(key.to_string(), receiver);
+                        return Lookup::Miss(Some(Flight {
+                            cache: self,
+                            key: key.to_string(),
+                            sender,
+                        }));
+                    }
+                }
+            };
+            // Fails once the flight is dropped, with or without a response
+            let _ = receiver.changed().await;
+            let shared = receiver.borrow().clone();
+            if let Some(entry) = shared {
+                return Lookup::Shared(entry);
+            }
+        }
+    }
+
+    async fn get_shared(&self, key: &str) -> Option<CachedResponse> {
+        let shared = self.shared.as_ref()?;
+        let key = shared.key(&format!("cache:{}", key));
+        let data = match shared.backend.get(&key).await {
+            Ok(data) => data?,
+            Err(err) => {
+                eprintln!("⚠️  Failed to read shared cache entry: {}", err);
+                return None;
+            }
+        };
+        match CachedResponse::decode(&data) {
+            Ok(entry) => Some(entry).filter(|entry| entry.expires > unix_now()),
+            // Written by an incompatible version
+            Err(_) => {
+                shared.backend.delete(&key).await.ok();
+                None
+            }
+        }
+    }
+
+    // Stores the entry, or refreshes it, for ttl_secs, or the configured TTL
+    pub(crate) fn put(
+        &self,
+        key: &str,
+        ttl_secs: Option<u64>,
+        mut entry: CachedResponse,
+    ) -> CachedResponse {
+        let ttl_secs = ttl_secs
+            .unwrap_or(self.config.ttl_secs)
+            .min(self.max_age_secs.unwrap_or(u64::MAX));
+        entry.expires = unix_now() + ttl_secs;
+        if ttl_secs == 0 {
+            return entry;
+        }
+        if let Some(disk) = &self.disk {
+            if let Err(err) = disk.put(key, &entry) {
+                eprintln!("⚠️  Failed to write cache entry: {}", err);
+            }
+        }
+        if let Some(shared) = self.shared.clone() {
+            let subject = entry.user.as_deref().map(|user| subject_log(&shared, user));
+            let name = key.to_string();
+            let key = shared.key(&format!("cache:{}", key));
+            let data = entry.encode();
+            tokio::spawn(async move {
+                let mut written = shared.backend.set(&key, data, Some(ttl_secs)).await;
+                if let (Ok(()), Some(subject)) = (&written, subject) {
+                    written = shared
+                        .backend
+                        .append(&subject, name.into_bytes(), Some(ttl_secs))
+                        .await;
+                }
+                if let Err(err) = written {
+                    eprintln!("⚠️  Failed to write shared cache entry: {}", err);
+                }
+            });
+        }
+        self.remember(key, entry.clone());
+        entry
+    }
+
+    // Drops the local entries `remove` matches, returning their keys
+    pub(crate) fn remove(
+        &self,
+        remove: impl Fn(&CachedResponse) -> bool,
+    ) -> io::Result<HashSet<String>> {
+        let mut removed = self.memory.lock().unwrap().remove_where(&remove);
+        if let Some(disk) = &self.disk {
+            removed.extend(disk.remove_where(&remove)?);
+        }
+        Ok(removed)
+    }
+
+    // Drops the local entries past the retention of bodies: as it caps the
+    // TTL, those are the expired ones and any stored before it applied
+    pub(crate) fn purge(&self) -> io::Result<HashSet<String>> {
+        let Some(max_age_secs) = self.max_age_secs else {
+            return Ok(HashSet::new());
+        };
+        let now = unix_now();
+        self.remove(|entry| entry.expires <= now || entry.expires > now + max_age_secs)
+    }
+
+    // Drops the user's shared entries, returning their keys
+    pub(crate) async fn remove_shared(&self, user: &str) -> StorageResult<HashSet<String>> {
+        let Some(shared) = &self.shared else {
+            return Ok(HashSet::new());
+        };
+        let subject = subject_log(shared, user);
+        let mut removed = HashSet::new();
+        for name in shared.backend.range(&subject, 0).await? {
+            let name = String::from_utf8_lossy(&name).into_owned();
+            shared
+                .backend
+                .delete(&shared.key(&format!("cache:{}", name)))
+                .await?;
+            removed.insert(name);
+        }
+        shared.backend.delete(&subject).await?;
+        Ok(removed)
+    }
+
+    fn remember(&self, key: &str, entry: CachedResponse) {
+        let mut memory = self.memory.lock().unwrap();
+        memory.insert(key.to_string(), entry, 1);
+        while memory.len() > self.config.max_entries {
+            memory.pop_oldest();
+        }
+    }
+}
+
+// The shared log of the keys a user's requests stored, so erasure finds them
+// without a scan. It is named by a hash, keeping the user out of key listings.
+fn subject_log(shared: &Store, user: &str) -> String {
+    use sha2::Digest;
+    let user = hex_encode(&sha2::Sha256::digest(user.as_bytes()));
+    shared.key(&format!("cache-subject:{}", user))
+}
+
+// Per-request cache headers, honoured for every client
+pub(crate) struct CacheControl {
+    // x-proxy-cache-bypass: skip the lookup; the fresh response is stored
+    pub(crate) bypass: bool,
+    // x-proxy-cache-ttl: seconds to keep the response, 0 to not store it
+    pub(crate) ttl_secs: Option<u64>,
+}
+
+impl CacheControl {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let text = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_lowercase())
+                .filter(|v| !v.is_empty())
+        };
+        Self {
+            bypass: text("x-proxy-cache-bypass")
+                .is_some_and(|v| !matches!(v.as_str(), "0" | "false" | "no")),
+            ttl_secs: text("x-proxy-cache-ttl").and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+fn remove_path(value: &mut Value, path: &str) {
+    let (parents, last) = match path.rsplit_once('.') {
+        Some((parents, last)) => (Some(parents), last),
+        None => (None, path),
+    };
+    let mut current = value;
+    for key in parents.into_iter().flat_map(|p| p.split('.')) {
+        match current.get_mut(key) {
+            Some(next) => current = next,
+            None => return,
+        }
+    }
+    if let Some(obj) = current.as_object_mut() {
+        obj.remove(last);
+    }
+}
+
+fn normalize_content(message: &mut Value) {
+    let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match message.get_mut("content") {
+        Some(Value::String(text)) => *text = normalize(text),
+        Some(Value::Array(parts)) => {
+            for part in parts {
+                if let Some(Value::String(text)) = part.get_mut("text") {
+                    *text = normalize(text);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// Values with a size, ordered by last use
+struct Lru<T> {
+    entries: HashMap<String, (T, u64, u64)>,
+    clock: u64,
+    total_size: u64,
+}
+
+impl<T> Default for Lru<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            clock: 0,
+            total_size: 0,
+        }
+    }
+}
+
+impl<T> Lru<T> {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn get(&mut self, key: &str) -> Option<&T> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.2 = self.clock;
+        Some(&entry.0)
+    }
+
+    fn insert(&mut self, key: String, value: T, size: u64) {
+        self.clock += 1;
+        self.total_size += size;
+        if let Some((_, old_size, _)) = self.entries.insert(key, (value, size, self.clock)) {
+            self.total_size -= old_size;
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<T> {
+        let (value, size, _) = self.entries.remove(key)?;
+        self.total_size -= size;
+        Some(value)
+    }
+
+    // Drops the values `remove` matches, returning their keys
+    fn remove_where(&mut self, remove: impl Fn(&T) -> bool) -> HashSet<String> {
+        let keys: HashSet<String> = self
+            .entries
+            .iter()
+            .filter(|(_, (value, _, _))| remove(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.remove(key);
+        }
+        keys
+    }
+
+    fn pop_oldest(&mut self) -> Option<(String, T)> {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, _, used))| *used)
+            .map(|(key, _)| key.clone())?;
+        let value = self.remove(&oldest)?;
+        Some((oldest, value))
+    }
+}
+
+// One file per entry: a JSON header line, then the body. The files' mtimes
+// keep the LRU order across restarts.
+struct DiskStore {
+    config: DiskCacheConfig,
+    dir: PathBuf,
+    // File sizes by key
+    index: Mutex<Lru<()>>,
+}
+
+impl DiskStore {
+    fn open(config: DiskCacheConfig) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().to_string();
+            let Some(key) = name.strip_suffix(".entry") else {
+                // Left over from an interrupted write
+                if name.ends_with(".tmp") {
+                    fs::remove_file(file.path()).ok();
+                }
+                continue;
+            };
+            let metadata = file.metadata()?;
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((used, key.to_string(), metadata.len()));
+        }
+        files.sort();
+        let mut index = Lru::default();
+        for (_, key, size) in files {
+            index.insert(key, (), size);
+        }
+        println!(
+            "   - Response Cache: {} entries ({} bytes) on disk in {}",
+            index.len(),
+            index.total_size,
+            config.path
+        );
+        Ok(Self {
+            config,
+            dir,
+            index: Mutex::new(index),
+        })
+    }
+
+    fn file(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.entry", key))
+    }
+
+    fn get(&self, key: &str, now: u64) -> Option<CachedResponse> {
+        self.index.lock().unwrap().get(key)?;
+        let path = self.file(key);
+        match read_entry(&path, self.config.compress) {
+            Ok(entry) if entry.expires > now || entry.revalidatable() => {
+                File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_modified(SystemTime::now()))
+                    .ok();
+                Some(entry)
+            }
+            _ => {
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: &str, entry: &CachedResponse) -> io::Result<()> {
+        let mut data = entry.encode();
+        if self.config.compress {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&data)?;
+            data = encoder.finish()?;
+        }
+        let size = data.len() as u64;
+        if size > self.config.max_bytes {
+            return Ok(());
+        }
+
+        // Written aside first so a crash never leaves a partial entry
+        let tmp = self.dir.join(format!("{}.tmp", key));
+        fs::write(&tmp, &data)?;
+        fs::rename(&tmp, self.file(key))?;
+
+        let mut index = self.index.lock().unwrap();
+        index.insert(key.to_string(), (), size);
+        while index.total_size > self.config.max_bytes {
+            match index.pop_oldest() {
+                Some((evicted, ())) => {
+                    fs::remove_file(self.file(&evicted)).ok();
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) {
+        self.index.lock().unwrap().remove(key);
+        fs::remove_file(self.file(key)).ok();
+    }
+
+    // Drops the entries `remove` matches, reading each file. Unreadable
+    // files are left to the next lookup.
+    fn remove_where(&self, remove: impl Fn(&CachedResponse) -> bool) -> io::Result<Vec<String>> {
+        let keys: Vec<String> = self.index.lock().unwrap().entries.keys().cloned().collect();
+        let mut removed = Vec::new();
+        for key in keys {
+            let path = self.file(&key);
+            if read_entry(&path, self.config.compress).is_ok_and(|entry| remove(&entry)) {
+                self.index.lock().unwrap().remove(&key);
+                match fs::remove_file(&path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => removed.push(key),
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn read_entry(path: &PathBuf, compressed: bool) -> io::Result<CachedResponse> {
+    let file = File::open(path)?;
+    let mut reader: Box<dyn Read> = if compressed {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    CachedResponse::decode(&data)
+}
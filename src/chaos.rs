@@ -0,0 +1,295 @@
+// Fault injection for testing how client applications cope with a misbehaving
+// provider: added latency, 429s and 500s, streams cut off mid-way and
+// malformed events, each at a configured rate. Off unless enabled through
+// [chaos] or /admin/chaos, and meant for test environments only.
+
+use crate::config::ChaosConfig;
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::transform::sse_event_end;
+use axum::BoxError;
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use futures_util::{ready, Stream};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// Stands in for an event, cut off in the middle of its JSON
+const MALFORMED_EVENT: &[u8] = b"data: {\"id\": \"chaos\", \"object\": \"chat.completion.chunk\", \"choices\": [{\"delta\": {\"content\": \"\n\n";
+
+#[derive(Clone, Copy)]
+enum Fault {
+    Latency,
+    RateLimit,
+    ServerError,
+    Disconnect,
+    Malformed,
+}
+
+const FAULTS: [Fault; 5] = [
+    Fault::Latency,
+    Fault::RateLimit,
+    Fault::ServerError,
+    Fault::Disconnect,
+    Fault::Malformed,
+];
+
+impl Fault {
+    fn as_str(self) -> &'static str {
+        match self {
+            Fault::Latency => "latency",
+            Fault::RateLimit => "rate_limit",
+            Fault::ServerError => "server_error",
+            Fault::Disconnect => "disconnect",
+            Fault::Malformed => "malformed",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Injected([AtomicU64; 5]);
+
+impl Injected {
+    fn count(&self, fault: Fault) {
+        self.0[fault as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// A random number below `bound`
+pub(crate) fn random(bound: u64) -> u64 {
+    RandomState::new().hash_one(std::time::Instant::now()) % bound.max(1)
+}
+
+fn chance(rate: f64) -> bool {
+    rate > 0.0 && (random(1_000_000) as f64) < rate * 1_000_000.0
+}
+
+pub(crate) struct Chaos {
+    config: RwLock<ChaosConfig>,
+    injected: Arc<Injected>,
+}
+
+// The faults planned for a stream
+pub(crate) struct StreamFaults {
+    cut_after: Option<usize>,
+    malformed_rate: f64,
+    injected: Arc<Injected>,
+}
+
+impl Chaos {
+    pub(crate) fn new(config: ChaosConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            injected: Arc::default(),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.config.read().unwrap().enabled
+    }
+
+    // Delays the request or answers it with an error before it is forwarded;
+    // otherwise the faults for its stream, if it has one
+    pub(crate) async fn inject(&self, client: &str) -> Result<Option<StreamFaults>, Response> {
+        let config = self.config.read().unwrap().clone();
+        if !config.enabled
+            || !(config.clients.is_empty() || config.clients.iter().any(|c| c == client))
+        {
+            return Ok(None);
+        }
+        if config.latency_ms > 0 && chance(config.latency_rate) {
+            self.injected.count(Fault::Latency);
+            tokio::time::sleep(Duration::from_millis(config.latency_ms)).await;
+        }
+        let error = if chance(config.rate_limit_rate) {
+            Some((
+                Fault::RateLimit,
+                ProxyError::RateLimited("Rate limit reached (injected fault)".to_string()),
+            ))
+        } else if chance(config.server_error_rate) {
+            Some((
+                Fault::ServerError,
+                ProxyError::Internal("The server had an error (injected fault)".to_string()),
+            ))
+        } else {
+            None
+        };
+        if let Some((fault, error)) = error {
+            self.injected.count(fault);
+            println!("🐒 Injected {} fault for {}", fault.as_str(), client);
+            let mut response = error.into_response();
+            response
+                .headers_mut()
+                .insert("x-proxy-chaos", HeaderValue::from_static(fault.as_str()));
+            if matches!(fault, Fault::RateLimit) {
+                response
+                    .headers_mut()
+                    .insert("retry-after", HeaderValue::from_static("1"));
+            }
+            return Err(response);
+        }
+        Ok(Some(StreamFaults {
+            cut_after: chance(config.disconnect_rate).then(|| 1 + random(3) as usize),
+            malformed_rate: config.malformed_rate,
+            injected: self.injected.clone(),
+        }))
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP openai_proxy_chaos_faults_total Faults injected for resilience testing.\n",
+        );
+        out.push_str("# TYPE openai_proxy_chaos_faults_total counter\n");
+        for fault in FAULTS {
+            out.push_str(&format!(
+                "openai_proxy_chaos_faults_total{{fault=\"{}\"}} {}\n",
+                fault.as_str(),
+                self.injected.0[fault as usize].load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+// Splits the stream into events to apply the faults to
+struct FaultyStream<S> {
+    inner: Pin<Box<S>>,
+    faults: StreamFaults,
+    pending: Vec<u8>,
+    queue: VecDeque<Bytes>,
+    sent: usize,
+    ended: bool,
+}
+
+impl<S, E> Stream for FaultyStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+{
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(event) = this.queue.pop_front() {
+                if this.faults.cut_after == Some(this.sent) {
+                    this.faults.injected.count(Fault::Disconnect);
+                    println!("🐒 Injected disconnect fault after {} events", this.sent);
+                    this.ended = true;
+                    this.queue.clear();
+                    return Poll::Ready(Some(Err(BoxError::from("injected disconnect"))));
+                }
+                this.sent += 1;
+                if event.starts_with(b"data: {") && chance(this.faults.malformed_rate) {
+                    this.faults.injected.count(Fault::Malformed);
+                    return Poll::Ready(Some(Ok(Bytes::from_static(MALFORMED_EVENT))));
+                }
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            match ready!(this.inner.as_mut().poll_next(cx)) {
+                Some(Ok(bytes)) => {
+                    this.pending.extend_from_slice(&bytes);
+                    while let Some((end, separator)) = sse_event_end(&this.pending) {
+                        let event: Vec<u8> = this.pending.drain(..end + separator).collect();
+                        this.queue.push_back(event.into());
+                    }
+                }
+                Some(Err(err)) => {
+                    this.ended = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                None => {
+                    this.ended = true;
+                    if !this.pending.is_empty() {
+                        let rest = std::mem::take(&mut this.pending);
+                        this.queue.push_back(rest.into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The streamed body, with the planned faults applied. A cut stream ends in a
+// body error, so the connection is dropped without the closing chunk.
+pub(crate) fn stream_body<S, E>(faults: Option<StreamFaults>, events: S) -> Body
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError> + 'static,
+{
+    match faults.filter(|f| f.cut_after.is_some() || f.malformed_rate > 0.0) {
+        Some(faults) => Body::from_stream(FaultyStream {
+            inner: Box::pin(events),
+            faults,
+            pending: Vec::new(),
+            queue: VecDeque::new(),
+            sent: 0,
+            ended: false,
+        }),
+        None => Body::from_stream(events),
+    }
+}
+
+pub(crate) async fn get_chaos_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let config = state.chaos.config.read().unwrap().clone();
+    Ok(json_response(&serde_json::json!(config)))
+}
+
+// Partial update, e.g. {"enabled": true, "rate_limit_rate": 0.2}
+pub(crate) async fn update_chaos_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let update: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid JSON: {}", e)))?;
+
+    let mut config = state.chaos.config.write().unwrap();
+    let mut current = serde_json::json!(*config);
+    if let (Some(current), Some(update)) = (current.as_object_mut(), update.as_object()) {
+        for (key, value) in update {
+            current.insert(key.clone(), value.clone());
+        }
+    }
+    let updated: ChaosConfig = serde_json::from_value(current)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid chaos settings: {}", e)))?;
+    let rates = [
+        updated.latency_rate,
+        updated.rate_limit_rate,
+        updated.server_error_rate,
+        updated.disconnect_rate,
+        updated.malformed_rate,
+    ];
+    if rates.iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+        return Err(ProxyError::InvalidRequest(
+            "Chaos rates must be between 0 and 1".to_string(),
+        ));
+    }
+    *config = updated;
+
+    println!(
+        "🐒 Fault injection {}",
+        if config.enabled { "on" } else { "off" }
+    );
+    Ok(json_response(&serde_json::json!(*config)))
+}
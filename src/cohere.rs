@@ -0,0 +1,328 @@
+// Cohere's v1 chat API behind the chat completions schema: the last user
+// message with chat_history and a preamble, tools as parameter_definitions,
+// and the newline-delimited JSON stream
+
+use crate::adapters::StreamTranslator;
+use crate::time::unix_now;
+use axum::body::Bytes;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+// Top-level fields v1 chat takes; everything else is dropped
+const REQUEST_FIELDS: &[&str] = &[
+    "model",
+    "message",
+    "chat_history",
+    "preamble",
+    "tools",
+    "tool_results",
+    "stream",
+    "max_tokens",
+    "temperature",
+    "p",
+    "k",
+    "seed",
+    "stop_sequences",
+    "frequency_penalty",
+    "presence_penalty",
+    "response_format",
+    "safety_mode",
+    "documents",
+    "connectors",
+];
+
+// The body after the generic renames
+pub(crate) fn translate_request(obj: &mut Map<String, Value>) {
+    let messages = match obj.remove("messages") {
+        Some(Value::Array(messages)) => messages,
+        _ => Vec::new(),
+    };
+    let mut preamble = Vec::new();
+    let mut history: Vec<Value> = Vec::new();
+    // Results name the call they answer only by id
+    let mut calls: HashMap<String, Value> = HashMap::new();
+    for message in &messages {
+        let text = text_of(&message["content"]);
+        match message["role"].as_str().unwrap_or("user") {
+            "system" | "developer" if history.is_empty() => preamble.push(text),
+            "system" | "developer" => history.push(json!({"role": "SYSTEM", "message": text})),
+            "assistant" => {
+                let mut entry = json!({"role": "CHATBOT", "message": text});
+                let tool_calls: Vec<Value> = message["tool_calls"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|call| {
+                        let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+                        let call_json = json!({
+                            "name": call["function"]["name"],
+                            "parameters": serde_json::from_str::<Value>(arguments)
+                                .unwrap_or_else(|_| json!({})),
+                        });
+                        if let Some(id) = call["id"].as_str() {
+                            calls.insert(id.to_string(), call_json.clone());
+                        }
+                        call_json
+                    })
+                    .collect();
+                if !tool_calls.is_empty() {
+                    entry["tool_calls"] = tool_calls.into();
+                }
+                history.push(entry);
+            }
+            "tool" => {
+                let call = message["tool_call_id"]
+                    .as_str()
+                    .and_then(|id| calls.get(id).cloned())
+                    .unwrap_or_else(|| json!({"name": "", "parameters": {}}));
+                let result = json!({"call": call, "outputs": outputs(&text)});
+                // Consecutive results make up one TOOL turn
+                match history.last_mut() {
+                    Some(last) if last["role"] == "TOOL" => {
+                        if let Some(results) = last["tool_results"].as_array_mut() {
+                            results.push(result);
+                        }
+                    }
+                    _ => history.push(json!({"role": "TOOL", "tool_results": [result]})),
+                }
+            }
+            _ => history.push(json!({"role": "USER", "message": text})),
+        }
+    }
+    // The last turn is the message itself, or the results it answers with
+    let message = match history.last().map(|last| last["role"].clone()) {
+        Some(role) if role == "USER" => history.pop().unwrap()["message"].take(),
+        Some(role) if role == "TOOL" => {
+            obj.insert(
+                "tool_results".to_string(),
+                history.pop().unwrap()["tool_results"].take(),
+            );
+            "".into()
+        }
+        _ => "".into(),
+    };
+    obj.insert("message".to_string(), message);
+    if !history.is_empty() {
+        obj.insert("chat_history".to_string(), history.into());
+    }
+    if !preamble.is_empty() {
+        obj.insert("preamble".to_string(), preamble.join("\n\n").into());
+    }
+
+    // v1 chat has no way to forbid tools other than leaving them out
+    if obj.get("tool_choice").is_some_and(|c| c == "none") {
+        obj.remove("tools");
+    }
+    if let Some(Value::Array(tools)) = obj.remove("tools") {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| tool_definition(&tool["function"]))
+            .collect();
+        if !tools.is_empty() {
+            obj.insert("tools".to_string(), tools.into());
+        }
+    }
+    if let Some(format) = obj.remove("response_format") {
+        match format["type"].as_str() {
+            Some("json_object") => {
+                obj.insert(
+                    "response_format".to_string(),
+                    json!({"type": "json_object"}),
+                );
+            }
+            Some("json_schema") => {
+                obj.insert(
+                    "response_format".to_string(),
+                    json!({"type": "json_object", "schema": format["json_schema"]["schema"]}),
+                );
+            }
+            _ => {}
+        }
+    }
+    if obj.remove("safe_prompt").is_some_and(|s| s == true) {
+        obj.insert("safety_mode".to_string(), "STRICT".into());
+    }
+    obj.retain(|field, _| REQUEST_FIELDS.contains(&field.as_str()));
+}
+
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().filter_map(|p| p["text"].as_str()).collect(),
+        _ => String::new(),
+    }
+}
+
+// Outputs are a list of objects; anything else is wrapped in one
+fn outputs(text: &str) -> Value {
+    match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(output)) => json!([output]),
+        Ok(Value::Array(items)) if items.iter().all(Value::is_object) => items.into(),
+        _ => json!([{"output": text}]),
+    }
+}
+
+// A JSON Schema function as a tool with Python-style parameter types
+fn tool_definition(function: &Value) -> Value {
+    let required: Vec<&str> = function["parameters"]["required"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let mut definitions = Map::new();
+    for (name, property) in function["parameters"]["properties"]
+        .as_object()
+        .into_iter()
+        .flatten()
+    {
+        let kind = match property["type"].as_str() {
+            Some("integer") => "int",
+            Some("number") => "float",
+            Some("boolean") => "bool",
+            Some("array") => "list",
+            Some("object") => "dict",
+            _ => "str",
+        };
+        let mut definition = json!({"type": kind, "required": required.contains(&name.as_str())});
+        if let Some(description) = property.get("description") {
+            definition["description"] = description.clone();
+        }
+        definitions.insert(name.clone(), definition);
+    }
+    json!({
+        "name": function["name"],
+        "description": function["description"].as_str().unwrap_or_default(),
+        "parameter_definitions": definitions,
+    })
+}
+
+fn finish_reason(reason: &str, tool_calls: bool) -> &'static str {
+    match reason {
+        "MAX_TOKENS" => "length",
+        "ERROR_TOXIC" | "ERROR_LIMIT" => "content_filter",
+        _ if tool_calls => "tool_calls",
+        _ => "stop",
+    }
+}
+
+fn usage(response: &Value) -> Option<Value> {
+    let units = response["meta"].get("billed_units")?;
+    let prompt = units["input_tokens"].as_u64().unwrap_or(0);
+    let completion = units["output_tokens"].as_u64().unwrap_or(0);
+    Some(json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    }))
+}
+
+fn tool_calls(id: &str, response: &Value) -> Vec<Value> {
+    response["tool_calls"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, call)| {
+            json!({
+                "index": i,
+                "id": format!("call_{}_{}", id, i),
+                "type": "function",
+                "function": {
+                    "name": call["name"],
+                    "arguments": call.get("parameters").unwrap_or(&json!({})).to_string(),
+                },
+            })
+        })
+        .collect()
+}
+
+// A v1 chat response as a chat completion
+pub(crate) fn translate_response(body: &[u8], id: &str, model: &str) -> Option<Bytes> {
+    let response: Value = serde_json::from_slice(body).ok()?;
+    let text = response["text"].as_str()?;
+    let mut calls = tool_calls(id, &response);
+    let reason = response["finish_reason"].as_str().unwrap_or("COMPLETE");
+    let mut message = json!({
+        "role": "assistant",
+        "content": Some(text).filter(|t| !t.is_empty()),
+    });
+    let reason = finish_reason(reason, !calls.is_empty());
+    if !calls.is_empty() {
+        for call in &mut calls {
+            call.as_object_mut().unwrap().remove("index");
+        }
+        message["tool_calls"] = calls.into();
+    }
+    let mut completion = json!({
+        "id": format!("chatcmpl-{}", id),
+        "object": "chat.completion",
+        "created": unix_now(),
+        "model": model,
+        "choices": [{"index": 0, "message": message, "finish_reason": reason}],
+    });
+    if let Some(usage) = usage(&response) {
+        completion["usage"] = usage;
+    }
+    serde_json::to_vec(&completion).ok().map(Into::into)
+}
+
+// The stream's JSON lines as chat.completion.chunk events
+pub(crate) struct CohereChunks {
+    id: String,
+    model: String,
+    created: u64,
+    tool_calls: bool,
+}
+
+impl CohereChunks {
+    pub(crate) fn new(id: &str, model: &str) -> Self {
+        Self {
+            id: format!("chatcmpl-{}", id),
+            model: model.to_string(),
+            created: unix_now(),
+            tool_calls: false,
+        }
+    }
+}
+
+impl StreamTranslator for CohereChunks {
+    fn event_end(&self, buffer: &[u8]) -> Option<(usize, usize)> {
+        buffer.iter().position(|&b| b == b'\n').map(|end| (end, 1))
+    }
+
+    fn translate(&mut self, event: &[u8], out: &mut Vec<u8>) {
+        let Ok(event) = serde_json::from_slice::<Value>(event) else {
+            return;
+        };
+        let mut delta = json!({});
+        let mut reason = None;
+        let mut usage_json = None;
+        match event["event_type"].as_str() {
+            Some("stream-start") => delta["role"] = "assistant".into(),
+            Some("text-generation") => delta["content"] = event["text"].clone(),
+            Some("tool-calls-generation") => {
+                let calls = tool_calls(&self.id, &event);
+                self.tool_calls |= !calls.is_empty();
+                delta["tool_calls"] = calls.into();
+            }
+            Some("stream-end") => {
+                let finish = event["finish_reason"].as_str().unwrap_or("COMPLETE");
+                reason = Some(finish_reason(finish, self.tool_calls));
+                usage_json = usage(&event["response"]);
+            }
+            _ => return,
+        }
+        let mut chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": reason}],
+        });
+        if let Some(usage) = usage_json {
+            chunk["usage"] = usage;
+        }
+        out.extend_from_slice(format!("data: {}\n\n", chunk).as_bytes());
+    }
+}
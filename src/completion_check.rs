@@ -0,0 +1,33 @@
+// Detection of chat completions worth one more request
+
+use crate::config::CompletionRetryConfig;
+use serde_json::Value;
+
+impl CompletionRetryConfig {
+    // Why the completion should be retried, if it should
+    pub(crate) fn check(&self, request: &Value, response: &[u8]) -> Option<&'static str> {
+        let response: Value = serde_json::from_slice(response).ok()?;
+        let choice = response.get("choices")?.get(0)?;
+        let message = &choice["message"];
+        let content = message["content"].as_str().unwrap_or_default();
+        let has_tool_calls = message["tool_calls"]
+            .as_array()
+            .is_some_and(|calls| !calls.is_empty());
+        if has_tool_calls {
+            return None;
+        }
+
+        if self.empty && content.trim().is_empty() && choice["finish_reason"] == "stop" {
+            return Some("empty_completion");
+        }
+        let json_mode = matches!(
+            request["response_format"]["type"].as_str(),
+            Some("json_object") | Some("json_schema")
+        );
+        if self.invalid_json && json_mode && serde_json::from_str::<Value>(content.trim()).is_err()
+        {
+            return Some("invalid_json");
+        }
+        None
+    }
+}
@@ -0,0 +1,102 @@
+// The proxy's response_regex and response_grammar extensions: guided decoding
+// on engines that have it, a check of the completion with retries elsewhere
+
+use crate::error::ProxyError;
+use crate::models::Engine;
+use crate::request::{OutgoingRequest, RequestTransform};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+// Takes the extensions out of a chat request. Returns the regex to check the
+// completion against when the model's engine cannot enforce it.
+fn constrain(
+    obj: &mut Map<String, Value>,
+    engine: Option<Engine>,
+) -> Result<Option<Regex>, ProxyError> {
+    let field = |obj: &mut Map<String, Value>, name: &str| match obj.remove(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(ProxyError::InvalidRequest(format!(
+            "{} must be a string",
+            name
+        ))),
+    };
+    let regex = field(obj, "response_regex")?;
+    let grammar = field(obj, "response_grammar")?;
+    let guided = match (regex, grammar) {
+        (Some(_), Some(_)) => {
+            return Err(ProxyError::InvalidRequest(
+                "response_regex and response_grammar cannot be combined".to_string(),
+            ))
+        }
+        (Some(regex), None) => Guided::Regex(regex),
+        (None, Some(grammar)) => Guided::Grammar(grammar),
+        (None, None) => return Ok(None),
+    };
+
+    match (engine, guided) {
+        (Some(Engine::Vllm), Guided::Regex(regex)) => {
+            obj.insert("guided_regex".to_string(), regex.into());
+        }
+        (Some(Engine::Vllm), Guided::Grammar(grammar)) => {
+            obj.insert("guided_grammar".to_string(), grammar.into());
+        }
+        (Some(Engine::Tgi), Guided::Regex(regex)) => {
+            obj.insert(
+                "response_format".to_string(),
+                serde_json::json!({"type": "regex", "value": regex}),
+            );
+        }
+        (_, Guided::Grammar(_)) => {
+            return Err(ProxyError::InvalidRequest(
+                "response_grammar needs a model served by vLLM, see engine_options".to_string(),
+            ))
+        }
+        (None, Guided::Regex(regex)) => {
+            if obj.get("stream").is_some_and(|s| s == true) {
+                return Err(ProxyError::InvalidRequest(
+                    "response_regex is only checked on buffered responses for this model"
+                        .to_string(),
+                ));
+            }
+            let anchored = Regex::new(&format!("^(?:{})$", regex)).map_err(|e| {
+                ProxyError::InvalidRequest(format!("Invalid response_regex: {}", e))
+            })?;
+            return Ok(Some(anchored));
+        }
+    }
+    Ok(None)
+}
+
+// The extensions of chat requests, for the engine of the model's server
+pub(crate) struct Constrain;
+
+impl RequestTransform for Constrain {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        if !request.path.ends_with("chat/completions") {
+            return Ok(());
+        }
+        let engine = request
+            .model_config
+            .and_then(|m| m.engine_options.as_ref())
+            .map(|options| options.engine);
+        request.emulated_regex = constrain(request.body.as_object_mut().unwrap(), engine)?;
+        Ok(())
+    }
+}
+
+enum Guided {
+    Regex(String),
+    Grammar(String),
+}
+
+// The first choice's content matches as a whole, surrounding whitespace aside
+pub(crate) fn satisfies(regex: &Regex, response: &[u8]) -> bool {
+    let Ok(response) = serde_json::from_slice::<Value>(response) else {
+        return false;
+    };
+    let content = response["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or_default();
+    regex.is_match(content.trim())
+}
@@ -0,0 +1,226 @@
+// Deny dictionaries of words and patterns, global or per tenant, checked on
+// the messages sent and on the text returned. Each match is counted for the
+// dictionary, so enforcement can be audited on /metrics.
+
+use crate::config::{DictionaryAction, DictionaryConfig};
+use crate::error::ProxyError;
+use crate::metrics::escape_label;
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::transform::ResponseTransform;
+use regex::Regex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub(crate) struct Dictionary {
+    name: String,
+    category: String,
+    action: DictionaryAction,
+    // None for the global dictionaries
+    tenant: Option<String>,
+    // The words as whole words and the patterns, case-insensitive
+    regex: Regex,
+    input_matches: AtomicU64,
+    output_matches: AtomicU64,
+}
+
+impl Dictionary {
+    pub(crate) fn new(config: &DictionaryConfig, tenant: Option<&str>) -> Result<Self, String> {
+        let mut alternatives: Vec<String> = config
+            .patterns
+            .iter()
+            .map(|pattern| format!("(?:{})", pattern))
+            .collect();
+        if !config.words.is_empty() {
+            let words: Vec<String> = config.words.iter().map(|w| regex::escape(w)).collect();
+            alternatives.push(format!(r"\b(?:{})\b", words.join("|")));
+        }
+        if alternatives.is_empty() {
+            return Err(format!(
+                "Dictionary {} has no words or patterns",
+                config.name
+            ));
+        }
+        let regex = Regex::new(&format!("(?i){}", alternatives.join("|")))
+            .map_err(|e| format!("Invalid pattern in dictionary {}: {}", config.name, e))?;
+        Ok(Self {
+            name: config.name.clone(),
+            category: config
+                .category
+                .clone()
+                .unwrap_or_else(|| config.name.clone()),
+            action: config.action,
+            tenant: tenant.map(str::to_string),
+            regex,
+            input_matches: AtomicU64::new(0),
+            output_matches: AtomicU64::new(0),
+        })
+    }
+
+    // Each match is replaced by as many asterisks as it has characters
+    fn mask(&self, text: &str) -> String {
+        self.regex
+            .replace_all(text, |caps: &regex::Captures| {
+                "*".repeat(caps[0].chars().count())
+            })
+            .into_owned()
+    }
+}
+
+// Every text of a request's messages or prompt, for checking in place
+fn request_texts(json: &mut serde_json::Value) -> Vec<&mut serde_json::Value> {
+    let mut texts = Vec::new();
+    let obj = match json.as_object_mut() {
+        Some(obj) => obj,
+        None => return texts,
+    };
+    for (field, value) in obj.iter_mut() {
+        match (field.as_str(), value) {
+            ("messages", serde_json::Value::Array(messages)) => {
+                for message in messages {
+                    match message.get_mut("content") {
+                        Some(content) if content.is_string() => texts.push(content),
+                        Some(serde_json::Value::Array(parts)) => texts.extend(
+                            parts
+                                .iter_mut()
+                                .filter_map(|p| p.get_mut("text"))
+                                .filter(|t| t.is_string()),
+                        ),
+                        _ => {}
+                    }
+                }
+            }
+            ("prompt", prompt) if prompt.is_string() => texts.push(prompt),
+            _ => {}
+        }
+    }
+    texts
+}
+
+// Blocks, masks or logs the matches in a request
+pub(crate) fn check_request(
+    dictionaries: &[Arc<Dictionary>],
+    json: &mut serde_json::Value,
+) -> Result<(), ProxyError> {
+    for text in request_texts(json) {
+        for dictionary in dictionaries {
+            let Some(current) = text.as_str().filter(|t| dictionary.regex.is_match(t)) else {
+                continue;
+            };
+            dictionary.input_matches.fetch_add(1, Ordering::Relaxed);
+            match dictionary.action {
+                DictionaryAction::Block => {
+                    println!("🚫 Request blocked by dictionary {}", dictionary.name);
+                    return Err(ProxyError::InvalidRequest(format!(
+                        "Request contains terms not allowed ({})",
+                        dictionary.category
+                    )));
+                }
+                DictionaryAction::Mask => *text = dictionary.mask(current).into(),
+                DictionaryAction::Log => {
+                    println!("📕 Request matched dictionary {}", dictionary.name)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Checks completion requests against the tenant's dictionaries and filters
+// what comes back
+pub(crate) struct CheckDictionaries;
+
+impl RequestTransform for CheckDictionaries {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let dictionaries = request.state.dictionaries_for(request.namespace.tenant);
+        if dictionaries.is_empty() || !request.path.ends_with("completions") {
+            return Ok(());
+        }
+        check_request(&dictionaries, &mut request.body)?;
+        request
+            .transforms
+            .push(Box::new(DictionaryFilter::new(dictionaries)));
+        Ok(())
+    }
+}
+
+// Applies the dictionaries to completions and streamed deltas. A blocked
+// completion has its content removed and finish_reason "content_filter"; a
+// blocked stream is cut there. Matches split across deltas are not seen.
+pub(crate) struct DictionaryFilter {
+    dictionaries: Vec<Arc<Dictionary>>,
+    blocked: bool,
+}
+
+impl DictionaryFilter {
+    pub(crate) fn new(dictionaries: Vec<Arc<Dictionary>>) -> Self {
+        Self {
+            dictionaries,
+            blocked: false,
+        }
+    }
+}
+
+impl ResponseTransform for DictionaryFilter {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        let Some(choices) = value.get_mut("choices").and_then(|c| c.as_array_mut()) else {
+            return;
+        };
+        for choice in choices {
+            for dictionary in &self.dictionaries {
+                let key = ["message", "delta"]
+                    .into_iter()
+                    .find(|key| choice[*key]["content"].is_string());
+                let text = match key {
+                    Some(key) => &mut choice[key]["content"],
+                    None if choice["text"].is_string() => &mut choice["text"],
+                    None => continue,
+                };
+                let Some(current) = text.as_str().filter(|t| dictionary.regex.is_match(t)) else {
+                    continue;
+                };
+                dictionary.output_matches.fetch_add(1, Ordering::Relaxed);
+                match dictionary.action {
+                    DictionaryAction::Block => {
+                        println!("🚫 Response blocked by dictionary {}", dictionary.name);
+                        *text = "".into();
+                        choice["finish_reason"] = "content_filter".into();
+                        self.blocked = true;
+                    }
+                    DictionaryAction::Mask => *text = dictionary.mask(current).into(),
+                    DictionaryAction::Log => {
+                        println!("📕 Response matched dictionary {}", dictionary.name)
+                    }
+                }
+            }
+        }
+    }
+
+    fn cut(&self) -> Option<&'static str> {
+        self.blocked.then_some("content_filter")
+    }
+}
+
+pub(crate) fn render<'a>(dictionaries: impl Iterator<Item = &'a Arc<Dictionary>>) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP openai_proxy_dictionary_matches_total Texts matching a deny dictionary.\n",
+    );
+    out.push_str("# TYPE openai_proxy_dictionary_matches_total counter\n");
+    for dictionary in dictionaries {
+        for (direction, matches) in [
+            ("input", &dictionary.input_matches),
+            ("output", &dictionary.output_matches),
+        ] {
+            out.push_str(&format!(
+                "openai_proxy_dictionary_matches_total{{dictionary=\"{}\",tenant=\"{}\",category=\"{}\",action=\"{}\",direction=\"{}\"}} {}\n",
+                escape_label(&dictionary.name),
+                escape_label(dictionary.tenant.as_deref().unwrap_or("")),
+                escape_label(&dictionary.category),
+                dictionary.action.as_str(),
+                direction,
+                matches.load(Ordering::Relaxed)
+            ));
+        }
+    }
+    out
+}
@@ -0,0 +1,301 @@
+// Upstream DNS: static host pins, a lookup cache that serves stale entries
+// while it refreshes, and connection reuse figures. hyper only resolves when
+// it opens a connection, so each lookup for a host is a new connection.
+
+use crate::config::{IpFamily, UpstreamConnectionsConfig};
+use crate::metrics::escape_label;
+use hyper_014::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub(crate) struct UpstreamResolver {
+    cache_ttl: Option<Duration>,
+    serve_stale: Duration,
+    hosts: HashMap<String, Vec<SocketAddr>>,
+    family: IpFamily,
+    cache: Mutex<HashMap<String, CachedLookup>>,
+    stats: Arc<ConnectionStats>,
+}
+
+struct CachedLookup {
+    addrs: Vec<SocketAddr>,
+    resolved: Instant,
+    refreshing: bool,
+}
+
+impl UpstreamResolver {
+    pub(crate) fn new(
+        config: &UpstreamConnectionsConfig,
+        stats: Arc<ConnectionStats>,
+    ) -> Result<Self, String> {
+        let mut hosts = HashMap::new();
+        for (host, ips) in &config.dns.hosts {
+            let addrs = ips
+                .iter()
+                .map(|ip| {
+                    // The port comes from the URL
+                    ip.parse::<IpAddr>()
+                        .map(|ip| SocketAddr::new(ip, 0))
+                        .map_err(|_| format!("Invalid IP {} for host {}", ip, host))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            hosts.insert(host.to_lowercase(), addrs);
+        }
+        Ok(Self {
+            cache_ttl: config.dns.cache_ttl_secs.map(Duration::from_secs),
+            serve_stale: Duration::from_secs(config.dns.serve_stale_secs),
+            hosts,
+            family: config.ip_family,
+            cache: Mutex::new(HashMap::new()),
+            stats,
+        })
+    }
+
+    // hyper tries the family of the first address, then the other one if
+    // that has not connected within 300ms (happy eyeballs)
+    fn order(&self, host: &str, mut addrs: Vec<SocketAddr>) -> std::io::Result<Vec<SocketAddr>> {
+        match self.family {
+            IpFamily::System => {}
+            IpFamily::PreferIpv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+            IpFamily::PreferIpv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+            IpFamily::Ipv6Only => addrs.retain(|a| a.is_ipv6()),
+            IpFamily::Ipv4Only => addrs.retain(|a| a.is_ipv4()),
+        }
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!("No address of the configured family for {}", host),
+            ));
+        }
+        Ok(addrs)
+    }
+
+    async fn lookup(host: &str) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, 0)).await?.collect())
+    }
+
+    async fn resolve_host(self: Arc<Self>, host: String) -> std::io::Result<Vec<SocketAddr>> {
+        self.stats.record_connection(&host);
+        if let Some(addrs) = self.hosts.get(&host) {
+            return Ok(addrs.clone());
+        }
+        let Some(ttl) = self.cache_ttl else {
+            return Self::lookup(&host).await;
+        };
+
+        if let Some(entry) = self.cache.lock().unwrap().get_mut(&host) {
+            let age = entry.resolved.elapsed();
+            if age < ttl {
+                self.stats.record_dns(&host, "cached");
+                return Ok(entry.addrs.clone());
+            }
+            // Served as is while one refresh runs in the background
+            if age < ttl + self.serve_stale {
+                if !entry.refreshing {
+                    entry.refreshing = true;
+                    let resolver = self.clone();
+                    let host = host.clone();
+                    tokio::spawn(async move { resolver.refresh(&host).await });
+                }
+                self.stats.record_dns(&host, "stale");
+                return Ok(entry.addrs.clone());
+            }
+        }
+        self.refresh(&host).await
+    }
+
+    async fn refresh(&self, host: &str) -> std::io::Result<Vec<SocketAddr>> {
+        let result = Self::lookup(host).await;
+        let mut cache = self.cache.lock().unwrap();
+        match result {
+            Ok(addrs) if !addrs.is_empty() => {
+                self.stats.record_dns(host, "resolved");
+                cache.insert(
+                    host.to_string(),
+                    CachedLookup {
+                        addrs: addrs.clone(),
+                        resolved: Instant::now(),
+                        refreshing: false,
+                    },
+                );
+                Ok(addrs)
+            }
+            result => {
+                self.stats.record_dns(host, "failed");
+                // The last good answer beats no answer while DNS is flaky
+                let usable = self.cache_ttl.unwrap_or_default() + self.serve_stale;
+                match cache
+                    .get_mut(host)
+                    .filter(|entry| entry.resolved.elapsed() < usable)
+                {
+                    Some(entry) => {
+                        entry.refreshing = false;
+                        eprintln!(
+                            "⚠️  DNS lookup for {} failed, keeping the cached addresses",
+                            host
+                        );
+                        Ok(entry.addrs.clone())
+                    }
+                    None => result,
+                }
+            }
+        }
+    }
+}
+
+// What reqwest calls; lookups outlive the call, so they hold the resolver
+pub(crate) struct SharedResolver(Arc<UpstreamResolver>);
+
+impl UpstreamResolver {
+    pub(crate) fn shared(self) -> Arc<SharedResolver> {
+        Arc::new(SharedResolver(Arc::new(self)))
+    }
+}
+
+impl Resolve for SharedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.0.clone();
+        let host = name.as_str().to_lowercase();
+        Box::pin(async move {
+            let addrs = resolver.clone().resolve_host(host.clone()).await?;
+            let addrs = resolver.order(&host, addrs)?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// Per upstream host; hosts given as IPs are never resolved and not counted
+#[derive(Default)]
+pub(crate) struct ConnectionStats {
+    hosts: Mutex<HashMap<String, HostStats>>,
+}
+
+#[derive(Default)]
+struct HostStats {
+    sends: u64,
+    connections: u64,
+    dns: HashMap<&'static str, u64>,
+    // Moving averages of the time to response headers
+    reused_ms: Option<f64>,
+    new_ms: Option<f64>,
+    warned: Option<Instant>,
+}
+
+impl ConnectionStats {
+    fn record_connection(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.entry(host.to_string()).or_default().connections += 1;
+    }
+
+    fn record_dns(&self, host: &str, result: &'static str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        *hosts
+            .entry(host.to_string())
+            .or_default()
+            .dns
+            .entry(result)
+            .or_default() += 1;
+    }
+
+    pub(crate) fn connections(&self, host: &str) -> u64 {
+        let hosts = self.hosts.lock().unwrap();
+        hosts.get(host).map_or(0, |h| h.connections)
+    }
+
+    // One request sent to the host; `opened` when a connection was opened
+    // for it, judged by the host's connection count
+    pub(crate) fn record_send(&self, host: &str, opened: bool, elapsed: Duration) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let stats = hosts.entry(host.to_string()).or_default();
+        stats.sends += 1;
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let average = match opened {
+            true => &mut stats.new_ms,
+            false => &mut stats.reused_ms,
+        };
+        *average = Some(average.map_or(ms, |avg| avg * 0.9 + ms * 0.1));
+
+        // Setup costing more than the request itself, at most once a minute
+        let (Some(new_ms), Some(reused_ms)) = (stats.new_ms, stats.reused_ms) else {
+            return;
+        };
+        let recently = stats
+            .warned
+            .is_some_and(|at| at.elapsed() < Duration::from_secs(60));
+        if opened && !recently && new_ms > reused_ms * 2.0 {
+            stats.warned = Some(Instant::now());
+            println!(
+                "🤝 Handshakes dominate latency to {}: {:.0}ms on new connections, {:.0}ms on reused ones",
+                host, new_ms, reused_ms
+            );
+        }
+    }
+
+    // Appended to the Prometheus output
+    pub(crate) fn render(&self) -> String {
+        let hosts = self.hosts.lock().unwrap();
+        let mut names: Vec<_> = hosts.keys().collect();
+        names.sort();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            out.push_str(&format!("# HELP openai_proxy_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE openai_proxy_{} {}\n", name, kind));
+            for (labels, value) in samples {
+                out.push_str(&format!("openai_proxy_{}{{{}}} {}\n", name, labels, value));
+            }
+        };
+        let per_host = |value: &dyn Fn(&HostStats) -> String| {
+            names
+                .iter()
+                .map(|host| {
+                    (
+                        format!("host=\"{}\"", escape_label(host)),
+                        value(&hosts[*host]),
+                    )
+                })
+                .collect()
+        };
+        metric(
+            "upstream_sends_total",
+            "counter",
+            "Requests sent to upstream hosts.",
+            per_host(&|h| h.sends.to_string()),
+        );
+        metric(
+            "upstream_connections_total",
+            "counter",
+            "Upstream connections opened.",
+            per_host(&|h| h.connections.to_string()),
+        );
+        metric(
+            "upstream_connection_reuse_ratio",
+            "gauge",
+            "Share of sends that went over an already open connection.",
+            per_host(&|h| match h.sends {
+                0 => "0".to_string(),
+                sends => (1.0 - (h.connections.min(sends) as f64 / sends as f64)).to_string(),
+            }),
+        );
+        let mut lookups = Vec::new();
+        for host in &names {
+            let mut results: Vec<_> = hosts[*host].dns.iter().collect();
+            results.sort();
+            for (result, count) in results {
+                lookups.push((
+                    format!("host=\"{}\",result=\"{}\"", escape_label(host), result),
+                    count.to_string(),
+                ));
+            }
+        }
+        metric(
+            "upstream_dns_lookups_total",
+            "counter",
+            "Cached DNS answers by result: resolved, cached, stale or failed.",
+            lookups,
+        );
+        out
+    }
+}
@@ -0,0 +1,107 @@
+// Embeddings of a fixed length, whatever the backend: the dimensions parameter
+// is sent to upstreams that support it, and vectors of any other length are
+// cut or zero-padded by the proxy and scaled back to unit length, so vector
+// stores can mix models without schema changes.
+
+use crate::error::ProxyError;
+use crate::models::{DimensionsMode, EmbeddingDimensions};
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::transform::ResponseTransform;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{Map, Value};
+
+fn apply_dimensions(obj: &mut Map<String, Value>, config: &EmbeddingDimensions) {
+    match config.mode {
+        DimensionsMode::Request => {
+            obj.insert("dimensions".to_string(), config.dimensions.into());
+        }
+        DimensionsMode::Truncate => {
+            obj.remove("dimensions");
+        }
+    }
+}
+
+// The model's dimensions, asked for or fixed up in the response
+pub(crate) struct RequestDimensions;
+
+impl RequestTransform for RequestDimensions {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(config) = request
+            .model_config
+            .and_then(|m| m.embedding_dimensions.as_ref())
+            .filter(|_| request.path.ends_with("embeddings"))
+        else {
+            return Ok(());
+        };
+        apply_dimensions(request.body.as_object_mut().unwrap(), config);
+        request.transforms.push(Box::new(FixDimensions {
+            dimensions: config.dimensions,
+            normalize: config.normalize,
+        }));
+        Ok(())
+    }
+}
+
+pub(crate) struct FixDimensions {
+    pub(crate) dimensions: usize,
+    pub(crate) normalize: bool,
+}
+
+impl FixDimensions {
+    // None when the vector already has the length
+    fn resize(&self, vector: &[f64]) -> Option<Vec<f64>> {
+        if vector.len() == self.dimensions {
+            return None;
+        }
+        let mut resized: Vec<f64> = vector.iter().copied().take(self.dimensions).collect();
+        resized.resize(self.dimensions, 0.0);
+        if self.normalize {
+            let norm = resized.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                resized.iter_mut().for_each(|x| *x /= norm);
+            }
+        }
+        Some(resized)
+    }
+}
+
+impl ResponseTransform for FixDimensions {
+    fn apply(&mut self, value: &mut Value) {
+        let Some(data) = value.get_mut("data").and_then(|d| d.as_array_mut()) else {
+            return;
+        };
+        for item in data {
+            let Some(embedding) = item.get_mut("embedding") else {
+                continue;
+            };
+            match embedding {
+                Value::Array(numbers) => {
+                    let vector: Vec<f64> =
+                        numbers.iter().map(|n| n.as_f64().unwrap_or(0.0)).collect();
+                    if let Some(resized) = self.resize(&vector) {
+                        *embedding = resized.into();
+                    }
+                }
+                // encoding_format "base64": little-endian 32-bit floats
+                Value::String(encoded) => {
+                    let Ok(bytes) = STANDARD.decode(encoded.as_bytes()) else {
+                        continue;
+                    };
+                    let vector: Vec<f64> = bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+                        .collect();
+                    if let Some(resized) = self.resize(&vector) {
+                        let bytes: Vec<u8> = resized
+                            .iter()
+                            .flat_map(|x| (*x as f32).to_le_bytes())
+                            .collect();
+                        *encoded = STANDARD.encode(bytes);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
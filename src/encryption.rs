@@ -0,0 +1,261 @@
+// Encryption at rest with AES-256-GCM: debug captures have their request,
+// upstream and response sections sealed before the file is written, agent
+// jobs their credentials before they are journaled, and clients' upstream
+// keys can be stored in the config as "enc:" values. The capture metadata
+// stays readable, so retention and erasure still find files.
+
+use crate::config::{ClientConfig, EncryptionKeyConfig, SecretsConfig, Settings};
+use crate::secrets::SecretsBackend;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_json::{Map, Value};
+
+const SEALED: [&str; 3] = ["request", "upstream", "response"];
+const ALGORITHM: &str = "AES-256-GCM";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const ENCRYPTED_PREFIX: &str = "enc:";
+
+// A secrets backend for keys needed before the proxy's own is set up
+pub(crate) fn secrets_backend(config: Option<&SecretsConfig>) -> Option<SecretsBackend> {
+    config.map(|config| SecretsBackend {
+        config: config.clone(),
+        client: reqwest::Client::new(),
+        keys: Vec::new(),
+    })
+}
+
+// A sealed capture file's contents in the clear, with the key of `settings`
+pub async fn decrypt_capture(settings: Settings, capture: &str) -> Result<String, String> {
+    let config = settings
+        .capture_encryption
+        .as_ref()
+        .ok_or("capture_encryption is not configured")?;
+    let key = SealingKey::load(config, secrets_backend(settings.secrets.as_ref()).as_ref()).await?;
+    let mut capture: Value =
+        serde_json::from_str(capture).map_err(|e| format!("invalid capture: {}", e))?;
+    key.open(&mut capture)?;
+    Ok(serde_json::to_string_pretty(&capture).unwrap_or_default())
+}
+
+// The tenant, client and provider a sealed upstream key belongs to, so a value
+// copied to another client or provider does not decrypt
+fn upstream_key_aad(tenant: &str, client: &str, provider: &str) -> String {
+    format!("{}/{}/{}", tenant, client, provider)
+}
+
+// An upstream key as an "enc:" value for the upstream_keys of `client` in
+// `tenant`, under `provider`
+pub async fn encrypt_upstream_key(
+    settings: Settings,
+    tenant: &str,
+    client: &str,
+    provider: &str,
+    upstream_key: &str,
+) -> Result<String, String> {
+    let config = settings
+        .upstream_key_encryption
+        .as_ref()
+        .ok_or("upstream_key_encryption is not configured")?;
+    let key = SealingKey::load(config, secrets_backend(settings.secrets.as_ref()).as_ref()).await?;
+    let aad = upstream_key_aad(tenant, client, provider);
+    let (nonce, sealed) = key.seal_bytes(upstream_key.trim().as_bytes(), aad.as_bytes())?;
+    Ok(format!(
+        "{}{}",
+        ENCRYPTED_PREFIX,
+        STANDARD.encode([&nonce[..], &sealed].concat())
+    ))
+}
+
+pub(crate) struct SealingKey {
+    key_id: String,
+    key: Vec<u8>,
+}
+
+impl SealingKey {
+    fn new(key_id: String, key: &str) -> Result<Self, String> {
+        let key = STANDARD
+            .decode(key.trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or("encryption key must be 32 bytes, base64-encoded")?;
+        Ok(Self { key_id, key })
+    }
+
+    // The key from the config, or fetched from the secrets backend
+    pub(crate) async fn load(
+        config: &EncryptionKeyConfig,
+        secrets: Option<&SecretsBackend>,
+    ) -> Result<Self, String> {
+        let key = match (&config.key, &config.key_secret) {
+            (Some(key), None) => key.clone(),
+            (None, Some(reference)) => {
+                let secrets = secrets.ok_or("key_secret needs a [secrets] backend")?;
+                secrets.fetch(reference).await?
+            }
+            _ => return Err("set one of key, key_file or key_secret".to_string()),
+        };
+        Self::new(config.key_id.clone(), &key)
+    }
+
+    // The ciphertext has the tag appended
+    fn seal_bytes(
+        &self,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<([u8; NONCE_LEN], Vec<u8>), String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce).map_err(|e| e.to_string())?;
+        let mut tag = [0u8; TAG_LEN];
+        let mut sealed = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            aad,
+            plaintext,
+            &mut tag,
+        )
+        .map_err(|e| e.to_string())?;
+        sealed.extend_from_slice(&tag);
+        Ok((nonce, sealed))
+    }
+
+    fn open_bytes(&self, nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+        if nonce.len() != NONCE_LEN || sealed.len() < TAG_LEN {
+            return Err("invalid ciphertext".to_string());
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            aad,
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| "does not decrypt with this key".to_string())
+    }
+
+    pub(crate) fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    // Base64 of the nonce and ciphertext, bound to aad
+    pub(crate) fn seal_text(&self, plaintext: &[u8], aad: &[u8]) -> Result<String, String> {
+        let (nonce, sealed) = self.seal_bytes(plaintext, aad)?;
+        Ok(STANDARD.encode([&nonce[..], &sealed].concat()))
+    }
+
+    pub(crate) fn open_text(&self, text: &str, aad: &[u8]) -> Result<Vec<u8>, String> {
+        let bytes = STANDARD
+            .decode(text)
+            .ok()
+            .filter(|bytes| bytes.len() > NONCE_LEN)
+            .ok_or("is not valid base64")?;
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        self.open_bytes(nonce, sealed, aad)
+    }
+
+    // Replaces the body sections with an "encrypted" envelope. The request id
+    // is authenticated with them, so sections cannot be moved between files.
+    pub(crate) fn seal(&self, capture: &mut Value) -> Result<(), String> {
+        let Some(obj) = capture.as_object_mut() else {
+            return Ok(());
+        };
+        let mut sections = Map::new();
+        for name in SEALED {
+            if let Some(section) = obj.remove(name) {
+                sections.insert(name.to_string(), section);
+            }
+        }
+        let plaintext = serde_json::to_vec(&sections).unwrap_or_default();
+        let aad = obj
+            .get("request_id")
+            .and_then(|id| id.as_str())
+            .unwrap_or("");
+        let (nonce, sealed) = self.seal_bytes(&plaintext, aad.as_bytes())?;
+        obj.insert(
+            "encrypted".to_string(),
+            serde_json::json!({
+                "algorithm": ALGORITHM,
+                "key_id": self.key_id,
+                "nonce": STANDARD.encode(nonce),
+                "ciphertext": STANDARD.encode(sealed),
+            }),
+        );
+        Ok(())
+    }
+
+    // Restores the sections of a sealed capture; others are left as they are
+    fn open(&self, capture: &mut Value) -> Result<(), String> {
+        let Some(obj) = capture.as_object_mut() else {
+            return Ok(());
+        };
+        let Some(envelope) = obj.remove("encrypted") else {
+            return Ok(());
+        };
+        if envelope["algorithm"] != ALGORITHM {
+            return Err(format!("unsupported algorithm {}", envelope["algorithm"]));
+        }
+        if envelope["key_id"] != self.key_id.as_str() {
+            return Err(format!(
+                "capture was sealed with key {}, not {}",
+                envelope["key_id"], self.key_id
+            ));
+        }
+        let field = |name: &str| {
+            envelope[name]
+                .as_str()
+                .and_then(|text| STANDARD.decode(text).ok())
+                .ok_or_else(|| format!("invalid {} in capture", name))
+        };
+        let aad = obj
+            .get("request_id")
+            .and_then(|id| id.as_str())
+            .unwrap_or("");
+        let plaintext = self
+            .open_bytes(&field("nonce")?, &field("ciphertext")?, aad.as_bytes())
+            .map_err(|err| format!("capture {}", err))?;
+        let sections: Map<String, Value> =
+            serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?;
+        obj.extend(sections);
+        Ok(())
+    }
+}
+
+// Decrypts the "enc:" values of a tenant client's upstream_keys in place;
+// other values are taken as they are
+pub(crate) fn open_upstream_keys(
+    tenant: &str,
+    client: &mut ClientConfig,
+    key: Option<&SealingKey>,
+) -> Result<(), String> {
+    for (provider, value) in client.upstream_keys.iter_mut() {
+        let Some(encrypted) = value.strip_prefix(ENCRYPTED_PREFIX) else {
+            continue;
+        };
+        let fail = |reason: &str| {
+            format!(
+                "upstream key {} of client {} {}",
+                provider, client.name, reason
+            )
+        };
+        let key = key.ok_or_else(|| fail("needs [upstream_key_encryption]"))?;
+        let bytes = STANDARD
+            .decode(encrypted)
+            .ok()
+            .filter(|bytes| bytes.len() > NONCE_LEN)
+            .ok_or_else(|| fail("is not valid base64"))?;
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let opened = key
+            .open_bytes(
+                nonce,
+                sealed,
+                upstream_key_aad(tenant, &client.name, provider).as_bytes(),
+            )
+            .map_err(|err| fail(&err))?;
+        *value = String::from_utf8(opened).map_err(|_| fail("is not text"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+// Errors returned to clients, in the OpenAI error envelope
+
+use axum::{
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+#[derive(Debug)]
+pub(crate) enum ProxyError {
+    RequestError(String),
+    ResponseError(String),
+    BodyReadError(String),
+    Unauthorized(String),
+    Forbidden(String),
+    BudgetExceeded(String),
+    InvalidRequest(String),
+    RateLimited(String),
+    Unavailable(String),
+    GatewayTimeout(String),
+    NotFound(String),
+    Internal(String),
+    RequestTimeout(String),
+    PayloadTooLarge(String),
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        // The original errors keep their plain-text body unless json_errors
+        // is set; see localize_errors
+        let plain = matches!(
+            self,
+            ProxyError::RequestError(_)
+                | ProxyError::ResponseError(_)
+                | ProxyError::BodyReadError(_)
+        );
+        let (status, error_type, message) = match self {
+            ProxyError::RequestError(msg) => (StatusCode::BAD_GATEWAY, "proxy_error", msg),
+            ProxyError::ResponseError(msg) => (StatusCode::BAD_GATEWAY, "proxy_error", msg),
+            ProxyError::BodyReadError(msg) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "proxy_error", msg)
+            }
+            ProxyError::Unauthorized(msg) => {
+                (StatusCode::UNAUTHORIZED, "authentication_error", msg)
+            }
+            ProxyError::Forbidden(msg) => (StatusCode::FORBIDDEN, "permission_error", msg),
+            ProxyError::BudgetExceeded(msg) => {
+                (StatusCode::TOO_MANY_REQUESTS, "budget_exceeded", msg)
+            }
+            ProxyError::InvalidRequest(msg) => {
+                (StatusCode::BAD_REQUEST, "invalid_request_error", msg)
+            }
+            ProxyError::RateLimited(msg) => {
+                (StatusCode::TOO_MANY_REQUESTS, "rate_limit_exceeded", msg)
+            }
+            ProxyError::Unavailable(msg) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "service_unavailable", msg)
+            }
+            ProxyError::GatewayTimeout(msg) => (StatusCode::GATEWAY_TIMEOUT, "timeout", msg),
+            ProxyError::NotFound(msg) => (StatusCode::NOT_FOUND, "not_found_error", msg),
+            ProxyError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", msg),
+            ProxyError::RequestTimeout(msg) => (StatusCode::REQUEST_TIMEOUT, "timeout", msg),
+            ProxyError::PayloadTooLarge(msg) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "invalid_request_error", msg)
+            }
+        };
+
+        let error = ErrorMessage {
+            error_type,
+            message,
+            plain,
+        };
+        let mut response = error.response(status, &error.message, false);
+        // Marks the response as the proxy's own, for localization
+        response.extensions_mut().insert(error);
+        response
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ErrorMessage {
+    pub(crate) error_type: &'static str,
+    pub(crate) message: String,
+    // Answered as "Proxy error: <message>" in plain text
+    pub(crate) plain: bool,
+}
+
+impl ErrorMessage {
+    // The body with `message`, in the OpenAI envelope unless the error is a
+    // plain one and `json` is not set
+    pub(crate) fn response(&self, status: StatusCode, message: &str, json: bool) -> Response {
+        let (content_type, body) = if self.plain && !json {
+            (
+                "text/plain; charset=utf-8",
+                format!("Proxy error: {}", message),
+            )
+        } else {
+            // Same envelope as OpenAI errors so SDKs can surface the message
+            let mut body = serde_json::json!({
+                "error": {
+                    "message": message,
+                    "type": self.error_type,
+                }
+            });
+            if message != self.message {
+                body["error"]["original_message"] = self.message.clone().into();
+            }
+            ("application/json", body.to_string())
+        };
+        Response::builder()
+            .status(status)
+            .header("content-type", content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+// A failed read of the client's body; 408 when body_read_timeout_ms ran out
+pub(crate) fn body_read_error(err: axum::Error) -> ProxyError {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&err);
+    while let Some(current) = source {
+        if current.is::<tower_http::timeout::TimeoutError>() {
+            return ProxyError::RequestTimeout("Timed out reading the request body".to_string());
+        }
+        source = current.source();
+    }
+    ProxyError::BodyReadError(err.to_string())
+}
+
+// The message of a caught panic, for logging
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Rewrites a vendor error body (Anthropic, Gemini, vLLM/TGI or plain text) into
+// the OpenAI envelope, keeping the original under "upstream_error". None when
+// the body already is an OpenAI error.
+pub(crate) fn translate_upstream_error(
+    status: StatusCode,
+    body: &[u8],
+) -> Option<(StatusCode, serde_json::Value)> {
+    let original: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(value) => value,
+        Err(_) => {
+            let text = String::from_utf8_lossy(body).trim().to_string();
+            if text.is_empty() {
+                return None;
+            }
+            serde_json::Value::String(text)
+        }
+    };
+    // Gemini wraps some errors in a one-element array
+    let value = match &original {
+        serde_json::Value::Array(items) if items.len() == 1 => &items[0],
+        value => value,
+    };
+    let error = &value["error"];
+
+    let (status, message, code) = if value["type"] == "error" && error.is_object() {
+        // Anthropic: {"type": "error", "error": {"type": "overloaded_error", "message": ...}}
+        let kind = error["type"].as_str().unwrap_or_default();
+        let mapped = match kind {
+            "invalid_request_error" => StatusCode::BAD_REQUEST,
+            "authentication_error" => StatusCode::UNAUTHORIZED,
+            "permission_error" => StatusCode::FORBIDDEN,
+            "not_found_error" => StatusCode::NOT_FOUND,
+            "request_too_large" => StatusCode::PAYLOAD_TOO_LARGE,
+            "rate_limit_error" => StatusCode::TOO_MANY_REQUESTS,
+            "overloaded_error" => StatusCode::SERVICE_UNAVAILABLE,
+            "api_error" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => status,
+        };
+        (mapped, error["message"].as_str(), Some(kind))
+    } else if error["status"].is_string() {
+        // Gemini: {"error": {"code": 429, "message": ..., "status": "RESOURCE_EXHAUSTED"}}
+        let kind = error["status"].as_str().unwrap_or_default();
+        let mapped = match kind {
+            "INVALID_ARGUMENT" | "FAILED_PRECONDITION" | "OUT_OF_RANGE" => StatusCode::BAD_REQUEST,
+            "UNAUTHENTICATED" => StatusCode::UNAUTHORIZED,
+            "PERMISSION_DENIED" => StatusCode::FORBIDDEN,
+            "NOT_FOUND" => StatusCode::NOT_FOUND,
+            "RESOURCE_EXHAUSTED" => StatusCode::TOO_MANY_REQUESTS,
+            "UNAVAILABLE" => StatusCode::SERVICE_UNAVAILABLE,
+            "DEADLINE_EXCEEDED" => StatusCode::GATEWAY_TIMEOUT,
+            "INTERNAL" => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => status,
+        };
+        (mapped, error["message"].as_str(), Some(kind))
+    } else if error.is_object() {
+        // Already OpenAI-shaped
+        return None;
+    } else if value["object"] == "error" {
+        // vLLM: {"object": "error", "message": ..., "type": "BadRequestError", "code": 400}
+        (status, value["message"].as_str(), value["type"].as_str())
+    } else if let Some(message) = error.as_str() {
+        // TGI and others: {"error": "...", "error_type": "validation"}
+        (status, Some(message), value["error_type"].as_str())
+    } else if let Some(text) = value.as_str() {
+        (status, Some(text), None)
+    } else {
+        (
+            status,
+            value["message"].as_str().or(value["detail"].as_str()),
+            None,
+        )
+    };
+
+    // Upstreams may use non-standard codes such as Anthropic's 529
+    let status = if status.as_u16() > 599 || status.as_u16() == 529 {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        status
+    };
+    let error_type = match status.as_u16() {
+        400 | 413 | 422 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        429 => "rate_limit_exceeded",
+        _ => "server_error",
+    };
+    let message: String = message
+        .unwrap_or("Upstream returned an error")
+        .chars()
+        .take(2000)
+        .collect();
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+            "code": code,
+            "param": null,
+        },
+        "upstream_error": original,
+    });
+    Some((status, body))
+}
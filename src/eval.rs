@@ -0,0 +1,379 @@
+// `openai_proxy eval`: runs a JSONL dataset through models via the proxy and
+// scores the answers, for comparing models on the same cases before routing
+// traffic to them
+
+use crate::bench::{percentile, BenchTarget};
+use crate::config::Settings;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// One line of the dataset, e.g.
+// {"id": "capital", "prompt": "Capital of France?", "expected": "Paris"}
+// A case passes when the answer meets all of its checks: `expected` for an
+// exact match, `pattern` for a regex and `json` for a valid JSON answer.
+#[derive(Debug, Clone)]
+pub struct EvalCase {
+    pub id: String,
+    pub messages: Value,
+    pub expected: Option<String>,
+    pub pattern: Option<Regex>,
+    pub json: bool,
+}
+
+// The cases of a JSONL dataset; errors name the line
+pub fn parse_dataset(text: &str) -> Result<Vec<EvalCase>, String> {
+    let mut cases = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |reason: String| format!("Line {}: {}", i + 1, reason);
+        let line: Value = serde_json::from_str(line).map_err(|e| invalid(e.to_string()))?;
+        let messages = match (&line["messages"], &line["prompt"]) {
+            (Value::Array(messages), _) => Value::Array(messages.clone()),
+            (_, Value::String(prompt)) => json!([{"role": "user", "content": prompt}]),
+            _ => return Err(invalid("needs messages or a prompt".to_string())),
+        };
+        let pattern = match line["pattern"].as_str() {
+            Some(pattern) => {
+                Some(Regex::new(pattern).map_err(|e| invalid(format!("invalid pattern: {}", e)))?)
+            }
+            None => None,
+        };
+        cases.push(EvalCase {
+            id: match &line["id"] {
+                Value::String(id) => id.clone(),
+                Value::Null => (cases.len() + 1).to_string(),
+                id => id.to_string(),
+            },
+            messages,
+            expected: line["expected"].as_str().map(str::to_string),
+            pattern,
+            json: line["json"].as_bool().unwrap_or(false),
+        });
+    }
+    Ok(cases)
+}
+
+#[derive(Debug, Clone)]
+pub struct EvalOptions {
+    pub models: Vec<String>,
+    pub concurrency: usize,
+    pub temperature: f64,
+    pub max_tokens: Option<u64>,
+    pub timeout: Duration,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        Self {
+            models: Vec::new(),
+            concurrency: 4,
+            temperature: 0.0,
+            max_tokens: None,
+            timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+// A case's answer from one model
+#[derive(Debug, Clone, Serialize)]
+pub struct CaseResult {
+    pub case: String,
+    pub model: String,
+    pub passed: bool,
+    // The checks the answer did not meet: "exact", "regex" or "json"
+    pub failed: Vec<&'static str>,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+// Passed and run cases by check
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CheckTotals {
+    pub passed: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelReport {
+    pub model: String,
+    pub cases: usize,
+    pub passed: usize,
+    pub pass_rate: f64,
+    pub errors: usize,
+    pub checks: BTreeMap<&'static str, CheckTotals>,
+    pub latency_ms_p50: Option<u64>,
+    pub latency_ms_p95: Option<u64>,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl ModelReport {
+    // One line per model for the terminal
+    pub fn summary(&self) -> String {
+        let ms = |v: Option<u64>| v.map_or("-".to_string(), |v| format!("{}ms", v));
+        let checks: Vec<String> = self
+            .checks
+            .iter()
+            .map(|(check, totals)| format!("{} {}/{}", check, totals.passed, totals.total))
+            .collect();
+        format!(
+            "{}: {}/{} passed ({:.1}%), {} errors{}{}, latency p50 {} p95 {}, {} prompt + {} completion tokens",
+            self.model,
+            self.passed,
+            self.cases,
+            self.pass_rate * 100.0,
+            self.errors,
+            if checks.is_empty() { "" } else { ", " },
+            checks.join(", "),
+            ms(self.latency_ms_p50),
+            ms(self.latency_ms_p95),
+            self.prompt_tokens,
+            self.completion_tokens,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EvalReport {
+    pub models: Vec<ModelReport>,
+    // By case, then in the order of the models
+    pub results: Vec<CaseResult>,
+}
+
+impl EvalReport {
+    // The cases some models passed and others did not
+    pub fn differing(&self) -> Vec<&str> {
+        let mut outcomes: BTreeMap<&str, (bool, bool)> = BTreeMap::new();
+        for result in &self.results {
+            let outcome = outcomes.entry(&result.case).or_default();
+            if result.passed {
+                outcome.0 = true;
+            } else {
+                outcome.1 = true;
+            }
+        }
+        let mut differing: Vec<&str> = Vec::new();
+        for result in &self.results {
+            if outcomes[result.case.as_str()] == (true, true)
+                && !differing.contains(&result.case.as_str())
+            {
+                differing.push(&result.case);
+            }
+        }
+        differing
+    }
+}
+
+// Serves the settings' proxy on a local port for the run, so the cases take
+// the routing, rules and usage accounting of live traffic
+pub async fn start_local(settings: Settings) -> std::io::Result<BenchTarget> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let limits = settings.connections.clone();
+    let app = crate::router(settings).await?;
+    tokio::spawn(async move {
+        let _ = crate::server::serve_router(listener, app, limits).await;
+    });
+    Ok(BenchTarget {
+        name: "proxy".to_string(),
+        api_base: format!("http://{}/v3", addr),
+        api_key: None,
+    })
+}
+
+// The answer's contents and token counts, or what went wrong
+async fn ask(
+    client: &reqwest::Client,
+    target: &BenchTarget,
+    options: &EvalOptions,
+    model: &str,
+    case: &EvalCase,
+) -> Result<(String, u64, u64), String> {
+    let mut body = json!({
+        "model": model,
+        "messages": case.messages,
+        "temperature": options.temperature,
+    });
+    if let Some(max_tokens) = options.max_tokens {
+        body["max_tokens"] = max_tokens.into();
+    }
+    let url = format!("{}/chat/completions", target.api_base.trim_end_matches('/'));
+    // Tagged so evaluation traffic can be told apart in usage records
+    let mut request = client
+        .post(url)
+        .header("x-proxy-tags", "source=eval")
+        .json(&body);
+    if let Some(key) = &target.api_key {
+        request = request.bearer_auth(key);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!(
+            "{}: {}",
+            status,
+            text.chars().take(200).collect::<String>()
+        ));
+    }
+    let answer: Value = response.json().await.map_err(|e| e.to_string())?;
+    let output = answer["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or("The response has no message content")?
+        .to_string();
+    let usage = &answer["usage"];
+    Ok((
+        output,
+        usage["prompt_tokens"].as_u64().unwrap_or(0),
+        usage["completion_tokens"].as_u64().unwrap_or(0),
+    ))
+}
+
+fn score(case: &EvalCase, output: &str) -> Vec<&'static str> {
+    let mut failed = Vec::new();
+    if case
+        .expected
+        .as_ref()
+        .is_some_and(|expected| output.trim() != expected.trim())
+    {
+        failed.push("exact");
+    }
+    if case
+        .pattern
+        .as_ref()
+        .is_some_and(|pattern| !pattern.is_match(output))
+    {
+        failed.push("regex");
+    }
+    if case.json && serde_json::from_str::<Value>(output.trim()).is_err() {
+        failed.push("json");
+    }
+    failed
+}
+
+// Sends every case to every model, `concurrency` requests at a time
+pub async fn run(target: &BenchTarget, cases: &[EvalCase], options: &EvalOptions) -> EvalReport {
+    let client = reqwest::Client::builder()
+        .timeout(options.timeout)
+        .build()
+        .unwrap_or_default();
+    let runs: Vec<(usize, usize)> = (0..cases.len())
+        .flat_map(|case| (0..options.models.len()).map(move |model| (case, model)))
+        .collect();
+    let runs = Arc::new(runs);
+    let cases = Arc::new(cases.to_vec());
+    let next = Arc::new(AtomicUsize::new(0));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let mut workers = Vec::new();
+    for _ in 0..options.concurrency.clamp(1, runs.len().max(1)) {
+        let (client, target, options) = (client.clone(), target.clone(), options.clone());
+        let (runs, cases, next, results) =
+            (runs.clone(), cases.clone(), next.clone(), results.clone());
+        workers.push(tokio::spawn(async move {
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&(case, model)) = runs.get(i) else {
+                    break;
+                };
+                let (case_ref, model_name) = (&cases[case], &options.models[model]);
+                let sent = Instant::now();
+                let answer = ask(&client, &target, &options, model_name, case_ref).await;
+                let latency_ms = sent.elapsed().as_millis() as u64;
+                let result = match answer {
+                    Ok((output, prompt_tokens, completion_tokens)) => {
+                        let failed = score(case_ref, &output);
+                        let result = CaseResult {
+                            case: case_ref.id.clone(),
+                            model: model_name.clone(),
+                            passed: failed.is_empty(),
+                            failed,
+                            output: Some(output),
+                            error: None,
+                            latency_ms,
+                        };
+                        (i, result, prompt_tokens, completion_tokens)
+                    }
+                    Err(error) => {
+                        let result = CaseResult {
+                            case: case_ref.id.clone(),
+                            model: model_name.clone(),
+                            passed: false,
+                            failed: Vec::new(),
+                            output: None,
+                            error: Some(error),
+                            latency_ms,
+                        };
+                        (i, result, 0, 0)
+                    }
+                };
+                results.lock().unwrap().push(result);
+            }
+        }));
+    }
+    for worker in workers {
+        let _ = worker.await;
+    }
+
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    results.sort_by_key(|(i, ..)| *i);
+    let models = options
+        .models
+        .iter()
+        .map(|model| {
+            let own: Vec<_> = results
+                .iter()
+                .filter(|(_, r, ..)| &r.model == model)
+                .collect();
+            let mut checks: BTreeMap<&'static str, CheckTotals> = BTreeMap::new();
+            for (i, result, ..) in &own {
+                let case = &cases[runs[*i].0];
+                let applied = [
+                    ("exact", case.expected.is_some()),
+                    ("regex", case.pattern.is_some()),
+                    ("json", case.json),
+                ];
+                for (check, _) in applied.iter().filter(|(_, applies)| *applies) {
+                    // Failed requests count against every check of their case
+                    let totals = checks.entry(check).or_default();
+                    totals.total += 1;
+                    if result.error.is_none() && !result.failed.contains(check) {
+                        totals.passed += 1;
+                    }
+                }
+            }
+            let mut latencies: Vec<u64> = own
+                .iter()
+                .filter(|(_, r, ..)| r.error.is_none())
+                .map(|(_, r, ..)| r.latency_ms)
+                .collect();
+            let passed = own.iter().filter(|(_, r, ..)| r.passed).count();
+            ModelReport {
+                model: model.clone(),
+                cases: own.len(),
+                passed,
+                pass_rate: match own.len() {
+                    0 => 0.0,
+                    n => passed as f64 / n as f64,
+                },
+                errors: own.iter().filter(|(_, r, ..)| r.error.is_some()).count(),
+                checks,
+                latency_ms_p50: percentile(&mut latencies, 0.50),
+                latency_ms_p95: percentile(&mut latencies, 0.95),
+                prompt_tokens: own.iter().map(|(_, _, p, _)| p).sum(),
+                completion_tokens: own.iter().map(|(_, _, _, c)| c).sum(),
+            }
+        })
+        .collect();
+    EvalReport {
+        models,
+        results: results.into_iter().map(|(_, result, ..)| result).collect(),
+    }
+}
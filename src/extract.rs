@@ -0,0 +1,63 @@
+// x-proxy-extract: a client passing a path such as "choices[0].message.content"
+// gets only that value of the JSON response, as plain text, for shell scripts
+// and low-code tools. Errors and streams are passed on unchanged.
+
+use crate::error::ProxyError;
+use crate::json_path::JsonPath;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+pub(crate) async fn extract_field(req: Request, next: Next) -> Response {
+    let Some(value) = req.headers().get("x-proxy-extract") else {
+        return next.run(req).await;
+    };
+    let text = value.to_str().unwrap_or_default().trim().to_string();
+    let path = match JsonPath::parse(&text) {
+        Ok(path) => path,
+        Err(e) => {
+            return ProxyError::InvalidRequest(format!("Invalid x-proxy-extract header: {}", e))
+                .into_response()
+        }
+    };
+    let response = next.run(req).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !response.status().is_success() || !is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            return ProxyError::ResponseError(format!("Failed to read response: {}", e))
+                .into_response()
+        }
+    };
+    let parsed: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(parsed) => parsed,
+        Err(_) => return Response::from_parts(parts, Body::from(body)),
+    };
+    // Strings are sent as they are, anything else as JSON
+    let extracted = match path.get(&parsed) {
+        Some(serde_json::Value::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+        None => {
+            return ProxyError::NotFound(format!("The response has no value at {}", text))
+                .into_response()
+        }
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    Response::from_parts(parts, Body::from(extracted))
+}
@@ -0,0 +1,166 @@
+// Client feedback on requests, posted to /v1/feedback
+
+use crate::config::FeedbackConfig;
+use crate::error::ProxyError;
+use crate::inspector::RequestSummary;
+use crate::retention::remove_lines;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::tenant::SignedRequest;
+use crate::time::{format_utc, unix_now};
+use axum::{
+    body::Bytes,
+    extract::{Request, State},
+    http::HeaderMap,
+    response::Response,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const MAX_COMMENT_CHARS: usize = 4000;
+
+#[derive(Default)]
+struct RecentRequests {
+    by_id: HashMap<String, Arc<RequestSummary>>,
+    order: VecDeque<String>,
+}
+
+pub(crate) struct FeedbackStore {
+    pub(crate) config: FeedbackConfig,
+    file: Mutex<File>,
+    recent: Mutex<RecentRequests>,
+}
+
+impl FeedbackStore {
+    pub(crate) fn open(config: FeedbackConfig) -> std::io::Result<Self> {
+        let path = Path::new(&config.path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            config,
+            file: Mutex::new(file),
+            recent: Mutex::new(RecentRequests::default()),
+        })
+    }
+
+    pub(crate) fn remember(&self, summary: Arc<RequestSummary>) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.order.push_back(summary.request_id.clone());
+        recent.by_id.insert(summary.request_id.clone(), summary);
+        while recent.order.len() > self.config.recent_requests {
+            if let Some(oldest) = recent.order.pop_front() {
+                recent.by_id.remove(&oldest);
+            }
+        }
+    }
+
+    fn lookup(&self, request_id: &str) -> Option<Arc<RequestSummary>> {
+        self.recent.lock().unwrap().by_id.get(request_id).cloned()
+    }
+
+    // Drops the entries `remove` matches, returning how many there were
+    pub(crate) fn remove(
+        &self,
+        remove: impl Fn(&serde_json::Value) -> bool,
+    ) -> std::io::Result<usize> {
+        remove_lines(&self.config.path, &self.file, remove)
+    }
+
+    fn write(&self, entry: &serde_json::Value) -> Result<(), ProxyError> {
+        writeln!(self.file.lock().unwrap(), "{}", entry)
+            .map_err(|err| ProxyError::BodyReadError(format!("Failed to store feedback: {}", err)))
+    }
+}
+
+// e.g. {"request_id": "req-42", "rating": "down", "comment": "Wrong answer"}
+#[derive(Debug, Deserialize)]
+struct Feedback {
+    request_id: String,
+    // A number (e.g. 1 to 5, or -1 and 1) or a label such as "up"
+    rating: serde_json::Value,
+    comment: Option<String>,
+}
+
+pub(crate) async fn feedback_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    req: Request,
+) -> Result<Response, ProxyError> {
+    let target = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "/v1/feedback".to_string());
+    let body: Bytes = axum::body::to_bytes(req.into_body(), usize::MAX)
+        .await
+        .map_err(|e| ProxyError::BodyReadError(e.to_string()))?;
+    let signed = SignedRequest {
+        method: "POST",
+        target: &target,
+        body: &body,
+    };
+    let namespace = state.resolve_namespace(None, &headers, &signed).await?;
+    let store = state.feedback.as_ref().ok_or_else(|| {
+        ProxyError::Forbidden("Feedback is disabled, set feedback.path".to_string())
+    })?;
+
+    let feedback: Feedback = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid feedback: {}", e)))?;
+    let rating_ok = match &feedback.rating {
+        serde_json::Value::Number(_) => true,
+        serde_json::Value::String(label) => !label.trim().is_empty(),
+        _ => false,
+    };
+    if !rating_ok {
+        return Err(ProxyError::InvalidRequest(
+            "rating must be a number or a non-empty string".to_string(),
+        ));
+    }
+    if feedback
+        .comment
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > MAX_COMMENT_CHARS)
+    {
+        return Err(ProxyError::InvalidRequest(format!(
+            "comment is longer than {} characters",
+            MAX_COMMENT_CHARS
+        )));
+    }
+
+    // Requests older than the in-memory window are stored without details
+    let request = store.lookup(&feedback.request_id);
+    let tenant = namespace.tenant.map(|t| t.name.as_str());
+    if let Some(request) = &request {
+        if tenant.is_some() && request.tenant.as_deref() != tenant {
+            return Err(ProxyError::Forbidden(
+                "Request belongs to another tenant".to_string(),
+            ));
+        }
+    }
+
+    let entry = serde_json::json!({
+        "time": format_utc(unix_now()),
+        "request_id": feedback.request_id,
+        "rating": feedback.rating,
+        "comment": feedback.comment,
+        "tenant": tenant,
+        "client": namespace.client.map(|c| c.name.as_str()),
+        "request": request.as_deref(),
+    });
+    store.write(&entry)?;
+    println!(
+        "💬 Feedback {} for {}",
+        entry["rating"], entry["request_id"]
+    );
+    Ok(json_response(&serde_json::json!({
+        "request_id": entry["request_id"],
+        "stored": true,
+        "request_found": request.is_some(),
+    })))
+}
@@ -0,0 +1,400 @@
+// Watches fine-tuning jobs created through the proxy: their status is polled
+// upstream and every change is relayed to the configured webhooks, and
+// /admin/fine_tunes lists the jobs of all providers in one place. With shared
+// storage, jobs are listed there and only the leader polls them, so each
+// change is relayed once per cluster.
+
+use crate::config::FineTunesConfig;
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::secrets::hex_encode;
+use crate::state::AppState;
+use crate::storage::Store;
+use crate::time::{format_utc, unix_now};
+use crate::upstream_auth::{auth_headers, UpstreamAuth};
+use axum::{extract::State, http::HeaderMap, response::Response};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Finished jobs beyond this many are dropped, oldest first
+const MAX_FINISHED_JOBS: usize = 1000;
+
+fn finished(status: &str) -> bool {
+    matches!(status, "succeeded" | "failed" | "cancelled")
+}
+
+// The key a job was created with
+enum JobKey {
+    // A client's own key, which only the replica that saw it polls with
+    Own(String),
+    // A configured upstream key, by fingerprint; shared jobs only hold this
+    Configured(String),
+}
+
+struct WatchedJob {
+    // The provider name, or the host for the default upstream
+    provider: String,
+    client: String,
+    // Where the job's status is read, with the key it was created with
+    url: String,
+    key: JobKey,
+    job: Value,
+    updated_at: u64,
+}
+
+impl WatchedJob {
+    fn status(&self) -> &str {
+        self.job["status"].as_str().unwrap_or("unknown")
+    }
+
+    fn view(&self) -> Value {
+        json!({
+            "id": self.job["id"],
+            "provider": self.provider,
+            "client": self.client,
+            "status": self.status(),
+            "model": self.job["model"],
+            "fine_tuned_model": self.job["fine_tuned_model"],
+            "error": self.job["error"],
+            "updated_at": format_utc(self.updated_at),
+        })
+    }
+
+    // The storage record of a shared job
+    fn record(&self) -> Option<Value> {
+        let JobKey::Configured(fingerprint) = &self.key else {
+            return None;
+        };
+        Some(json!({
+            "provider": self.provider,
+            "client": self.client,
+            "url": self.url,
+            "key": fingerprint,
+            "job": self.job,
+            "updated_at": self.updated_at,
+        }))
+    }
+
+    fn from_record(record: &Value) -> Option<Self> {
+        Some(Self {
+            provider: record["provider"].as_str()?.to_string(),
+            client: record["client"].as_str()?.to_string(),
+            url: record["url"].as_str()?.to_string(),
+            key: JobKey::Configured(record["key"].as_str()?.to_string()),
+            job: record["job"].clone(),
+            updated_at: record["updated_at"].as_u64()?,
+        })
+    }
+}
+
+fn fingerprint(key: &str) -> String {
+    hex_encode(&Sha256::digest(key.as_bytes())[..8])
+}
+
+// The configured upstream key with this fingerprint, if any
+fn configured_key(state: &AppState, fingerprint_of: &str) -> Option<String> {
+    let tenants = state.tenants.iter().map(|t| t.openai_api_key.get());
+    let providers = state.providers.iter().flat_map(|p| {
+        let pooled = p.key_pool.iter().flat_map(|pool| &pool.keys);
+        p.api_key
+            .iter()
+            .chain(pooled.map(|k| &k.key))
+            .map(|k| k.get())
+    });
+    std::iter::once(state.openai_api_key.get())
+        .chain(tenants)
+        .chain(providers)
+        .find(|key| fingerprint(key) == fingerprint_of)
+}
+
+pub(crate) struct FineTunes {
+    config: FineTunesConfig,
+    client: reqwest::Client,
+    jobs: Mutex<BTreeMap<String, WatchedJob>>,
+}
+
+impl FineTunes {
+    pub(crate) fn new(config: FineTunesConfig, client: reqwest::Client) -> Self {
+        Self {
+            config,
+            client,
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Starts watching a job from the upstream's answer to its creation
+    pub(crate) async fn watch(
+        &self,
+        state: &AppState,
+        created: &[u8],
+        create_url: &str,
+        provider: &str,
+        key: &str,
+        client: &str,
+    ) {
+        let Ok(job) = serde_json::from_slice::<Value>(created) else {
+            return;
+        };
+        let Some(id) = job["id"].as_str().map(str::to_string) else {
+            return;
+        };
+        println!("🎛️  Watching fine-tuning job {} on {}", id, provider);
+        // Jobs of configured keys can be polled by any replica
+        let key = match &state.storage {
+            Some(_) if configured_key(state, &fingerprint(key)).is_some() => {
+                JobKey::Configured(fingerprint(key))
+            }
+            _ => JobKey::Own(key.to_string()),
+        };
+        let watched = WatchedJob {
+            provider: provider.to_string(),
+            client: client.to_string(),
+            url: format!(
+                "{}/{}",
+                create_url.split('?').next().unwrap_or_default(),
+                id
+            ),
+            key,
+            job,
+            updated_at: unix_now(),
+        };
+        self.relay(&watched, None);
+        if let (Some(store), Some(record)) = (&state.storage, watched.record()) {
+            if let Err(err) = share(store, &id, &record).await {
+                eprintln!("⚠️  Failed to share fine-tuning job {}: {}", id, err);
+            }
+        }
+        self.jobs.lock().unwrap().insert(id, watched);
+    }
+
+    // Sends a status change to every webhook
+    fn relay(&self, job: &WatchedJob, previous: Option<&str>) {
+        let event = json!({
+            "type": format!("fine_tuning.job.{}", job.status()),
+            "previous_status": previous,
+            "provider": job.provider,
+            "client": job.client,
+            "job": job.job,
+            "timestamp": format_utc(unix_now()),
+        });
+        for url in &self.config.webhooks {
+            let request = self.client.post(url).json(&event);
+            tokio::spawn(async move {
+                match request.send().await {
+                    Ok(response) if !response.status().is_success() => {
+                        eprintln!("⚠️  Fine-tuning webhook returned {}", response.status());
+                    }
+                    Err(err) => eprintln!("⚠️  Fine-tuning webhook failed: {}", err),
+                    Ok(_) => {}
+                }
+            });
+        }
+    }
+
+    // Reads the status of every unfinished job once: the jobs only this
+    // replica can poll, and on the leader the shared ones. Other replicas
+    // reload the shared jobs for their listing.
+    async fn poll(&self, state: &AppState) {
+        if let Some(store) = &state.storage {
+            if let Err(err) = self.load_shared(store).await {
+                eprintln!("⚠️  Failed to load shared fine-tuning jobs: {}", err);
+            }
+        }
+        let leader = state.leadership.is_leader();
+        let pending: Vec<(String, String, String, Option<String>)> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, job)| !finished(job.status()))
+            .filter_map(|(id, job)| {
+                let key = match &job.key {
+                    JobKey::Own(key) => Some(key.clone()),
+                    JobKey::Configured(_) if !leader => return None,
+                    JobKey::Configured(fingerprint) => configured_key(state, fingerprint),
+                };
+                Some((id.clone(), job.provider.clone(), job.url.clone(), key))
+            })
+            .collect();
+        for (id, provider, url, key) in pending {
+            let Some(key) = key else {
+                eprintln!("⚠️  No configured key polls fine-tuning job {}", id);
+                continue;
+            };
+            let auth = state
+                .providers
+                .iter()
+                .find(|p| p.config.name == provider)
+                .and_then(|p| p.auth.as_ref());
+            let job = match self.fetch(auth, &url, &key).await {
+                Ok(job) => job,
+                Err(err) => {
+                    eprintln!("⚠️  Failed to poll fine-tuning job {}: {}", id, err);
+                    continue;
+                }
+            };
+            let record = {
+                let mut jobs = self.jobs.lock().unwrap();
+                let Some(watched) = jobs.get_mut(&id) else {
+                    continue;
+                };
+                let previous = watched.status().to_string();
+                watched.job = job;
+                if watched.status() == previous {
+                    continue;
+                }
+                println!("🎛️  Fine-tuning job {} is {}", id, watched.status());
+                watched.updated_at = unix_now();
+                self.relay(watched, Some(&previous));
+                watched.record()
+            };
+            if let (Some(store), Some(record)) = (&state.storage, record) {
+                if let Err(err) = share(store, &id, &record).await {
+                    eprintln!("⚠️  Failed to share fine-tuning job {}: {}", id, err);
+                }
+            }
+        }
+        self.prune();
+    }
+
+    // Takes the shared jobs' latest records from the storage
+    async fn load_shared(&self, store: &Store) -> Result<(), String> {
+        let ids = store.backend.hgetall(&store.key(SHARED_JOBS)).await?;
+        for (id, watching) in ids {
+            // Unfinished jobs, and the final record of one seen unfinished
+            let seen_done = self
+                .jobs
+                .lock()
+                .unwrap()
+                .get(&id)
+                .map(|job| finished(job.status()));
+            if seen_done == Some(true) || (watching <= 0 && seen_done.is_none()) {
+                continue;
+            }
+            let Some(record) = store.backend.get(&store.key(&job_key(&id))).await? else {
+                continue;
+            };
+            let Some(job) = serde_json::from_slice(&record)
+                .ok()
+                .as_ref()
+                .and_then(WatchedJob::from_record)
+            else {
+                continue;
+            };
+            self.jobs.lock().unwrap().insert(id, job);
+        }
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        auth: Option<&UpstreamAuth>,
+        url: &str,
+        key: &str,
+    ) -> Result<Value, String> {
+        let headers = auth_headers(auth, key, "GET", url, None)
+            .await
+            .map_err(|_| "no upstream credentials".to_string())?;
+        let mut request = self.client.get(url);
+        for (name, value) in &headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("upstream returned {}", response.status()));
+        }
+        response.json().await.map_err(|e| e.to_string())
+    }
+
+    fn prune(&self) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut done: Vec<(u64, String)> = jobs
+            .iter()
+            .filter(|(_, job)| finished(job.status()))
+            .map(|(id, job)| (job.updated_at, id.clone()))
+            .collect();
+        if done.len() <= MAX_FINISHED_JOBS {
+            return;
+        }
+        done.sort();
+        for (_, id) in &done[..done.len() - MAX_FINISHED_JOBS] {
+            jobs.remove(id);
+        }
+    }
+}
+
+// Shared jobs: a hash of job IDs, 1 while a job is unfinished and 0 after,
+// and a record per job
+const SHARED_JOBS: &str = "fine_tunes:jobs";
+// Records of finished jobs are kept this long
+const FINISHED_RECORD_TTL_SECS: u64 = 7 * 24 * 3600;
+
+fn job_key(id: &str) -> String {
+    format!("fine_tunes:job:{}", id)
+}
+
+// Writes a shared job's record, and lists it while it is unfinished
+async fn share(store: &Store, id: &str, record: &Value) -> Result<(), String> {
+    let done = record["job"]["status"].as_str().is_some_and(finished);
+    let ttl = done.then_some(FINISHED_RECORD_TTL_SECS);
+    let bytes = serde_json::to_vec(record).unwrap_or_default();
+    store
+        .backend
+        .set(&store.key(&job_key(id)), bytes, ttl)
+        .await?;
+    let index = store.key(SHARED_JOBS);
+    let watching = store.backend.hincr(&index, id, 0, None).await?;
+    match (done, watching) {
+        (false, 0) => store.backend.hincr(&index, id, 1, None).await?,
+        (true, 1..) => store.backend.hincr(&index, id, -watching, None).await?,
+        _ => watching,
+    };
+    Ok(())
+}
+
+// Polls the watched jobs every poll_interval_secs
+pub(crate) fn start(state: &Arc<AppState>) {
+    let Some(fine_tunes) = &state.fine_tunes else {
+        return;
+    };
+    let period = Duration::from_secs(fine_tunes.config.poll_interval_secs.max(1));
+    let state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(period).await;
+            if let Some(fine_tunes) = &state.fine_tunes {
+                fine_tunes.poll(&state).await;
+            }
+        }
+    });
+}
+
+pub(crate) async fn list_fine_tunes_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let fine_tunes = state
+        .fine_tunes
+        .as_ref()
+        .ok_or_else(|| ProxyError::NotFound("Fine-tuning jobs are not watched".to_string()))?;
+    let jobs = fine_tunes.jobs.lock().unwrap();
+    // Job counts by provider and status
+    let mut providers: HashMap<&str, BTreeMap<&str, u64>> = HashMap::new();
+    for job in jobs.values() {
+        *providers
+            .entry(job.provider.as_str())
+            .or_default()
+            .entry(job.status())
+            .or_default() += 1;
+    }
+    let data: Vec<Value> = jobs.values().map(WatchedJob::view).collect();
+    Ok(json_response(&json!({
+        "object": "list",
+        "data": data,
+        "providers": providers,
+    })))
+}
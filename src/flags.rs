@@ -0,0 +1,151 @@
+// Feature flags: kill switches for risky features, set in [feature_flags] and
+// toggled at runtime through /admin/flags. Every flag defaults to on; the
+// feature behind it still needs its own configuration.
+
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use axum::{extract::State, http::HeaderMap, response::Response};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Flag {
+    ResponseCache,
+    ThinkingInjection,
+    PostProcessing,
+    PromptCaching,
+    CompletionRetry,
+    RoutingRules,
+}
+
+const FLAGS: [Flag; 6] = [
+    Flag::ResponseCache,
+    Flag::ThinkingInjection,
+    Flag::PostProcessing,
+    Flag::PromptCaching,
+    Flag::CompletionRetry,
+    Flag::RoutingRules,
+];
+
+impl Flag {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Flag::ResponseCache => "response_cache",
+            Flag::ThinkingInjection => "thinking_injection",
+            Flag::PostProcessing => "post_processing",
+            Flag::PromptCaching => "prompt_caching",
+            Flag::CompletionRetry => "completion_retry",
+            Flag::RoutingRules => "routing_rules",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        FLAGS.into_iter().find(|flag| flag.name() == name)
+    }
+}
+
+pub(crate) struct FeatureFlags {
+    enabled: [AtomicBool; FLAGS.len()],
+}
+
+impl FeatureFlags {
+    pub(crate) fn new(config: &HashMap<String, bool>) -> Result<Self, String> {
+        let flags = Self {
+            enabled: std::array::from_fn(|_| AtomicBool::new(true)),
+        };
+        for (name, on) in config {
+            let flag = Flag::from_name(name).ok_or_else(|| unknown_flag(name))?;
+            flags.set(flag, *on);
+        }
+        Ok(flags)
+    }
+
+    pub(crate) fn enabled(&self, flag: Flag) -> bool {
+        self.enabled[flag as usize].load(Ordering::Relaxed)
+    }
+
+    fn set(&self, flag: Flag, on: bool) {
+        self.enabled[flag as usize].store(on, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> BTreeMap<&'static str, bool> {
+        FLAGS
+            .into_iter()
+            .map(|flag| (flag.name(), self.enabled(flag)))
+            .collect()
+    }
+
+    pub(crate) fn disabled(&self) -> Vec<&'static str> {
+        FLAGS
+            .into_iter()
+            .filter(|flag| !self.enabled(*flag))
+            .map(Flag::name)
+            .collect()
+    }
+
+    // Appended to the Prometheus output
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP openai_proxy_feature_flag Feature flag state, 1 when on.\n");
+        out.push_str("# TYPE openai_proxy_feature_flag gauge\n");
+        for (name, on) in self.snapshot() {
+            out.push_str(&format!(
+                "openai_proxy_feature_flag{{flag=\"{}\"}} {}\n",
+                name, on as u8
+            ));
+        }
+        out
+    }
+}
+
+fn unknown_flag(name: &str) -> String {
+    let known: Vec<_> = FLAGS.into_iter().map(Flag::name).collect();
+    format!(
+        "Unknown feature flag {} (known flags: {})",
+        name,
+        known.join(", ")
+    )
+}
+
+pub(crate) async fn get_flags_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    Ok(json_response(&serde_json::json!({
+        "flags": state.flags.snapshot(),
+    })))
+}
+
+// Partial update, e.g. {"response_cache": false}
+pub(crate) async fn update_flags_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let update: HashMap<String, bool> = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid flags: {}", e)))?;
+    // All names are checked before anything changes
+    let mut changes = Vec::new();
+    for (name, on) in &update {
+        let flag =
+            Flag::from_name(name).ok_or_else(|| ProxyError::InvalidRequest(unknown_flag(name)))?;
+        changes.push((flag, *on));
+    }
+    for (flag, on) in changes {
+        if state.flags.enabled(flag) != on {
+            println!(
+                "🚩 Feature flag {} turned {}",
+                flag.name(),
+                if on { "on" } else { "off" }
+            );
+        }
+        state.flags.set(flag, on);
+    }
+    Ok(json_response(&serde_json::json!({
+        "flags": state.flags.snapshot(),
+    })))
+}
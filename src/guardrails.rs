@@ -0,0 +1,425 @@
+// Limits on chat payloads and remote image inlining
+
+use crate::config::{GuardrailsConfig, UserFieldPolicy};
+use crate::error::ProxyError;
+use crate::request::OutgoingRequest;
+use crate::state::AppState;
+use hyper_014::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+const MAX_IMAGE_REDIRECTS: usize = 5;
+
+impl GuardrailsConfig {
+    // Applies the user_field policy; `identity` is the authenticated client
+    pub(crate) fn check_user(
+        &self,
+        obj: &mut serde_json::Map<String, serde_json::Value>,
+        identity: Option<String>,
+    ) -> Result<(), ProxyError> {
+        let present = obj
+            .get("user")
+            .and_then(|u| u.as_str())
+            .is_some_and(|u| !u.trim().is_empty());
+        match self.user_field {
+            _ if present => Ok(()),
+            UserFieldPolicy::Off => Ok(()),
+            UserFieldPolicy::Populate => {
+                if let Some(identity) = identity {
+                    obj.insert("user".to_string(), identity.into());
+                }
+                Ok(())
+            }
+            UserFieldPolicy::Require => Err(ProxyError::InvalidRequest(
+                "The user field is required, set it to an identifier of the end user".to_string(),
+            )),
+        }
+    }
+
+    // Whether check_request has anything to check on the path
+    pub(crate) fn applies(&self, path: &str) -> bool {
+        let chat = self.max_messages.is_some()
+            || self.max_message_chars.is_some()
+            || self.max_images.is_some()
+            || self.max_image_base64_bytes.is_some()
+            || !self.allowed_image_url_schemes.is_empty()
+            || self.inline_remote_images;
+        (chat && path.ends_with("chat/completions"))
+            || (self.user_field != UserFieldPolicy::Off && path.ends_with("completions"))
+    }
+
+    pub(crate) fn check_chat(&self, body: &serde_json::Value) -> Result<(), ProxyError> {
+        let Some(messages) = body.get("messages").and_then(|m| m.as_array()) else {
+            return Ok(());
+        };
+
+        if let Some(max) = self.max_messages {
+            if messages.len() > max {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Too many messages: {} (maximum is {})",
+                    messages.len(),
+                    max
+                )));
+            }
+        }
+
+        let mut images = 0;
+        let mut image_bytes = 0;
+        for (index, message) in messages.iter().enumerate() {
+            let (chars, message_images) = match message.get("content") {
+                Some(serde_json::Value::String(text)) => (text.chars().count(), 0),
+                Some(serde_json::Value::Array(parts)) => {
+                    parts.iter().fold((0, 0), |(chars, images), part| {
+                        match part.get("type").and_then(|t| t.as_str()) {
+                            Some("text") => (
+                                chars
+                                    + part
+                                        .get("text")
+                                        .and_then(|t| t.as_str())
+                                        .map(|t| t.chars().count())
+                                        .unwrap_or(0),
+                                images,
+                            ),
+                            Some("image_url") | Some("input_image") => (chars, images + 1),
+                            _ => (chars, images),
+                        }
+                    })
+                }
+                _ => (0, 0),
+            };
+
+            for url in message_image_urls(message) {
+                if let Some(scheme) = url.split_once(':').map(|(scheme, _)| scheme) {
+                    if !self.allowed_image_url_schemes.is_empty()
+                        && !self
+                            .allowed_image_url_schemes
+                            .iter()
+                            .any(|s| s.eq_ignore_ascii_case(scheme))
+                    {
+                        return Err(ProxyError::InvalidRequest(format!(
+                            "Image URL scheme \"{}\" is not allowed in message {}",
+                            scheme, index
+                        )));
+                    }
+                }
+                if url.starts_with("data:") {
+                    image_bytes += url.split_once(',').map(|(_, data)| data.len()).unwrap_or(0);
+                }
+            }
+
+            if let Some(max) = self.max_message_chars {
+                if chars > max {
+                    return Err(ProxyError::InvalidRequest(format!(
+                        "Message {} is too long: {} characters (maximum is {})",
+                        index, chars, max
+                    )));
+                }
+            }
+            images += message_images;
+        }
+
+        if let Some(max) = self.max_images {
+            if images > max {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Too many images: {} (maximum is {})",
+                    images, max
+                )));
+            }
+        }
+
+        if let Some(max) = self.max_image_base64_bytes {
+            if image_bytes > max {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Image data is too large: {} base64 bytes (maximum is {})",
+                    image_bytes, max
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Checks a request body before its stages rewrite it, fetching remote images
+// into it when configured
+pub(crate) async fn check_request(request: &mut OutgoingRequest<'_, '_>) -> Result<(), ProxyError> {
+    let guardrails = &request.state.guardrails;
+    if request.path.ends_with("chat/completions") {
+        guardrails.check_chat(&request.body)?;
+        if guardrails.inline_remote_images
+            && inline_remote_images(request.state, &mut request.body).await?
+        {
+            // Inlined images count towards the base64 budget
+            guardrails.check_chat(&request.body)?;
+        }
+    }
+    if request.path.ends_with("completions") {
+        let namespace = request.namespace;
+        let identity = namespace
+            .tenant
+            .zip(namespace.client)
+            .map(|(tenant, client)| format!("{}/{}", tenant.name, client.name));
+        guardrails.check_user(request.body.as_object_mut().unwrap(), identity)?;
+    }
+    Ok(())
+}
+
+// Image URLs of a chat message, in both the {"url": ...} and plain string forms
+pub(crate) fn message_image_urls(message: &serde_json::Value) -> Vec<&str> {
+    let Some(parts) = message.get("content").and_then(|c| c.as_array()) else {
+        return Vec::new();
+    };
+    parts
+        .iter()
+        .filter_map(|part| {
+            let image = part.get("image_url")?;
+            image
+                .get("url")
+                .and_then(|u| u.as_str())
+                .or_else(|| image.as_str())
+        })
+        .collect()
+}
+
+// Replace http(s) image URLs with base64 data URLs, returns whether anything changed
+pub(crate) async fn inline_remote_images(
+    state: &AppState,
+    body: &mut serde_json::Value,
+) -> Result<bool, ProxyError> {
+    use base64::Engine;
+
+    let Some(messages) = body.get_mut("messages").and_then(|m| m.as_array_mut()) else {
+        return Ok(false);
+    };
+
+    let mut changed = false;
+    for message in messages.iter_mut() {
+        let Some(parts) = message.get_mut("content").and_then(|c| c.as_array_mut()) else {
+            continue;
+        };
+        for part in parts.iter_mut() {
+            let Some(image) = part.get_mut("image_url") else {
+                continue;
+            };
+            let slot = match image {
+                serde_json::Value::Object(fields) => match fields.get_mut("url") {
+                    Some(url) => url,
+                    None => continue,
+                },
+                string => string,
+            };
+            let Some(url) = slot.as_str().map(|u| u.to_string()) else {
+                continue;
+            };
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                continue;
+            }
+
+            let (content_type, bytes) =
+                fetch_image(state, &url, state.guardrails.max_remote_image_bytes).await?;
+            *slot = serde_json::Value::String(format!(
+                "data:{};base64,{}",
+                content_type,
+                base64::engine::general_purpose::STANDARD.encode(&bytes)
+            ));
+            changed = true;
+        }
+    }
+
+    if changed {
+        println!("🖼️  Inlined remote images as base64");
+    }
+    Ok(changed)
+}
+
+pub(crate) async fn fetch_image(
+    state: &AppState,
+    url: &str,
+    max_bytes: usize,
+) -> Result<(String, Vec<u8>), ProxyError> {
+    let parsed = Url::parse(url)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid image URL {}: {}", url, e)))?;
+    check_image_url(&parsed, &state.guardrails.remote_image_hosts).map_err(|e| {
+        ProxyError::InvalidRequest(format!("Refused to fetch image {}: {}", url, e))
+    })?;
+    let mut response = state.image_client.get(parsed).send().await.map_err(|e| {
+        // The resolver's and redirect policy's refusals are the error's source
+        let reason = std::error::Error::source(&e)
+            .map(|source| format!("{}: {}", e, source))
+            .unwrap_or_else(|| e.to_string());
+        ProxyError::InvalidRequest(format!("Failed to fetch image {}: {}", url, reason))
+    })?;
+    if !response.status().is_success() {
+        return Err(ProxyError::InvalidRequest(format!(
+            "Failed to fetch image {}: status {}",
+            url,
+            response.status()
+        )));
+    }
+
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with("image/"))
+        .unwrap_or("image/png")
+        .to_string();
+
+    // Read incrementally so oversized images are rejected without buffering them
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| ProxyError::InvalidRequest(format!("Failed to fetch image {}: {}", url, e)))?
+    {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > max_bytes {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Image {} is larger than {} bytes",
+                url, max_bytes
+            )));
+        }
+    }
+
+    Ok((content_type, bytes))
+}
+
+// Image fetches must not reach the proxy's own network, e.g. the cloud
+// metadata service at 169.254.169.254. Hosts given as IPs are checked here,
+// for the URL and every redirect; names by the resolver, so the address
+// connected to is the one that was checked. System proxies are ignored, as
+// they would resolve the name instead.
+pub(crate) fn image_client(config: &GuardrailsConfig) -> reqwest::Result<reqwest::Client> {
+    let allowed = Arc::new(config.remote_image_hosts.clone());
+    let resolver = Arc::new(ImageResolver {
+        allowed: allowed.clone(),
+    });
+    reqwest::Client::builder()
+        .no_proxy()
+        .dns_resolver(resolver)
+        .redirect(Policy::custom(move |attempt: Attempt| {
+            if attempt.previous().len() > MAX_IMAGE_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match check_image_url(attempt.url(), &allowed) {
+                Ok(()) => attempt.follow(),
+                Err(reason) => attempt.error(format!("redirect refused: {}", reason)),
+            }
+        }))
+        .build()
+}
+
+fn check_image_url(url: &Url, allowed: &[String]) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("scheme {} is not fetched", url.scheme()));
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !allowed.is_empty() {
+        return match host_allowed(host, allowed) {
+            true => Ok(()),
+            false => Err(format!("host {} is not in remote_image_hosts", host)),
+        };
+    }
+    let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    else {
+        return Ok(());
+    };
+    match is_public(ip) {
+        true => Ok(()),
+        false => Err(format!("{} is not a public address", ip)),
+    }
+}
+
+// Exact names, or "*.example.com" for its subdomains
+fn host_allowed(host: &str, allowed: &[String]) -> bool {
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    allowed
+        .iter()
+        .any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain.to_ascii_lowercase().as_str())
+                .is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+            None => host.eq_ignore_ascii_case(pattern),
+        })
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_v4(v4);
+            }
+            let segments = ip.segments();
+            // NAT64 addresses carry an IPv4 one in their last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public_v4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local fc00::/7 and link-local fe80::/10
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                // Documentation 2001:db8::/32
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved 240.0.0.0/4
+        || a >= 240)
+}
+
+// Resolves image hosts, refusing names with any non-public address so a
+// record cannot point the fetch inside
+struct ImageResolver {
+    allowed: Arc<Vec<String>>,
+}
+
+impl Resolve for ImageResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let trusted = host_allowed(&host, &self.allowed);
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if !trusted {
+                if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                    return Err(format!(
+                        "{} resolves to {}, which is not a public address",
+                        host,
+                        addr.ip()
+                    )
+                    .into());
+                }
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,155 @@
+// Live feed of finished requests for the /admin/inspect WebSocket
+
+use crate::error::ProxyError;
+use crate::proxy::RequestLog;
+use crate::state::AppState;
+use crate::time::{format_utc, unix_now};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+// Summaries a slow subscriber may fall behind by before it skips ahead
+const FEED_CAPACITY: usize = 256;
+
+// What a finished request looks like on the feed. Bodies, headers and keys
+// are never included.
+#[derive(Debug, Serialize, Clone)]
+pub(crate) struct RequestSummary {
+    pub(crate) request_id: String,
+    pub(crate) finished_at: String,
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) model: Option<String>,
+    // Set when a routing rule picked the model
+    pub(crate) requested_model: Option<String>,
+    pub(crate) client: String,
+    pub(crate) tenant: Option<String>,
+    // The end user named in the request's user field
+    pub(crate) user: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) tags: BTreeMap<String, String>,
+    pub(crate) provider: String,
+    pub(crate) status: u16,
+    pub(crate) latency_ms: u64,
+    pub(crate) prompt_tokens: Option<u64>,
+    pub(crate) completion_tokens: Option<u64>,
+    pub(crate) streaming: bool,
+    pub(crate) trace_id: Option<String>,
+}
+
+impl RequestSummary {
+    pub(crate) fn new(log: &RequestLog, status: u16, latency_ms: u64) -> Self {
+        Self {
+            request_id: log.request_id.clone(),
+            finished_at: format_utc(unix_now()),
+            method: log.method.clone(),
+            path: log.path.clone(),
+            model: log.model.clone(),
+            requested_model: log.requested_model.clone(),
+            client: log.client.clone(),
+            tenant: log.tenant.clone(),
+            user: log.user.clone(),
+            tags: log.tags.clone(),
+            provider: log.provider.clone(),
+            status,
+            latency_ms,
+            prompt_tokens: log.prompt_tokens,
+            completion_tokens: log.completion_tokens,
+            streaming: log.streaming,
+            trace_id: log.trace_id.clone(),
+        }
+    }
+}
+
+pub(crate) struct Inspector {
+    feed: broadcast::Sender<Arc<RequestSummary>>,
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Self {
+            feed: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+}
+
+impl Inspector {
+    pub(crate) fn publish(&self, summary: Arc<RequestSummary>) {
+        if self.feed.receiver_count() > 0 {
+            let _ = self.feed.send(summary);
+        }
+    }
+}
+
+// Optional filters, e.g. /admin/inspect?tenant=acme&model=gpt-4o
+#[derive(Debug, Deserialize)]
+pub(crate) struct InspectFilter {
+    model: Option<String>,
+    tenant: Option<String>,
+    client: Option<String>,
+}
+
+impl InspectFilter {
+    fn matches(&self, summary: &RequestSummary) -> bool {
+        let field = |filter: &Option<String>, value: Option<&str>| {
+            filter.as_deref().map(|f| value == Some(f)).unwrap_or(true)
+        };
+        field(&self.model, summary.model.as_deref())
+            && field(&self.tenant, summary.tenant.as_deref())
+            && field(&self.client, Some(&summary.client))
+    }
+}
+
+pub(crate) async fn inspect_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(filter): Query<InspectFilter>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let feed = state.inspector.feed.subscribe();
+    Ok(ws.on_upgrade(move |socket| relay(socket, feed, filter)))
+}
+
+async fn relay(
+    mut socket: WebSocket,
+    mut feed: broadcast::Receiver<Arc<RequestSummary>>,
+    filter: InspectFilter,
+) {
+    println!("🔍 Inspector connected");
+    loop {
+        tokio::select! {
+            summary = feed.recv() => match summary {
+                Ok(summary) if filter.matches(&summary) => {
+                    let text = serde_json::to_string(&*summary).unwrap_or_default();
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    let notice = serde_json::json!({"skipped": skipped}).to_string();
+                    if socket.send(Message::Text(notice)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Messages from the client are ignored until it closes
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    println!("🔍 Inspector disconnected");
+}
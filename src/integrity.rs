@@ -0,0 +1,209 @@
+// Content hashes for file transfers: a Content-MD5 (base64, as in RFC 1864) or
+// x-content-sha256 (hex) of the client is checked while the upload streams
+// through, those of the upstream against the downloaded body, and responses
+// get both, so a transfer corrupted on the way is noticed instead of stored.
+
+use crate::config::IntegrityConfig;
+use crate::secrets::{hex_decode, hex_encode};
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::BoxError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::{ready, Stream};
+use openssl::hash::{Hasher, MessageDigest};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+const SHA256_HEADER: &str = "x-content-sha256";
+
+pub(crate) struct Integrity {
+    paths: Vec<String>,
+    generate: bool,
+}
+
+impl Integrity {
+    // Passthrough endpoints are always covered
+    pub(crate) fn new(config: IntegrityConfig, passthrough_paths: &[String]) -> Self {
+        let mut paths = config.paths;
+        paths.extend(passthrough_paths.iter().cloned());
+        Self {
+            paths,
+            generate: config.generate,
+        }
+    }
+
+    pub(crate) fn applies(&self, path: &str) -> bool {
+        self.paths
+            .iter()
+            .any(|suffix| path.ends_with(suffix.as_str()))
+    }
+
+    // The hash headers for a response body, unless turned off
+    pub(crate) fn headers(&self, body: &[u8]) -> Vec<(HeaderName, HeaderValue)> {
+        if !self.generate {
+            return Vec::new();
+        }
+        let mut digests = Digests::new();
+        digests.update(body);
+        let (md5, sha256) = digests.finish();
+        [
+            ("content-md5", STANDARD.encode(md5)),
+            (SHA256_HEADER, hex_encode(&sha256)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_static(name),
+                HeaderValue::from_str(&value).ok()?,
+            ))
+        })
+        .collect()
+    }
+}
+
+// The hashes a body is declared to have
+pub(crate) struct Expected {
+    md5: Option<Vec<u8>>,
+    sha256: Option<Vec<u8>>,
+}
+
+// None without hash headers
+pub(crate) fn expected(headers: &HeaderMap) -> Result<Option<Expected>, String> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+    };
+    let md5 = header("content-md5")
+        .map(|value| STANDARD.decode(value).ok().filter(|d| d.len() == 16))
+        .map(|digest| digest.ok_or("Invalid Content-MD5 header"))
+        .transpose()?;
+    let sha256 = header(SHA256_HEADER)
+        .map(|value| hex_decode(value).filter(|d| d.len() == 32))
+        .map(|digest| digest.ok_or("Invalid x-content-sha256 header"))
+        .transpose()?;
+    Ok((md5.is_some() || sha256.is_some()).then_some(Expected { md5, sha256 }))
+}
+
+impl Expected {
+    // The header the hashes do not match
+    fn check(&self, md5: &[u8], sha256: &[u8]) -> Result<(), &'static str> {
+        if self.md5.as_deref().is_some_and(|expected| expected != md5) {
+            return Err("Content-MD5");
+        }
+        if self
+            .sha256
+            .as_deref()
+            .is_some_and(|expected| expected != sha256)
+        {
+            return Err("x-content-sha256");
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn verify(expected: &Expected, body: &[u8]) -> Result<(), &'static str> {
+    let mut digests = Digests::new();
+    digests.update(body);
+    let (md5, sha256) = digests.finish();
+    expected.check(&md5, &sha256)
+}
+
+struct Digests {
+    md5: Option<Hasher>,
+    sha256: Option<Hasher>,
+}
+
+impl Digests {
+    fn new() -> Self {
+        Self {
+            md5: Hasher::new(MessageDigest::md5()).ok(),
+            sha256: Hasher::new(MessageDigest::sha256()).ok(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for hasher in [&mut self.md5, &mut self.sha256] {
+            if hasher.as_mut().is_some_and(|h| h.update(data).is_err()) {
+                *hasher = None;
+            }
+        }
+    }
+
+    // Empty digests, which match nothing, if hashing failed
+    fn finish(&mut self) -> (Vec<u8>, Vec<u8>) {
+        let finish = |hasher: &mut Option<Hasher>| {
+            hasher
+                .as_mut()
+                .and_then(|h| h.finish().ok())
+                .map(|digest| digest.to_vec())
+                .unwrap_or_default()
+        };
+        (finish(&mut self.md5), finish(&mut self.sha256))
+    }
+}
+
+// Hashes a streamed upload as it is sent. On a mismatch the stream ends in an
+// error instead, so the upstream gets a broken upload rather than a corrupted
+// file, and the mismatch is set for the response to the client.
+struct VerifiedStream<S> {
+    inner: Pin<Box<S>>,
+    expected: Expected,
+    digests: Digests,
+    mismatch: Arc<OnceLock<String>>,
+    ended: bool,
+}
+
+impl<S, E> Stream for VerifiedStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+{
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        match ready!(this.inner.as_mut().poll_next(cx)) {
+            Some(Ok(bytes)) => {
+                this.digests.update(&bytes);
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            Some(Err(err)) => {
+                this.ended = true;
+                Poll::Ready(Some(Err(err.into())))
+            }
+            None => {
+                this.ended = true;
+                let (md5, sha256) = this.digests.finish();
+                match this.expected.check(&md5, &sha256) {
+                    Ok(()) => Poll::Ready(None),
+                    Err(header) => {
+                        let message = format!("Request body does not match its {}", header);
+                        println!("🧾 {}, upload aborted", message);
+                        let _ = this.mismatch.set(message.clone());
+                        Poll::Ready(Some(Err(BoxError::from(message))))
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The upload, checked as it streams, and where a mismatch is reported
+pub(crate) fn verified_body(expected: Expected, body: Body) -> (Body, Arc<OnceLock<String>>) {
+    let mismatch = Arc::new(OnceLock::new());
+    let stream = VerifiedStream {
+        inner: Box::pin(body.into_data_stream()),
+        expected,
+        digests: Digests::new(),
+        mismatch: mismatch.clone(),
+        ended: false,
+    };
+    (Body::from_stream(stream), mismatch)
+}
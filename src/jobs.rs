@@ -0,0 +1,628 @@
+// Background agent jobs, journaled to a JSONL file so a restart resumes them.
+// The journal never holds a client's credentials in the clear: a tenant
+// client's key is looked up in the config again, anything else is sealed.
+
+use crate::agents::AgentRun;
+use crate::config::AgentJobsConfig;
+use crate::encryption::SealingKey;
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::tenant::client_key;
+use crate::time::{format_utc, unix_now};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::task::AbortHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+// Headers that hold secrets, kept out of the journal's plain text
+const CREDENTIAL_HEADERS: [&str; 7] = [
+    "authorization",
+    "proxy-authorization",
+    "x-proxy-key",
+    "api-key",
+    "x-api-key",
+    "x-goog-api-key",
+    "cookie",
+];
+
+// Header names and values
+type Headers = Vec<(String, String)>;
+
+// The tenant client a job runs as
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JobClient {
+    tenant: String,
+    name: String,
+}
+
+// Credentials other than the client's key, e.g. the caller's own upstream key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+enum Credentials {
+    // Sealed with [agent_jobs.encryption], with the job id authenticated
+    Sealed { key_id: String, headers: String },
+    // Not journaled for lack of a key, so the job cannot resume
+    Dropped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Job {
+    id: String,
+    status: JobStatus,
+    created_at: u64,
+    updated_at: u64,
+    run: AgentRun,
+    // The client's headers without credentials, replayed for the steps left
+    // after a restart
+    headers: Headers,
+    // The tenant client whose key authenticated the job
+    #[serde(default)]
+    client: Option<JobClient>,
+    #[serde(default)]
+    credentials: Option<Credentials>,
+    peer: SocketAddr,
+    // Status and body of the final response
+    result: Option<(u16, serde_json::Value)>,
+}
+
+impl Job {
+    pub(crate) fn user(&self) -> Option<&str> {
+        self.run.user()
+    }
+
+    // Settled before `cutoff`, in Unix seconds
+    pub(crate) fn settled_before(&self, cutoff: u64) -> bool {
+        self.status != JobStatus::Running && self.updated_at < cutoff
+    }
+
+    // What the admin API shows: no headers or credentials
+    fn view(&self, detailed: bool) -> serde_json::Value {
+        let mut view = serde_json::json!({
+            "id": self.id,
+            "object": "agent.job",
+            "model": self.run.model,
+            "tenant": self.run.tenant,
+            "status": self.status,
+            "steps": self.run.steps,
+            "created_at": format_utc(self.created_at),
+            "updated_at": format_utc(self.updated_at),
+        });
+        if detailed {
+            view["run"] = serde_json::json!(self.run);
+            if let Some((status, body)) = &self.result {
+                view["result"] = serde_json::json!({"status": status, "body": body});
+            }
+        }
+        view
+    }
+}
+
+fn header_map(pairs: &[(String, String)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+// The headers without and with credentials
+fn split_credentials(pairs: Headers) -> (Headers, Headers) {
+    pairs
+        .into_iter()
+        .partition(|(name, _)| !CREDENTIAL_HEADERS.contains(&name.to_lowercase().as_str()))
+}
+
+// The tenant client the key in `headers` belongs to
+fn job_client(state: &AppState, headers: &HeaderMap) -> Option<(JobClient, String)> {
+    let key = client_key(headers)?;
+    state.tenants.iter().find_map(|tenant| {
+        let client = tenant
+            .clients
+            .iter()
+            .find(|c| c.key.as_deref() == Some(key))?;
+        Some((
+            JobClient {
+                tenant: tenant.name.clone(),
+                name: client.name.clone(),
+            },
+            key.to_string(),
+        ))
+    })
+}
+
+// Owner-only, as the journal holds the conversations
+fn create_private(path: &std::path::Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+// One line of the journal
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum JournalEvent {
+    Submitted {
+        job: Box<Job>,
+    },
+    Step {
+        id: String,
+        time: u64,
+        run: AgentRun,
+    },
+    Finished {
+        id: String,
+        time: u64,
+        status: JobStatus,
+        result: Option<(u16, serde_json::Value)>,
+    },
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: BTreeMap<String, Job>,
+    // Tasks of the running jobs
+    tasks: HashMap<String, AbortHandle>,
+}
+
+pub(crate) struct AgentJobs {
+    path: PathBuf,
+    file: Mutex<File>,
+    jobs: Mutex<Jobs>,
+    key: Option<SealingKey>,
+}
+
+impl AgentJobs {
+    // Replays the journal and rewrites it with one entry per job, dropping
+    // finished jobs past their retention
+    pub(crate) fn open(config: AgentJobsConfig, key: Option<SealingKey>) -> io::Result<Self> {
+        let path = std::path::Path::new(&config.journal);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut jobs: BTreeMap<String, Job> = BTreeMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                // A crash can leave the last line half-written
+                let Ok(event) = serde_json::from_str::<JournalEvent>(&line?) else {
+                    continue;
+                };
+                apply(&mut jobs, event);
+            }
+        }
+        let now = unix_now();
+        jobs.retain(|_, job| {
+            job.status == JobStatus::Running || job.updated_at + config.retention_secs > now
+        });
+        // Journals written before credentials were kept apart
+        for job in jobs.values_mut() {
+            let (headers, credentials) = split_credentials(std::mem::take(&mut job.headers));
+            job.headers = headers;
+            if !credentials.is_empty() {
+                job.credentials = Some(seal_credentials(key.as_ref(), &job.id, &credentials));
+            }
+        }
+
+        let file = write_journal(path, &jobs)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            jobs: Mutex::new(Jobs {
+                jobs,
+                tasks: HashMap::new(),
+            }),
+            key,
+        })
+    }
+
+    // Forgets the jobs `remove` matches, stopping the running ones, and
+    // rewrites the journal without them
+    pub(crate) fn remove(&self, remove: impl Fn(&Job) -> bool) -> io::Result<usize> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let ids: Vec<String> = jobs
+            .jobs
+            .values()
+            .filter(|job| remove(job))
+            .map(|job| job.id.clone())
+            .collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        for id in &ids {
+            jobs.jobs.remove(id);
+            if let Some(task) = jobs.tasks.remove(id) {
+                task.abort();
+            }
+        }
+        let mut file = self.file.lock().unwrap();
+        *file = write_journal(&self.path, &jobs.jobs)?;
+        Ok(ids.len())
+    }
+
+    fn write(&self, event: &JournalEvent) {
+        let line = serde_json::to_string(event).unwrap_or_default();
+        if let Err(err) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            eprintln!("⚠️  Failed to write agent job journal: {}", err);
+        }
+    }
+
+    pub(crate) fn submit(
+        &self,
+        state: &Arc<AppState>,
+        run: AgentRun,
+        headers: HeaderMap,
+        peer: SocketAddr,
+    ) -> serde_json::Value {
+        let now = unix_now();
+        let id = state.next_request_id().replacen("req_", "job_", 1);
+        let pairs = headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let (plain, mut credentials) = split_credentials(pairs);
+        // The client's key is taken from the config again on resume
+        let client = job_client(state, &headers).map(|(client, key)| {
+            credentials.retain(|(_, value)| {
+                let value = value.trim();
+                value != key && value.strip_prefix("Bearer ").map(str::trim) != Some(&key)
+            });
+            client
+        });
+        let credentials = (!credentials.is_empty())
+            .then(|| seal_credentials(self.key.as_ref(), &id, &credentials));
+        let job = Job {
+            id,
+            status: JobStatus::Running,
+            created_at: now,
+            updated_at: now,
+            run,
+            headers: plain,
+            client,
+            credentials,
+            peer,
+            result: None,
+        };
+        println!("🗂️  Agent job {} submitted for {}", job.id, job.run.model);
+        self.write(&JournalEvent::Submitted {
+            job: Box::new(job.clone()),
+        });
+        let view = job.view(false);
+        self.start(state, job, headers);
+        view
+    }
+
+    // The headers a job was submitted with, credentials included
+    fn restore_headers(&self, state: &AppState, job: &Job) -> Result<HeaderMap, String> {
+        let mut headers = header_map(&job.headers);
+        if let Some(client) = &job.client {
+            let key = state
+                .tenants
+                .iter()
+                .find(|t| t.name == client.tenant)
+                .and_then(|t| t.clients.iter().find(|c| c.name == client.name))
+                .and_then(|c| c.key.as_deref())
+                .ok_or_else(|| {
+                    format!(
+                        "client {} of tenant {} no longer has a key",
+                        client.name, client.tenant
+                    )
+                })?;
+            let key = HeaderValue::from_str(key).map_err(|e| e.to_string())?;
+            headers.insert("x-proxy-key", key);
+        }
+        match &job.credentials {
+            None => {}
+            Some(Credentials::Dropped) => {
+                return Err(
+                    "its credentials were not journaled, set [agent_jobs.encryption] to keep them"
+                        .to_string(),
+                )
+            }
+            Some(Credentials::Sealed {
+                key_id,
+                headers: sealed,
+            }) => {
+                let key = self
+                    .key
+                    .as_ref()
+                    .ok_or("its credentials are sealed and [agent_jobs.encryption] is not set")?;
+                if key.key_id() != key_id {
+                    return Err(format!(
+                        "its credentials were sealed with key {}, not {}",
+                        key_id,
+                        key.key_id()
+                    ));
+                }
+                let opened = key
+                    .open_text(sealed, job.id.as_bytes())
+                    .map_err(|err| format!("its credentials {}", err))?;
+                let pairs: Headers = serde_json::from_slice(&opened).map_err(|e| e.to_string())?;
+                headers.extend(header_map(&pairs));
+            }
+        }
+        Ok(headers)
+    }
+
+    // Starts the jobs left running by the previous process
+    pub(crate) fn resume(&self, state: &Arc<AppState>) {
+        let outstanding: Vec<Job> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .jobs
+            .values()
+            .filter(|job| job.status == JobStatus::Running)
+            .cloned()
+            .collect();
+        if !outstanding.is_empty() {
+            println!("🗂️  Resuming {} agent job(s)", outstanding.len());
+        }
+        for job in outstanding {
+            match self.restore_headers(state, &job) {
+                Ok(headers) => self.start(state, job, headers),
+                Err(err) => {
+                    let message = format!("Agent job {} cannot resume: {}", job.id, err);
+                    eprintln!("⚠️  {}", message);
+                    let body = serde_json::json!({"error": {
+                        "message": message,
+                        "type": "job_not_resumable",
+                    }});
+                    self.finish(&job.id, JobStatus::Failed, Some((401, body)));
+                }
+            }
+        }
+    }
+
+    fn start(&self, state: &Arc<AppState>, job: Job, headers: HeaderMap) {
+        let id = job.id.clone();
+        // Spawned under the lock, so the task's finish waits for its handle
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.jobs.insert(id.clone(), job.clone());
+        let task_state = state.clone();
+        let task = tokio::spawn(async move {
+            let response = job
+                .run
+                .execute(&task_state, job.peer, &headers, Some(&job.id))
+                .await;
+            let response = response.unwrap_or_else(IntoResponse::into_response);
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap_or_default();
+            let body = serde_json::from_slice(&body)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned().into());
+            let outcome = if status.is_success() {
+                JobStatus::Completed
+            } else {
+                JobStatus::Failed
+            };
+            if let Some(jobs) = &task_state.agent_jobs {
+                jobs.finish(&job.id, outcome, Some((status.as_u16(), body)));
+            }
+        });
+        jobs.tasks.insert(id, task.abort_handle());
+    }
+
+    pub(crate) fn record_step(&self, id: &str, run: &AgentRun) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs
+            .jobs
+            .get_mut(id)
+            .filter(|j| j.status == JobStatus::Running)
+        else {
+            return;
+        };
+        let event = JournalEvent::Step {
+            id: id.to_string(),
+            time: unix_now(),
+            run: run.clone(),
+        };
+        apply_to(job, &event);
+        self.write(&event);
+    }
+
+    // Settles a running job; false when it already was
+    fn finish(
+        &self,
+        id: &str,
+        status: JobStatus,
+        result: Option<(u16, serde_json::Value)>,
+    ) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.tasks.remove(id);
+        let Some(job) = jobs
+            .jobs
+            .get_mut(id)
+            .filter(|j| j.status == JobStatus::Running)
+        else {
+            return false;
+        };
+        let event = JournalEvent::Finished {
+            id: id.to_string(),
+            time: unix_now(),
+            status,
+            result,
+        };
+        apply_to(job, &event);
+        self.write(&event);
+        println!("🗂️  Agent job {} {}", id, status.as_str());
+        true
+    }
+
+    fn cancel(&self, id: &str) -> Result<serde_json::Value, ProxyError> {
+        let task = self.jobs.lock().unwrap().tasks.get(id).cloned();
+        if !self.finish(id, JobStatus::Cancelled, None) {
+            return match self.get(id) {
+                Some(job) => Err(ProxyError::InvalidRequest(format!(
+                    "Agent job {} is already {}",
+                    id,
+                    job["status"].as_str().unwrap_or_default()
+                ))),
+                None => Err(ProxyError::NotFound(format!("No agent job {}", id))),
+            };
+        }
+        if let Some(task) = task {
+            task.abort();
+        }
+        Ok(self.get(id).unwrap_or_default())
+    }
+
+    fn get(&self, id: &str) -> Option<serde_json::Value> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .jobs
+            .get(id)
+            .map(|job| job.view(true))
+    }
+
+    fn list(&self) -> Vec<serde_json::Value> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.jobs.values().map(|job| job.view(false)).collect()
+    }
+}
+
+// Writes one entry per job to a new journal, which replaces the old one, and
+// opens it for appending
+fn write_journal(path: &std::path::Path, jobs: &BTreeMap<String, Job>) -> io::Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = create_private(&tmp)?;
+    for job in jobs.values() {
+        let event = JournalEvent::Submitted {
+            job: Box::new(job.clone()),
+        };
+        writeln!(file, "{}", serde_json::to_string(&event)?)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+fn seal_credentials(key: Option<&SealingKey>, id: &str, pairs: &Headers) -> Credentials {
+    let Some(key) = key else {
+        return Credentials::Dropped;
+    };
+    let plaintext = serde_json::to_vec(pairs).unwrap_or_default();
+    match key.seal_text(&plaintext, id.as_bytes()) {
+        Ok(headers) => Credentials::Sealed {
+            key_id: key.key_id().to_string(),
+            headers,
+        },
+        Err(err) => {
+            eprintln!(
+                "⚠️  Failed to seal the credentials of agent job {}: {}",
+                id, err
+            );
+            Credentials::Dropped
+        }
+    }
+}
+
+fn apply(jobs: &mut BTreeMap<String, Job>, event: JournalEvent) {
+    match event {
+        JournalEvent::Submitted { job } => {
+            jobs.insert(job.id.clone(), *job);
+        }
+        JournalEvent::Step { ref id, .. } | JournalEvent::Finished { ref id, .. } => {
+            if let Some(job) = jobs.get_mut(id) {
+                apply_to(job, &event);
+            }
+        }
+    }
+}
+
+fn apply_to(job: &mut Job, event: &JournalEvent) {
+    match event {
+        JournalEvent::Submitted { .. } => {}
+        JournalEvent::Step { time, run, .. } => {
+            job.run = run.clone();
+            job.updated_at = *time;
+        }
+        JournalEvent::Finished {
+            time,
+            status,
+            result,
+            ..
+        } => {
+            job.status = *status;
+            job.result = result.clone();
+            job.updated_at = *time;
+        }
+    }
+}
+
+fn jobs_of(state: &AppState) -> Result<&AgentJobs, ProxyError> {
+    state
+        .agent_jobs
+        .as_ref()
+        .ok_or_else(|| ProxyError::NotFound("Agent jobs are disabled".to_string()))
+}
+
+pub(crate) async fn list_jobs_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let jobs = jobs_of(&state)?.list();
+    Ok(json_response(
+        &serde_json::json!({"object": "list", "data": jobs}),
+    ))
+}
+
+pub(crate) async fn get_job_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let job = jobs_of(&state)?
+        .get(&id)
+        .ok_or_else(|| ProxyError::NotFound(format!("No agent job {}", id)))?;
+    Ok(json_response(&job))
+}
+
+pub(crate) async fn cancel_job_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let job = jobs_of(&state)?.cancel(&id)?;
+    Ok(json_response(&job))
+}
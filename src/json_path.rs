@@ -0,0 +1,165 @@
+// A small subset of JSONPath for addressing request and response fields:
+// "$.messages[0].content", "choices[-1].text" or "$['odd.key']". Only field
+// names and array indexes, with negative indexes counting from the end.
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    pub(crate) fn parse(path: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("Invalid path {}: {}", path, reason);
+        let mut rest = path.trim();
+        rest = rest.strip_prefix('$').unwrap_or(rest);
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(bracketed) = rest.strip_prefix('[') {
+                let end = bracketed.find(']').ok_or_else(|| invalid("missing ]"))?;
+                let inner = bracketed[..end].trim();
+                let quoted = ['\'', '"']
+                    .into_iter()
+                    .find_map(|q| inner.strip_prefix(q).and_then(|i| i.strip_suffix(q)));
+                segments.push(match quoted {
+                    Some(key) => Segment::Key(key.to_string()),
+                    None => Segment::Index(inner.parse().map_err(|_| invalid("bad index"))?),
+                });
+                rest = &bracketed[end + 1..];
+                continue;
+            }
+            // The leading dot is optional for the first field
+            let field = rest.strip_prefix('.').unwrap_or(rest);
+            if field.len() == rest.len() && !segments.is_empty() {
+                return Err(invalid("expected . or ["));
+            }
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            if end == 0 {
+                return Err(invalid("empty field name"));
+            }
+            segments.push(Segment::Key(field[..end].to_string()));
+            rest = &field[end..];
+        }
+        Ok(Self { segments })
+    }
+
+    // "$" itself, the whole value
+    pub(crate) fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub(crate) fn get<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        walk(&self.segments, value)
+    }
+
+    // The value at the path, starting at the fields of an object; None for
+    // the root
+    pub(crate) fn get_field<'a>(&self, obj: &'a Map<String, Value>) -> Option<&'a Value> {
+        match self.segments.split_first()? {
+            (Segment::Key(key), rest) => walk(rest, obj.get(key)?),
+            (Segment::Index(_), _) => None,
+        }
+    }
+
+    // Sets the field, adding missing objects on the way; false when an array
+    // index is out of range or a value in between is not an object
+    pub(crate) fn set(&self, value: &mut Value, new: Value) -> bool {
+        let Some((last, parents)) = self.segments.split_last() else {
+            *value = new;
+            return true;
+        };
+        let mut current = value;
+        for segment in parents {
+            current = match segment {
+                Segment::Key(key) => {
+                    if current.is_null() {
+                        *current = Value::Object(Map::new());
+                    }
+                    let Some(obj) = current.as_object_mut() else {
+                        return false;
+                    };
+                    obj.entry(key.clone()).or_insert(Value::Null)
+                }
+                Segment::Index(index) => match index_mut(current, *index) {
+                    Some(item) => item,
+                    None => return false,
+                },
+            };
+        }
+        match last {
+            Segment::Key(key) => {
+                if current.is_null() {
+                    *current = Value::Object(Map::new());
+                }
+                match current.as_object_mut() {
+                    Some(obj) => {
+                        obj.insert(key.clone(), new);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Segment::Index(index) => match index_mut(current, *index) {
+                Some(item) => {
+                    *item = new;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    // The removed value, if the field was there
+    pub(crate) fn remove(&self, value: &mut Value) -> Option<Value> {
+        let (last, parents) = self.segments.split_last()?;
+        let mut current = value;
+        for segment in parents {
+            current = match segment {
+                Segment::Key(key) => current.get_mut(key)?,
+                Segment::Index(index) => index_mut(current, *index)?,
+            };
+        }
+        match last {
+            Segment::Key(key) => current.as_object_mut()?.remove(key),
+            Segment::Index(index) => {
+                let items = current.as_array_mut()?;
+                let index = resolve(*index, items.len())?;
+                Some(items.remove(index))
+            }
+        }
+    }
+}
+
+fn walk<'a>(segments: &[Segment], value: &'a Value) -> Option<&'a Value> {
+    segments
+        .iter()
+        .try_fold(value, |current, segment| match segment {
+            Segment::Key(key) => current.get(key),
+            Segment::Index(index) => {
+                let items = current.as_array()?;
+                items.get(resolve(*index, items.len())?)
+            }
+        })
+}
+
+fn resolve(index: i64, len: usize) -> Option<usize> {
+    let resolved = if index < 0 {
+        len.checked_sub(index.unsigned_abs() as usize)?
+    } else {
+        index as usize
+    };
+    (resolved < len).then_some(resolved)
+}
+
+fn index_mut(value: &mut Value, index: i64) -> Option<&mut Value> {
+    let items = value.as_array_mut()?;
+    let index = resolve(index, items.len())?;
+    items.get_mut(index)
+}
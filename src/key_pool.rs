@@ -0,0 +1,253 @@
+// Pooled upstream keys of a provider, e.g. of accounts on different tiers.
+// Each request takes a key at random, weighted by the requests the key has
+// left as of its latest rate-limit headers, so a low-tier key is not sent as
+// much traffic as a high-tier one and an exhausted key rests until its reset.
+
+use crate::chaos::random;
+use crate::metrics::escape_label;
+use crate::secrets::UpstreamKey;
+use crate::time::{parse_utc, unix_now};
+use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Counts without a reset header are trusted this long
+const DEFAULT_RESET: Duration = Duration::from_secs(60);
+// Rests a key after a 429 without retry-after or reset headers
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+// Longer resets and retry-afters are taken as this long
+const MAX_RESET: Duration = Duration::from_secs(24 * 3600);
+
+// What the upstream reported left of a limit, until its reset
+#[derive(Clone, Copy)]
+struct Remaining {
+    left: u64,
+    until: Instant,
+}
+
+#[derive(Default)]
+struct KeyQuota {
+    requests: Option<Remaining>,
+    tokens: Option<Remaining>,
+}
+
+impl KeyQuota {
+    // Requests left, None when no current count is known. A key out of
+    // tokens has none left either.
+    fn weight(&self, now: Instant) -> Option<u64> {
+        let current = |r: &Option<Remaining>| r.filter(|r| r.until > now).map(|r| r.left);
+        match (current(&self.requests), current(&self.tokens)) {
+            (_, Some(0)) => Some(0),
+            (requests, _) => requests,
+        }
+    }
+}
+
+pub(crate) struct PooledKey {
+    pub(crate) name: String,
+    pub(crate) key: Arc<UpstreamKey>,
+    quota: Mutex<KeyQuota>,
+    selected: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+impl PooledKey {
+    // Takes the counts from an upstream response
+    pub(crate) fn observe(&self, status: u16, headers: &HeaderMap) {
+        let now = Instant::now();
+        let header = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name)?.to_str().ok())
+                .map(str::trim)
+        };
+        let remaining = |left: &[&str], reset: &[&str]| {
+            let left = header(left)?.parse::<f64>().ok()?.max(0.0) as u64;
+            let until = after(
+                now,
+                header(reset).and_then(parse_reset).unwrap_or(DEFAULT_RESET),
+            );
+            Some(Remaining { left, until })
+        };
+        let requests = remaining(
+            &[
+                "x-ratelimit-remaining-requests",
+                "anthropic-ratelimit-requests-remaining",
+            ],
+            &[
+                "x-ratelimit-reset-requests",
+                "anthropic-ratelimit-requests-reset",
+            ],
+        );
+        let tokens = remaining(
+            &[
+                "x-ratelimit-remaining-tokens",
+                "anthropic-ratelimit-tokens-remaining",
+            ],
+            &[
+                "x-ratelimit-reset-tokens",
+                "anthropic-ratelimit-tokens-reset",
+            ],
+        );
+        let mut quota = self.quota.lock().unwrap();
+        if requests.is_some() {
+            quota.requests = requests;
+        }
+        if tokens.is_some() {
+            quota.tokens = tokens;
+        }
+        if status == 429 {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            // Rests until the upstream's reset, whichever limit was hit
+            let backoff = header(&["retry-after"])
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_secs);
+            let until = [quota.requests, quota.tokens]
+                .into_iter()
+                .flatten()
+                .filter(|r| r.left == 0)
+                .map(|r| r.until)
+                .max()
+                .or(backoff.map(|backoff| after(now, backoff)))
+                .unwrap_or(now + DEFAULT_BACKOFF);
+            quota.requests = Some(Remaining { left: 0, until });
+        }
+    }
+}
+
+// "6m0s", "1.5s" or "20ms" as sent by OpenAI, or the time of the reset as
+// sent by Anthropic
+fn parse_reset(value: &str) -> Option<Duration> {
+    if value.contains('T') {
+        let at = parse_utc(value.split('.').next()?)?;
+        return Some(Duration::from_secs(at.saturating_sub(unix_now())));
+    }
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..end].parse().ok()?;
+        rest = &rest[end..];
+        let (unit, len) = match rest {
+            _ if rest.starts_with("ms") => (0.001, 2),
+            _ if rest.starts_with('s') => (1.0, 1),
+            _ if rest.starts_with('m') => (60.0, 1),
+            _ if rest.starts_with('h') => (3600.0, 1),
+            _ => return None,
+        };
+        total += number * unit;
+        rest = &rest[len..];
+    }
+    Some(Duration::try_from_secs_f64(total).unwrap_or(MAX_RESET))
+}
+
+// The instant a wait ends, at most MAX_RESET away
+fn after(now: Instant, wait: Duration) -> Instant {
+    now.checked_add(wait.min(MAX_RESET)).unwrap_or(now)
+}
+
+pub(crate) struct KeyPool {
+    pub(crate) keys: Vec<PooledKey>,
+}
+
+impl KeyPool {
+    pub(crate) fn new(keys: Vec<(String, Arc<UpstreamKey>)>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .map(|(name, key)| PooledKey {
+                    name,
+                    key,
+                    quota: Mutex::new(KeyQuota::default()),
+                    selected: AtomicU64::new(0),
+                    rate_limited: AtomicU64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    // A key for the next request. Keys without a known count weigh as much
+    // as the best known one, so new keys get traffic and report their count.
+    pub(crate) fn select(&self) -> &PooledKey {
+        let now = Instant::now();
+        let weights: Vec<Option<u64>> = self
+            .keys
+            .iter()
+            .map(|key| key.quota.lock().unwrap().weight(now))
+            .collect();
+        let unknown = weights.iter().flatten().max().copied().unwrap_or(0).max(1);
+        let mut weights: Vec<u64> = weights.iter().map(|w| w.unwrap_or(unknown)).collect();
+        // Every key is exhausted: spread evenly rather than send nothing
+        if weights.iter().all(|w| *w == 0) {
+            weights.iter_mut().for_each(|w| *w = 1);
+        }
+        let mut pick = random(weights.iter().sum());
+        let index = weights
+            .iter()
+            .position(|w| {
+                if pick < *w {
+                    return true;
+                }
+                pick -= w;
+                false
+            })
+            .unwrap_or(0);
+        let key = &self.keys[index];
+        key.selected.fetch_add(1, Ordering::Relaxed);
+        // Counted down until the response brings the upstream's count, so a
+        // burst does not all land on the same key
+        if let Some(requests) = key.quota.lock().unwrap().requests.as_mut() {
+            requests.left = requests.left.saturating_sub(1);
+        }
+        key
+    }
+}
+
+pub(crate) fn render<'a>(pools: impl Iterator<Item = (&'a str, &'a KeyPool)>) -> String {
+    let pools: Vec<_> = pools.collect();
+    if pools.is_empty() {
+        return String::new();
+    }
+    let now = Instant::now();
+    let mut out = String::new();
+    let mut metric =
+        |name: &str, kind: &str, help: &str, value: &dyn Fn(&PooledKey) -> Option<u64>| {
+            out.push_str(&format!("# HELP openai_proxy_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE openai_proxy_{} {}\n", name, kind));
+            for (provider, pool) in &pools {
+                for key in &pool.keys {
+                    if let Some(value) = value(key) {
+                        out.push_str(&format!(
+                            "openai_proxy_{}{{provider=\"{}\",key=\"{}\"}} {}\n",
+                            name,
+                            escape_label(provider),
+                            escape_label(&key.name),
+                            value
+                        ));
+                    }
+                }
+            }
+        };
+    metric(
+        "upstream_key_requests_total",
+        "counter",
+        "Requests sent with each pooled upstream key.",
+        &|key| Some(key.selected.load(Ordering::Relaxed)),
+    );
+    metric(
+        "upstream_key_rate_limited_total",
+        "counter",
+        "429 responses to requests with each pooled upstream key.",
+        &|key| Some(key.rate_limited.load(Ordering::Relaxed)),
+    );
+    metric(
+        "upstream_key_remaining_requests",
+        "gauge",
+        "Requests left on each pooled upstream key until its reset, 0 once out of tokens.",
+        &|key| key.quota.lock().unwrap().weight(now),
+    );
+    out
+}
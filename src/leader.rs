@@ -0,0 +1,121 @@
+// Leader election over the shared storage: one replica holds a lease and runs
+// the cluster's singleton tasks. Without storage every instance leads itself.
+
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::storage::Store;
+use axum::{extract::State, http::HeaderMap, response::Response};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) struct Leadership {
+    // Unique per process, e.g. "web-1-4242-9f3c01aa"
+    pub(crate) instance: String,
+    store: Option<Arc<Store>>,
+    leader: AtomicBool,
+}
+
+impl Leadership {
+    pub(crate) fn new(store: Option<Arc<Store>>) -> Self {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "proxy".to_string());
+        let random = std::collections::hash_map::RandomState::new().hash_one(std::process::id());
+        Self {
+            instance: format!("{}-{}-{:08x}", host, std::process::id(), random as u32),
+            leader: AtomicBool::new(store.is_none()),
+            store,
+        }
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    // Campaigns once now, then renews or retries every third of the lease
+    pub(crate) async fn start(self: &Arc<Self>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let lease_secs = store.config.leader_lease_secs.max(3);
+        self.campaign(lease_secs).await;
+        let leadership = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(lease_secs / 3)).await;
+                leadership.campaign(lease_secs).await;
+            }
+        });
+    }
+
+    async fn campaign(&self, lease_secs: u64) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let key = store.key("leader");
+        let leader = match store
+            .backend
+            .acquire_lease(&key, &self.instance, lease_secs)
+            .await
+        {
+            Ok(leader) => leader,
+            // Stepping down, since another replica may take over meanwhile
+            Err(err) => {
+                eprintln!("⚠️  Leader election failed: {}", err);
+                false
+            }
+        };
+        if self.leader.swap(leader, Ordering::Relaxed) != leader {
+            match leader {
+                true => println!("👑 {} is now the leader", self.instance),
+                false => println!("👑 {} is no longer the leader", self.instance),
+            }
+        }
+    }
+
+    // Claims a one-off action, e.g. sending an alert, for the whole cluster
+    // for ttl_secs. Only the first replica to ask gets it.
+    pub(crate) async fn claim(&self, name: &str, ttl_secs: u64) -> bool {
+        let Some(store) = &self.store else {
+            return true;
+        };
+        let key = store.key(&format!("claim:{}", name));
+        match store
+            .backend
+            .acquire_lease(&key, &self.instance, ttl_secs.max(1))
+            .await
+        {
+            Ok(claimed) => claimed,
+            // Better twice than never
+            Err(err) => {
+                eprintln!("⚠️  Failed to claim {}: {}", name, err);
+                true
+            }
+        }
+    }
+
+    async fn current_leader(&self) -> Option<String> {
+        let store = self.store.as_ref()?;
+        let owner = store.backend.get(&store.key("leader")).await.ok()??;
+        Some(String::from_utf8_lossy(&owner).into_owned())
+    }
+}
+
+// This instance and who leads the cluster
+pub(crate) async fn cluster_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let leadership = &state.leadership;
+    let leader = match leadership.is_leader() {
+        true => Some(leadership.instance.clone()),
+        false => leadership.current_leader().await,
+    };
+    Ok(json_response(&serde_json::json!({
+        "instance": leadership.instance,
+        "is_leader": leadership.is_leader(),
+        "leader": leader,
+    })))
+}
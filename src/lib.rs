@@ -0,0 +1,134 @@
+// OpenAI-compatible API proxy, usable as a binary or embedded in another service:
+//
+//     let settings = openai_proxy::Settings::load()?;
+//     openai_proxy::serve(settings).await?;
+
+mod access_log;
+mod adapters;
+mod agents;
+mod alerts;
+mod audit;
+pub mod bench;
+mod buffers;
+mod cache;
+mod chaos;
+mod cohere;
+mod completion_check;
+mod config;
+mod constrained;
+mod dictionaries;
+mod dns;
+mod embeddings;
+mod encryption;
+mod error;
+pub mod eval;
+mod extract;
+mod feedback;
+mod fine_tunes;
+mod flags;
+mod guardrails;
+mod inspector;
+mod integrity;
+mod jobs;
+mod json_path;
+mod key_pool;
+mod leader;
+mod limits;
+mod localization;
+mod loops;
+mod metrics;
+mod model_match;
+mod models;
+mod modes;
+mod overrides;
+mod pacing;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod postprocess;
+mod probes;
+mod profiles;
+mod prompts;
+mod providers;
+mod proxy;
+mod request;
+mod resume;
+mod retention;
+mod router;
+mod routing;
+mod rules;
+mod schedule;
+mod secrets;
+mod server;
+mod smoothing;
+mod sqlite;
+mod staging;
+mod state;
+mod status;
+mod storage;
+mod tags;
+mod templates;
+mod tenant;
+pub mod testing;
+mod time;
+mod tokens;
+mod trace;
+mod transcripts;
+mod transform;
+mod upstream_auth;
+mod usage;
+mod validate;
+mod vertex;
+
+use std::sync::Arc;
+
+pub use crate::config::Settings;
+pub use crate::encryption::{decrypt_capture, encrypt_upstream_key};
+
+// Builds the proxy's router, e.g. to nest it into an existing axum app. It must
+// be served with `into_make_service_with_connect_info::<SocketAddr>()`. Of the
+// [connections] limits only body_read_timeout_ms applies to it.
+pub async fn router(settings: Settings) -> std::io::Result<axum::Router> {
+    let body_read_timeout = settings.connections.body_read_timeout_ms;
+    let state = Arc::new(state::AppState::from_settings(settings).await?);
+    if let Some(jobs) = &state.agent_jobs {
+        jobs.resume(&state);
+    }
+    retention::start(&state);
+    fine_tunes::start(&state);
+    probes::start(&state);
+    let app = router::router(state);
+    Ok(match body_read_timeout {
+        Some(ms) => app.layer(tower_http::timeout::RequestBodyTimeoutLayer::new(
+            std::time::Duration::from_millis(ms),
+        )),
+        None => app,
+    })
+}
+
+// Binds to server_host:server_port and serves until the process exits
+pub async fn serve(settings: Settings) -> std::io::Result<()> {
+    let bind_addr = settings.server_address();
+    let listener = server::bind(&settings).await.map_err(|err| {
+        std::io::Error::new(
+            err.kind(),
+            format!("Failed to bind to {}: {}", bind_addr, err),
+        )
+    })?;
+    serve_on(listener, settings).await
+}
+
+// Serves on an already bound listener; server_host and server_port are ignored
+pub async fn serve_on(
+    listener: tokio::net::TcpListener,
+    settings: Settings,
+) -> std::io::Result<()> {
+    let limits = settings.connections.clone();
+    let app = router(settings).await?;
+    let bind_addr = listener.local_addr()?;
+
+    println!("🚀 OpenAI Proxy Server running on http://{}", bind_addr);
+    println!("📝 Usage: http://{}/v1/chat/completions", bind_addr);
+    println!("🔧 Press Ctrl+C to stop");
+
+    server::serve_router(listener, app, limits).await
+}
@@ -0,0 +1,487 @@
+// Concurrency caps on in-flight requests and per-minute rate quotas
+
+use crate::config::{ConcurrencyConfig, ProviderConfig, SharedRateLimitConfig};
+use crate::error::ProxyError;
+use crate::models::ModelInfo;
+use crate::storage::Store;
+use crate::tenant::{Namespace, Tenant};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Semaphores backing the in-flight caps
+pub(crate) struct ConcurrencyLimits {
+    pub(crate) config: ConcurrencyConfig,
+    pub(crate) global: Option<Arc<Semaphore>>,
+    // Keyed by "tenant/client"
+    pub(crate) clients: HashMap<String, Arc<Semaphore>>,
+    // Keyed by model ID
+    pub(crate) models: HashMap<String, Arc<Semaphore>>,
+    // Client TPM limits, keyed by "tenant/client"
+    pub(crate) client_quotas: HashMap<String, Arc<RateQuota>>,
+}
+
+impl ConcurrencyLimits {
+    pub(crate) fn new(
+        config: ConcurrencyConfig,
+        models: &[ModelInfo],
+        tenants: &[Arc<Tenant>],
+    ) -> Self {
+        let mut clients = HashMap::new();
+        let mut client_quotas = HashMap::new();
+        let mut model_limits = HashMap::new();
+        let mut add_models = |models: &[ModelInfo]| {
+            for model in models {
+                if let Some(max) = model.max_in_flight {
+                    // The first definition of a model ID sets its cap
+                    model_limits
+                        .entry(model.id.clone())
+                        .or_insert_with(|| Arc::new(Semaphore::new(max)));
+                }
+            }
+        };
+        add_models(models);
+        for tenant in tenants {
+            add_models(&tenant.available_models);
+            for client in &tenant.clients {
+                let key = format!("{}/{}", tenant.name, client.name);
+                if let Some(max) = client.max_in_flight {
+                    clients.insert(key.clone(), Arc::new(Semaphore::new(max)));
+                }
+                // Same queue-or-reject behaviour as the in-flight caps
+                let max_delay = match config.mode.as_str() {
+                    "reject" => Duration::ZERO,
+                    _ => Duration::from_millis(config.queue_timeout_ms),
+                };
+                let scope = format!("client {}", client.name);
+                if let Some(quota) = RateQuota::new(scope, None, client.tpm, max_delay) {
+                    client_quotas.insert(key, quota);
+                }
+            }
+        }
+        Self {
+            global: config
+                .max_in_flight
+                .map(|max| Arc::new(Semaphore::new(max))),
+            config,
+            clients,
+            models: model_limits,
+            client_quotas,
+        }
+    }
+
+    pub(crate) async fn acquire(
+        &self,
+        namespace: &Namespace<'_>,
+        model: Option<&str>,
+    ) -> Result<Vec<OwnedSemaphorePermit>, ProxyError> {
+        let client = match (namespace.tenant, namespace.client) {
+            (Some(tenant), Some(client)) => self
+                .clients
+                .get(&format!("{}/{}", tenant.name, client.name))
+                .map(|semaphore| (format!("client {}", client.name), semaphore)),
+            _ => None,
+        };
+        let model = model
+            .and_then(|id| self.models.get(id).map(|semaphore| (id, semaphore)))
+            .map(|(id, semaphore)| (format!("model {}", id), semaphore));
+        let global = self
+            .global
+            .as_ref()
+            .map(|semaphore| ("the proxy".to_string(), semaphore));
+
+        // Always taken in the same order so waiting requests cannot deadlock
+        let mut permits = Vec::new();
+        for (scope, semaphore) in [client, model, global].into_iter().flatten() {
+            permits.push(self.acquire_one(&scope, semaphore).await?);
+        }
+        Ok(permits)
+    }
+
+    // Counts the request against its client's TPM limit, if there is one
+    pub(crate) async fn admit_client(
+        &self,
+        namespace: &Namespace<'_>,
+        estimated_tokens: u64,
+    ) -> Result<Option<TokenReservation>, ProxyError> {
+        let quota = match (namespace.tenant, namespace.client) {
+            (Some(tenant), Some(client)) => self
+                .client_quotas
+                .get(&format!("{}/{}", tenant.name, client.name)),
+            _ => None,
+        };
+        match quota {
+            Some(quota) => quota.admit(1.0, estimated_tokens).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn acquire_one(
+        &self,
+        scope: &str,
+        semaphore: &Arc<Semaphore>,
+    ) -> Result<OwnedSemaphorePermit, ProxyError> {
+        let limited =
+            || ProxyError::RateLimited(format!("Too many concurrent requests for {}", scope));
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if self.config.mode == "reject" {
+            return Err(limited());
+        }
+
+        println!("⏳ Waiting for a free slot for {}", scope);
+        let timeout = Duration::from_millis(self.config.queue_timeout_ms);
+        match tokio::time::timeout(timeout, semaphore.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(limited()),
+        }
+    }
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Tokens a request is counted with, its estimate until the usage is known
+struct TokenEntry {
+    at: Instant,
+    tokens: u64,
+    id: u64,
+}
+
+// Requests and tokens spent in the last minute
+#[derive(Default)]
+pub(crate) struct RateWindow {
+    requests: VecDeque<Instant>,
+    tokens: VecDeque<TokenEntry>,
+    next_id: u64,
+}
+
+impl RateWindow {
+    fn prune(&mut self, now: Instant) {
+        while matches!(self.requests.front(), Some(t) if now.duration_since(*t) >= RATE_WINDOW) {
+            self.requests.pop_front();
+        }
+        while matches!(self.tokens.front(), Some(e) if now.duration_since(e.at) >= RATE_WINDOW) {
+            self.tokens.pop_front();
+        }
+    }
+
+    // Requests and tokens counted in the window
+    fn usage(&mut self, now: Instant) -> (u64, u64) {
+        self.prune(now);
+        let tokens = self.tokens.iter().map(|e| e.tokens).sum();
+        (self.requests.len() as u64, tokens)
+    }
+
+    // Time until a request projected to spend `incoming` tokens fits under the
+    // limits, None if it fits now. A request larger than the whole TPM limit
+    // fits once the window is empty.
+    fn wait_time(
+        &self,
+        now: Instant,
+        rpm: Option<u64>,
+        tpm: Option<u64>,
+        incoming: u64,
+    ) -> Option<Duration> {
+        let expiry = |t: Instant| (t + RATE_WINDOW).saturating_duration_since(now);
+        let mut wait = None;
+        if let Some(rpm) = rpm {
+            let used = self.requests.len() as u64;
+            if used >= rpm {
+                let oldest = self.requests[(used - rpm) as usize];
+                wait = Some(expiry(oldest));
+            }
+        }
+        if let Some(tpm) = tpm {
+            let mut used: u64 = self.tokens.iter().map(|e| e.tokens).sum();
+            for entry in &self.tokens {
+                if used == 0 || used + incoming <= tpm {
+                    break;
+                }
+                used -= entry.tokens;
+                wait = wait.max(Some(expiry(entry.at)));
+            }
+        }
+        wait.map(|wait| wait + Duration::from_millis(1))
+    }
+}
+
+// Per-minute request and token limits of a provider or client
+pub(crate) struct RateQuota {
+    // e.g. "provider openai", for log and error messages
+    scope: String,
+    rpm: Option<u64>,
+    tpm: Option<u64>,
+    max_delay: Duration,
+    window: Mutex<RateWindow>,
+    // Set when the limits are shared with the other replicas
+    shared: OnceLock<SharedQuota>,
+}
+
+// A quota's counts in the shared storage, in one hash per minute. Admission
+// stays local: this replica's counts are pushed and the others' pulled every
+// sync interval, or sooner once it has counted max_unsynced of a limit.
+struct SharedQuota {
+    store: Arc<Store>,
+    // e.g. "provider:openai"
+    name: String,
+    config: SharedRateLimitConfig,
+    counts: Mutex<SharedCounts>,
+    syncing: AtomicBool,
+}
+
+#[derive(Default)]
+struct SharedCounts {
+    // Counted here, not yet in the storage
+    pending_requests: i64,
+    pending_tokens: i64,
+    // The other replicas' usage over the last minute, as of the last sync
+    other_requests: u64,
+    other_tokens: u64,
+}
+
+impl RateQuota {
+    pub(crate) fn new(
+        scope: String,
+        rpm: Option<u64>,
+        tpm: Option<u64>,
+        max_delay: Duration,
+    ) -> Option<Arc<Self>> {
+        if rpm.is_none() && tpm.is_none() {
+            return None;
+        }
+        Some(Arc::new(Self {
+            scope,
+            rpm,
+            tpm,
+            max_delay,
+            window: Mutex::new(RateWindow::default()),
+            shared: OnceLock::new(),
+        }))
+    }
+
+    // Makes the limits cluster-wide and starts syncing them
+    pub(crate) fn share(
+        self: &Arc<Self>,
+        store: Arc<Store>,
+        name: String,
+        config: SharedRateLimitConfig,
+    ) {
+        let interval = Duration::from_millis(config.sync_interval_ms.max(10));
+        let shared = SharedQuota {
+            store,
+            name,
+            config,
+            counts: Mutex::new(SharedCounts::default()),
+            syncing: AtomicBool::new(false),
+        };
+        if self.shared.set(shared).is_err() {
+            return;
+        }
+        let quota = self.clone();
+        tokio::spawn(async move {
+            loop {
+                quota.sync().await;
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    // Pushes this replica's new counts and pulls the cluster's
+    async fn sync(self: &Arc<Self>) {
+        let Some(shared) = self.shared.get() else {
+            return;
+        };
+        if shared.syncing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (requests, tokens) = {
+            let mut counts = shared.counts.lock().unwrap();
+            let pending = (counts.pending_requests, counts.pending_tokens);
+            counts.pending_requests = 0;
+            counts.pending_tokens = 0;
+            pending
+        };
+        match shared.exchange(requests, tokens).await {
+            Ok((cluster_requests, cluster_tokens)) => {
+                // Requests admitted during the exchange are still pending,
+                // and not in the cluster's counts yet
+                let mut window = self.window.lock().unwrap();
+                let (own_requests, own_tokens) = window.usage(Instant::now());
+                let mut counts = shared.counts.lock().unwrap();
+                let synced = |own: u64, pending: i64| own.saturating_sub(pending.max(0) as u64);
+                counts.other_requests =
+                    cluster_requests.saturating_sub(synced(own_requests, counts.pending_requests));
+                counts.other_tokens =
+                    cluster_tokens.saturating_sub(synced(own_tokens, counts.pending_tokens));
+                drop(window);
+            }
+            Err(err) => {
+                eprintln!(
+                    "⚠️  Failed to sync the rate limit of {}: {}",
+                    self.scope, err
+                );
+                let mut counts = shared.counts.lock().unwrap();
+                counts.pending_requests += requests;
+                counts.pending_tokens += tokens;
+            }
+        }
+        shared.syncing.store(false, Ordering::Release);
+    }
+
+    // Adds to the counts not yet synced; a sync is started early once they
+    // exceed the allowed share of a limit
+    fn count_shared(self: &Arc<Self>, requests: i64, tokens: i64) {
+        let Some(shared) = self.shared.get() else {
+            return;
+        };
+        let over = |pending: i64, limit: Option<u64>| {
+            limit.is_some_and(|limit| pending as f64 >= limit as f64 * shared.config.max_unsynced)
+        };
+        let early = {
+            let mut counts = shared.counts.lock().unwrap();
+            counts.pending_requests += requests;
+            counts.pending_tokens += tokens;
+            over(counts.pending_requests, self.rpm) || over(counts.pending_tokens, self.tpm)
+        };
+        if early && !shared.syncing.load(Ordering::Acquire) {
+            let quota = self.clone();
+            tokio::spawn(async move { quota.sync().await });
+        }
+    }
+
+    pub(crate) fn for_provider(config: &ProviderConfig) -> Option<Arc<Self>> {
+        Self::new(
+            format!("provider {}", config.name),
+            config.rpm,
+            config.tpm,
+            Duration::from_millis(config.max_delay_ms),
+        )
+    }
+
+    // Waits up to max_delay until the request fits into `share` of the limits,
+    // less what the other replicas used, then counts it with its estimated
+    // tokens
+    pub(crate) async fn admit(
+        self: &Arc<Self>,
+        share: f64,
+        estimated_tokens: u64,
+    ) -> Result<TokenReservation, ProxyError> {
+        let scaled = |limit: u64| ((limit as f64 * share) as u64).max(1);
+        let (rpm, tpm) = (self.rpm.map(scaled), self.tpm.map(scaled));
+        let deadline = Instant::now() + self.max_delay;
+        let mut delayed = false;
+        loop {
+            let (other_requests, other_tokens) = self.shared.get().map_or((0, 0), |shared| {
+                let counts = shared.counts.lock().unwrap();
+                (counts.other_requests, counts.other_tokens)
+            });
+            let rpm = rpm.map(|limit| limit.saturating_sub(other_requests));
+            let tpm = tpm.map(|limit| limit.saturating_sub(other_tokens));
+            let wait = {
+                let mut window = self.window.lock().unwrap();
+                let now = Instant::now();
+                window.prune(now);
+                // The other replicas use it all up: ask again after the next sync
+                let wait = match self.shared.get() {
+                    Some(shared) if rpm == Some(0) || tpm == Some(0) => Some(
+                        Duration::from_millis(shared.config.sync_interval_ms.max(10)),
+                    ),
+                    _ => window.wait_time(now, rpm, tpm, estimated_tokens),
+                };
+                match wait {
+                    Some(wait) => wait,
+                    None => {
+                        let id = window.next_id;
+                        window.next_id += 1;
+                        window.requests.push_back(now);
+                        window.tokens.push_back(TokenEntry {
+                            at: now,
+                            tokens: estimated_tokens,
+                            id,
+                        });
+                        // Counted under the window's lock, so a sync never
+                        // finds it in the window without it pending
+                        self.count_shared(1, estimated_tokens as i64);
+                        drop(window);
+                        return Ok(TokenReservation {
+                            quota: self.clone(),
+                            id,
+                        });
+                    }
+                }
+            };
+            if Instant::now() + wait > deadline {
+                return Err(ProxyError::RateLimited(format!(
+                    "Rate limit of {} reached",
+                    self.scope
+                )));
+            }
+            if !delayed {
+                println!(
+                    "⏳ Waiting up to {}ms for rate limit headroom of {}",
+                    wait.as_millis(),
+                    self.scope
+                );
+                delayed = true;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// A request's place in a quota's token window. Without a settle the
+// estimate stays counted.
+pub(crate) struct TokenReservation {
+    quota: Arc<RateQuota>,
+    id: u64,
+}
+
+impl TokenReservation {
+    // Replaces the estimate with the tokens the upstream reported
+    pub(crate) fn settle(self, tokens: u64) {
+        let mut window = self.quota.window.lock().unwrap();
+        let Some(entry) = window.tokens.iter_mut().find(|e| e.id == self.id) else {
+            return;
+        };
+        let change = tokens as i64 - entry.tokens as i64;
+        entry.tokens = tokens;
+        drop(window);
+        self.quota.count_shared(0, change);
+    }
+}
+
+impl SharedQuota {
+    // Adds this replica's counts to the current minute and returns the
+    // cluster's over the last minute: the current one plus the part of the
+    // previous one still inside the sliding window
+    async fn exchange(&self, requests: i64, tokens: i64) -> Result<(u64, u64), String> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let minute = millis / 60_000;
+        let key = |minute: u64| self.store.key(&format!("rate:{}:{}", self.name, minute));
+        let (current, previous) = (key(minute), key(minute.saturating_sub(1)));
+        let backend = &self.store.backend;
+        // Kept until they no longer overlap the window
+        let ttl = Some(120);
+        let counts: Vec<(String, i64)> = [("requests", requests), ("tokens", tokens)]
+            .into_iter()
+            .filter(|(_, count)| *count != 0)
+            .map(|(field, count)| (field.to_string(), count))
+            .collect();
+        let current = backend.hincr_all(&current, &counts, ttl).await?;
+        let previous = backend.hgetall(&previous).await?;
+        let overlap = 1.0 - (millis % 60_000) as f64 / 60_000.0;
+        let total = |field: &str| {
+            let count = |hash: &std::collections::BTreeMap<String, i64>| {
+                hash.get(field).copied().unwrap_or(0).max(0) as f64
+            };
+            (count(&current) + count(&previous) * overlap).round() as u64
+        };
+        Ok((total("requests"), total("tokens")))
+    }
+}
@@ -0,0 +1,265 @@
+// Translation of the proxy's own error messages, in the configured language or
+// the request's Accept-Language. A message is looked up by how it starts, then
+// by its error type; the English text is kept in "original_message" so the details of
+// a type-level translation are not lost.
+
+use crate::config::LocalizationConfig;
+use crate::error::ErrorMessage;
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Messages by error type for the languages shipped with the proxy
+const BUILT_IN: &[(&str, &[(&str, &str)])] = &[
+    (
+        "de",
+        &[
+            ("authentication_error", "Authentifizierung fehlgeschlagen"),
+            ("permission_error", "Zugriff verweigert"),
+            ("budget_exceeded", "Das Token-Budget ist aufgebraucht"),
+            (
+                "rate_limit_exceeded",
+                "Zu viele Anfragen, bitte später erneut versuchen",
+            ),
+            ("invalid_request_error", "Ungültige Anfrage"),
+            ("not_found_error", "Nicht gefunden"),
+            (
+                "service_unavailable",
+                "Dienst vorübergehend nicht verfügbar",
+            ),
+            ("timeout", "Zeitüberschreitung der Anfrage"),
+            ("proxy_error", "Fehler bei der Weiterleitung der Anfrage"),
+            ("internal_error", "Interner Fehler"),
+        ],
+    ),
+    (
+        "es",
+        &[
+            ("authentication_error", "Error de autenticación"),
+            ("permission_error", "Acceso denegado"),
+            ("budget_exceeded", "Se ha agotado el presupuesto de tokens"),
+            (
+                "rate_limit_exceeded",
+                "Demasiadas solicitudes, inténtelo más tarde",
+            ),
+            ("invalid_request_error", "Solicitud no válida"),
+            ("not_found_error", "No encontrado"),
+            (
+                "service_unavailable",
+                "Servicio no disponible temporalmente",
+            ),
+            ("timeout", "Se agotó el tiempo de espera de la solicitud"),
+            ("proxy_error", "Error al reenviar la solicitud"),
+            ("internal_error", "Error interno"),
+        ],
+    ),
+    (
+        "fr",
+        &[
+            ("authentication_error", "Échec de l'authentification"),
+            ("permission_error", "Accès refusé"),
+            ("budget_exceeded", "Le budget de jetons est épuisé"),
+            (
+                "rate_limit_exceeded",
+                "Trop de requêtes, veuillez réessayer plus tard",
+            ),
+            ("invalid_request_error", "Requête non valide"),
+            ("not_found_error", "Introuvable"),
+            ("service_unavailable", "Service temporairement indisponible"),
+            ("timeout", "Délai d'attente de la requête dépassé"),
+            ("proxy_error", "Erreur lors du transfert de la requête"),
+            ("internal_error", "Erreur interne"),
+        ],
+    ),
+    (
+        "ja",
+        &[
+            ("authentication_error", "認証に失敗しました"),
+            ("permission_error", "アクセスが拒否されました"),
+            ("budget_exceeded", "トークンの予算を使い切りました"),
+            (
+                "rate_limit_exceeded",
+                "リクエストが多すぎます。しばらくしてから再試行してください",
+            ),
+            ("invalid_request_error", "無効なリクエストです"),
+            ("not_found_error", "見つかりません"),
+            ("service_unavailable", "サービスは一時的に利用できません"),
+            ("timeout", "リクエストがタイムアウトしました"),
+            ("proxy_error", "リクエストの転送中にエラーが発生しました"),
+            ("internal_error", "内部エラー"),
+        ],
+    ),
+    (
+        "pt",
+        &[
+            ("authentication_error", "Falha na autenticação"),
+            ("permission_error", "Acesso negado"),
+            ("budget_exceeded", "O orçamento de tokens foi esgotado"),
+            (
+                "rate_limit_exceeded",
+                "Muitas solicitações, tente novamente mais tarde",
+            ),
+            ("invalid_request_error", "Solicitação inválida"),
+            ("not_found_error", "Não encontrado"),
+            (
+                "service_unavailable",
+                "Serviço temporariamente indisponível",
+            ),
+            ("timeout", "A solicitação excedeu o tempo limite"),
+            ("proxy_error", "Erro ao encaminhar a solicitação"),
+            ("internal_error", "Erro interno"),
+        ],
+    ),
+];
+
+#[derive(Default)]
+struct Translations {
+    by_type: HashMap<String, String>,
+    // English message starts and their translations, longest first
+    by_message: Vec<(String, String)>,
+}
+
+pub(crate) struct MessageCatalog {
+    default_language: String,
+    accept_language: bool,
+    languages: HashMap<String, Translations>,
+}
+
+impl MessageCatalog {
+    pub(crate) fn new(config: LocalizationConfig) -> Self {
+        let mut languages: HashMap<String, Translations> = BUILT_IN
+            .iter()
+            .map(|(language, entries)| {
+                let by_type = entries
+                    .iter()
+                    .map(|(error_type, text)| (error_type.to_string(), text.to_string()))
+                    .collect();
+                let translations = Translations {
+                    by_type,
+                    by_message: Vec::new(),
+                };
+                (language.to_string(), translations)
+            })
+            .collect();
+        for entry in config.messages {
+            let translations = languages.entry(entry.language.to_lowercase()).or_default();
+            match (entry.message, entry.error_type) {
+                (Some(message), _) => translations.by_message.push((message, entry.text)),
+                (None, Some(error_type)) => {
+                    translations.by_type.insert(error_type, entry.text);
+                }
+                (None, None) => {}
+            }
+        }
+        for translations in languages.values_mut() {
+            translations
+                .by_message
+                .sort_by_key(|(message, _)| std::cmp::Reverse(message.len()));
+        }
+        Self {
+            default_language: config.default_language.to_lowercase(),
+            accept_language: config.accept_language,
+            languages,
+        }
+    }
+
+    fn knows(&self, language: &str) -> bool {
+        language == "en" || self.languages.contains_key(language)
+    }
+
+    // The best known language of the Accept-Language header by quality, as a
+    // full tag such as "pt-br" or its primary subtag
+    pub(crate) fn language(&self, headers: &HeaderMap) -> String {
+        let accepted = headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| self.accept_language);
+        let mut ranges: Vec<(String, f32)> = accepted
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim().to_lowercase();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // Stable, so equal qualities keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        for (tag, _) in ranges {
+            if tag == "*" {
+                break;
+            }
+            let primary = tag.split('-').next().unwrap_or_default();
+            if let Some(known) = [tag.as_str(), primary].into_iter().find(|l| self.knows(l)) {
+                return known.to_string();
+            }
+        }
+        self.default_language.clone()
+    }
+
+    // None when the message stays in English
+    fn translate(&self, language: &str, error: &ErrorMessage) -> Option<String> {
+        let translations = self.languages.get(language)?;
+        translations
+            .by_message
+            .iter()
+            .find(|(message, _)| error.message.starts_with(message.as_str()))
+            .map(|(_, text)| text)
+            .or_else(|| translations.by_type.get(error.error_type))
+            .cloned()
+    }
+}
+
+// Rewrites the body of the proxy's error responses; the headers, such as
+// retry-after, are kept
+pub(crate) async fn localize_errors(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let json = state.json_errors;
+    let language = state
+        .localization
+        .as_ref()
+        .map(|catalog| catalog.language(req.headers()));
+    let response = next.run(req).await;
+    let Some(error) = response.extensions().get::<ErrorMessage>().cloned() else {
+        return response;
+    };
+    let translated = state
+        .localization
+        .as_ref()
+        .zip(language.as_deref())
+        .and_then(|(catalog, language)| catalog.translate(language, &error));
+    // Plain-text errors only change with json_errors or a translation
+    if translated.is_none() && !(error.plain && json) {
+        return response;
+    }
+    let message = translated.as_deref().unwrap_or(&error.message);
+    let (rewritten, body) = error
+        .response(response.status(), message, json)
+        .into_parts();
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Some(content_type) = rewritten.headers.get(header::CONTENT_TYPE) {
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, content_type.clone());
+    }
+    if let Some(value) = translated
+        .and(language)
+        .and_then(|language| HeaderValue::from_str(&language).ok())
+    {
+        parts.headers.insert(header::CONTENT_LANGUAGE, value);
+    }
+    Response::from_parts(parts, body)
+}
@@ -0,0 +1,109 @@
+// Detection of generations stuck repeating themselves: the streamed text is
+// cut into word n-grams, and one recurring back to back at a fixed period,
+// with every word in between repeated as well, means a loop. Tables, code
+// and JSON arrays repeat n-grams too, but with differing words between them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+pub(crate) struct LoopDetector {
+    ngram_words: usize,
+    max_repeats: u32,
+    // Longest period looked for, in words
+    window_words: usize,
+    // Hashes of the last window_words complete words
+    words: VecDeque<u64>,
+    // Words seen so far
+    position: usize,
+    // A word still being streamed
+    partial: String,
+    // Latest position of each n-gram within the window, by hash
+    last_seen: HashMap<u64, usize>,
+    // The period of the repetition under way and the consecutive words
+    // matching the word one period earlier
+    period: usize,
+    matched: usize,
+}
+
+impl LoopDetector {
+    pub(crate) fn new(ngram_words: usize, max_repeats: u32, window_words: usize) -> Self {
+        let ngram_words = ngram_words.max(1);
+        Self {
+            ngram_words,
+            max_repeats: max_repeats.max(2),
+            window_words: window_words.max(ngram_words),
+            words: VecDeque::new(),
+            position: 0,
+            partial: String::new(),
+            last_seen: HashMap::new(),
+            period: 0,
+            matched: 0,
+        }
+    }
+
+    // Takes the next piece of text; true once an n-gram repeats too often
+    pub(crate) fn feed(&mut self, text: &str) -> bool {
+        let mut looping = false;
+        for c in text.chars() {
+            if !c.is_whitespace() {
+                self.partial.push(c);
+                continue;
+            }
+            if self.partial.is_empty() {
+                continue;
+            }
+            let word = std::mem::take(&mut self.partial).to_lowercase();
+            looping |= self.push_word(&word);
+        }
+        looping
+    }
+
+    fn push_word(&mut self, word: &str) -> bool {
+        let mut hasher = DefaultHasher::new();
+        word.hash(&mut hasher);
+        let word = hasher.finish();
+
+        // The repetition under way goes on while words match one period back
+        if self.period > 0 && self.words.len() >= self.period {
+            if self.words[self.words.len() - self.period] == word {
+                self.matched += 1;
+            } else {
+                self.period = 0;
+                self.matched = 0;
+            }
+        }
+
+        self.words.push_back(word);
+        if self.words.len() > self.window_words {
+            self.words.pop_front();
+        }
+        let position = self.position;
+        self.position += 1;
+        if self.words.len() < self.ngram_words {
+            return false;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        self.words
+            .range(self.words.len() - self.ngram_words..)
+            .for_each(|word| word.hash(&mut hasher));
+        let ngram = hasher.finish();
+        if let Some(previous) = self.last_seen.insert(ngram, position) {
+            if self.period == 0 && position - previous < self.window_words {
+                self.period = position - previous;
+                self.matched = self.ngram_words;
+            }
+        }
+        // Drops n-grams too old to start a repetition, keeping the map
+        // within about twice the window
+        if self.last_seen.len() > 2 * self.window_words {
+            let window = self.window_words;
+            self.last_seen.retain(|_, seen| position - *seen < window);
+        }
+
+        // The n-gram occurs once more for every period matched beyond it
+        let repeats = 2 + (self.matched.saturating_sub(self.ngram_words)) / self.period.max(1);
+        self.period > 0 && repeats >= self.max_repeats as usize
+    }
+}
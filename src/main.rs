@@ -1,329 +1,319 @@
-use axum::{
-    body::Body,
-    extract::{Request, State},
-    http::{header::HeaderValue, HeaderMap, HeaderName, StatusCode},
-    response::{IntoResponse, Response},
-    routing::{get, post},
-    Router,
-};
-use config::Config;
-use serde::Deserialize;
-use std::str::FromStr;
-use std::sync::Arc;
-use tower_http::cors::CorsLayer;
+use openai_proxy::bench::{BenchOptions, BenchTarget};
+use openai_proxy::eval::EvalOptions;
+use openai_proxy::Settings;
 
-#[derive(Clone)]
-struct AppState {
-    openai_api_key: String,
-    openai_api_base: String,
-    client: reqwest::Client,
-    available_models: Vec<ModelInfo>,
-}
-
-#[derive(Debug, Deserialize, Clone, serde::Serialize)]
-struct ModelInfo {
-    id: String,
-    object: String,
-    owned_by: String,
-    #[serde(default)]
-    enable_thinking: bool,
-    #[serde(default = "default_reasoning_effort")]
-    reasoning_effort: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct Settings {
-    openai_api_key: String,
-    openai_api_base: String,
-    #[serde(default = "default_api_version")]
-    api_version: String,
-    server_host: String,
-    server_port: u16,
-    #[serde(default)]
-    available_models: Vec<ModelInfo>,
-}
-
-fn default_api_version() -> String {
-    "v1".to_string()
-}
-
-fn default_reasoning_effort() -> String {
-    "medium".to_string()
-}
-
-#[derive(Debug)]
-enum ProxyError {
-    RequestError(String),
-    ResponseError(String),
-    BodyReadError(String),
-}
-
-impl IntoResponse for ProxyError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            ProxyError::RequestError(msg) => (StatusCode::BAD_GATEWAY, msg),
-            ProxyError::ResponseError(msg) => (StatusCode::BAD_GATEWAY, msg),
-            ProxyError::BodyReadError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-        };
-
-        let body = Body::from(format!("Proxy error: {}", message));
-        Response::builder().status(status).body(body).unwrap()
+#[tokio::main]
+async fn main() {
+    // openai_proxy [--profile NAME] | openai_proxy check-config [--print-effective] [--profile NAME]
+    // | openai_proxy bench [options] | openai_proxy eval --dataset FILE --models A,B [options]
+    // | openai_proxy decrypt-capture FILE [--profile NAME]
+    // | openai_proxy encrypt-key TENANT CLIENT PROVIDER [--profile NAME] < key
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let profile = args
+        .iter()
+        .position(|arg| arg == "--profile")
+        .map(|i| args.get(i + 1).cloned().unwrap_or_default());
+
+    if args.first().map(|arg| arg.as_str()) == Some("check-config") {
+        check_config(
+            profile.as_deref(),
+            args.iter().any(|a| a == "--print-effective"),
+        );
+        return;
     }
-}
-
-impl Settings {
-    fn load() -> Result<Self, config::ConfigError> {
-        // Load .env file if exists
-        dotenv::dotenv().ok();
-
-        let config = Config::builder()
-            // Read from config.toml file
-            .add_source(config::File::with_name("config").required(false))
-            // Read from environment variables (higher priority)
-            .add_source(config::Environment::with_prefix("APP").separator("_"))
-            // Set default values
-            .set_default("openai_api_base", "https://api.openai.com")?
-            .set_default("api_version", "v1")?
-            .set_default("server_host", "127.0.0.1")?
-            .set_default("server_port", 8080)?
-            .build()?;
-
-        config.try_deserialize()
+    if args.first().map(|arg| arg.as_str()) == Some("bench") {
+        bench(&args[1..], profile.as_deref()).await;
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("eval") {
+        eval(&args[1..], profile.as_deref()).await;
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("decrypt-capture") {
+        decrypt_capture(args.get(1), profile.as_deref()).await;
+        return;
+    }
+    if args.first().map(|arg| arg.as_str()) == Some("encrypt-key") {
+        encrypt_key(&args[1..], profile.as_deref()).await;
+        return;
     }
-}
 
-#[tokio::main]
-async fn main() {
     // 加载配置
-    let settings = Settings::load().unwrap_or_else(|err| {
+    let settings = Settings::load_profile(profile.as_deref()).unwrap_or_else(|err| {
         eprintln!("❌ Failed to load configuration: {}", err);
         eprintln!("💡 Please create a config.toml file or set environment variables");
         std::process::exit(1);
     });
 
-    println!("📋 Configuration loaded:");
-    println!(
-        "   - Server: {}:{}",
-        settings.server_host, settings.server_port
-    );
-    println!("   - API Base: {}", settings.openai_api_base);
-    println!("   - API Version: {}", settings.api_version);
-    println!(
-        "   - API Key: {}***",
-        &settings.openai_api_key.chars().take(10).collect::<String>()
-    );
-    println!(
-        "   - Available Models: {} models configured",
-        settings.available_models.len()
-    );
-
-    let state = Arc::new(AppState {
-        openai_api_key: settings.openai_api_key,
-        openai_api_base: settings.openai_api_base,
-        client: reqwest::Client::new(),
-        available_models: settings.available_models,
-    });
-
-    // Build router
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/v3/*path", post(proxy_handler))
-        .route("/v3/*path", get(proxy_handler))
-        .layer(CorsLayer::permissive())
-        .with_state(state);
-
-    let bind_addr = format!("{}:{}", settings.server_host, settings.server_port);
-    let listener = tokio::net::TcpListener::bind(&bind_addr)
-        .await
-        .unwrap_or_else(|err| {
-            eprintln!("❌ Failed to bind to {}: {}", bind_addr, err);
-            std::process::exit(1);
-        });
-
-    println!("🚀 OpenAI Proxy Server running on http://{}", bind_addr);
-    println!("📝 Usage: http://{}/v1/chat/completions", bind_addr);
-    println!("🔧 Press Ctrl+C to stop");
-
-    axum::serve(listener, app).await.unwrap();
-}
-
-async fn root() -> &'static str {
-    "OpenAI API Proxy Server is running!"
+    if let Err(err) = openai_proxy::serve(settings).await {
+        eprintln!("❌ {}", err);
+        std::process::exit(1);
+    }
 }
 
-async fn proxy_handler(
-    State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-    req: Request,
-) -> Result<Response, ProxyError> {
-    // Extract path and query before consuming the request
-    let path = req.uri().path().trim_start_matches('/').to_string();
-    let query = req.uri().query().unwrap_or("").to_string();
-
-    // Build OpenAI API URL using configured API base
-    let openai_url = if query.is_empty() {
-        format!("{}/{}", state.openai_api_base.trim_end_matches('/'), path)
-    } else {
-        format!(
-            "{}/{}?{}",
-            state.openai_api_base.trim_end_matches('/'),
-            path,
-            query
-        )
-    };
-
-    println!("📤 Proxying request to: {}", openai_url);
-
-    // Get original HTTP method
-    let method = req.method().clone();
-
-    // Read request body
-    let body_bytes = axum::body::to_bytes(req.into_body(), usize::MAX)
-        .await
-        .map_err(|e| ProxyError::BodyReadError(e.to_string()))?;
-
-    // Modify request body to add thinking configuration based on the requested model
-    let modified_body = if !body_bytes.is_empty() {
-        match serde_json::from_slice::<serde_json::Value>(&body_bytes) {
-            Ok(mut json) => {
-                if json.is_object() {
-                    let obj = json.as_object_mut().unwrap();
-
-                    // Extract model name first (immutable borrow)
-                    let model_name = obj
-                        .get("model")
-                        .and_then(|v| v.as_str())
-                        .map(|s| s.to_string());
-
-                    // Release immutable borrow before mutating
-                    if let Some(model_name) = model_name {
-                        // Find model configuration
-                        if let Some(model_config) =
-                            state.available_models.iter().find(|m| m.id == model_name)
-                        {
-                            // Add thinking parameters if enabled for this model
-                            if model_config.enable_thinking {
-                                obj.insert(
-                                    "thinking".to_string(),
-                                    serde_json::json!({"type": "enabled"}),
-                                );
-                                obj.insert(
-                                    "reasoning_effort".to_string(),
-                                    serde_json::Value::String(
-                                        model_config.reasoning_effort.clone(),
-                                    ),
-                                );
-                                println!(
-                                    "🧠 Applied deep thinking for model {} (effort: {})",
-                                    model_name, model_config.reasoning_effort
-                                );
-                            }
-                        }
-                    }
-                }
-
-                serde_json::to_vec(&json).unwrap_or_else(|_| body_bytes.to_vec())
+// Validates the layered configuration and optionally prints the merged result
+fn check_config(profile: Option<&str>, print_effective: bool) {
+    if let Err(err) = Settings::load_profile(profile) {
+        eprintln!("❌ Invalid configuration: {}", err);
+        std::process::exit(1);
+    }
+    if print_effective {
+        match Settings::effective_toml(profile) {
+            Ok(text) => print!("{}", text),
+            Err(err) => {
+                eprintln!("❌ {}", err);
+                std::process::exit(1);
             }
-            Err(_) => body_bytes.to_vec(),
         }
     } else {
-        body_bytes.to_vec()
-    };
+        println!("✅ Configuration is valid");
+    }
+}
 
-    // Convert Axum's Method to Reqwest's Method
-    let reqwest_method = match method.as_str() {
-        "GET" => reqwest::Method::GET,
-        "POST" => reqwest::Method::POST,
-        "PUT" => reqwest::Method::PUT,
-        "DELETE" => reqwest::Method::DELETE,
-        "PATCH" => reqwest::Method::PATCH,
-        "HEAD" => reqwest::Method::HEAD,
-        "OPTIONS" => reqwest::Method::OPTIONS,
-        _ => reqwest::Method::POST, // Default to POST
+// Prints a capture sealed with [capture_encryption] in the clear
+async fn decrypt_capture(path: Option<&String>, profile: Option<&str>) {
+    let fail = |message: String| -> ! {
+        eprintln!("❌ {}", message);
+        std::process::exit(1);
     };
-
-    // Build forwarding request
-    let mut request_builder = state
-        .client
-        .request(reqwest_method, &openai_url)
-        .header("Authorization", format!("Bearer {}", state.openai_api_key))
-        .header("Content-Type", "application/json");
-
-    // Forward other necessary headers
-    for (name, value) in headers.iter() {
-        let name_str = name.as_str();
-        // Skip certain headers that should not be forwarded
-        if name_str != "host" && name_str != "authorization" && name_str != "content-length" {
-            // Convert Axum header name/value to string representations for Reqwest
-            request_builder =
-                request_builder.header(name.as_str(), value.to_str().unwrap_or_default());
-        }
+    let path = path.unwrap_or_else(|| fail("decrypt-capture needs a capture file".to_string()));
+    let settings = Settings::load_profile(profile)
+        .unwrap_or_else(|err| fail(format!("Failed to load configuration: {}", err)));
+    let capture = std::fs::read_to_string(path)
+        .unwrap_or_else(|err| fail(format!("Failed to read {}: {}", path, err)));
+    match openai_proxy::decrypt_capture(settings, &capture).await {
+        Ok(text) => println!("{}", text),
+        Err(err) => fail(err),
     }
+}
 
-    // Add request body
-    if !modified_body.is_empty() {
-        request_builder = request_builder.body(modified_body);
+// Prints an upstream key read from stdin as an "enc:" value for the
+// upstream_keys of one tenant client and provider
+async fn encrypt_key(args: &[String], profile: Option<&str>) {
+    let fail = |message: String| -> ! {
+        eprintln!("❌ {}", message);
+        std::process::exit(1);
+    };
+    let [tenant, client, provider] = match args {
+        [tenant, client, provider, ..] if !tenant.starts_with("--") => [tenant, client, provider],
+        _ => fail("encrypt-key needs the tenant, client and provider of the key".to_string()),
+    };
+    let settings = Settings::load_profile(profile)
+        .unwrap_or_else(|err| fail(format!("Failed to load configuration: {}", err)));
+    let key = std::io::read_to_string(std::io::stdin())
+        .unwrap_or_else(|err| fail(format!("Failed to read the key: {}", err)));
+    match openai_proxy::encrypt_upstream_key(settings, tenant, client, provider, &key).await {
+        Ok(value) => println!("{}", value),
+        Err(err) => fail(err),
     }
+}
 
-    // Send request
-    let response = request_builder
-        .send()
-        .await
-        .map_err(|e| ProxyError::RequestError(e.to_string()))?;
-
-    // Get response status
-    let status = StatusCode::from_u16(response.status().as_u16())
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+// openai_proxy bench [--url URL [--key KEY]]... [--provider NAME]... [--model M]
+//   [--requests N] [--concurrency N] [--prompt-chars N] [--max-tokens N]
+//   [--no-stream] [--json]
+// Without --url or --provider it targets the proxy of the loaded config.
+async fn bench(args: &[String], profile: Option<&str>) {
+    let fail = |message: String| -> ! {
+        eprintln!("❌ {}", message);
+        std::process::exit(1);
+    };
+    let mut options = BenchOptions::default();
+    let mut urls = Vec::new();
+    let mut providers = Vec::new();
+    let mut key = None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .unwrap_or_else(|| fail(format!("{} needs a value", arg)))
+        };
+        let number = |value: String| {
+            value
+                .parse::<usize>()
+                .unwrap_or_else(|_| fail(format!("{} needs a number", arg)))
+        };
+        match arg.as_str() {
+            "--url" => urls.push(value()),
+            "--key" => key = Some(value()),
+            "--provider" => providers.push(value()),
+            "--model" => options.model = value(),
+            "--requests" => options.requests = number(value()),
+            "--concurrency" => options.concurrency = number(value()),
+            "--prompt-chars" => options.prompt_chars = number(value()),
+            "--max-tokens" => options.max_tokens = number(value()) as u64,
+            "--no-stream" => options.stream = false,
+            "--json" => json = true,
+            "--profile" => {
+                value();
+            }
+            other => fail(format!("Unknown bench option {}", other)),
+        }
+    }
 
-    // Check if this is a /models endpoint and response is 404
-    if path.ends_with("/models") && status == StatusCode::NOT_FOUND {
-        // println!("⚠️  /models endpoint returned 404, using configured models");
-        return Ok(return_configured_models(&state));
+    let mut targets: Vec<BenchTarget> = urls
+        .into_iter()
+        .map(|url| BenchTarget {
+            name: url.clone(),
+            api_base: url,
+            api_key: key.clone(),
+        })
+        .collect();
+    if targets.is_empty() || !providers.is_empty() {
+        let settings = Settings::load_profile(profile)
+            .unwrap_or_else(|err| fail(format!("Failed to load configuration: {}", err)));
+        targets
+            .extend(BenchTarget::from_settings(&settings, &providers).unwrap_or_else(|e| fail(e)));
     }
 
-    // Get response headers
-    let mut response_headers = HeaderMap::new();
-    for (name, value) in response.headers().iter() {
-        if name != "content-length" && name != "transfer-encoding" {
-            // Convert reqwest headers to axum headers
-            if let Ok(header_name) = HeaderName::from_str(name.as_str()) {
-                if let Ok(header_value) = HeaderValue::from_str(value.to_str().unwrap_or_default())
-                {
-                    response_headers.insert(header_name, header_value);
-                }
+    if !json {
+        println!(
+            "🏋️ {} requests per target, {} concurrent, {} prompt chars, {}",
+            options.requests,
+            options.concurrency,
+            options.prompt_chars,
+            if options.stream {
+                "streaming"
+            } else {
+                "buffered"
             }
+        );
+    }
+    let reports = openai_proxy::bench::run(&targets, &options).await;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&reports).unwrap_or_default()
+        );
+        return;
+    }
+    for report in &reports {
+        println!("📊 {}", report.summary());
+        if let Some(error) = &report.sample_error {
+            println!("   ⚠️  {}", error);
         }
     }
-
-    // Get response body
-    let response_body = response
-        .bytes()
-        .await
-        .map_err(|e| ProxyError::ResponseError(e.to_string()))?;
-
-    println!("✅ Response status: {}", status);
-
-    // Build response
-    let mut resp = Response::new(Body::from(response_body));
-    *resp.status_mut() = status;
-    *resp.headers_mut() = response_headers;
-
-    Ok(resp)
 }
 
-fn return_configured_models(state: &AppState) -> Response {
-    let models_response = serde_json::json!({
-        "object": "list",
-        "data": state.available_models
-    });
-
-    let json_body = serde_json::to_string(&models_response).unwrap_or_else(|_| "{}".to_string());
+// openai_proxy eval --dataset FILE --models A,B [--url URL [--key KEY]]
+//   [--concurrency N] [--temperature T] [--max-tokens N] [--json]
+// Without --url the proxy of the loaded config is started for the run.
+// Exits with 1 when any case failed.
+async fn eval(args: &[String], profile: Option<&str>) {
+    let fail = |message: String| -> ! {
+        eprintln!("❌ {}", message);
+        std::process::exit(1);
+    };
+    let mut options = EvalOptions::default();
+    let mut dataset = None;
+    let mut url = None;
+    let mut key = None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .unwrap_or_else(|| fail(format!("{} needs a value", arg)))
+        };
+        let number = |value: String| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|n| *n >= 0.0)
+                .unwrap_or_else(|| fail(format!("{} needs a number", arg)))
+        };
+        match arg.as_str() {
+            "--dataset" => dataset = Some(value()),
+            "--models" => options.models.extend(
+                value()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|m| !m.is_empty())
+                    .map(str::to_string),
+            ),
+            "--url" => url = Some(value()),
+            "--key" => key = Some(value()),
+            "--concurrency" => options.concurrency = number(value()) as usize,
+            "--temperature" => options.temperature = number(value()),
+            "--max-tokens" => options.max_tokens = Some(number(value()) as u64),
+            "--json" => json = true,
+            "--profile" => {
+                value();
+            }
+            other => fail(format!("Unknown eval option {}", other)),
+        }
+    }
+    let dataset = dataset.unwrap_or_else(|| fail("eval needs --dataset".to_string()));
+    if options.models.is_empty() {
+        fail("eval needs --models".to_string());
+    }
+    let text = std::fs::read_to_string(&dataset)
+        .unwrap_or_else(|err| fail(format!("Failed to read {}: {}", dataset, err)));
+    let cases = openai_proxy::eval::parse_dataset(&text)
+        .unwrap_or_else(|err| fail(format!("{}: {}", dataset, err)));
+
+    let target = match url {
+        Some(url) => BenchTarget {
+            name: url.clone(),
+            api_base: url,
+            api_key: key,
+        },
+        None => {
+            let settings = Settings::load_profile(profile)
+                .unwrap_or_else(|err| fail(format!("Failed to load configuration: {}", err)));
+            openai_proxy::eval::start_local(settings)
+                .await
+                .unwrap_or_else(|err| fail(err.to_string()))
+        }
+    };
 
-    let mut response = Response::new(Body::from(json_body));
-    *response.status_mut() = StatusCode::OK;
-    response.headers_mut().insert(
-        HeaderName::from_static("content-type"),
-        HeaderValue::from_static("application/json"),
-    );
-    response
+    if !json {
+        println!(
+            "🧪 {} cases against {}",
+            cases.len(),
+            options.models.join(", ")
+        );
+    }
+    let report = openai_proxy::eval::run(&target, &cases, &options).await;
+    let all_passed = report.models.iter().all(|m| m.passed == m.cases);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).unwrap_or_default()
+        );
+    } else {
+        for model in &report.models {
+            println!("📊 {}", model.summary());
+        }
+        let differing = report.differing();
+        if !differing.is_empty() {
+            println!("🔍 Cases the models disagree on:");
+        }
+        for case in differing {
+            let outcomes: Vec<String> = report
+                .results
+                .iter()
+                .filter(|r| r.case == case)
+                .map(|r| match (&r.error, r.passed) {
+                    (Some(_), _) => format!("{} error", r.model),
+                    (None, true) => format!("{} ✅", r.model),
+                    (None, false) => format!("{} ❌ {}", r.model, r.failed.join("+")),
+                })
+                .collect();
+            println!("   {}: {}", case, outcomes.join(", "));
+        }
+        for result in report.results.iter().filter(|r| r.error.is_some()).take(1) {
+            println!(
+                "   ⚠️  {} on {}: {}",
+                result.model,
+                result.case,
+                result.error.as_deref().unwrap_or_default()
+            );
+        }
+    }
+    if !all_passed {
+        std::process::exit(1);
+    }
 }
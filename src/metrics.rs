@@ -0,0 +1,533 @@
+// Prometheus counters and the statsd emitter
+
+use crate::config::StatsdConfig;
+use crate::transform::StreamStats;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Running totals behind the Prometheus and usage stream latency figures
+#[derive(Default, Clone, Copy, serde::Serialize)]
+#[serde(into = "StreamSummary")]
+pub(crate) struct StreamTotals {
+    pub(crate) streams: u64,
+    pub(crate) ttft_streams: u64,
+    pub(crate) ttft_ms: u64,
+    pub(crate) duration_ms: u64,
+    pub(crate) completion_tokens: u64,
+    pub(crate) generation_secs: f64,
+}
+
+impl StreamTotals {
+    pub(crate) fn add(&mut self, stats: &StreamStats) {
+        self.streams += 1;
+        if let Some(ttft_ms) = stats.ttft_ms {
+            self.ttft_streams += 1;
+            self.ttft_ms += ttft_ms;
+        }
+        self.duration_ms += stats.duration_ms;
+        self.completion_tokens += stats.completion_tokens;
+        self.generation_secs += stats.generation_secs;
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct StreamSummary {
+    pub(crate) streams: u64,
+    pub(crate) avg_ttft_ms: u64,
+    pub(crate) avg_duration_ms: u64,
+    pub(crate) completion_tokens: u64,
+    pub(crate) tokens_per_second: f64,
+}
+
+impl From<StreamTotals> for StreamSummary {
+    fn from(totals: StreamTotals) -> Self {
+        Self {
+            streams: totals.streams,
+            avg_ttft_ms: totals.ttft_ms / totals.ttft_streams.max(1),
+            avg_duration_ms: totals.duration_ms / totals.streams.max(1),
+            completion_tokens: totals.completion_tokens,
+            tokens_per_second: if totals.generation_secs > 0.0 {
+                (totals.completion_tokens as f64 / totals.generation_secs * 10.0).round() / 10.0
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct RequestTotals {
+    pub(crate) count: u64,
+    pub(crate) duration_ms: u64,
+}
+
+#[derive(Default, Clone)]
+pub(crate) struct PromptTotals {
+    // Requests by status
+    pub(crate) requests: BTreeMap<u16, u64>,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+}
+
+// Prompts after this many share the "other" label
+const MAX_PROMPTS: usize = 500;
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct TagTotals {
+    pub(crate) requests: u64,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+}
+
+// In-process counters rendered in the Prometheus text format on /metrics
+#[derive(Default)]
+pub(crate) struct Metrics {
+    // Keyed by (model, provider, status)
+    pub(crate) requests: Mutex<HashMap<(String, String, u16), RequestTotals>>,
+    // Keyed by (model, provider)
+    pub(crate) streams: Mutex<HashMap<(String, String), StreamTotals>>,
+    // Streams the upstream broke off, keyed by (model, provider, outcome)
+    pub(crate) stream_failures: Mutex<HashMap<(String, String, &'static str), u64>>,
+    // Requests and tokens keyed by (tag key, tag value), for the metric_keys
+    pub(crate) tags: Mutex<HashMap<(String, String), TagTotals>>,
+    // Keyed by system prompt fingerprint
+    pub(crate) prompts: Mutex<HashMap<String, PromptTotals>>,
+    // Panics caught in handlers and stream transforms
+    pub(crate) panics: AtomicU64,
+    // Body bytes held in memory by proxied requests: the largest single
+    // request and the sum over all of them
+    pub(crate) buffered_bytes_max: AtomicU64,
+    pub(crate) buffered_bytes_total: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn record_request(
+        &self,
+        model: &str,
+        provider: &str,
+        status: u16,
+        duration_ms: u64,
+    ) {
+        let mut requests = self.requests.lock().unwrap();
+        let totals = requests
+            .entry((model.to_string(), provider.to_string(), status))
+            .or_default();
+        totals.count += 1;
+        totals.duration_ms += duration_ms;
+    }
+
+    pub(crate) fn record_buffered(&self, bytes: u64) {
+        self.buffered_bytes_max.fetch_max(bytes, Ordering::Relaxed);
+        self.buffered_bytes_total
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_stream(&self, model: &str, provider: &str, stats: &StreamStats) {
+        let mut streams = self.streams.lock().unwrap();
+        streams
+            .entry((model.to_string(), provider.to_string()))
+            .or_default()
+            .add(stats);
+    }
+
+    pub(crate) fn record_stream_failure(&self, model: &str, provider: &str, outcome: &'static str) {
+        *self
+            .stream_failures
+            .lock()
+            .unwrap()
+            .entry((model.to_string(), provider.to_string(), outcome))
+            .or_default() += 1;
+    }
+
+    pub(crate) fn record_tag(
+        &self,
+        key: &str,
+        value: &str,
+        requests: u64,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let mut tags = self.tags.lock().unwrap();
+        let totals = tags
+            .entry((key.to_string(), value.to_string()))
+            .or_default();
+        totals.requests += requests;
+        totals.prompt_tokens += prompt_tokens;
+        totals.completion_tokens += completion_tokens;
+    }
+
+    // A stream's tokens come without a status, its request was counted before
+    pub(crate) fn record_prompt(
+        &self,
+        fingerprint: &str,
+        status: Option<u16>,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let mut prompts = self.prompts.lock().unwrap();
+        let key = match prompts.contains_key(fingerprint) || prompts.len() < MAX_PROMPTS {
+            true => fingerprint,
+            false => "other",
+        };
+        let totals = prompts.entry(key.to_string()).or_default();
+        if let Some(status) = status {
+            *totals.requests.entry(status).or_default() += 1;
+        }
+        totals.prompt_tokens += prompt_tokens;
+        totals.completion_tokens += completion_tokens;
+    }
+
+    pub(crate) fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let mut requests: Vec<_> = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut streams: Vec<_> = self
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        streams.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut stream_failures: Vec<_> = self
+            .stream_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        stream_failures.sort();
+        let mut tags: Vec<_> = self
+            .tags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        tags.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut prompts: Vec<_> = self
+            .prompts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        prompts.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            out.push_str(&format!("# HELP openai_proxy_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE openai_proxy_{} {}\n", name, kind));
+            for (labels, value) in samples {
+                out.push_str(&format!("openai_proxy_{}{{{}}} {}\n", name, labels, value));
+            }
+        };
+        let labels = |model: &str, provider: &str| {
+            format!(
+                "model=\"{}\",provider=\"{}\"",
+                escape_label(model),
+                escape_label(provider)
+            )
+        };
+
+        metric(
+            "requests_total",
+            "counter",
+            "Proxied requests.",
+            requests
+                .iter()
+                .map(|((model, provider, status), totals)| {
+                    (
+                        format!("{},status=\"{}\"", labels(model, provider), status),
+                        totals.count.to_string(),
+                    )
+                })
+                .collect(),
+        );
+        metric(
+            "request_duration_seconds_total",
+            "counter",
+            "Time spent until response headers were returned.",
+            requests
+                .iter()
+                .map(|((model, provider, status), totals)| {
+                    (
+                        format!("{},status=\"{}\"", labels(model, provider), status),
+                        (totals.duration_ms as f64 / 1000.0).to_string(),
+                    )
+                })
+                .collect(),
+        );
+
+        let stream_metric = |value: fn(&StreamTotals) -> f64| -> Vec<(String, String)> {
+            streams
+                .iter()
+                .map(|((model, provider), totals)| {
+                    (labels(model, provider), value(totals).to_string())
+                })
+                .collect()
+        };
+        metric(
+            "streams_total",
+            "counter",
+            "Completed event streams.",
+            stream_metric(|t| t.streams as f64),
+        );
+        metric(
+            "stream_ttft_seconds_total",
+            "counter",
+            "Summed time to first token.",
+            stream_metric(|t| t.ttft_ms as f64 / 1000.0),
+        );
+        metric(
+            "stream_ttft_streams_total",
+            "counter",
+            "Streams that produced at least one token.",
+            stream_metric(|t| t.ttft_streams as f64),
+        );
+        metric(
+            "stream_duration_seconds_total",
+            "counter",
+            "Summed total stream duration.",
+            stream_metric(|t| t.duration_ms as f64 / 1000.0),
+        );
+        metric(
+            "stream_completion_tokens_total",
+            "counter",
+            "Completion tokens delivered over streams.",
+            stream_metric(|t| t.completion_tokens as f64),
+        );
+        metric(
+            "stream_generation_seconds_total",
+            "counter",
+            "Summed time from first token to end of stream.",
+            stream_metric(|t| t.generation_secs),
+        );
+        metric(
+            "stream_tokens_per_second",
+            "gauge",
+            "Average generation speed since startup.",
+            stream_metric(|t| StreamSummary::from(*t).tokens_per_second),
+        );
+        metric(
+            "stream_failures_total",
+            "counter",
+            "Streams the upstream broke off, by how the client's stream was ended.",
+            stream_failures
+                .iter()
+                .map(|((model, provider, outcome), count)| {
+                    (
+                        format!("{},outcome=\"{}\"", labels(model, provider), outcome),
+                        count.to_string(),
+                    )
+                })
+                .collect(),
+        );
+        let tag_labels = |key: &str, value: &str| {
+            format!(
+                "tag=\"{}\",value=\"{}\"",
+                escape_label(key),
+                escape_label(value)
+            )
+        };
+        metric(
+            "tag_requests_total",
+            "counter",
+            "Requests by the tags listed in request_tags.metric_keys.",
+            tags.iter()
+                .map(|((key, value), totals)| (tag_labels(key, value), totals.requests.to_string()))
+                .collect(),
+        );
+        metric(
+            "tag_tokens_total",
+            "counter",
+            "Prompt and completion tokens by the tags listed in request_tags.metric_keys.",
+            tags.iter()
+                .flat_map(|((key, value), totals)| {
+                    [
+                        ("prompt", totals.prompt_tokens),
+                        ("completion", totals.completion_tokens),
+                    ]
+                    .map(|(kind, tokens)| {
+                        (
+                            format!("{},kind=\"{}\"", tag_labels(key, value), kind),
+                            tokens.to_string(),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        metric(
+            "prompt_requests_total",
+            "counter",
+            "Requests by system prompt fingerprint.",
+            prompts
+                .iter()
+                .flat_map(|(prompt, totals)| {
+                    totals.requests.iter().map(move |(status, count)| {
+                        (
+                            format!("prompt=\"{}\",status=\"{}\"", escape_label(prompt), status),
+                            count.to_string(),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        metric(
+            "prompt_tokens_total",
+            "counter",
+            "Prompt and completion tokens by system prompt fingerprint.",
+            prompts
+                .iter()
+                .flat_map(|(prompt, totals)| {
+                    [
+                        ("prompt", totals.prompt_tokens),
+                        ("completion", totals.completion_tokens),
+                    ]
+                    .map(|(kind, tokens)| {
+                        (
+                            format!("prompt=\"{}\",kind=\"{}\"", escape_label(prompt), kind),
+                            tokens.to_string(),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        metric(
+            "panics_total",
+            "counter",
+            "Panics caught while handling requests.",
+            vec![(
+                String::new(),
+                self.panics.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+        metric(
+            "request_buffered_bytes_max",
+            "gauge",
+            "Most body bytes one request held in memory since startup.",
+            vec![(
+                String::new(),
+                self.buffered_bytes_max.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+        metric(
+            "request_buffered_bytes_total",
+            "counter",
+            "Body bytes held in memory, summed over requests.",
+            vec![(
+                String::new(),
+                self.buffered_bytes_total
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            )],
+        );
+        out
+    }
+}
+
+pub(crate) fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Clone)]
+pub(crate) struct StatsdClient {
+    pub(crate) config: StatsdConfig,
+    pub(crate) socket: Arc<UdpSocket>,
+}
+
+impl StatsdClient {
+    // Binds in the address family of the agent, so IPv6-only agents work too
+    pub(crate) fn connect(config: StatsdConfig) -> std::io::Result<Self> {
+        let mut last_error = None;
+        for agent in config.address.to_socket_addrs()? {
+            let local: SocketAddr = match agent {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = match UdpSocket::bind(local).and_then(|socket| {
+                socket.connect(agent)?;
+                Ok(socket)
+            }) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    last_error = Some(err);
+                    continue;
+                }
+            };
+            // Metrics are best effort and must never stall a request
+            socket.set_nonblocking(true)?;
+            return Ok(Self {
+                config,
+                socket: Arc::new(socket),
+            });
+        }
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "address does not resolve to any host",
+            )
+        }))
+    }
+
+    pub(crate) fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "c", tags);
+    }
+
+    pub(crate) fn timing(&self, name: &str, millis: u64, tags: &[(&str, &str)]) {
+        self.send(name, &millis.to_string(), "ms", tags);
+    }
+
+    pub(crate) fn gauge(&self, name: &str, value: f64, tags: &[(&str, &str)]) {
+        self.send(name, &format!("{:.1}", value), "g", tags);
+    }
+
+    pub(crate) fn send(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let mut line = format!("{}.{}:{}|{}", self.config.prefix, name, value, kind);
+        if self.config.dogstatsd {
+            let tags: Vec<String> = self
+                .config
+                .tags
+                .iter()
+                .cloned()
+                .chain(
+                    tags.iter()
+                        .map(|(key, value)| format!("{}:{}", key, sanitize_tag(value))),
+                )
+                .collect();
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+// Tag values may not contain the dogstatsd separators
+pub(crate) fn sanitize_tag(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if matches!(c, ',' | '|' | '#' | ' ') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
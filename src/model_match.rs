@@ -0,0 +1,116 @@
+// Model selectors shared by catalog entries, routing rules, allowlists and
+// budgets: an exact ID, a "*"/"?" wildcard, "re:<regex>" or "@<group>"
+
+use crate::models::{wildcard_match, ModelInfo};
+use regex::Regex;
+use std::collections::HashMap;
+
+pub(crate) struct ModelMatcher {
+    // [model_groups], name to selectors
+    groups: HashMap<String, Vec<String>>,
+    // Compiled "re:" selectors, keyed by the selector
+    regexes: HashMap<String, Regex>,
+}
+
+pub(crate) fn is_pattern(selector: &str) -> bool {
+    selector.starts_with('@') || selector.starts_with("re:") || selector.contains(['*', '?'])
+}
+
+impl ModelMatcher {
+    // Compiles the groups and every selector used elsewhere in the config
+    pub(crate) fn new<'a>(
+        groups: HashMap<String, Vec<String>>,
+        selectors: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, String> {
+        let mut regexes = HashMap::new();
+        let mut compile = |selector: &str| -> Result<(), String> {
+            if let Some(pattern) = selector.strip_prefix("re:") {
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| format!("Invalid model pattern {}: {}", selector, e))?;
+                regexes.insert(selector.to_string(), regex);
+            }
+            Ok(())
+        };
+        for (name, members) in &groups {
+            for member in members {
+                if member.starts_with('@') {
+                    return Err(format!(
+                        "Model group {} refers to {}; groups cannot contain groups",
+                        name, member
+                    ));
+                }
+                compile(member)?;
+            }
+        }
+        for selector in selectors {
+            if let Some(group) = selector.strip_prefix('@') {
+                if !groups.contains_key(group) {
+                    return Err(format!("Unknown model group {}", selector));
+                }
+            }
+            compile(selector)?;
+        }
+        Ok(Self { groups, regexes })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub(crate) fn group_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.groups.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    pub(crate) fn matches(&self, selector: &str, model: &str) -> bool {
+        if let Some(group) = selector.strip_prefix('@') {
+            return self
+                .groups
+                .get(group)
+                .is_some_and(|members| members.iter().any(|m| self.matches(m, model)));
+        }
+        match self.regexes.get(selector) {
+            Some(regex) => regex.is_match(model),
+            None => wildcard_match(selector, model),
+        }
+    }
+
+    pub(crate) fn matches_any(&self, selectors: &[String], model: &str) -> bool {
+        selectors.iter().any(|s| self.matches(s, model))
+    }
+
+    // The catalog entry for a model: its own entry, otherwise the first
+    // pattern entry matching it
+    pub(crate) fn find_model<'a>(
+        &self,
+        models: &'a [ModelInfo],
+        model: &str,
+    ) -> Option<&'a ModelInfo> {
+        models.iter().find(|m| m.id == model).or_else(|| {
+            models
+                .iter()
+                .find(|m| is_pattern(&m.id) && self.matches(&m.id, model))
+        })
+    }
+
+    // Selectors replaced by the concrete catalog IDs they match, in order
+    pub(crate) fn expand(&self, selectors: &[String], models: &[ModelInfo]) -> Vec<String> {
+        let mut ids: Vec<String> = Vec::new();
+        for selector in selectors {
+            if !is_pattern(selector) {
+                ids.push(selector.clone());
+                continue;
+            }
+            let matching = models
+                .iter()
+                .filter(|m| !is_pattern(&m.id) && self.matches(selector, &m.id));
+            for model in matching {
+                if !ids.contains(&model.id) {
+                    ids.push(model.id.clone());
+                }
+            }
+        }
+        ids
+    }
+}
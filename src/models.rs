@@ -0,0 +1,694 @@
+// Model catalog handling and per-model request parameters
+
+use crate::config::default_true;
+use crate::error::ProxyError;
+use crate::flags::Flag;
+use crate::model_match::is_pattern;
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::state::AppState;
+use crate::transform::{RestoreModelName, StripEngineFields, StripReasoning};
+use axum::{
+    body::Body,
+    http::{header::HeaderValue, HeaderName, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize, Clone, serde::Serialize)]
+pub(crate) struct ModelInfo {
+    // Also a wildcard, "re:" or "@group" selector; such entries apply their
+    // settings to every matching model without an entry of its own
+    pub(crate) id: String,
+    #[serde(default = "default_model_object")]
+    pub(crate) object: String,
+    #[serde(default)]
+    pub(crate) owned_by: String,
+    #[serde(default)]
+    pub(crate) enable_thinking: bool,
+    #[serde(default)]
+    pub(crate) reasoning_effort: ReasoningEffort,
+    // Thinking tokens for upstreams taking a budget instead of an effort level;
+    // derived from reasoning_effort when unset
+    #[serde(default, skip_serializing)]
+    pub(crate) thinking_budget_tokens: Option<u64>,
+    // Capability metadata, reported as-is in the /models listing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) context_length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_output_tokens: Option<u64>,
+    #[serde(default)]
+    pub(crate) supports_tools: bool,
+    #[serde(default)]
+    pub(crate) supports_vision: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pricing: Option<ModelPricing>,
+    // Stop sequences appended to every request for this model
+    #[serde(default, skip_serializing)]
+    pub(crate) stop: Vec<String>,
+    // Token biases merged into logit_bias, client-provided entries win
+    #[serde(default, skip_serializing)]
+    pub(crate) logit_bias: HashMap<String, i64>,
+    // Cap on concurrent requests for this model across all clients
+    #[serde(default, skip_serializing)]
+    pub(crate) max_in_flight: Option<usize>,
+    // Hard cap on generated tokens, sent as max_tokens and enforced on streams
+    #[serde(default, skip_serializing)]
+    pub(crate) output_token_cap: Option<u64>,
+    // Remove reasoning_content from responses before they reach the client
+    #[serde(default, skip_serializing)]
+    pub(crate) strip_reasoning: bool,
+    // Name of the [[providers]] entry serving this model
+    #[serde(default, skip_serializing)]
+    pub(crate) provider: Option<String>,
+    // Providers tried in order when the upstream fails, see [retries]
+    #[serde(default, skip_serializing)]
+    pub(crate) fallbacks: Vec<String>,
+    // Rewrites of the assistant text, for buffered and streamed responses
+    #[serde(default, skip_serializing)]
+    pub(crate) post_process: Option<PostProcessConfig>,
+    // Anthropic cache_control breakpoints added to requests for this model
+    #[serde(default, skip_serializing)]
+    pub(crate) prompt_caching: Option<PromptCaching>,
+    // Sampling and guided decoding fields of a self-hosted TGI or vLLM server
+    #[serde(default, skip_serializing)]
+    pub(crate) engine_options: Option<EngineOptions>,
+    // A fixed length for this model's embeddings
+    #[serde(default, skip_serializing)]
+    pub(crate) embedding_dimensions: Option<EmbeddingDimensions>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct EmbeddingDimensions {
+    pub(crate) dimensions: usize,
+    #[serde(default)]
+    pub(crate) mode: DimensionsMode,
+    // Scale resized vectors back to unit length
+    #[serde(default = "default_true")]
+    pub(crate) normalize: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DimensionsMode {
+    // Sent as the dimensions parameter; vectors are still resized if the
+    // upstream ignores it
+    #[default]
+    Request,
+    // Resized by the proxy only, for upstreams without the parameter
+    Truncate,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct EngineOptions {
+    #[serde(default)]
+    pub(crate) engine: Engine,
+    pub(crate) best_of: Option<u64>,
+    pub(crate) use_beam_search: Option<bool>,
+    pub(crate) repetition_penalty: Option<f64>,
+    #[serde(default)]
+    pub(crate) guided: GuidedDecoding,
+    // Any other fields, sent as they are
+    #[serde(default)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+    // Remove the engine's own fields from responses, for strict OpenAI clients
+    #[serde(default = "default_true")]
+    pub(crate) strip_response_fields: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Engine {
+    #[default]
+    Vllm,
+    Tgi,
+}
+
+// The first one set applies; vLLM takes all of them, TGI all but grammars
+#[derive(Debug, Deserialize, Clone, Default)]
+pub(crate) struct GuidedDecoding {
+    pub(crate) regex: Option<String>,
+    // EBNF, vLLM only
+    pub(crate) grammar: Option<String>,
+    pub(crate) json: Option<serde_json::Value>,
+    pub(crate) choice: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct PromptCaching {
+    // Breakpoint on the last system block
+    #[serde(default = "default_true")]
+    pub(crate) system: bool,
+    // Breakpoint at the end of the conversation history once it is this long
+    pub(crate) min_prefix_chars: Option<usize>,
+    // Sent in the anthropic-beta header, merged with the client's
+    #[serde(default = "default_caching_beta")]
+    pub(crate) beta: String,
+}
+
+// Prices in USD per million tokens
+#[derive(Debug, Deserialize, Clone, serde::Serialize)]
+pub(crate) struct ModelPricing {
+    pub(crate) prompt: f64,
+    pub(crate) completion: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) cached_prompt: Option<f64>,
+}
+
+fn default_model_object() -> String {
+    "model".to_string()
+}
+
+#[derive(Debug, Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ReasoningEffort {
+    None,
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ReasoningEffort::None => "none",
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(ReasoningEffort::None),
+            // OpenAI's "minimal" is the closest to a small budget
+            "minimal" | "low" => Some(ReasoningEffort::Low),
+            "medium" => Some(ReasoningEffort::Medium),
+            "high" => Some(ReasoningEffort::High),
+            _ => None,
+        }
+    }
+
+    // The level closest to a thinking budget, for upstreams taking a level
+    pub(crate) fn from_budget(tokens: u64) -> Self {
+        match tokens {
+            0 => ReasoningEffort::None,
+            1..=4096 => ReasoningEffort::Low,
+            4097..=16384 => ReasoningEffort::Medium,
+            _ => ReasoningEffort::High,
+        }
+    }
+
+    // Budget sent to upstreams that take thinking tokens instead of a level
+    pub(crate) fn budget_tokens(self) -> u64 {
+        match self {
+            ReasoningEffort::None => 0,
+            ReasoningEffort::Low => 2048,
+            ReasoningEffort::Medium => 8192,
+            ReasoningEffort::High => 24576,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct PostProcessConfig {
+    // Regex replacements, applied in order
+    #[serde(default)]
+    pub(crate) replace: Vec<TextReplacement>,
+    // Unwrap an answer that is one fenced code block
+    #[serde(default)]
+    pub(crate) strip_code_fences: bool,
+    // Added unless the text already starts or ends with it
+    pub(crate) prefix: Option<String>,
+    pub(crate) suffix: Option<String>,
+    // Model text beyond this many characters is dropped
+    pub(crate) max_chars: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct TextReplacement {
+    pub(crate) pattern: String,
+    // May refer to capture groups as $1 or ${name}
+    #[serde(default)]
+    pub(crate) with: String,
+}
+
+fn default_caching_beta() -> String {
+    "prompt-caching-2024-07-31".to_string()
+}
+
+// OpenAI answers 400 to requests with more stop sequences
+pub(crate) const MAX_STOP_SEQUENCES: usize = 4;
+
+// Append configured stop sequences after any the client sent. The configured
+// ones always go out; over the limit, the client's last ones are dropped.
+fn apply_stop_sequences(obj: &mut serde_json::Map<String, serde_json::Value>, stop: &[String]) {
+    if stop.is_empty() {
+        return;
+    }
+
+    let sent: Vec<serde_json::Value> = match obj.remove("stop") {
+        Some(serde_json::Value::String(s)) => vec![serde_json::Value::String(s)],
+        Some(serde_json::Value::Array(items)) => items,
+        _ => Vec::new(),
+    };
+    let mut sequences: Vec<serde_json::Value> = Vec::new();
+    for sequence in sent {
+        if !sequences.contains(&sequence) {
+            sequences.push(sequence);
+        }
+    }
+    for sequence in stop.iter().take(MAX_STOP_SEQUENCES) {
+        if !sequences.iter().any(|s| s.as_str() == Some(sequence)) {
+            sequences.push(serde_json::Value::String(sequence.clone()));
+        }
+    }
+    let mut dropped = 0;
+    while sequences.len() > MAX_STOP_SEQUENCES {
+        let Some(last) = sequences
+            .iter()
+            .rposition(|s| !s.as_str().is_some_and(|s| stop.iter().any(|c| c == s)))
+        else {
+            break;
+        };
+        sequences.remove(last);
+        dropped += 1;
+    }
+    if dropped > 0 {
+        println!(
+            "⚠️  Dropped {} of the client's stop sequences to stay within {}",
+            dropped, MAX_STOP_SEQUENCES
+        );
+    }
+    obj.insert("stop".to_string(), serde_json::Value::Array(sequences));
+}
+
+fn apply_logit_bias(
+    obj: &mut serde_json::Map<String, serde_json::Value>,
+    logit_bias: &HashMap<String, i64>,
+) {
+    if logit_bias.is_empty() {
+        return;
+    }
+
+    let entry = obj
+        .entry("logit_bias")
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+    if !entry.is_object() {
+        *entry = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let biases = entry.as_object_mut().unwrap();
+    for (token, bias) in logit_bias {
+        biases
+            .entry(token.clone())
+            .or_insert_with(|| serde_json::Value::from(*bias));
+    }
+}
+
+// Clamps the completion limits a request asks for to a hard cap, or sets one
+fn apply_output_cap(obj: &mut serde_json::Map<String, serde_json::Value>, cap: u64) {
+    let mut limited = false;
+    for field in ["max_tokens", "max_completion_tokens"] {
+        if let Some(value) = obj.get_mut(field) {
+            limited = true;
+            if value.as_u64().is_none_or(|v| v > cap) {
+                *value = cap.into();
+            }
+        }
+    }
+    if !limited {
+        obj.insert("max_tokens".to_string(), cap.into());
+    }
+}
+
+// Response fields TGI and vLLM add beyond the OpenAI schema
+pub(crate) const ENGINE_RESPONSE_FIELDS: &[&str] = &[
+    "prompt_logprobs",
+    "prompt_token_ids",
+    "kv_transfer_params",
+    "details",
+];
+pub(crate) const ENGINE_CHOICE_FIELDS: &[&str] = &["stop_reason", "token_ids", "details"];
+
+// Adds the engine fields the client did not send itself
+fn apply_engine_options(
+    obj: &mut serde_json::Map<String, serde_json::Value>,
+    options: &EngineOptions,
+) {
+    let mut fields = options.extra.clone();
+    if let Some(best_of) = options.best_of {
+        fields.insert("best_of".to_string(), best_of.into());
+    }
+    if let Some(beam_search) = options.use_beam_search {
+        fields.insert("use_beam_search".to_string(), beam_search.into());
+    }
+    if let Some(penalty) = options.repetition_penalty {
+        fields.insert("repetition_penalty".to_string(), penalty.into());
+    }
+    let guided = &options.guided;
+    match options.engine {
+        Engine::Vllm => {
+            if let Some(regex) = &guided.regex {
+                fields.insert("guided_regex".to_string(), regex.clone().into());
+            } else if let Some(grammar) = &guided.grammar {
+                fields.insert("guided_grammar".to_string(), grammar.clone().into());
+            } else if let Some(schema) = &guided.json {
+                fields.insert("guided_json".to_string(), schema.clone());
+            } else if let Some(choice) = &guided.choice {
+                fields.insert("guided_choice".to_string(), choice.clone().into());
+            }
+        }
+        // TGI constrains output through response_format; choices become a regex
+        Engine::Tgi => {
+            let format = if let Some(regex) = &guided.regex {
+                Some(serde_json::json!({"type": "regex", "value": regex}))
+            } else if let Some(schema) = &guided.json {
+                Some(serde_json::json!({"type": "json", "value": schema}))
+            } else {
+                guided.choice.as_ref().map(|choice| {
+                    let alternatives: Vec<String> = choice.iter().map(|c| regex::escape(c)).collect();
+                    serde_json::json!({"type": "regex", "value": format!("({})", alternatives.join("|"))})
+                })
+            };
+            if let Some(format) = format {
+                fields.insert("response_format".to_string(), format);
+            }
+        }
+    }
+    for (field, value) in fields {
+        obj.entry(field).or_insert(value);
+    }
+}
+
+// Add cache_control breakpoints to a chat request, in OpenAI content parts or
+// the Anthropic top-level system field. Requests that already carry breakpoints
+// are left alone, the client manages caching itself then.
+fn apply_prompt_caching(
+    obj: &mut serde_json::Map<String, serde_json::Value>,
+    caching: &PromptCaching,
+) -> usize {
+    if has_cache_breakpoint(obj) {
+        return 0;
+    }
+
+    let mut added = 0;
+    if caching.system {
+        if let Some(system) = obj.get_mut("system") {
+            added += mark_cache_breakpoint(system) as usize;
+        }
+    }
+    let Some(messages) = obj.get_mut("messages").and_then(|m| m.as_array_mut()) else {
+        return added;
+    };
+    if caching.system {
+        let system = messages
+            .iter()
+            .take_while(|m| matches!(m["role"].as_str(), Some("system") | Some("developer")))
+            .count();
+        if system > 0 {
+            added += mark_cache_breakpoint(&mut messages[system - 1]["content"]) as usize;
+        }
+    }
+    // Everything but the newest message is resent on the next turn
+    if let Some(min_chars) = caching.min_prefix_chars {
+        if messages.len() >= 2 {
+            let history = messages.len() - 2;
+            let chars: usize = messages[..=history]
+                .iter()
+                .map(|m| content_chars(&m["content"]))
+                .sum();
+            if chars >= min_chars {
+                added += mark_cache_breakpoint(&mut messages[history]["content"]) as usize;
+            }
+        }
+    }
+    added
+}
+
+// Breakpoints sit on content parts, in the system field or in any message
+fn has_cache_breakpoint(obj: &serde_json::Map<String, serde_json::Value>) -> bool {
+    let marked = |content: &serde_json::Value| {
+        content
+            .as_array()
+            .is_some_and(|parts| parts.iter().any(|part| part.get("cache_control").is_some()))
+    };
+    obj.get("system").is_some_and(marked)
+        || obj
+            .get("messages")
+            .and_then(|m| m.as_array())
+            .is_some_and(|messages| {
+                messages
+                    .iter()
+                    .any(|m| m.get("content").is_some_and(marked))
+            })
+}
+
+fn mark_cache_breakpoint(content: &mut serde_json::Value) -> bool {
+    let breakpoint = serde_json::json!({"type": "ephemeral"});
+    match content {
+        serde_json::Value::String(text) => {
+            *content = serde_json::json!([
+                {"type": "text", "text": text, "cache_control": breakpoint}
+            ]);
+            true
+        }
+        serde_json::Value::Array(parts) => match parts.last_mut().and_then(|p| p.as_object_mut()) {
+            Some(part) => {
+                part.insert("cache_control".to_string(), breakpoint);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn content_chars(content: &serde_json::Value) -> usize {
+    match content {
+        serde_json::Value::String(text) => text.chars().count(),
+        serde_json::Value::Array(parts) => parts
+            .iter()
+            .filter_map(|p| p["text"].as_str())
+            .map(|t| t.chars().count())
+            .sum(),
+        _ => 0,
+    }
+}
+
+pub(crate) struct InjectStopSequences;
+
+impl RequestTransform for InjectStopSequences {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        if let Some(model) = request.model_config {
+            apply_stop_sequences(request.body.as_object_mut().unwrap(), &model.stop);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct InjectLogitBias;
+
+impl RequestTransform for InjectLogitBias {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        if let Some(model) = request.model_config {
+            apply_logit_bias(request.body.as_object_mut().unwrap(), &model.logit_bias);
+        }
+        Ok(())
+    }
+}
+
+// Options of the model's self-hosted engine; the fields the engine adds to
+// its responses may be stripped
+pub(crate) struct InjectEngineOptions;
+
+impl RequestTransform for InjectEngineOptions {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(options) = request.model_config.and_then(|m| m.engine_options.as_ref()) else {
+            return Ok(());
+        };
+        apply_engine_options(request.body.as_object_mut().unwrap(), options);
+        if options.strip_response_fields {
+            request.transforms.push(Box::new(StripEngineFields));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct StripReasoningTraces;
+
+impl RequestTransform for StripReasoningTraces {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        if request.model_config.is_some_and(|m| m.strip_reasoning) {
+            request.transforms.push(Box::new(StripReasoning));
+        }
+        Ok(())
+    }
+}
+
+// Cache breakpoints, unless x-proxy-no-cache asks for none
+pub(crate) struct InjectPromptCaching;
+
+impl RequestTransform for InjectPromptCaching {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(caching) = request
+            .model_config
+            .and_then(|m| m.prompt_caching.as_ref())
+            .filter(|_| !request.overrides.no_cache)
+            .filter(|_| request.state.flags.enabled(Flag::PromptCaching))
+        else {
+            return Ok(());
+        };
+        if apply_prompt_caching(request.body.as_object_mut().unwrap(), caching) > 0 {
+            request.anthropic_beta = Some(caching.beta.clone());
+        }
+        Ok(())
+    }
+}
+
+// Maps a renamed catalog ID back to the upstream model ID, and the responses
+// back to the catalog ID
+pub(crate) struct RenameModel;
+
+impl RequestTransform for RenameModel {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(model) = &request.model else {
+            return Ok(());
+        };
+        let catalog = &request.state.model_catalog;
+        if let Some(rename) = catalog.rename.iter().find(|r| &r.to == model) {
+            request.body["model"] = serde_json::Value::String(rename.from.clone());
+            request.transforms.push(Box::new(RestoreModelName {
+                exposed: rename.to.clone(),
+                upstream: rename.from.clone(),
+            }));
+        }
+        Ok(())
+    }
+}
+
+// The lower of the client's and the model's output token caps
+pub(crate) struct CapOutput;
+
+impl RequestTransform for CapOutput {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        if let Some(cap) = request.model_config.and_then(|m| m.output_token_cap) {
+            request.output_cap = Some(request.output_cap.map_or(cap, |c| c.min(cap)));
+        }
+        if let Some(cap) = request
+            .output_cap
+            .filter(|_| request.path.ends_with("completions"))
+        {
+            apply_output_cap(request.body.as_object_mut().unwrap(), cap);
+        }
+        Ok(())
+    }
+}
+
+// Apply the model_catalog filters, renames and metadata to an upstream model list
+pub(crate) fn curate_model_list(
+    state: &AppState,
+    models: &[ModelInfo],
+    body: &[u8],
+) -> Option<axum::body::Bytes> {
+    let catalog = &state.model_catalog;
+    if catalog.hide.is_empty()
+        && catalog.show.is_empty()
+        && catalog.rename.is_empty()
+        && !catalog.inject_metadata
+    {
+        return None;
+    }
+
+    let mut json: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let data = json.get_mut("data")?.as_array_mut()?;
+
+    data.retain(|model| {
+        let id = model.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+        let matcher = &state.model_matcher;
+        let shown = catalog.show.is_empty() || matcher.matches_any(&catalog.show, id);
+        shown && !matcher.matches_any(&catalog.hide, id)
+    });
+
+    for model in data.iter_mut() {
+        let Some(entry) = model.as_object_mut() else {
+            continue;
+        };
+        let id = entry
+            .get("id")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        let exposed_id = match catalog.rename.iter().find(|r| r.from == id) {
+            Some(rename) => {
+                entry.insert(
+                    "id".to_string(),
+                    serde_json::Value::String(rename.to.clone()),
+                );
+                rename.to.clone()
+            }
+            None => id,
+        };
+
+        if catalog.inject_metadata {
+            let configured = state.model_matcher.find_model(models, &exposed_id);
+            if let Some(serde_json::Value::Object(metadata)) =
+                configured.and_then(|m| serde_json::to_value(m).ok())
+            {
+                for (key, value) in metadata {
+                    if key != "id" {
+                        entry.insert(key, value);
+                    }
+                }
+            }
+        }
+    }
+
+    serde_json::to_vec(&json).ok().map(Into::into)
+}
+
+// Glob-style matching supporting "*" (any run) and "?" (any single character)
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+pub(crate) fn return_configured_models(models: &[ModelInfo]) -> Response {
+    // Wildcard and group entries only carry settings
+    let listed: Vec<&ModelInfo> = models.iter().filter(|m| !is_pattern(&m.id)).collect();
+    let models_response = serde_json::json!({
+        "object": "list",
+        "data": listed
+    });
+
+    let json_body = serde_json::to_string(&models_response).unwrap_or_else(|_| "{}".to_string());
+
+    let mut response = Response::new(Body::from(json_body));
+    *response.status_mut() = StatusCode::OK;
+    response.headers_mut().insert(
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
@@ -0,0 +1,80 @@
+// Read-only and maintenance modes, e.g. while the upstream is migrated
+
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderMap, HeaderValue, Method},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+// The 503 for a request the current mode does not allow, if any
+pub(crate) fn check(
+    state: &AppState,
+    method: &Method,
+    path: &str,
+    client: &str,
+) -> Option<Response> {
+    let modes = state.modes.read().unwrap();
+    let message = if modes.maintenance && !modes.allowed_clients.iter().any(|c| c == client) {
+        "The proxy is down for maintenance"
+    } else if modes.read_only && is_mutating(method, path, &modes.read_only_paths) {
+        "The proxy is in read-only mode"
+    } else {
+        return None;
+    };
+    let mut response = ProxyError::Unavailable(message.to_string()).into_response();
+    response
+        .headers_mut()
+        .insert("retry-after", HeaderValue::from(modes.retry_after_secs));
+    Some(response)
+}
+
+fn is_mutating(method: &Method, path: &str, read_only_paths: &[String]) -> bool {
+    match *method {
+        Method::DELETE | Method::PUT | Method::PATCH => true,
+        Method::POST => path
+            .split('/')
+            .any(|segment| read_only_paths.iter().any(|p| p == segment)),
+        _ => false,
+    }
+}
+
+pub(crate) async fn get_modes_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let modes = state.modes.read().unwrap().clone();
+    Ok(json_response(&serde_json::json!(modes)))
+}
+
+// Partial update, e.g. {"maintenance": true}
+pub(crate) async fn update_modes_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let update: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid JSON: {}", e)))?;
+
+    let mut modes = state.modes.write().unwrap();
+    let mut current = serde_json::json!(*modes);
+    if let (Some(current), Some(update)) = (current.as_object_mut(), update.as_object()) {
+        for (key, value) in update {
+            current.insert(key.clone(), value.clone());
+        }
+    }
+    *modes = serde_json::from_value(current)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid modes: {}", e)))?;
+
+    println!(
+        "🚧 Maintenance mode {}, read-only mode {}",
+        if modes.maintenance { "on" } else { "off" },
+        if modes.read_only { "on" } else { "off" }
+    );
+    Ok(json_response(&serde_json::json!(*modes)))
+}
@@ -0,0 +1,74 @@
+// Temporary upstream base overrides set through /admin/upstream
+
+use crate::time::{format_utc, unix_now};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Clone, serde::Serialize)]
+pub(crate) struct UpstreamOverride {
+    pub(crate) api_base: String,
+    // None applies to every model
+    pub(crate) model: Option<String>,
+    pub(crate) reason: Option<String>,
+    pub(crate) expires_at: String,
+    #[serde(skip)]
+    pub(crate) expires: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct UpstreamOverrides {
+    // Keyed by model, None for the global override
+    entries: RwLock<HashMap<Option<String>, UpstreamOverride>>,
+}
+
+impl UpstreamOverrides {
+    pub(crate) fn set(
+        &self,
+        api_base: String,
+        model: Option<String>,
+        reason: Option<String>,
+        ttl_secs: u64,
+    ) -> UpstreamOverride {
+        let expires = unix_now().saturating_add(ttl_secs);
+        let entry = UpstreamOverride {
+            api_base,
+            model: model.clone(),
+            reason,
+            expires_at: format_utc(expires),
+            expires,
+        };
+        self.entries.write().unwrap().insert(model, entry.clone());
+        entry
+    }
+
+    pub(crate) fn remove(&self, model: Option<&str>) -> Option<UpstreamOverride> {
+        self.entries
+            .write()
+            .unwrap()
+            .remove(&model.map(|m| m.to_string()))
+    }
+
+    // Overrides that have not expired yet, dropping the rest
+    pub(crate) fn active(&self) -> Vec<UpstreamOverride> {
+        let now = unix_now();
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        let mut active: Vec<UpstreamOverride> = entries.values().cloned().collect();
+        active.sort_by(|a, b| a.model.cmp(&b.model));
+        active
+    }
+
+    // The base to use instead of the configured one; a model override wins over the global one
+    pub(crate) fn resolve(&self, model: Option<&str>) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        if entries.is_empty() {
+            return None;
+        }
+        let now = unix_now();
+        let model_entry = model.and_then(|m| entries.get(&Some(m.to_string())));
+        model_entry
+            .filter(|entry| entry.expires > now)
+            .or_else(|| entries.get(&None).filter(|entry| entry.expires > now))
+            .map(|entry| entry.api_base.clone())
+    }
+}
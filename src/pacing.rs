@@ -0,0 +1,143 @@
+// Request pacing per provider: a leaky bucket that lets requests out one
+// interval of 60s / rpm apart, so a burst from clients is queued briefly
+// instead of reaching the upstream at once and coming back as 429s
+
+use crate::config::PacingConfig;
+use crate::error::ProxyError;
+use crate::metrics::escape_label;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub(crate) struct Pacer {
+    interval: Duration,
+    // How far ahead of the schedule a burst may run
+    tolerance: Duration,
+    max_queue: Duration,
+    // When the bucket has drained, the theoretical arrival time of the next
+    // request; None until the first one
+    drained_at: Mutex<Option<Instant>>,
+    sent: AtomicU64,
+    delayed: AtomicU64,
+    rejected: AtomicU64,
+    delay_ms: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl Pacer {
+    // None without an rpm to pace to
+    pub(crate) fn new(config: &PacingConfig, provider_rpm: Option<u64>) -> Option<Self> {
+        let rpm = config.rpm.or(provider_rpm).filter(|rpm| *rpm > 0)?;
+        let interval = Duration::from_secs(60) / rpm.min(u32::MAX as u64) as u32;
+        Some(Self {
+            interval,
+            tolerance: interval * config.burst.saturating_sub(1).min(u32::MAX as u64) as u32,
+            max_queue: Duration::from_millis(config.max_queue_ms),
+            drained_at: Mutex::new(None),
+            sent: AtomicU64::new(0),
+            delayed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            delay_ms: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+        })
+    }
+
+    // Takes the request's slot, in arrival order, and waits for it. A slot
+    // further out than max_queue is not taken.
+    pub(crate) async fn wait(&self, provider: &str) -> Result<(), ProxyError> {
+        let delay = {
+            let mut drained_at = self.drained_at.lock().unwrap();
+            let now = Instant::now();
+            let start = drained_at.map_or(now, |at| at.max(now));
+            let delay = start.duration_since(now).saturating_sub(self.tolerance);
+            if delay > self.max_queue {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(ProxyError::RateLimited(format!(
+                    "Request queue of provider {} is full",
+                    provider
+                )));
+            }
+            *drained_at = Some(start + self.interval);
+            delay
+        };
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        if delay.is_zero() {
+            return Ok(());
+        }
+        self.delayed.fetch_add(1, Ordering::Relaxed);
+        self.delay_ms
+            .fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+        let _queued = Queued::enter(&self.queued);
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+}
+
+// Counts a waiting request until it leaves the queue, also when the client
+// goes away first
+struct Queued<'a>(&'a AtomicU64);
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicU64) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self(queued)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn render<'a>(pacers: impl Iterator<Item = (&'a str, &'a Pacer)>) -> String {
+    let pacers: Vec<_> = pacers.collect();
+    if pacers.is_empty() {
+        return String::new();
+    }
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&Pacer) -> String| {
+        out.push_str(&format!("# HELP openai_proxy_{} {}\n", name, help));
+        out.push_str(&format!("# TYPE openai_proxy_{} {}\n", name, kind));
+        for (provider, pacer) in &pacers {
+            out.push_str(&format!(
+                "openai_proxy_{}{{provider=\"{}\"}} {}\n",
+                name,
+                escape_label(provider),
+                value(pacer)
+            ));
+        }
+    };
+    let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+    metric(
+        "pacing_requests_total",
+        "counter",
+        "Requests let through by the provider's pacer.",
+        &|p| count(&p.sent),
+    );
+    metric(
+        "pacing_delayed_total",
+        "counter",
+        "Paced requests that had to queue.",
+        &|p| count(&p.delayed),
+    );
+    metric(
+        "pacing_delay_seconds_total",
+        "counter",
+        "Summed queue delay added by pacing.",
+        &|p| (p.delay_ms.load(Ordering::Relaxed) as f64 / 1000.0).to_string(),
+    );
+    metric(
+        "pacing_rejected_total",
+        "counter",
+        "Requests not queued because the wait would exceed max_queue_ms.",
+        &|p| count(&p.rejected),
+    );
+    metric(
+        "pacing_queued",
+        "gauge",
+        "Requests currently waiting for their slot.",
+        &|p| count(&p.queued),
+    );
+    out
+}
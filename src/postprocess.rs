@@ -0,0 +1,218 @@
+// Per-model rewriting of the final assistant text
+
+use crate::error::ProxyError;
+use crate::flags::Flag;
+use crate::models::{ModelInfo, PostProcessConfig};
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::transform::ResponseTransform;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub(crate) struct PostProcessor {
+    replacements: Vec<(Regex, String)>,
+    strip_code_fences: bool,
+    prefix: Option<String>,
+    suffix: Option<String>,
+    max_chars: Option<usize>,
+}
+
+impl PostProcessor {
+    pub(crate) fn new(config: &PostProcessConfig) -> Result<Self, regex::Error> {
+        let replacements = config
+            .replace
+            .iter()
+            .map(|r| Ok((Regex::new(&r.pattern)?, r.with.clone())))
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(Self {
+            replacements,
+            strip_code_fences: config.strip_code_fences,
+            prefix: config.prefix.clone().filter(|p| !p.is_empty()),
+            suffix: config.suffix.clone().filter(|s| !s.is_empty()),
+            max_chars: config.max_chars,
+        })
+    }
+
+    // Replacements and fence stripping need the whole text; the remaining steps
+    // can be applied to a stream as it arrives
+    fn needs_full_text(&self) -> bool {
+        !self.replacements.is_empty() || self.strip_code_fences
+    }
+
+    // The complete pipeline: replacements, fence stripping, trimming to
+    // max_chars, then the prefix and suffix unless the text already has them
+    pub(crate) fn process(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (pattern, with) in &self.replacements {
+            text = pattern.replace_all(&text, with.as_str()).into_owned();
+        }
+        if self.strip_code_fences {
+            text = strip_code_fences(&text);
+        }
+        if let Some(max_chars) = self.max_chars {
+            text = text.chars().take(max_chars).collect();
+        }
+        if let Some(prefix) = &self.prefix {
+            if !text.starts_with(prefix.as_str()) {
+                text.insert_str(0, prefix);
+            }
+        }
+        if let Some(suffix) = &self.suffix {
+            if !text.ends_with(suffix.as_str()) {
+                text.push_str(suffix);
+            }
+        }
+        text
+    }
+}
+
+// Removes a fence wrapping the whole text, including its language tag
+fn strip_code_fences(text: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.len() < 6 || !trimmed.starts_with("```") || !trimmed.ends_with("```") {
+        return text.to_string();
+    }
+    let inner = &trimmed[3..trimmed.len() - 3];
+    let inner = match inner.find('\n') {
+        Some(newline) => &inner[newline + 1..],
+        None => inner,
+    };
+    inner.trim_end_matches('\n').to_string()
+}
+
+// Compiled processors by model ID; the first definition of an ID wins
+pub(crate) fn build_post_processors<'a>(
+    models: impl Iterator<Item = &'a ModelInfo>,
+) -> Result<HashMap<String, Arc<PostProcessor>>, String> {
+    let mut processors = HashMap::new();
+    for model in models {
+        if let (Some(config), false) = (&model.post_process, processors.contains_key(&model.id)) {
+            let processor = PostProcessor::new(config)
+                .map_err(|e| format!("Invalid post_process pattern for {}: {}", model.id, e))?;
+            processors.insert(model.id.clone(), Arc::new(processor));
+        }
+    }
+    Ok(processors)
+}
+
+// Post-processes the responses of models with a post_process section
+pub(crate) struct AddPostProcessor;
+
+impl RequestTransform for AddPostProcessor {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let state = request.state;
+        if let Some(processor) = request
+            .model_config
+            .and_then(|m| state.post_processors.get(&m.id))
+            .filter(|_| state.flags.enabled(Flag::PostProcessing))
+        {
+            request
+                .transforms
+                .push(Box::new(PostProcess::new(processor.clone())));
+        }
+        Ok(())
+    }
+}
+
+// Text of one choice of a stream
+#[derive(Default)]
+struct StreamedChoice {
+    // Held back until the prefix can be decided or, with full-text steps, the end
+    held: String,
+    prefix_decided: bool,
+    model_chars: usize,
+    output: String,
+}
+
+// Applies a PostProcessor to chat completions and streamed deltas. A stream
+// ends up with the same text as the buffered response would have.
+pub(crate) struct PostProcess {
+    pub(crate) processor: Arc<PostProcessor>,
+    choices: HashMap<u64, StreamedChoice>,
+}
+
+impl PostProcess {
+    pub(crate) fn new(processor: Arc<PostProcessor>) -> Self {
+        Self {
+            processor,
+            choices: HashMap::new(),
+        }
+    }
+
+    fn stream_delta(&mut self, index: u64, delta: &str, finished: bool) -> String {
+        let processor = &self.processor;
+        let choice = self.choices.entry(index).or_default();
+        if processor.needs_full_text() {
+            choice.held.push_str(delta);
+            if !finished {
+                return String::new();
+            }
+            let text = processor.process(&choice.held);
+            choice.output.push_str(&text);
+            return text;
+        }
+
+        let mut out = String::new();
+        let take = |text: &str, choice: &mut StreamedChoice, out: &mut String| {
+            let room = processor
+                .max_chars
+                .map(|max| max.saturating_sub(choice.model_chars))
+                .unwrap_or(usize::MAX);
+            let taken: String = text.chars().take(room).collect();
+            choice.model_chars += taken.chars().count();
+            out.push_str(&taken);
+        };
+        if choice.prefix_decided {
+            take(delta, choice, &mut out);
+        } else {
+            choice.held.push_str(delta);
+            let prefix = processor.prefix.as_deref().unwrap_or_default();
+            let undecided = choice.held.len() < prefix.len() && prefix.starts_with(&choice.held);
+            if !undecided || finished {
+                choice.prefix_decided = true;
+                if !choice.held.starts_with(prefix) {
+                    out.push_str(prefix);
+                }
+                let held = std::mem::take(&mut choice.held);
+                take(&held, choice, &mut out);
+            }
+        }
+        choice.output.push_str(&out);
+        if finished {
+            if let Some(suffix) = &processor.suffix {
+                if !choice.output.ends_with(suffix.as_str()) {
+                    out.push_str(suffix);
+                    choice.output.push_str(suffix);
+                }
+            }
+        }
+        out
+    }
+}
+
+impl ResponseTransform for PostProcess {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        let Some(choices) = value.get_mut("choices").and_then(|c| c.as_array_mut()) else {
+            return;
+        };
+        for choice in choices {
+            if let Some(content) = choice["message"]["content"].as_str() {
+                choice["message"]["content"] = self.processor.process(content).into();
+                continue;
+            }
+            if !choice["delta"].is_object() {
+                continue;
+            }
+            let index = choice["index"].as_u64().unwrap_or(0);
+            let finished = !choice["finish_reason"].is_null();
+            let delta = choice["delta"]["content"].as_str().map(str::to_string);
+            if delta.is_none() && !finished {
+                continue;
+            }
+            let text = self.stream_delta(index, delta.as_deref().unwrap_or_default(), finished);
+            if delta.is_some() || !text.is_empty() {
+                choice["delta"]["content"] = text.into();
+            }
+        }
+    }
+}
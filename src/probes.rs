@@ -0,0 +1,310 @@
+// Synthetic probes: a small chat request to each configured model on a
+// schedule, through the regular proxy path, so an outage shows before users
+// report it. A model failing several probes in a row is degraded: cost rules
+// pass it over and its requests try the fallback providers first. With
+// shared storage only the leader probes, and the other replicas take its
+// results from the storage.
+
+use crate::config::ProbesConfig;
+use crate::error::ProxyError;
+use crate::metrics::escape_label;
+use crate::proxy::proxy_handler;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::storage::Store;
+use crate::time::{format_utc, unix_now};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue, Method},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Marks a probe's request, which goes to the model's own provider only and
+// past the response cache
+#[derive(Clone, Copy)]
+pub(crate) struct ProbeRequest;
+
+#[derive(Default, Serialize, Deserialize)]
+struct ProbeStatus {
+    successes: u64,
+    failures: u64,
+    // Failures since the last success
+    failing: u32,
+    degraded: bool,
+    last_latency_ms: Option<u64>,
+    last_error: Option<String>,
+    last_probe_at: Option<u64>,
+}
+
+pub(crate) struct Probes {
+    pub(crate) config: ProbesConfig,
+    models: Mutex<BTreeMap<String, ProbeStatus>>,
+}
+
+impl Probes {
+    pub(crate) fn new(config: ProbesConfig) -> Self {
+        let models = config
+            .models
+            .iter()
+            .map(|model| (model.clone(), ProbeStatus::default()))
+            .collect();
+        Self {
+            config,
+            models: Mutex::new(models),
+        }
+    }
+
+    pub(crate) fn degraded(&self, model: &str) -> bool {
+        self.models
+            .lock()
+            .unwrap()
+            .get(model)
+            .is_some_and(|status| status.degraded)
+    }
+
+    fn record(&self, model: &str, latency_ms: u64, error: Option<String>) {
+        let mut models = self.models.lock().unwrap();
+        let status = models.entry(model.to_string()).or_default();
+        status.last_probe_at = Some(unix_now());
+        status.last_latency_ms = Some(latency_ms);
+        match error {
+            None => {
+                if status.degraded {
+                    println!("💚 Probe for {} succeeded, no longer degraded", model);
+                }
+                status.successes += 1;
+                status.failing = 0;
+                status.degraded = false;
+                status.last_error = None;
+            }
+            Some(error) => {
+                status.failures += 1;
+                status.failing += 1;
+                if !status.degraded && status.failing >= self.config.failures.max(1) {
+                    eprintln!(
+                        "🩺 Model {} degraded after {} failed probes: {}",
+                        model, status.failing, error
+                    );
+                    status.degraded = true;
+                }
+                status.last_error = Some(error);
+            }
+        }
+    }
+
+    // One probe through the proxy, None when it succeeded
+    async fn probe(&self, state: &Arc<AppState>, model: &str) -> Option<String> {
+        let body = json!({
+            "model": model,
+            "messages": [{"role": "user", "content": self.config.prompt}],
+            "max_tokens": self.config.max_tokens,
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        headers.insert("x-proxy-tags", HeaderValue::from_static("source=probe"));
+        if let Some(key) = &self.config.api_key {
+            if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", key)) {
+                headers.insert("authorization", value);
+            }
+        }
+        let mut request = match Request::builder()
+            .method(Method::POST)
+            .uri("/v3/chat/completions")
+            .body(Body::from(body.to_string()))
+        {
+            Ok(request) => request,
+            Err(err) => return Some(err.to_string()),
+        };
+        request.extensions_mut().insert(ProbeRequest);
+        let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+        let response = proxy_handler(State(state.clone()), ConnectInfo(peer), headers, request);
+        let timeout = Duration::from_secs(self.config.timeout_secs.max(1));
+        let response = match tokio::time::timeout(timeout, response).await {
+            Ok(response) => response,
+            Err(_) => return Some(format!("no answer within {}s", timeout.as_secs())),
+        };
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), 64 * 1024).await;
+        match (status.is_success(), body) {
+            (true, Ok(_)) => None,
+            (true, Err(err)) => Some(format!("failed to read the answer: {}", err)),
+            (false, body) => {
+                let message = body
+                    .ok()
+                    .and_then(|b| serde_json::from_slice::<Value>(&b).ok())
+                    .and_then(|v| v["error"]["message"].as_str().map(str::to_string));
+                Some(match message {
+                    Some(message) => format!("{}: {}", status.as_u16(), message),
+                    None => status.as_u16().to_string(),
+                })
+            }
+        }
+    }
+
+    async fn run(&self, state: &Arc<AppState>) {
+        let probes = self.config.models.iter().map(|model| async move {
+            let started = Instant::now();
+            let error = self.probe(state, model).await;
+            (model, started.elapsed().as_millis() as u64, error)
+        });
+        for (model, latency_ms, error) in futures_util::future::join_all(probes).await {
+            self.record(model, latency_ms, error);
+        }
+    }
+
+    // The results for the other replicas, until a few rounds have passed
+    async fn publish(&self, store: &Store) {
+        let statuses = serde_json::to_vec(&*self.models.lock().unwrap()).unwrap_or_default();
+        let ttl = self.config.interval_secs.max(1) * 3;
+        let key = store.key(SHARED_STATUS);
+        if let Err(err) = store.backend.set(&key, statuses, Some(ttl)).await {
+            eprintln!("⚠️  Failed to share probe results: {}", err);
+        }
+    }
+
+    // Takes the leader's results; the replica's own stay without them
+    async fn load(&self, store: &Store) {
+        let statuses = match store.backend.get(&store.key(SHARED_STATUS)).await {
+            Ok(Some(statuses)) => statuses,
+            Ok(None) => return,
+            Err(err) => {
+                eprintln!("⚠️  Failed to load probe results: {}", err);
+                return;
+            }
+        };
+        let Ok(statuses) = serde_json::from_slice::<BTreeMap<String, ProbeStatus>>(&statuses)
+        else {
+            return;
+        };
+        let mut models = self.models.lock().unwrap();
+        for (model, status) in statuses {
+            let was_degraded = models.get(&model).is_some_and(|s| s.degraded);
+            if status.degraded != was_degraded {
+                match status.degraded {
+                    true => eprintln!("🩺 Model {} degraded, as the leader's probes found", model),
+                    false => println!(
+                        "💚 Model {} no longer degraded, as the leader's probes found",
+                        model
+                    ),
+                }
+            }
+            models.insert(model, status);
+        }
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let models = self.models.lock().unwrap();
+        let mut out = String::new();
+        out.push_str("# HELP openai_proxy_probes_total Synthetic probe requests by outcome.\n");
+        out.push_str("# TYPE openai_proxy_probes_total counter\n");
+        for (model, status) in models.iter() {
+            for (outcome, count) in [("success", status.successes), ("failure", status.failures)] {
+                out.push_str(&format!(
+                    "openai_proxy_probes_total{{model=\"{}\",outcome=\"{}\"}} {}\n",
+                    escape_label(model),
+                    outcome,
+                    count
+                ));
+            }
+        }
+        out.push_str(
+            "# HELP openai_proxy_probe_latency_seconds Duration of the last probe per model.\n",
+        );
+        out.push_str("# TYPE openai_proxy_probe_latency_seconds gauge\n");
+        for (model, status) in models.iter() {
+            if let Some(ms) = status.last_latency_ms {
+                out.push_str(&format!(
+                    "openai_proxy_probe_latency_seconds{{model=\"{}\"}} {}\n",
+                    escape_label(model),
+                    ms as f64 / 1000.0
+                ));
+            }
+        }
+        out.push_str(
+            "# HELP openai_proxy_model_degraded Whether probes marked the model degraded.\n",
+        );
+        out.push_str("# TYPE openai_proxy_model_degraded gauge\n");
+        for (model, status) in models.iter() {
+            out.push_str(&format!(
+                "openai_proxy_model_degraded{{model=\"{}\"}} {}\n",
+                escape_label(model),
+                status.degraded as u8
+            ));
+        }
+        out
+    }
+}
+
+// The leader's latest results, for the other replicas
+const SHARED_STATUS: &str = "probes:status";
+
+// Probes once at startup, then every interval, on the leader. The others
+// load its results every sync interval of the storage.
+pub(crate) fn start(state: &Arc<AppState>) {
+    let Some(probes) = &state.probes else {
+        return;
+    };
+    let period = Duration::from_secs(probes.config.interval_secs.max(1));
+    let state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let Some(probes) = &state.probes else {
+                return;
+            };
+            match &state.storage {
+                _ if state.leadership.is_leader() => {
+                    probes.run(&state).await;
+                    if let Some(store) = &state.storage {
+                        probes.publish(store).await;
+                    }
+                }
+                Some(store) => {
+                    probes.load(store).await;
+                    let sync = Duration::from_secs(store.config.sync_interval_secs.max(1));
+                    tokio::time::sleep(sync.min(period)).await;
+                    continue;
+                }
+                // Without storage every instance leads
+                None => {}
+            }
+            tokio::time::sleep(period).await;
+        }
+    });
+}
+
+pub(crate) async fn list_probes_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let probes = state
+        .probes
+        .as_ref()
+        .ok_or_else(|| ProxyError::NotFound("Probes are not configured".to_string()))?;
+    let data: Vec<Value> = probes
+        .models
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(model, status)| {
+            json!({
+                "model": model,
+                "degraded": status.degraded,
+                "successes": status.successes,
+                "failures": status.failures,
+                "consecutive_failures": status.failing,
+                "last_latency_ms": status.last_latency_ms,
+                "last_error": status.last_error,
+                "last_probe_at": status.last_probe_at.map(format_utc),
+            })
+        })
+        .collect();
+    Ok(json_response(&json!({"object": "list", "data": data})))
+}
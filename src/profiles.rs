@@ -0,0 +1,97 @@
+// Per-client parameter profiles merged into requests
+
+use crate::config::ParameterProfile;
+use crate::error::ProxyError;
+use crate::request::{OutgoingRequest, RequestTransform};
+
+type Object = serde_json::Map<String, serde_json::Value>;
+
+// Fills in the profile's defaults where the request leaves them out and
+// drops tools the profile does not allow
+fn apply_profile(obj: &mut Object, profile: &ParameterProfile) -> Result<(), ProxyError> {
+    if let Some(temperature) = profile.temperature {
+        obj.entry("temperature").or_insert(temperature.into());
+    }
+    if let Some(max_tokens) = profile.max_tokens {
+        if !obj.contains_key("max_tokens") && !obj.contains_key("max_completion_tokens") {
+            obj.insert("max_tokens".to_string(), max_tokens.into());
+        }
+    }
+    if let (Some(prompt), Some(messages)) = (
+        &profile.system_prompt,
+        obj.get_mut("messages").and_then(|m| m.as_array_mut()),
+    ) {
+        let has_system = messages
+            .iter()
+            .any(|m| m["role"] == "system" || m["role"] == "developer");
+        if !has_system {
+            messages.insert(0, serde_json::json!({"role": "system", "content": prompt}));
+        }
+    }
+    if let Some(allowed) = &profile.allowed_tools {
+        filter_tools(obj, allowed, &profile.name)?;
+    }
+    Ok(())
+}
+
+// Applies the profile named by the client's configuration
+pub(crate) struct ApplyProfile;
+
+impl RequestTransform for ApplyProfile {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(name) = request.namespace.client.and_then(|c| c.profile.as_ref()) else {
+            return Ok(());
+        };
+        match request
+            .state
+            .parameter_profiles
+            .iter()
+            .find(|p| &p.name == name)
+        {
+            Some(profile) => apply_profile(request.body.as_object_mut().unwrap(), profile),
+            None => Ok(()),
+        }
+    }
+}
+
+fn tool_name(tool: &serde_json::Value) -> Option<&str> {
+    tool["function"]["name"].as_str().or(tool["name"].as_str())
+}
+
+fn filter_tools(obj: &mut Object, allowed: &[String], profile: &str) -> Result<(), ProxyError> {
+    let Some(tools) = obj.get_mut("tools").and_then(|t| t.as_array_mut()) else {
+        return Ok(());
+    };
+    let before = tools.len();
+    tools.retain(|tool| tool_name(tool).is_some_and(|name| allowed.iter().any(|a| a == name)));
+    if tools.len() < before {
+        println!(
+            "🔧 Dropped {} tools not allowed by profile {}",
+            before - tools.len(),
+            profile
+        );
+    }
+    let remaining: Vec<String> = tools
+        .iter()
+        .filter_map(|t| tool_name(t).map(|n| n.to_string()))
+        .collect();
+
+    // A forced tool has to survive the filter
+    if let Some(forced) = obj
+        .get("tool_choice")
+        .and_then(|c| c["function"]["name"].as_str())
+    {
+        if !remaining.iter().any(|name| name == forced) {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Tool {} is not allowed for this client",
+                forced
+            )));
+        }
+    }
+    if remaining.is_empty() {
+        obj.remove("tools");
+        obj.remove("tool_choice");
+        obj.remove("parallel_tool_calls");
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+// Fingerprints of the system prompt a request was sent with, so usage and
+// metrics can be broken down by prompt version across applications. Only the
+// system and developer instructions count, with whitespace collapsed and
+// numbers masked, so dates or counts filled into a template keep its
+// fingerprint and the user's messages never change it.
+
+use crate::error::ProxyError;
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::secrets::hex_encode;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+fn texts<'a>(content: &'a Value, out: &mut Vec<&'a str>) {
+    match content {
+        Value::String(text) => out.push(text),
+        Value::Array(parts) => {
+            for part in parts {
+                match part {
+                    Value::String(text) => out.push(text),
+                    _ => {
+                        if let Some(text) = part.get("text").and_then(|t| t.as_str()) {
+                            out.push(text);
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn normalize(text: &str, out: &mut String) {
+    let mut digits = false;
+    for word in text.split_whitespace() {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        for c in word.chars() {
+            if c.is_ascii_digit() {
+                if !digits {
+                    out.push('#');
+                }
+                digits = true;
+            } else {
+                out.push(c);
+                digits = false;
+            }
+        }
+        digits = false;
+    }
+}
+
+// 16 hex characters, None for a request without system instructions
+fn fingerprint(obj: &Map<String, Value>) -> Option<String> {
+    let mut parts = Vec::new();
+    // The Responses API's instructions and a top-level system prompt
+    for field in ["instructions", "system"] {
+        if let Some(content) = obj.get(field) {
+            texts(content, &mut parts);
+        }
+    }
+    let system = obj
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .into_iter()
+        .flatten()
+        .filter(|m| matches!(m["role"].as_str(), Some("system") | Some("developer")));
+    for message in system {
+        texts(&message["content"], &mut parts);
+    }
+    hash(&parts)
+}
+
+// Records the served template's fingerprint, or the request's own
+pub(crate) struct FingerprintPrompt;
+
+impl RequestTransform for FingerprintPrompt {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        request.log.prompt_fingerprint = match request.served.take() {
+            Some(served) => {
+                request.log.prompt_version = Some(served.label);
+                served.fingerprint
+            }
+            None => fingerprint(request.body.as_object().unwrap()),
+        };
+        Ok(())
+    }
+}
+
+// Of a prompt template's text
+pub(crate) fn fingerprint_text(text: &str) -> Option<String> {
+    hash(&[text])
+}
+
+fn hash(parts: &[&str]) -> Option<String> {
+    let mut normalized = String::new();
+    for part in parts {
+        normalize(part, &mut normalized);
+    }
+    if normalized.is_empty() {
+        return None;
+    }
+    let digest = Sha256::digest(normalized.as_bytes());
+    Some(hex_encode(&digest[..8]))
+}
@@ -0,0 +1,109 @@
+// Named upstreams that models can be bound to, with replica selection
+
+use crate::adapters::RequestShaper;
+use crate::config::ProviderConfig;
+use crate::key_pool::KeyPool;
+use crate::limits::RateQuota;
+use crate::pacing::Pacer;
+use crate::secrets::UpstreamKey;
+use crate::upstream_auth::UpstreamAuth;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub(crate) struct Provider {
+    pub(crate) config: ProviderConfig,
+    // None uses the key of the namespace the request is served in
+    pub(crate) api_key: Option<Arc<UpstreamKey>>,
+    // Set with further keys, which then take requests instead of api_key
+    pub(crate) key_pool: Option<KeyPool>,
+    pub(crate) quota: Option<Arc<RateQuota>>,
+    pub(crate) pacer: Option<Pacer>,
+    pub(crate) shaper: RequestShaper,
+    pub(crate) auth: Option<UpstreamAuth>,
+    next_replica: AtomicU64,
+}
+
+impl Provider {
+    pub(crate) fn new(
+        config: ProviderConfig,
+        api_key: Option<Arc<UpstreamKey>>,
+        pooled: Vec<(String, Arc<UpstreamKey>)>,
+    ) -> Self {
+        // The provider's own key is pooled as "default"
+        let key_pool = (!pooled.is_empty()).then(|| {
+            let own = api_key.clone().map(|key| ("default".to_string(), key));
+            KeyPool::new(own.into_iter().chain(pooled).collect())
+        });
+        Self {
+            key_pool,
+            quota: RateQuota::for_provider(&config),
+            pacer: config
+                .pacing
+                .as_ref()
+                .and_then(|pacing| Pacer::new(pacing, config.rpm)),
+            shaper: RequestShaper::new(&config),
+            auth: config.auth.clone().map(UpstreamAuth::new),
+            config,
+            api_key,
+            next_replica: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn in_maintenance(&self, now: u64) -> bool {
+        self.config.maintenance.iter().any(|w| w.contains(now))
+    }
+
+    pub(crate) fn bases(&self) -> Vec<&str> {
+        std::iter::once(self.config.api_base.as_str())
+            .chain(self.config.replicas.iter().map(|r| r.as_str()))
+            .collect()
+    }
+
+    // Requests of the same conversation keep hitting the same replica, so its
+    // prompt cache stays warm; everything else is spread round-robin
+    pub(crate) fn select_base(&self, fingerprint: Option<&[u8]>) -> &str {
+        let bases = self.bases();
+        if bases.len() == 1 {
+            return bases[0];
+        }
+        match fingerprint.filter(|_| self.config.sticky_routing) {
+            // Rendezvous hashing: only conversations on a removed replica move
+            Some(fingerprint) => bases
+                .iter()
+                .max_by_key(|base| rendezvous_weight(fingerprint, base))
+                .unwrap(),
+            None => {
+                let next = self.next_replica.fetch_add(1, Ordering::Relaxed);
+                bases[(next % bases.len() as u64) as usize]
+            }
+        }
+    }
+}
+
+fn rendezvous_weight(fingerprint: &[u8], base: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(fingerprint)
+        .chain_update(base.as_bytes())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// Hash of the conversation prefix: the leading system messages plus the first
+// other message, or the start of a completions prompt
+pub(crate) fn conversation_fingerprint(body: &serde_json::Value) -> Option<Vec<u8>> {
+    let prefix = if let Some(messages) = body.get("messages").and_then(|m| m.as_array()) {
+        let is_system = |m: &serde_json::Value| {
+            matches!(m["role"].as_str(), Some("system") | Some("developer"))
+        };
+        let system: Vec<&serde_json::Value> =
+            messages.iter().take_while(|m| is_system(m)).collect();
+        let first = messages.iter().find(|m| !is_system(m));
+        serde_json::json!([system, first]).to_string()
+    } else if let Some(prompt) = body.get("prompt").and_then(|p| p.as_str()) {
+        prompt.chars().take(2048).collect()
+    } else {
+        return None;
+    };
+    Some(Sha256::digest(prefix.as_bytes()).to_vec())
+}
@@ -0,0 +1,1839 @@
+// Forwarding of client requests to the upstream
+
+use crate::adapters::{beta_headers, is_beta_header, thinking_to_effort, RequestShaper};
+use crate::cache::{CacheControl, CachedResponse, Flight, Lookup, ResponseCache};
+use crate::chaos;
+use crate::config::{AdapterKind, ProviderConfig};
+use crate::constrained::satisfies;
+use crate::error::{body_read_error, translate_upstream_error, ProxyError};
+use crate::flags::Flag;
+use crate::guardrails::inline_remote_images;
+use crate::inspector::RequestSummary;
+use crate::integrity;
+use crate::key_pool::KeyPool;
+use crate::limits::RateQuota;
+use crate::loops::LoopDetector;
+use crate::models::{curate_model_list, return_configured_models, ModelInfo, ModelPricing};
+use crate::modes;
+use crate::pacing::Pacer;
+use crate::probes::ProbeRequest;
+use crate::providers::Provider;
+use crate::request::{enforces_policy, OutgoingRequest};
+use crate::resume;
+use crate::router::json_response;
+use crate::routing::RequestOverrides;
+use crate::secrets::{hex_encode, UpstreamKey};
+use crate::smoothing::PacedStream;
+use crate::staging::{FileStaging, StagedUpload};
+use crate::state::{capture_body, AppState, CaptureRecord};
+use crate::tags::{self, header_tags};
+use crate::tenant::{bearer_token, Namespace, SignedRequest};
+use crate::time::{format_utc, unix_now};
+use crate::trace::TraceContext;
+use crate::transcripts::RecordTranscript;
+use crate::transform::{
+    extract_usage, transform_json_body, NormalizeFinishReason, ResponseTransform, Salvage,
+    SalvageRetry, StreamPipeline, UpstreamStream,
+};
+use crate::upstream_auth::{auth_headers, UpstreamAuth};
+use crate::usage::{cost, UsageRecord};
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request, State},
+    http::{header::HeaderValue, HeaderMap, HeaderName, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+pub(crate) fn provider_name(api_base: &str) -> String {
+    reqwest::Url::parse(api_base)
+        .ok()
+        .and_then(|url| url.host_str().map(|h| h.to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// A client body forwarded as it arrives. reqwest wants a Sync stream, which
+// the mutex provides; it is never locked.
+struct SyncBody(Mutex<axum::body::BodyDataStream>);
+
+impl SyncBody {
+    fn new(body: Body) -> Self {
+        Self(Mutex::new(body.into_data_stream()))
+    }
+}
+
+impl Stream for SyncBody {
+    type Item = Result<axum::body::Bytes, axum::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.get_mut().unwrap().poll_next_unpin(cx)
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+// An upstream a request can be sent to
+struct UpstreamTarget<'a> {
+    // Provider name, or the host for the default upstream
+    name: String,
+    api_base: String,
+    key: &'a UpstreamKey,
+    // Takes the place of key when the provider pools several
+    key_pool: Option<&'a KeyPool>,
+    // None for the default upstream
+    config: Option<&'a ProviderConfig>,
+    // Quota with the provider's interactive reserve
+    quota: Option<(&'a Arc<RateQuota>, f64)>,
+    pacer: Option<&'a Pacer>,
+    shaper: Option<&'a RequestShaper>,
+    // None sends the key as a bearer token
+    auth: Option<&'a UpstreamAuth>,
+}
+
+impl<'a> UpstreamTarget<'a> {
+    // The entry of a client's upstream_keys used for this target
+    fn key_name(&self) -> &str {
+        self.config.map_or("default", |config| config.name.as_str())
+    }
+
+    // One of the provider's replicas, with its own key or the namespace's
+    fn provider(
+        provider: &'a Provider,
+        fingerprint: Option<&[u8]>,
+        namespace_key: &'a UpstreamKey,
+    ) -> Self {
+        Self {
+            name: provider.config.name.clone(),
+            api_base: provider.select_base(fingerprint).to_string(),
+            key: provider.api_key.as_deref().unwrap_or(namespace_key),
+            key_pool: provider.key_pool.as_ref(),
+            config: Some(&provider.config),
+            quota: provider
+                .quota
+                .as_ref()
+                .map(|quota| (quota, provider.config.interactive_reserve.clamp(0.0, 1.0))),
+            pacer: provider.pacer.as_ref(),
+            shaper: Some(&provider.shaper).filter(|s| !s.is_empty()),
+            auth: provider.auth.as_ref(),
+        }
+    }
+}
+
+// Asks the upstream that broke off a stream for the rest of the completion
+// without streaming. The text already sent goes along as an assistant message
+// for upstreams that continue a prefilled answer.
+fn salvage_retry(
+    state: Arc<AppState>,
+    request: reqwest::RequestBuilder,
+    provider: Option<String>,
+    key: String,
+    url: String,
+    body: Bytes,
+) -> SalvageRetry {
+    Box::new(move |sent: String| {
+        Box::pin(async move {
+            let mut json: serde_json::Value = serde_json::from_slice(&body).ok()?;
+            json["stream"] = false.into();
+            json.as_object_mut()?.remove("stream_options");
+            if !sent.is_empty() {
+                json["messages"]
+                    .as_array_mut()?
+                    .push(serde_json::json!({"role": "assistant", "content": sent}));
+            }
+            let body = serde_json::to_vec(&json).ok()?;
+            let auth = state
+                .providers
+                .iter()
+                .find(|p| Some(&p.config.name) == provider.as_ref())
+                .and_then(|p| p.auth.as_ref());
+            let auth = auth_headers(auth, &key, "POST", &url, Some(&body))
+                .await
+                .ok()?;
+            let mut request = request.build().ok()?;
+            for (name, value) in auth {
+                request.headers_mut().insert(
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes()).ok()?,
+                    reqwest::header::HeaderValue::from_str(&value).ok()?,
+                );
+            }
+            *request.body_mut() = Some(body.into());
+            let response = state.client.execute(request).await.ok()?;
+            if !response.status().is_success() {
+                return None;
+            }
+            let completion: serde_json::Value = response.json().await.ok()?;
+            let content = completion["choices"][0]["message"]["content"].as_str()?;
+            // Some upstreams repeat the prefix they were given
+            Some(
+                content
+                    .strip_prefix(sent.as_str())
+                    .unwrap_or(content)
+                    .to_string(),
+            )
+        })
+    })
+}
+
+// Per-request details collected while forwarding, used for access logging
+#[derive(Clone)]
+pub(crate) struct RequestLog {
+    pub(crate) request_id: String,
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) model: Option<String>,
+    // The model the client asked for, when a routing rule picked another
+    pub(crate) requested_model: Option<String>,
+    pub(crate) client: String,
+    pub(crate) tenant: Option<String>,
+    // The end user named in the request's user field
+    pub(crate) user: Option<String>,
+    // Labels from x-proxy-tags and the metadata field
+    pub(crate) tags: BTreeMap<String, String>,
+    // Fingerprint of the system prompt, see prompts.rs
+    pub(crate) prompt_fingerprint: Option<String>,
+    // The prompt template version served, e.g. "support@v2"
+    pub(crate) prompt_version: Option<String>,
+    pub(crate) provider: String,
+    // Token counts of a buffered completion response
+    pub(crate) prompt_tokens: Option<u64>,
+    pub(crate) completion_tokens: Option<u64>,
+    // Set when the response is relayed as a stream and accounted when it ends
+    pub(crate) streaming: bool,
+    // Prices of the requested model, for the usage ledger
+    pub(crate) pricing: Option<ModelPricing>,
+    pub(crate) trace_id: Option<String>,
+    // Request and response body bytes held in memory
+    pub(crate) buffered_bytes: u64,
+}
+
+pub(crate) async fn proxy_handler(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    req: Request,
+) -> Response {
+    let started = Instant::now();
+    let mut log = RequestLog {
+        request_id: state.next_request_id(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        model: None,
+        requested_model: None,
+        client: peer.ip().to_string(),
+        tenant: None,
+        user: None,
+        tags: BTreeMap::new(),
+        prompt_fingerprint: None,
+        prompt_version: None,
+        provider: "unknown".to_string(),
+        prompt_tokens: None,
+        completion_tokens: None,
+        streaming: false,
+        pricing: None,
+        trace_id: None,
+        buffered_bytes: 0,
+    };
+
+    let mut response = forward_request(&state, headers, req, &mut log)
+        .await
+        .into_response();
+    if let Ok(value) = HeaderValue::from_str(&log.request_id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    if state.metadata_headers {
+        add_metadata_headers(response.headers_mut(), &log, started.elapsed());
+    }
+
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if let Some(access_log) = &state.access_log {
+        access_log.write(&format!(
+            "{} {} {} {} {} {} {}ms model={} tenant={} trace={}",
+            format_utc(unix_now()),
+            log.request_id,
+            log.client,
+            log.method,
+            log.path,
+            response.status().as_u16(),
+            duration_ms,
+            log.model.as_deref().unwrap_or("-"),
+            log.tenant.as_deref().unwrap_or("-"),
+            log.trace_id.as_deref().unwrap_or("-")
+        ));
+    }
+
+    state.metrics.record_buffered(log.buffered_bytes);
+    state.record_tags(
+        &log,
+        1,
+        log.prompt_tokens.unwrap_or(0),
+        log.completion_tokens.unwrap_or(0),
+    );
+    state.metrics.record_request(
+        log.model.as_deref().unwrap_or("unknown"),
+        &log.provider,
+        response.status().as_u16(),
+        duration_ms,
+    );
+    if let Some(version) = &log.prompt_version {
+        state.prompt_templates.record(
+            version,
+            Some(response.status().as_u16()),
+            log.prompt_tokens.unwrap_or(0),
+            log.completion_tokens.unwrap_or(0),
+        );
+    }
+    if let Some(fingerprint) = &log.prompt_fingerprint {
+        state.metrics.record_prompt(
+            fingerprint,
+            Some(response.status().as_u16()),
+            log.prompt_tokens.unwrap_or(0),
+            log.completion_tokens.unwrap_or(0),
+        );
+    }
+    if let (Some(alerts), false) = (&state.alerts, log.streaming) {
+        alerts.record(
+            &log,
+            response.status().as_u16(),
+            duration_ms,
+            log.completion_tokens,
+        );
+    }
+
+    // Streams are published once they end
+    if !log.streaming {
+        let status = response.status().as_u16();
+        state.request_finished(RequestSummary::new(&log, status, duration_ms));
+    }
+
+    if let Some(statsd) = &state.statsd {
+        let status = response.status().as_u16().to_string();
+        let mut tags = vec![
+            ("model", log.model.as_deref().unwrap_or("unknown")),
+            ("provider", log.provider.as_str()),
+            ("status", status.as_str()),
+            ("client", log.client.as_str()),
+            ("tenant", log.tenant.as_deref().unwrap_or("none")),
+        ];
+        tags.extend(tags::metric_tags(&state.request_tags, &log.tags));
+        statsd.count("requests", 1, &tags);
+        statsd.timing("request.duration_ms", duration_ms, &tags);
+    }
+
+    response
+}
+
+// The upstream request a dry run would have sent, with credentials masked
+fn dry_run_response(
+    request: &reqwest::Request,
+    auth: &[(String, String)],
+    provider: &str,
+    fallbacks: Vec<&str>,
+) -> Response {
+    let headers: serde_json::Map<String, serde_json::Value> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let masked = auth.iter().any(|(header, _)| name == header.as_str());
+            let value = match masked {
+                true => "***".to_string(),
+                false => value.to_str().unwrap_or_default().to_string(),
+            };
+            (name.to_string(), value.into())
+        })
+        .collect();
+    let body = request.body().and_then(|body| body.as_bytes());
+    let body = match body.map(serde_json::from_slice::<serde_json::Value>) {
+        Some(Ok(json)) => json,
+        Some(Err(_)) => String::from_utf8_lossy(body.unwrap_or_default()).into(),
+        None => serde_json::Value::Null,
+    };
+    json_response(&serde_json::json!({
+        "dry_run": true,
+        "provider": provider,
+        "fallbacks": fallbacks,
+        "method": request.method().as_str(),
+        "url": request.url().as_str(),
+        "headers": headers,
+        "body": body,
+    }))
+}
+
+// How the request was served, for clients and gateways that do not parse bodies
+fn add_metadata_headers(headers: &mut HeaderMap, log: &RequestLog, elapsed: Duration) {
+    let mut set = |name: &'static str, value: &str| {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    };
+    if log.provider != "unknown" && log.provider != "cache" {
+        set("x-proxy-upstream", &log.provider);
+    }
+    if let Some(model) = &log.model {
+        set("x-proxy-model-used", model);
+    }
+    if let Some(version) = &log.prompt_version {
+        set("x-proxy-prompt-version", version);
+    }
+    // Streams are priced once they end, after the headers went out
+    if let (Some(pricing), Some(prompt_tokens), Some(completion_tokens)) =
+        (&log.pricing, log.prompt_tokens, log.completion_tokens)
+    {
+        let cost = cost(pricing, prompt_tokens, completion_tokens);
+        set("x-proxy-cost-usd", &format!("{:.6}", cost));
+    }
+    set("x-proxy-latency-ms", &elapsed.as_millis().to_string());
+    headers
+        .entry("x-proxy-cache")
+        .or_insert(HeaderValue::from_static("miss"));
+}
+
+// A client reconnecting to a stream gets the rest of it, not a new one
+async fn resume_stream(
+    state: &AppState,
+    headers: &HeaderMap,
+    log: &mut RequestLog,
+) -> Option<Response> {
+    let buffers = state.stream_resumption.as_ref()?;
+    let (stream_id, n) = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(resume::parse_event_id)?;
+    match buffers.resume(stream_id, n, &resume::owner(headers)).await {
+        Some(rest) => {
+            println!("♻️  Resuming stream {} after event {}", stream_id, n);
+            log.provider = "resumed".to_string();
+            let mut resp = Response::new(Body::from_stream(rest));
+            resp.headers_mut().insert(
+                "content-type",
+                HeaderValue::from_static("text/event-stream"),
+            );
+            Some(resp)
+        }
+        None => {
+            println!("♻️  Stream {} cannot be resumed, sending anew", stream_id);
+            None
+        }
+    }
+}
+
+// x-proxy-dry-run: the would-be upstream request is returned instead
+fn dry_run_requested(
+    state: &AppState,
+    headers: &HeaderMap,
+    namespace: &Namespace<'_>,
+) -> Result<bool, ProxyError> {
+    let dry_run = headers
+        .get("x-proxy-dry-run")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"));
+    if dry_run {
+        let allowed = match namespace.client {
+            Some(client) => client.dry_run,
+            None => namespace.tenant.is_none() && state.allow_dry_run,
+        };
+        if !allowed {
+            return Err(ProxyError::Forbidden(
+                "Dry runs are not allowed for this client".to_string(),
+            ));
+        }
+    }
+    Ok(dry_run)
+}
+
+// Checks the declared hashes of a file upload and stages it to disk, or
+// takes back a staged one named by x-proxy-upload-id. A streamed body is
+// checked while it is sent; the mismatch it finds is returned with the upload.
+async fn receive_upload(
+    staging: Option<&FileStaging>,
+    integrity: bool,
+    headers: &mut HeaderMap,
+    body_bytes: &[u8],
+    streamed_body: &mut Option<Body>,
+    log: &RequestLog,
+) -> Result<(Option<Arc<StagedUpload>>, Option<Arc<OnceLock<String>>>), ProxyError> {
+    let resumed_upload = staging
+        .and(headers.get("x-proxy-upload-id"))
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut upload_mismatch = None;
+    let expected = match integrity {
+        true if resumed_upload.is_none() => {
+            integrity::expected(headers).map_err(ProxyError::InvalidRequest)?
+        }
+        _ => None,
+    };
+    if let Some(expected) = expected {
+        match streamed_body.take() {
+            Some(body) => {
+                let (body, mismatch) = integrity::verified_body(expected, body);
+                *streamed_body = Some(body);
+                upload_mismatch = Some(mismatch);
+            }
+            None => integrity::verify(&expected, body_bytes).map_err(|header| {
+                ProxyError::InvalidRequest(format!("Request body does not match its {}", header))
+            })?,
+        }
+    }
+    let Some(staging) = staging else {
+        return Ok((None, upload_mismatch));
+    };
+    if let Some(id) = &resumed_upload {
+        let upload = staging.resume(id, &log.client)?;
+        println!("📥 Sending staged upload {} again", id);
+        if let Some(content_type) = &upload.content_type {
+            headers.insert("content-type", content_type.clone());
+        }
+        return Ok((Some(upload), upload_mismatch));
+    }
+    let Some(body) = streamed_body.take() else {
+        return Ok((None, upload_mismatch));
+    };
+    let content_type = headers.get("content-type").cloned();
+    match staging.stage(&log.request_id, body, content_type).await {
+        Ok(upload) => Ok((Some(upload), upload_mismatch)),
+        // A hash mismatch ends the body early
+        Err(err) => Err(match upload_mismatch.as_ref().and_then(|m| m.get()) {
+            Some(mismatch) => ProxyError::InvalidRequest(mismatch.clone()),
+            None => err,
+        }),
+    }
+}
+
+// Routing headers need the client's permission
+fn routing_overrides(
+    state: &AppState,
+    headers: &HeaderMap,
+    namespace: &Namespace<'_>,
+) -> Result<RequestOverrides, ProxyError> {
+    let overrides = RequestOverrides::from_headers(headers);
+    if !overrides.is_empty() {
+        let allowed = match namespace.client {
+            Some(client) => client.routing_overrides,
+            None => namespace.tenant.is_none() && state.allow_routing_overrides,
+        };
+        if !allowed {
+            return Err(ProxyError::Forbidden(
+                "Routing override headers are not allowed for this client".to_string(),
+            ));
+        }
+        if let Some(name) = &overrides.provider {
+            if !state.providers.iter().any(|p| &p.config.name == name) {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Unknown provider {}",
+                    name
+                )));
+            }
+        }
+    }
+    Ok(overrides)
+}
+
+// The upstreams to try, in order: the model's provider, or the namespace's
+// upstream, then its fallbacks
+fn upstream_targets<'a>(
+    state: &'a AppState,
+    namespace: &Namespace<'a>,
+    model_provider: Option<&str>,
+    fallbacks: &[String],
+    fingerprint: Option<&[u8]>,
+    model: Option<&str>,
+) -> Result<Vec<UpstreamTarget<'a>>, ProxyError> {
+    // Models bound to a provider go to one of its replicas with its key
+    let find_provider = |name: &str| state.providers.iter().find(|p| p.config.name == name);
+    let provider = model_provider.and_then(find_provider);
+    let mut primary = match provider {
+        Some(provider) => UpstreamTarget::provider(provider, fingerprint, namespace.api_key),
+        None => UpstreamTarget {
+            name: provider_name(namespace.api_base),
+            api_base: namespace.api_base.to_string(),
+            key: namespace.api_key,
+            key_pool: None,
+            config: None,
+            quota: None,
+            pacer: None,
+            shaper: None,
+            auth: None,
+        },
+    };
+
+    // Providers in a maintenance window are skipped in favour of the fallbacks
+    let now = unix_now();
+    let in_maintenance = |provider: &Provider| {
+        let down = provider.in_maintenance(now);
+        if down {
+            println!(
+                "🚧 Provider {} is in maintenance, skipping",
+                provider.config.name
+            );
+        }
+        down
+    };
+
+    // An admin override redirects traffic away from the configured base
+    let mut primary_down = provider.is_some_and(in_maintenance);
+    if let Some(api_base) = state.upstream_overrides.resolve(model) {
+        primary.name = provider_name(&api_base);
+        primary.api_base = api_base;
+        primary_down = false;
+    }
+
+    // Fallback providers are tried after the primary, within the retry budget
+    let mut targets = Vec::new();
+    if !primary_down {
+        targets.push(primary);
+    }
+    for provider in fallbacks
+        .iter()
+        .filter_map(|name| find_provider(name))
+        .filter(|provider| !in_maintenance(provider))
+    {
+        targets.push(UpstreamTarget::provider(
+            provider,
+            fingerprint,
+            namespace.api_key,
+        ));
+    }
+    // A model failing its probes tries the fallbacks first
+    if targets.len() > 1 && !primary_down && model.is_some_and(|m| state.degraded(m)) {
+        println!(
+            "🩺 Model {} is degraded, trying its fallbacks first",
+            model.unwrap_or_default()
+        );
+        targets.rotate_left(1);
+    }
+    if targets.is_empty() {
+        return Err(ProxyError::Unavailable(format!(
+            "All providers for {} are in maintenance",
+            model.unwrap_or("this model")
+        )));
+    }
+    Ok(targets)
+}
+
+// Convert Axum's Method to Reqwest's Method
+fn upstream_method(method: &Method) -> reqwest::Method {
+    match method.as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "DELETE" => reqwest::Method::DELETE,
+        "PATCH" => reqwest::Method::PATCH,
+        "HEAD" => reqwest::Method::HEAD,
+        "OPTIONS" => reqwest::Method::OPTIONS,
+        _ => reqwest::Method::POST, // Default to POST
+    }
+}
+
+// A request's place in the response cache, for storing what the upstream
+// answers
+struct Caching<'a> {
+    key: Option<String>,
+    ttl: Option<u64>,
+    // An expired entry with an ETag is revalidated instead of fetched again
+    stale: Option<CachedResponse>,
+    // Identical requests wait for this one's response
+    flight: Option<Flight<'a>>,
+    if_none_match: Option<String>,
+}
+
+// Identical buffered requests are answered from the cache: the Err is the
+// cached response
+async fn lookup_cache<'a>(
+    cache: Option<&'a ResponseCache>,
+    headers: &HeaderMap,
+    method: &Method,
+    path: &str,
+    // A GET's query and upstream credential; a GET without one is not cached
+    get_target: Option<String>,
+    forwarded_json: Option<&serde_json::Value>,
+    log: &mut RequestLog,
+) -> Result<Caching<'a>, Response> {
+    let if_none_match = headers
+        .get("if-none-match")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut ttl = CacheControl::from_headers(headers).ttl_secs;
+    let key = cache.and_then(|cache| {
+        if method == Method::GET && cache.caches_get(path) {
+            ttl = ttl.or(Some(cache.config.get_ttl_secs));
+            let target = get_target?;
+            return Some(cache.key(log.tenant.as_deref(), &target, &serde_json::Value::Null));
+        }
+        let cacheable = method == Method::POST
+            && cache.caches_path(path)
+            && forwarded_json.is_some_and(|json| json["stream"].as_bool() != Some(true));
+        let json = forwarded_json.filter(|_| cacheable)?;
+        Some(cache.key(log.tenant.as_deref(), path, json))
+    });
+    let cache_control = CacheControl::from_headers(headers);
+    let lookup = match (cache, &key) {
+        (Some(cache), Some(key)) if !cache_control.bypass => Some(cache.lookup(key).await),
+        _ => None,
+    };
+    let stale = match (cache, &key) {
+        (Some(cache), Some(key)) if method == Method::GET => {
+            cache.get_stale(key).filter(|entry| entry.etag.is_some())
+        }
+        _ => None,
+    };
+    let mut flight = None;
+    let hit = match lookup {
+        Some(Lookup::Hit(entry)) => Some((entry, "hit")),
+        Some(Lookup::Shared(entry)) => Some((entry, "coalesced")),
+        Some(Lookup::Miss(leader)) => {
+            flight = leader;
+            None
+        }
+        None => None,
+    };
+    if let Some((entry, outcome)) = hit {
+        println!("💾 Cache {} for {}", outcome, path);
+        log.provider = "cache".to_string();
+        return Err(entry.into_response(outcome, if_none_match.as_deref()));
+    }
+    Ok(Caching {
+        key,
+        ttl,
+        stale,
+        flight,
+        if_none_match,
+    })
+}
+
+// What the targets authenticate a request with, so cached file and model
+// lists only go to callers of the same account: the caller's or client's
+// own key or the target's, with the OpenAI organization and project, as a
+// hash. None when the key is only picked at the send.
+fn upstream_credential(
+    headers: &HeaderMap,
+    namespace: &Namespace<'_>,
+    caller_key: Option<&str>,
+    targets: &[UpstreamTarget<'_>],
+) -> Option<String> {
+    use sha2::Digest;
+    let own_keys = namespace
+        .client
+        .filter(|c| caller_key.is_none() && !c.upstream_keys.is_empty());
+    let mut hasher = sha2::Sha256::new();
+    for target in targets {
+        let key = match (caller_key, own_keys) {
+            (Some(key), _) => key.to_string(),
+            (None, Some(client)) => client.upstream_keys.get(target.key_name())?.clone(),
+            (None, None) if target.key_pool.is_some() => return None,
+            (None, None) => target.key.get(),
+        };
+        hasher.update(key.as_bytes());
+        hasher.update(b"\n");
+    }
+    let client = namespace.client;
+    for (header, value) in [
+        (
+            "openai-organization",
+            client.and_then(|c| c.openai_organization.as_ref()),
+        ),
+        (
+            "openai-project",
+            client.and_then(|c| c.openai_project.as_ref()),
+        ),
+    ] {
+        let value = value
+            .map(String::as_str)
+            .or_else(|| headers.get(header).and_then(|v| v.to_str().ok()));
+        hasher.update(value.unwrap_or_default().as_bytes());
+        hasher.update(b"\n");
+    }
+    Some(hex_encode(&hasher.finalize()))
+}
+
+// In passthrough mode the caller's own key is used, unless the Authorization
+// header is what authenticated the caller with the proxy
+fn caller_key<'h>(
+    state: &AppState,
+    headers: &'h HeaderMap,
+    namespace: &Namespace<'_>,
+) -> Option<&'h str> {
+    let passthrough = namespace
+        .client
+        .and_then(|c| c.key_passthrough)
+        .unwrap_or(state.key_passthrough);
+    bearer_token(headers).filter(|key| {
+        passthrough
+            && namespace
+                .client
+                .map(|c| c.key.as_deref() != Some(*key))
+                .unwrap_or(true)
+    })
+}
+
+pub(crate) async fn forward_request(
+    state: &Arc<AppState>,
+    mut headers: HeaderMap,
+    req: Request,
+    log: &mut RequestLog,
+) -> Result<Response, ProxyError> {
+    let received = Instant::now();
+    log.tags = header_tags(&headers)?;
+    // Extract path and query before consuming the request
+    let mut path = req.uri().path().trim_start_matches('/').to_string();
+    let query = req.uri().query().unwrap_or("").to_string();
+    let target = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+
+    // Get original HTTP method
+    let method = req.method().clone();
+    let probing = req.extensions().get::<ProbeRequest>().is_some();
+
+    // A "/t/{tenant}/" prefix selects the tenant and is not forwarded upstream
+    let mut tenant_prefix = None;
+    if let Some(rest) = path.strip_prefix("t/") {
+        if let Some((tenant, upstream_path)) = rest.split_once('/') {
+            tenant_prefix = Some(tenant.to_string());
+            path = upstream_path.to_string();
+        }
+    }
+
+    // Passthrough bodies are streamed upstream as they arrive, and uploads to
+    // disk, unless the signature needs them
+    let staging = state
+        .file_staging
+        .as_ref()
+        .filter(|s| s.applies(&method, &path));
+    let streamed = !headers.contains_key("x-proxy-signature")
+        && (staging.is_some()
+            || state
+                .passthrough_paths
+                .iter()
+                .any(|suffix| path.ends_with(suffix.as_str())));
+    let (mut body_bytes, mut streamed_body) = if streamed {
+        (axum::body::Bytes::new(), Some(req.into_body()))
+    } else {
+        let body = state
+            .buffers
+            .read(req.into_body().into_data_stream(), content_length(&headers))
+            .await
+            .map_err(body_read_error)?;
+        (body, None)
+    };
+
+    let signed = SignedRequest {
+        method: method.as_str(),
+        target: &target,
+        body: &body_bytes,
+    };
+    let namespace = state
+        .resolve_namespace(tenant_prefix.as_deref(), &headers, &signed)
+        .await?;
+    if let Some(tenant) = namespace.tenant {
+        log.tenant = Some(tenant.name.clone());
+        if let Some(client) = namespace.client {
+            log.client = client.name.clone();
+        }
+        tenant.check_budget()?;
+    }
+    if let Some(resumed) = resume_stream(state, &headers, log).await {
+        return Ok(resumed);
+    }
+    if let Some(rejection) = modes::check(state, &method, &path, &log.client) {
+        return Ok(rejection);
+    }
+    let stream_faults = match state.chaos.inject(&log.client).await {
+        Ok(faults) => faults,
+        Err(injected) => return Ok(injected),
+    };
+    let dry_run = dry_run_requested(state, &headers, &namespace)?;
+    let capture = state.should_capture(&log.client);
+    // A passthrough body policy applies to goes through the request stages
+    let policed = staging.is_none() && enforces_policy(state, &namespace, &path);
+    // Captured requests and dry runs keep their body, so it is read after all
+    if let Some(body) = streamed_body.take_if(|_| capture || dry_run || policed) {
+        body_bytes = state
+            .buffers
+            .read(body.into_data_stream(), content_length(&headers))
+            .await
+            .map_err(body_read_error)?;
+    }
+    log.buffered_bytes += body_bytes.len() as u64;
+    let integrity = state.integrity.as_ref().filter(|i| i.applies(&path));
+    let (staged, upload_mismatch) = receive_upload(
+        staging,
+        integrity.is_some(),
+        &mut headers,
+        &body_bytes,
+        &mut streamed_body,
+        log,
+    )
+    .await?;
+    let trace = TraceContext::from_headers(&headers, state.trace_context.start_new);
+
+    // x-request-timeout-ms, or the client's default, bounds the whole request
+    let timeout_ms = headers
+        .get("x-request-timeout-ms")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .or(namespace.client.and_then(|c| c.request_timeout_ms));
+    let deadline = timeout_ms.map(|ms| received + Duration::from_millis(ms));
+    log.trace_id = trace.as_ref().map(|t| t.trace_id.clone());
+
+    let overrides = routing_overrides(state, &headers, &namespace)?;
+
+    // Per-model rewrites of the response, buffered or streamed
+    let mut transforms: Vec<Box<dyn ResponseTransform>> = Vec::new();
+    // Catalog entry the model's settings come from
+    let mut model_config = None;
+    let mut fingerprint = None;
+    let mut interactive = false;
+    let mut estimated_tokens = 0;
+    let mut anthropic_beta = None;
+    let mut transcript_request = None;
+    // The forwarded body as JSON, for per-provider shaping
+    let mut forwarded_json = None;
+    let mut client_thinking = false;
+    let mut emulated_regex = None;
+    let mut rule_headers = Vec::new();
+    let mut output_cap = namespace.client.and_then(|c| c.output_token_cap);
+
+    // JSON object bodies go through the request stages, see request.rs
+    let modified_body = if !body_bytes.is_empty() && (!streamed || policed) {
+        match serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+            Ok(mut json) => {
+                if json.is_object() {
+                    let mut request = OutgoingRequest::new(
+                        state, &namespace, &headers, &path, &overrides, log, json,
+                    );
+                    request.transform().await?;
+                    json = request.body;
+                    transforms = request.transforms;
+                    model_config = request.model_config;
+                    fingerprint = request.fingerprint;
+                    interactive = request.interactive;
+                    estimated_tokens = request.estimated_tokens;
+                    anthropic_beta = request.anthropic_beta;
+                    transcript_request = request.transcript_request;
+                    client_thinking = request.client_thinking;
+                    emulated_regex = request.emulated_regex;
+                    rule_headers = request.rule_headers;
+                    output_cap = request.output_cap;
+                }
+
+                let forwarded = state.buffers.serialize(&json);
+                log.buffered_bytes += forwarded.len() as u64;
+                if json.is_object() {
+                    forwarded_json = Some(json);
+                }
+                forwarded
+            }
+            Err(_) => body_bytes.clone(),
+        }
+    } else {
+        body_bytes.clone()
+    };
+    let model_entry = model_config.map(|m: &ModelInfo| m.id.clone());
+    let mut model_provider = model_config.and_then(|m| m.provider.clone());
+    let mut model_fallbacks = model_config.map_or(&[][..], |m| &m.fallbacks[..]);
+
+    match headers
+        .get("x-proxy-priority")
+        .and_then(|v| v.to_str().ok())
+    {
+        Some("interactive") => interactive = true,
+        Some("batch") => interactive = false,
+        _ => {}
+    }
+
+    if let Some(map) = &state.finish_reasons {
+        if path.ends_with("completions") {
+            transforms.push(Box::new(NormalizeFinishReason { map: map.clone() }));
+        }
+    }
+
+    // Last, so the transcript has the response the client gets
+    if let (Some(collector), Some(request)) = (&state.transcripts, transcript_request) {
+        if collector.accepts_model(&state.model_matcher, log.model.as_deref()) {
+            transforms.push(Box::new(RecordTranscript::new(collector.clone(), request)));
+        }
+    }
+
+    if let Some(name) = &overrides.provider {
+        model_provider = Some(name.clone());
+    }
+    // A streamed body can only be sent once, and probes test the model's own
+    // provider
+    if overrides.no_fallback || streamed_body.is_some() || probing {
+        model_fallbacks = &[];
+    }
+
+    let targets = upstream_targets(
+        state,
+        &namespace,
+        model_provider.as_deref(),
+        model_fallbacks,
+        fingerprint.as_deref(),
+        log.model.as_deref(),
+    )?;
+    let upstream_url = |api_base: &str| {
+        if query.is_empty() {
+            format!("{}/{}", api_base.trim_end_matches('/'), path)
+        } else {
+            format!("{}/{}?{}", api_base.trim_end_matches('/'), path, query)
+        }
+    };
+
+    let reqwest_method = upstream_method(&method);
+
+    let cache = state
+        .response_cache
+        .as_ref()
+        .filter(|_| state.flags.enabled(Flag::ResponseCache))
+        .filter(|_| !dry_run && !probing && !overrides.no_cache);
+    let caller_key = caller_key(state, &headers, &namespace);
+    let get_target = (method == Method::GET)
+        .then(|| upstream_credential(&headers, &namespace, caller_key, &targets))
+        .flatten()
+        .map(|credential| format!("GET {}?{}\n{}", path, query, credential));
+    let caching = match lookup_cache(
+        cache,
+        &headers,
+        &method,
+        &path,
+        get_target,
+        forwarded_json.as_ref(),
+        log,
+    )
+    .await
+    {
+        Ok(caching) => caching,
+        Err(hit) => return Ok(hit),
+    };
+    let streamed_body = Mutex::new(streamed_body.map(SyncBody::new));
+    let replayable = streamed_body.lock().unwrap().is_none();
+    let per_provider = if replayable {
+        state.retries.per_provider.max(1)
+    } else {
+        1
+    };
+
+    // Held until the response, or the stream relaying it, is complete
+    let permits = state
+        .limits
+        .acquire(&namespace, model_entry.as_deref())
+        .await?;
+    // Settled with the actual usage once the response is complete
+    let mut reservations = Vec::new();
+    reservations.extend(
+        state
+            .limits
+            .admit_client(&namespace, estimated_tokens)
+            .await?,
+    );
+
+    // Build forwarding request
+    let build_request = |url: &str,
+                         auth: &[(String, String)],
+                         timeout: Option<Duration>,
+                         body: &axum::body::Bytes,
+                         beta: &[(String, String)]| {
+        let mut request_builder = state.client.request(reqwest_method.clone(), url);
+        // The client's own content type, e.g. of a multipart upload, is kept
+        if !headers.contains_key("content-type") {
+            request_builder = request_builder.header("Content-Type", "application/json");
+        }
+        for (header, value) in auth {
+            request_builder = request_builder.header(header.as_str(), value.as_str());
+        }
+
+        // Forward other necessary headers
+        for (name, value) in headers.iter() {
+            let name_str = name.as_str();
+            // Skip certain headers that should not be forwarded
+            if name_str != "host"
+                && name_str != "authorization"
+                && name_str != "content-length"
+                && !name_str.starts_with("x-proxy-")
+                && name_str != "traceparent"
+                && name_str != "x-request-timeout-ms"
+                && name_str != "tracestate"
+                && !is_beta_header(name_str)
+                && !beta.iter().any(|(header, _)| header == name_str)
+                && !auth.iter().any(|(header, _)| header == name_str)
+                && !(name_str == "if-none-match" && caching.stale.is_some())
+            {
+                // Convert Axum header name/value to string representations for Reqwest
+                request_builder =
+                    request_builder.header(name.as_str(), value.to_str().unwrap_or_default());
+            }
+        }
+
+        for (header, value) in beta {
+            request_builder = request_builder.header(header.as_str(), value.as_str());
+        }
+        if let Some(etag) = caching
+            .stale
+            .as_ref()
+            .and_then(|entry| entry.etag.as_deref())
+        {
+            request_builder = request_builder.header("if-none-match", etag);
+        }
+        if let Some(trace) = trace.as_ref().filter(|_| state.trace_context.forward) {
+            request_builder = request_builder.header("traceparent", trace.traceparent());
+            if let Some(trace_state) = &trace.state {
+                request_builder = request_builder.header("tracestate", trace_state.as_str());
+            }
+        }
+
+        if let Some(timeout) = timeout {
+            request_builder = request_builder.timeout(timeout);
+        }
+
+        // Add request body
+        if let Some(upload) = &staged {
+            request_builder = request_builder
+                .header("content-length", upload.len)
+                .body(upload.body());
+        } else if let Some(body) = streamed_body.lock().unwrap().take() {
+            request_builder = request_builder.body(reqwest::Body::wrap_stream(body));
+        } else if !body.is_empty() {
+            request_builder = request_builder.body(body.clone());
+        }
+        request_builder
+    };
+
+    // Send the request, retrying and falling back within the budget
+    let retries = &state.retries;
+    let retry_budget = Duration::from_millis(retries.max_elapsed_ms);
+    let started = Instant::now();
+    let mut attempts: Vec<String> = Vec::new();
+    let mut sends = 0;
+    let mut outcome = None;
+    // Why nothing was sent: out of quota, or no upstream credentials
+    let mut unsent = None;
+    let mut deadline_passed = false;
+    // Gemini only takes inline image data, remote images are fetched once
+    if let Some(json) = forwarded_json.as_mut().filter(|_| {
+        targets
+            .iter()
+            .any(|t| t.shaper.is_some_and(|s| s.inlines_images()))
+    }) {
+        inline_remote_images(state, json).await?;
+    }
+    'chain: for (index, target) in targets.iter().enumerate() {
+        // Providers with other parameter names get their own copy of the body
+        let openai = target.shaper.is_none_or(|s| s.is_openai());
+        let body = match &forwarded_json {
+            Some(json) if target.shaper.is_some() || (client_thinking && openai) => {
+                let mut json = json.clone();
+                let obj = json.as_object_mut().unwrap();
+                if client_thinking && openai {
+                    thinking_to_effort(obj);
+                }
+                if let Some(shaper) = target.shaper {
+                    shaper.apply(obj);
+                }
+                let body = state.buffers.serialize(&json);
+                log.buffered_bytes += body.len() as u64;
+                body
+            }
+            _ => modified_body.clone(),
+        };
+        let extra_beta: Vec<_> = anthropic_beta
+            .iter()
+            .map(|beta| ("anthropic-beta", beta.as_str()))
+            .collect();
+        let mut beta = beta_headers(
+            target.config,
+            &headers,
+            &path,
+            forwarded_json.as_ref(),
+            &extra_beta,
+        );
+        // The client's OpenAI organization and project replace any it sent, so
+        // its usage is billed to that project
+        if target
+            .config
+            .is_none_or(|p| p.adapter == AdapterKind::Openai)
+        {
+            if let Some(client) = namespace.client {
+                let project = [
+                    ("openai-organization", &client.openai_organization),
+                    ("openai-project", &client.openai_project),
+                ];
+                beta.extend(
+                    project
+                        .into_iter()
+                        .filter_map(|(header, value)| Some((header.to_string(), value.clone()?))),
+                );
+            }
+        }
+        beta.extend(rule_headers.iter().cloned());
+        for _ in 0..per_provider {
+            if sends > 0 {
+                let backoff = Duration::from_millis(retries.backoff_ms << (sends - 1).min(10));
+                if sends >= retries.max_attempts || started.elapsed() + backoff >= retry_budget {
+                    break 'chain;
+                }
+                if deadline.is_some_and(|d| Instant::now() + backoff >= d) {
+                    break 'chain;
+                }
+                tokio::time::sleep(backoff).await;
+                println!("🔁 Attempt {} via {}", sends + 1, target.name);
+            }
+
+            // The upstream gets what is left of the client's deadline
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let margin = Duration::from_millis(state.deadlines.margin_ms);
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining <= margin {
+                        deadline_passed = true;
+                        break 'chain;
+                    }
+                    Some(remaining - margin)
+                }
+                None => None,
+            };
+
+            // Clients with their own upstream keys only go where they have one,
+            // so their traffic is never billed to the shared account
+            let own_key = match namespace
+                .client
+                .filter(|c| caller_key.is_none() && !c.upstream_keys.is_empty())
+            {
+                Some(client) => match client.upstream_keys.get(target.key_name()) {
+                    Some(key) => Some(key.as_str()),
+                    None => {
+                        attempts.push(format!("{}:no_key", target.name));
+                        unsent = Some(ProxyError::Forbidden(format!(
+                            "Client {} has no upstream key for {}",
+                            client.name, target.name
+                        )));
+                        continue 'chain;
+                    }
+                },
+                None => None,
+            };
+
+            // Bursts queue for their slot; a provider whose queue is too long
+            // is skipped like one out of quota
+            if let Some(pacer) = target.pacer.filter(|_| !dry_run) {
+                if let Err(err) = pacer.wait(&target.name).await {
+                    attempts.push(format!("{}:queue_full", target.name));
+                    unsent = Some(err);
+                    continue 'chain;
+                }
+            }
+            // A provider out of quota is skipped in favour of the next fallback
+            if let Some((quota, reserve)) = target.quota.filter(|_| !dry_run) {
+                // Batch requests leave the interactive reserve to streaming ones
+                let share = if interactive { 1.0 } else { 1.0 - reserve };
+                match quota.admit(share, estimated_tokens).await {
+                    Ok(reservation) => reservations.push(reservation),
+                    Err(err) => {
+                        attempts.push(format!("{}:throttled", target.name));
+                        unsent = Some(err);
+                        continue 'chain;
+                    }
+                }
+            }
+            sends += 1;
+
+            // Translated adapters have endpoints of their own
+            let url = forwarded_json
+                .as_ref()
+                .filter(|_| path.ends_with("chat/completions"))
+                .and_then(|json| target.shaper?.endpoint(&target.api_base, json))
+                .unwrap_or_else(|| upstream_url(&target.api_base));
+            println!("📤 Proxying request to: {}", url);
+            // Pooled keys are only used in place of the provider's own key
+            let pooled = target
+                .key_pool
+                .filter(|_| caller_key.is_none() && own_key.is_none())
+                .map(|pool| pool.select());
+            let upstream_key = pooled.map_or(target.key, |pooled| &pooled.key);
+            let mut api_key = match caller_key.or(own_key) {
+                Some(key) => key.to_string(),
+                None => upstream_key.get(),
+            };
+            // Streamed bodies are not read, so they cannot be signed
+            let signed_body = Some(&body[..]).filter(|_| replayable && staged.is_none());
+            let sign = |key: String| {
+                let method = reqwest_method.as_str();
+                let url = url.clone();
+                async move { auth_headers(target.auth, &key, method, &url, signed_body).await }
+            };
+            let auth = match sign(api_key.clone()).await {
+                Ok(auth) => auth,
+                Err(err) => {
+                    attempts.push(format!("{}:auth", target.name));
+                    unsent = Some(err);
+                    continue 'chain;
+                }
+            };
+            if dry_run {
+                log.provider = target.name.clone();
+                let request = build_request(&url, &auth, timeout, &body, &beta)
+                    .build()
+                    .map_err(|e| ProxyError::InvalidRequest(e.to_string()))?;
+                let fallbacks = targets[index + 1..].iter().map(|t| t.name.as_str());
+                return Ok(dry_run_response(
+                    &request,
+                    &auth,
+                    &target.name,
+                    fallbacks.collect(),
+                ));
+            }
+            let sent = Instant::now();
+            let host = reqwest::Url::parse(&url)
+                .ok()
+                .and_then(|u| u.host_str().map(str::to_string))
+                .unwrap_or_default();
+            let connections = state.connection_stats.connections(&host);
+            let mut result = build_request(&url, &auth, timeout, &body, &beta)
+                .send()
+                .await;
+            let opened = state.connection_stats.connections(&host) > connections;
+            state
+                .connection_stats
+                .record_send(&host, opened, sent.elapsed());
+
+            // An OAuth2 token may have been revoked: fetch a new one and retry once
+            let unauthorized =
+                matches!(&result, Ok(r) if r.status() == reqwest::StatusCode::UNAUTHORIZED);
+            if let Some(upstream_auth) = target.auth.filter(|_| unauthorized && replayable) {
+                if upstream_auth.invalidate().await {
+                    if let Ok(auth) = sign(api_key.clone()).await {
+                        println!("🔐 Retrying with a new upstream access token");
+                        result = build_request(&url, &auth, timeout, &body, &beta)
+                            .send()
+                            .await;
+                    }
+                }
+            }
+
+            // The key may have been rotated: refresh it from the secrets backend and retry once
+            let unauthorized =
+                matches!(&result, Ok(r) if r.status() == reqwest::StatusCode::UNAUTHORIZED);
+            if unauthorized && caller_key.is_none() && own_key.is_none() && replayable {
+                if let Some(secrets) = &state.secrets {
+                    if let Some(new_key) = secrets
+                        .refresh_after_unauthorized(upstream_key, &api_key)
+                        .await
+                    {
+                        // A key that cannot be signed with is noted, and the 401 kept
+                        match sign(new_key.clone()).await {
+                            Ok(auth) => {
+                                println!("🔑 Retrying with refreshed upstream key");
+                                result = build_request(&url, &auth, timeout, &body, &beta)
+                                    .send()
+                                    .await;
+                                api_key = new_key;
+                            }
+                            Err(_) => attempts.push(format!("{}:auth", target.name)),
+                        }
+                    }
+                }
+            }
+
+            let retryable = match &result {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    if let Some(pooled) = pooled {
+                        pooled.observe(status, response.headers());
+                    }
+                    attempts.push(format!("{}:{}", target.name, status));
+                    retries.statuses.contains(&status)
+                }
+                Err(err) if err.is_timeout() && timeout.is_some() => {
+                    attempts.push(format!("{}:timeout", target.name));
+                    deadline_passed = true;
+                    false
+                }
+                Err(_) => {
+                    attempts.push(format!("{}:error", target.name));
+                    true
+                }
+            };
+            outcome = Some((
+                result,
+                url,
+                sent,
+                target,
+                api_key,
+                body.clone(),
+                beta.clone(),
+            ));
+            if !retryable {
+                break 'chain;
+            }
+        }
+    }
+    let deadline_error = || {
+        ProxyError::GatewayTimeout(format!(
+            "Request deadline of {} ms exceeded",
+            timeout_ms.unwrap_or(0)
+        ))
+    };
+    if deadline_passed {
+        return Err(deadline_error());
+    }
+    let Some((result, openai_url, sent, sent_target, api_key, sent_body, sent_beta)) = outcome
+    else {
+        return Err(unsent.expect("a skipped provider when nothing was sent"));
+    };
+    log.provider = sent_target.name.clone();
+    // The adapter and model of a translated request, for the chat completion
+    let translation = forwarded_json
+        .as_ref()
+        .filter(|_| path.ends_with("chat/completions"))
+        .and_then(|json| {
+            let shaper = sent_target.shaper.filter(|s| s.translates())?;
+            Some((shaper, json["model"].as_str()?.to_string()))
+        });
+    if let Some(mismatch) = upload_mismatch.as_ref().and_then(|m| m.get()) {
+        return Err(ProxyError::InvalidRequest(mismatch.clone()));
+    }
+    // A staged upload the upstream did not take is kept to be sent again
+    let kept_upload = staged.as_ref().and_then(|upload| {
+        let taken = matches!(&result, Ok(r) if r.status().is_success());
+        staging?.finish(upload, &log.client, taken)
+    });
+    let kept_upload = kept_upload.and_then(|id| HeaderValue::from_str(&id).ok());
+    let retries_possible = targets.len() > 1 || per_provider > 1;
+    let response = result.map_err(|e| {
+        if retries_possible {
+            ProxyError::RequestError(format!("{} (attempts: {})", e, attempts.join(", ")))
+        } else {
+            ProxyError::RequestError(e.to_string())
+        }
+    });
+    let response = match (response, &kept_upload) {
+        (Err(err), Some(id)) => {
+            let mut response = err.into_response();
+            response
+                .headers_mut()
+                .insert("x-proxy-upload-id", id.clone());
+            return Ok(response);
+        }
+        (response, _) => response?,
+    };
+
+    // Get response status
+    let status = StatusCode::from_u16(response.status().as_u16())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    // Check if this is a /models endpoint and response is 404
+    if path.ends_with("/models") && status == StatusCode::NOT_FOUND {
+        // println!("⚠️  /models endpoint returned 404, using configured models");
+        return Ok(return_configured_models(namespace.models));
+    }
+
+    // The upstream confirmed the expired entry is still current
+    if let (StatusCode::NOT_MODIFIED, Some(entry), Some(cache), Some(key)) =
+        (status, &caching.stale, &state.response_cache, &caching.key)
+    {
+        println!("💾 Cache revalidated for {}", path);
+        let entry = cache.put(key, caching.ttl, entry.clone());
+        let response = entry
+            .clone()
+            .into_response("revalidated", caching.if_none_match.as_deref());
+        if let Some(flight) = caching.flight {
+            flight.finish(entry);
+        }
+        return Ok(response);
+    }
+
+    // Get response headers
+    let mut response_headers = HeaderMap::new();
+    for (name, value) in response.headers().iter() {
+        if name != "content-length" && name != "transfer-encoding" {
+            // Convert reqwest headers to axum headers
+            if let Ok(header_name) = HeaderName::from_str(name.as_str()) {
+                if let Ok(header_value) = HeaderValue::from_str(value.to_str().unwrap_or_default())
+                {
+                    response_headers.insert(header_name, header_value);
+                }
+            }
+        }
+    }
+
+    if let Some(id) = kept_upload {
+        response_headers.insert("x-proxy-upload-id", id);
+    }
+    if retries_possible || state.metadata_headers {
+        if let Ok(value) = HeaderValue::from_str(&attempts.join(", ")) {
+            response_headers.insert("x-proxy-attempts", value);
+        }
+    }
+
+    let capture = capture.then(|| {
+        state.capture_document(CaptureRecord {
+            log,
+            request_headers: &headers,
+            request_body: &body_bytes,
+            forwarded_body: &modified_body,
+            upstream_url: &openai_url,
+            status,
+            response_headers: &response_headers,
+        })
+    });
+
+    // Event streams are relayed chunk by chunk and accounted once they end
+    let is_event_stream = response_headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/event-stream"))
+        .unwrap_or(false);
+    // Not every translated upstream streams server-sent events
+    let translated_stream = translation.is_some()
+        && forwarded_json
+            .as_ref()
+            .is_some_and(|json| json["stream"] == true);
+    if (is_event_stream || translated_stream) && status.is_success() {
+        println!("✅ Response status: {} (streaming)", status);
+        log.streaming = true;
+        let finished_state = state.clone();
+        let stream_log = log.clone();
+        let mut upstream: UpstreamStream = Box::pin(response.bytes_stream());
+        if let Some((shaper, model)) = &translation {
+            upstream = shaper.translate_stream(upstream, &log.request_id, model);
+            response_headers.insert(
+                "content-type",
+                HeaderValue::from_static("text/event-stream"),
+            );
+            response_headers.remove("content-length");
+        }
+        // A broken stream may be completed by asking again without streaming
+        let salvage = state.stream_salvage.as_ref().map(|salvage| {
+            let retry =
+                (salvage.retry && translation.is_none() && path.ends_with("chat/completions"))
+                    .then(|| {
+                        let margin = Duration::from_millis(state.deadlines.margin_ms);
+                        let timeout = deadline.map(|d| {
+                            d.saturating_duration_since(Instant::now())
+                                .saturating_sub(margin)
+                        });
+                        salvage_retry(
+                            state.clone(),
+                            build_request(&openai_url, &[], timeout, &Bytes::new(), &sent_beta),
+                            sent_target.config.map(|c| c.name.clone()),
+                            api_key.clone(),
+                            openai_url.clone(),
+                            sent_body.clone(),
+                        )
+                    });
+            Salvage {
+                error_chunk: salvage.error_chunk,
+                retry,
+            }
+        });
+        let mut pipeline = StreamPipeline::new(
+            upstream,
+            sent,
+            transforms,
+            capture.is_some(),
+            Box::new(move |stats| {
+                drop(permits);
+                // Streams without a usage chunk keep their estimate
+                if stats.prompt_tokens > 0 {
+                    for reservation in reservations {
+                        reservation.settle(stats.prompt_tokens + stats.completion_tokens);
+                    }
+                }
+                finished_state.finish_stream(&stream_log, stats, capture);
+            }),
+        );
+        if let Some(salvage) = salvage {
+            pipeline = pipeline.with_salvage(salvage);
+        }
+        if let Some(cap) = output_cap.filter(|_| path.ends_with("completions")) {
+            // The cap holds for each of the choices asked for
+            let choices = forwarded_json
+                .as_ref()
+                .and_then(|json| json["n"].as_u64())
+                .unwrap_or(1);
+            pipeline = pipeline.with_output_cap(cap * choices.max(1));
+        }
+        let model = log.model.as_deref().unwrap_or_default();
+        let selected =
+            |models: &[String]| models.is_empty() || state.model_matcher.matches_any(models, model);
+        if let Some(loops) = state
+            .loop_detection
+            .as_ref()
+            .filter(|l| selected(&l.models))
+        {
+            pipeline = pipeline.with_loop_detection(LoopDetector::new(
+                loops.ngram_words,
+                loops.max_repeats,
+                loops.window_words,
+            ));
+        }
+        let pacing = state
+            .stream_pacing
+            .as_ref()
+            .filter(|pacing| selected(&pacing.models));
+        let events: UpstreamStream = match pacing {
+            Some(pacing) => Box::pin(PacedStream::new(
+                pipeline,
+                pacing.max_tokens_per_second,
+                pacing.burst_tokens,
+            )),
+            None => Box::pin(pipeline),
+        };
+        let body = match &state.stream_resumption {
+            Some(buffers) => chaos::stream_body(
+                stream_faults,
+                buffers.record(resume::owner(&headers), events).await,
+            ),
+            None => chaos::stream_body(stream_faults, events),
+        };
+        let mut resp = Response::new(body);
+        *resp.status_mut() = status;
+        *resp.headers_mut() = response_headers;
+        return Ok(resp);
+    }
+
+    // Get response body
+    let length = response.content_length();
+    let mut response_body = state
+        .buffers
+        .read(response.bytes_stream(), length)
+        .await
+        .map_err(|e| {
+            if e.is_timeout() && deadline.is_some() {
+                deadline_error()
+            } else {
+                ProxyError::ResponseError(e.to_string())
+            }
+        })?;
+    log.buffered_bytes += response_body.len() as u64;
+    if let Some(fine_tunes) = &state.fine_tunes {
+        if method == Method::POST && path.ends_with("fine_tuning/jobs") && status.is_success() {
+            fine_tunes
+                .watch(
+                    state,
+                    &response_body,
+                    &openai_url,
+                    &sent_target.name,
+                    &api_key,
+                    &log.client,
+                )
+                .await;
+        }
+    }
+    if integrity.is_some() && status.is_success() {
+        if let Some(expected) =
+            integrity::expected(&response_headers).map_err(ProxyError::ResponseError)?
+        {
+            integrity::verify(&expected, &response_body).map_err(|header| {
+                println!("🧾 Upstream body does not match its {}", header);
+                ProxyError::ResponseError(format!(
+                    "Upstream response body does not match its {}",
+                    header
+                ))
+            })?;
+        }
+    }
+    if let Some((shaper, model)) = translation.as_ref().filter(|_| status.is_success()) {
+        if let Some(body) = shaper.translate_response(&response_body, &log.request_id, model) {
+            response_body = body;
+            response_headers.insert("content-type", HeaderValue::from_static("application/json"));
+        }
+    }
+
+    // An empty completion, or broken JSON in JSON mode, is requested once more
+    let retry_reason = state
+        .completion_retry
+        .as_ref()
+        .filter(|_| status.is_success() && path.ends_with("chat/completions"))
+        .filter(|_| state.flags.enabled(Flag::CompletionRetry))
+        .and_then(|policy| {
+            let reason = policy.check(forwarded_json.as_ref()?, &response_body)?;
+            Some((policy, reason))
+        });
+    // Sends a body once more to the upstream that answered, None if that fails
+    let request_id = log.request_id.clone();
+    let resend = |body: Bytes| {
+        let margin = Duration::from_millis(state.deadlines.margin_ms);
+        let timeout = deadline.map(|d| {
+            d.saturating_duration_since(Instant::now())
+                .saturating_sub(margin)
+        });
+        let (openai_url, sent_beta, translation) = (&openai_url, &sent_beta, &translation);
+        let (method, key, auth, request_id) = (
+            reqwest_method.as_str(),
+            &api_key,
+            sent_target.auth,
+            &request_id,
+        );
+        async move {
+            // Never sent without its credentials: that is a failed retry
+            let auth = auth_headers(auth, key, method, openai_url, Some(&body))
+                .await
+                .ok()?;
+            let retried = match build_request(openai_url, &auth, timeout, &body, sent_beta)
+                .send()
+                .await
+            {
+                Ok(retry) if retry.status().is_success() => retry.bytes().await.ok(),
+                _ => None,
+            };
+            match translation {
+                Some((shaper, model)) => {
+                    retried.and_then(|body| shaper.translate_response(&body, request_id, model))
+                }
+                None => retried,
+            }
+        }
+    };
+    if let Some((policy, reason)) = retry_reason {
+        println!("🔁 Retrying completion ({})", reason);
+        let mut retry_body = sent_body.clone();
+        if let Some(model) = &policy.model {
+            if let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&sent_body) {
+                json["model"] = model.clone().into();
+                retry_body = serde_json::to_vec(&json).unwrap_or_default().into();
+                log.model = Some(model.clone());
+                log.pricing = state
+                    .model_matcher
+                    .find_model(namespace.models, model)
+                    .and_then(|m| m.pricing.clone());
+            }
+        }
+        if let Some(retried) = resend(retry_body).await {
+            // The discarded completion was still spent
+            if let (Some(tenant), Some((prompt_tokens, completion_tokens))) =
+                (namespace.tenant, extract_usage(&response_body))
+            {
+                tenant.record_usage(log.model.as_deref(), prompt_tokens, completion_tokens);
+            }
+            response_body = retried;
+        }
+        response_headers.insert("x-proxy-retried", HeaderValue::from_static(reason));
+    }
+
+    // A completion off the requested pattern is asked for again, a few times
+    if let Some(regex) = emulated_regex.as_ref().filter(|_| status.is_success()) {
+        let mut retries = 0;
+        while !satisfies(regex, &response_body) {
+            if retries == state.constrained_output.max_retries {
+                return Err(ProxyError::ResponseError(format!(
+                    "Completion did not match response_regex after {} attempts",
+                    retries + 1
+                )));
+            }
+            retries += 1;
+            println!(
+                "🧩 Completion does not match response_regex, retrying ({})",
+                retries
+            );
+            if let Some(retried) = resend(sent_body.clone()).await {
+                if let (Some(tenant), Some((prompt_tokens, completion_tokens))) =
+                    (namespace.tenant, extract_usage(&response_body))
+                {
+                    tenant.record_usage(log.model.as_deref(), prompt_tokens, completion_tokens);
+                }
+                response_body = retried;
+            }
+        }
+        if retries > 0 {
+            response_headers.insert("x-proxy-constraint-retries", HeaderValue::from(retries));
+        }
+    }
+
+    drop(permits);
+    println!("✅ Response status: {}", status);
+
+    let usage = extract_usage(&response_body);
+    if let Some(tenant) = namespace.tenant {
+        let (prompt_tokens, completion_tokens) = usage.unwrap_or((0, 0));
+        tenant.record_usage(log.model.as_deref(), prompt_tokens, completion_tokens);
+    }
+    log.prompt_tokens = usage.map(|(prompt_tokens, _)| prompt_tokens);
+    log.completion_tokens = usage.map(|(_, completion_tokens)| completion_tokens);
+    if let Some((prompt_tokens, completion_tokens)) = usage {
+        for reservation in reservations {
+            reservation.settle(prompt_tokens + completion_tokens);
+        }
+        if let Some(ledger) = &state.usage_ledger {
+            ledger.record(&UsageRecord::new(log, prompt_tokens, completion_tokens));
+        }
+    }
+
+    let is_json = response_headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json"))
+        .unwrap_or(false);
+
+    // Vendor error bodies are rewritten into the OpenAI envelope
+    let translated = (state.translate_upstream_errors && !status.is_success())
+        .then(|| translate_upstream_error(status, &response_body))
+        .flatten();
+    let (status, response_body) = match translated {
+        Some((mapped, body)) => {
+            println!("🔀 Translated upstream error ({} -> {})", status, mapped);
+            response_headers.insert("content-type", HeaderValue::from_static("application/json"));
+            (mapped, axum::body::Bytes::from(body.to_string()))
+        }
+        None => (status, response_body),
+    };
+
+    let response_body = if path.ends_with("/models") && status.is_success() && is_json {
+        curate_model_list(state, namespace.models, &response_body).unwrap_or(response_body)
+    } else if is_json {
+        transform_json_body(&response_body, &mut transforms).unwrap_or(response_body)
+    } else {
+        response_body
+    };
+
+    if let Some(mut capture) = capture {
+        capture["response"]["body"] = capture_body(&response_body);
+        state.save_capture(&log.request_id, capture);
+    }
+
+    if let (Some(cache), Some(key)) = (&state.response_cache, &caching.key) {
+        if status.is_success() && is_json {
+            let content_type = response_headers
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("application/json");
+            let etag = response_headers.get("etag").and_then(|v| v.to_str().ok());
+            let entry = CachedResponse::new(
+                status.as_u16(),
+                content_type,
+                etag,
+                log.user.as_deref(),
+                response_body.clone(),
+            );
+            let entry = cache.put(key, caching.ttl, entry);
+            if let Some(flight) = caching.flight {
+                flight.finish(entry);
+            }
+        }
+        response_headers.insert("x-proxy-cache", HeaderValue::from_static("miss"));
+    }
+
+    if let Some(integrity) = integrity.filter(|_| status.is_success()) {
+        response_headers.extend(integrity.headers(&response_body));
+    }
+
+    // Build response
+    let mut resp = Response::new(Body::from(response_body));
+    *resp.status_mut() = status;
+    *resp.headers_mut() = response_headers;
+
+    Ok(resp)
+}
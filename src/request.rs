@@ -0,0 +1,228 @@
+// Request transforms: the stages a client's JSON body goes through before it
+// is sent upstream. Each stage rewrites the body or notes what the stages
+// after it, the upstream request and the response need; features plug in as
+// stages of their own, the way response rewrites are ResponseTransforms.
+
+use crate::constrained::Constrain;
+use crate::dictionaries::CheckDictionaries;
+use crate::embeddings::RequestDimensions;
+use crate::error::ProxyError;
+use crate::guardrails;
+use crate::models::{
+    CapOutput, InjectEngineOptions, InjectLogitBias, InjectPromptCaching, InjectStopSequences,
+    ModelInfo, RenameModel, StripReasoningTraces,
+};
+use crate::postprocess::AddPostProcessor;
+use crate::profiles::ApplyProfile;
+use crate::prompts::FingerprintPrompt;
+use crate::providers::conversation_fingerprint;
+use crate::proxy::RequestLog;
+use crate::routing::{RequestOverrides, RouteModel};
+use crate::rules::{ApplyRules, InjectThinking};
+use crate::state::AppState;
+use crate::tags::ForwardTags;
+use crate::templates::{RenderPromptTemplate, Served};
+use crate::tenant::Namespace;
+use crate::tokens::estimate_request_tokens;
+use crate::transcripts::KeepClientRequest;
+use crate::transform::ResponseTransform;
+use axum::http::HeaderMap;
+use regex::Regex;
+use serde_json::Value;
+
+// A JSON object body on its way upstream, with what the stages so far found
+// out about it
+pub(crate) struct OutgoingRequest<'a, 'l> {
+    pub(crate) state: &'a AppState,
+    pub(crate) namespace: &'a Namespace<'a>,
+    pub(crate) headers: &'a HeaderMap,
+    // The upstream path, without a tenant prefix
+    pub(crate) path: &'a str,
+    pub(crate) overrides: &'a RequestOverrides,
+    pub(crate) log: &'l mut RequestLog,
+    pub(crate) body: Value,
+    // Rewrites of the response, buffered or streamed, in the order added
+    pub(crate) transforms: Vec<Box<dyn ResponseTransform>>,
+    // The model the request goes to, once routing and rules settled it
+    pub(crate) model: Option<String>,
+    // Its catalog entry, its own or a wildcard entry's
+    pub(crate) model_config: Option<&'a ModelInfo>,
+    // The prompt template version rendered into the body
+    pub(crate) served: Option<Served>,
+    // Conversation fingerprint for sticky replicas
+    pub(crate) fingerprint: Option<Vec<u8>>,
+    // Streaming requests are interactive unless x-proxy-priority says otherwise
+    pub(crate) interactive: bool,
+    pub(crate) estimated_tokens: u64,
+    // The client's own request, for the transcript
+    pub(crate) transcript_request: Option<Value>,
+    // An Anthropic-style thinking object sent by the client, converted for
+    // OpenAI-compatible targets
+    pub(crate) client_thinking: bool,
+    // Upstream headers added by [[rules]]
+    pub(crate) rule_headers: Vec<(String, String)>,
+    // Prompt caching flag for the anthropic-beta header
+    pub(crate) anthropic_beta: Option<String>,
+    // Hard cap on generated tokens, the lower of the client's and the model's
+    pub(crate) output_cap: Option<u64>,
+    // A response_regex the upstream cannot enforce, checked on the response
+    pub(crate) emulated_regex: Option<Regex>,
+}
+
+// One step of the way from the client's body to the upstream's
+pub(crate) trait RequestTransform: Sync {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError>;
+}
+
+// In order: the client's own prompt and parameters, the model it goes to,
+// then that model's settings. Response transforms run in the order these
+// stages add them.
+const STAGES: &[&dyn RequestTransform] = &[
+    &CheckDictionaries,
+    &KeepClientRequest,
+    // Before the profile, which adds its prompt only to requests without one
+    &RenderPromptTemplate,
+    &ApplyProfile,
+    &MeasureRequest,
+    &RouteModel,
+    &ApplyRules,
+    &ReadClientFields,
+    &FingerprintPrompt,
+    &ForwardTags,
+    &ResolveModel,
+    &InjectThinking,
+    &InjectStopSequences,
+    &InjectLogitBias,
+    &InjectEngineOptions,
+    &StripReasoningTraces,
+    &RequestDimensions,
+    &AddPostProcessor,
+    &InjectPromptCaching,
+    &RenameModel,
+    &CapOutput,
+    &Constrain,
+];
+
+impl<'a, 'l> OutgoingRequest<'a, 'l> {
+    pub(crate) fn new(
+        state: &'a AppState,
+        namespace: &'a Namespace<'a>,
+        headers: &'a HeaderMap,
+        path: &'a str,
+        overrides: &'a RequestOverrides,
+        log: &'l mut RequestLog,
+        body: Value,
+    ) -> Self {
+        Self {
+            state,
+            namespace,
+            headers,
+            path,
+            overrides,
+            log,
+            body,
+            transforms: Vec::new(),
+            model: None,
+            model_config: None,
+            served: None,
+            fingerprint: None,
+            interactive: false,
+            estimated_tokens: 0,
+            transcript_request: None,
+            client_thinking: false,
+            rule_headers: Vec::new(),
+            anthropic_beta: None,
+            output_cap: namespace.client.and_then(|c| c.output_token_cap),
+            emulated_regex: None,
+        }
+    }
+
+    // Runs the guardrails, then every stage
+    pub(crate) async fn transform(&mut self) -> Result<(), ProxyError> {
+        guardrails::check_request(self).await?;
+        for stage in STAGES {
+            stage.apply(self)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_allowed(&self, model: &str) -> Result<(), ProxyError> {
+        match self.namespace.client {
+            Some(client)
+                if client
+                    .allowed_models
+                    .as_ref()
+                    .is_some_and(|a| !self.state.model_matcher.matches_any(a, model)) =>
+            {
+                Err(ProxyError::Forbidden(format!(
+                    "Model {} is not allowed for client {}",
+                    model, client.name
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Whether a stage could refuse the body on this path: the client's models
+// or budgets, [[rules]], dictionaries or guardrails. Passthrough bodies skip
+// the stages only when none could.
+pub(crate) fn enforces_policy(state: &AppState, namespace: &Namespace<'_>, path: &str) -> bool {
+    namespace.client.is_some_and(|c| c.allowed_models.is_some())
+        || namespace
+            .tenant
+            .is_some_and(|t| !t.model_budgets.is_empty())
+        || !state.rules.is_empty()
+        || !state.dictionaries.is_empty()
+        || state.guardrails.applies(path)
+}
+
+// Fingerprint, priority and token estimate of the body the client shaped
+struct MeasureRequest;
+
+impl RequestTransform for MeasureRequest {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        request.fingerprint = conversation_fingerprint(&request.body);
+        request.interactive = request.body["stream"].as_bool() == Some(true);
+        request.estimated_tokens = estimate_request_tokens(&request.body);
+        Ok(())
+    }
+}
+
+// The end user and thinking object, before tags may fill in the user
+struct ReadClientFields;
+
+impl RequestTransform for ReadClientFields {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let body = &request.body;
+        request.client_thinking = body.get("thinking").is_some_and(|t| t.is_object());
+        request.log.user = body
+            .get("user")
+            .and_then(|u| u.as_str())
+            .map(str::to_string);
+        Ok(())
+    }
+}
+
+// Looks up the settled model, after its budget is checked
+struct ResolveModel;
+
+impl RequestTransform for ResolveModel {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(model) = &request.model else {
+            return Ok(());
+        };
+        let state = request.state;
+        request.log.model = Some(model.clone());
+        if let Some(tenant) = request.namespace.tenant {
+            tenant.check_model_budget(&state.model_matcher, model)?;
+        }
+        request.model_config = state
+            .model_matcher
+            .find_model(request.namespace.models, model);
+        if let Some(model_config) = request.model_config {
+            request.log.pricing = model_config.pricing.clone();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+// Resumable streams: events are numbered with SSE ids and kept for a while, so
+// a client that lost its connection reconnects with Last-Event-ID and gets the
+// rest instead of a new generation. With [storage] each stream is also a
+// session log there, so the client may reconnect to another replica.
+
+use crate::config::StreamResumptionConfig;
+use crate::secrets::hex_encode;
+use crate::storage::Store;
+use crate::tenant::client_key;
+use crate::transform::{sse_event_end, UpstreamStream};
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+// How long a shared stream still running is kept after its latest event
+const SHARED_IDLE: Duration = Duration::from_secs(300);
+
+// How often a stream shared by another replica is checked for new events
+const SHARED_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub(crate) struct StreamBuffers {
+    window: Duration,
+    streams: Mutex<HashMap<String, Arc<BufferedStream>>>,
+    store: Option<Arc<Store>>,
+}
+
+struct BufferedStream {
+    // Who may resume it: a hash of the client key that started it
+    owner: String,
+    events: Mutex<Events>,
+    // The number of events so far, for followers waiting on the next one
+    updates: watch::Sender<usize>,
+}
+
+#[derive(Default)]
+struct Events {
+    list: Vec<Bytes>,
+    // When the upstream stream ended, and whether it failed
+    ended: Option<Instant>,
+    failed: bool,
+}
+
+// A Last-Event-ID of "{stream_id}:{n}"
+pub(crate) fn parse_event_id(value: &str) -> Option<(&str, usize)> {
+    let (stream_id, n) = value.trim().rsplit_once(':')?;
+    Some((stream_id, n.parse().ok()?))
+}
+
+// Streams are numbered with random ids, not request ids: those are
+// sequential, and a caller without a key shares its owner with every other
+// such caller
+fn new_stream_id() -> String {
+    let mut id = [0; 16];
+    openssl::rand::rand_bytes(&mut id).expect("random stream id");
+    hex_encode(&id)
+}
+
+pub(crate) fn owner(headers: &HeaderMap) -> String {
+    hex_encode(&Sha256::digest(client_key(headers).unwrap_or_default()))
+}
+
+// The session log of a shared stream: the owner, the numbered events, then
+// "ended" or "failed". Events all begin with "id: ", so neither is mistaken
+// for one.
+fn session_key(store: &Store, stream_id: &str) -> String {
+    store.key(&format!("resume:{}", stream_id))
+}
+
+enum Shared {
+    Event(Bytes),
+    End { failed: bool },
+}
+
+impl StreamBuffers {
+    pub(crate) fn new(config: StreamResumptionConfig, store: Option<Arc<Store>>) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            streams: Mutex::new(HashMap::new()),
+            store,
+        }
+    }
+
+    // Streams ended longer ago than the window are dropped
+    fn prune(&self, streams: &mut HashMap<String, Arc<BufferedStream>>) {
+        streams.retain(|_, stream| {
+            let events = stream.events.lock().unwrap();
+            events
+                .ended
+                .is_none_or(|ended| ended.elapsed() < self.window)
+        });
+    }
+
+    // Reads `inner` to the end whether or not the client stays connected, and
+    // returns the client's view of it
+    pub(crate) async fn record(
+        &self,
+        owner: String,
+        mut inner: UpstreamStream,
+    ) -> impl Stream<Item = std::io::Result<Bytes>> + Send {
+        let stream_id = new_stream_id();
+        let stream = Arc::new(BufferedStream {
+            owner,
+            events: Mutex::new(Events::default()),
+            updates: watch::channel(0).0,
+        });
+        {
+            let mut streams = self.streams.lock().unwrap();
+            self.prune(&mut streams);
+            streams.insert(stream_id.clone(), stream.clone());
+        }
+
+        // The owner is written first, so the stream is known to the other
+        // replicas before the client sees it; the events follow in the
+        // background, so a slow storage does not hold the stream up
+        let (shared, queue) = mpsc::unbounded_channel();
+        if let Some(store) = &self.store {
+            let log = session_key(store, &stream_id);
+            let running = Some((self.window + SHARED_IDLE).as_secs());
+            let owner = stream.owner.clone().into_bytes();
+            match store.backend.append(&log, owner, running).await {
+                Ok(()) => {
+                    tokio::spawn(share(store.clone(), log, queue, self.window));
+                }
+                Err(err) => eprintln!("⚠️  Failed to share stream {}: {}", stream_id, err),
+            }
+        }
+
+        let recorded = stream.clone();
+        tokio::spawn(async move {
+            let mut pending = Vec::new();
+            let mut failed = false;
+            while let Some(chunk) = inner.next().await {
+                let Ok(chunk) = chunk else {
+                    failed = true;
+                    break;
+                };
+                pending.extend_from_slice(&chunk);
+                while let Some((end, separator)) = sse_event_end(&pending) {
+                    let event: Vec<u8> = pending.drain(..end + separator).collect();
+                    let _ = shared.send(Shared::Event(recorded.push(&stream_id, &event)));
+                }
+            }
+            if !pending.is_empty() {
+                let _ = shared.send(Shared::Event(recorded.push(&stream_id, &pending)));
+            }
+            let _ = shared.send(Shared::End { failed });
+            let mut events = recorded.events.lock().unwrap();
+            events.ended = Some(Instant::now());
+            events.failed = failed;
+            let count = events.list.len();
+            drop(events);
+            recorded.updates.send_replace(count);
+        });
+        follow(stream, 0)
+    }
+
+    // The events after `last_event`, then the rest as it arrives, from this
+    // replica or the storage. None when the stream is unknown, expired or
+    // started with another client key.
+    pub(crate) async fn resume(
+        &self,
+        stream_id: &str,
+        last_event: usize,
+        owner: &str,
+    ) -> Option<BoxStream<'static, std::io::Result<Bytes>>> {
+        let local = {
+            let mut streams = self.streams.lock().unwrap();
+            self.prune(&mut streams);
+            streams.get(stream_id).cloned()
+        };
+        if let Some(stream) = local {
+            return (stream.owner == owner).then(|| follow(stream, last_event + 1).boxed());
+        }
+        let store = self.store.clone()?;
+        let log = session_key(&store, stream_id);
+        let entries = match store.backend.range(&log, 0).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                eprintln!("⚠️  Failed to load shared stream {}: {}", stream_id, err);
+                return None;
+            }
+        };
+        if entries.first()? != owner.as_bytes() {
+            return None;
+        }
+        Some(follow_shared(store, log, entries, last_event + 1).boxed())
+    }
+}
+
+// Writes a stream's events to its session log until the stream ends or the
+// storage fails
+async fn share(
+    store: Arc<Store>,
+    log: String,
+    mut queue: mpsc::UnboundedReceiver<Shared>,
+    window: Duration,
+) {
+    let running = Some((window + SHARED_IDLE).as_secs());
+    let mut written = Ok(());
+    while let (Ok(()), Some(next)) = (&written, queue.recv().await) {
+        written = match next {
+            Shared::Event(event) => store.backend.append(&log, event.to_vec(), running).await,
+            Shared::End { failed } => {
+                let end = if failed { "failed" } else { "ended" };
+                let ended = Some(window.as_secs().max(1));
+                store.backend.append(&log, end.into(), ended).await
+            }
+        };
+    }
+    if let Err(err) = written {
+        eprintln!("⚠️  Failed to share stream {}: {}", log, err);
+    }
+}
+
+impl BufferedStream {
+    fn push(&self, stream_id: &str, event: &[u8]) -> Bytes {
+        let mut events = self.events.lock().unwrap();
+        let mut numbered = format!("id: {}:{}\n", stream_id, events.list.len()).into_bytes();
+        numbered.extend_from_slice(event);
+        let numbered = Bytes::from(numbered);
+        events.list.push(numbered.clone());
+        let count = events.list.len();
+        drop(events);
+        self.updates.send_replace(count);
+        numbered
+    }
+}
+
+enum Step {
+    Event(Bytes),
+    Failed,
+    Ended,
+    Wait,
+}
+
+fn follow(
+    stream: Arc<BufferedStream>,
+    from: usize,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send {
+    let updates = stream.updates.subscribe();
+    // None once the end has been passed on
+    futures_util::stream::unfold(
+        (stream, updates, Some(from)),
+        |(stream, mut updates, next)| async move {
+            let next = next?;
+            loop {
+                let step = {
+                    let events = stream.events.lock().unwrap();
+                    match events.list.get(next) {
+                        Some(event) => Step::Event(event.clone()),
+                        None if events.failed => Step::Failed,
+                        None if events.ended.is_some() => Step::Ended,
+                        None => Step::Wait,
+                    }
+                };
+                match step {
+                    Step::Event(event) => {
+                        return Some((Ok(event), (stream, updates, Some(next + 1))))
+                    }
+                    Step::Failed => {
+                        let err = std::io::Error::other("upstream stream failed");
+                        return Some((Err(err), (stream, updates, None)));
+                    }
+                    Step::Ended => return None,
+                    // The sender lives as long as the stream
+                    Step::Wait => {
+                        let _ = updates.changed().await;
+                    }
+                }
+            }
+        },
+    )
+}
+
+// Follows a stream another replica is recording, from its session log.
+// `entries` is the log so far; the events start after the owner.
+fn follow_shared(
+    store: Arc<Store>,
+    log: String,
+    entries: Vec<Vec<u8>>,
+    from: usize,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send {
+    // The log may lag behind what the client was sent
+    let read = entries.len().max(1 + from);
+    let pending: VecDeque<Vec<u8>> = entries.into_iter().skip(1 + from).collect();
+    // None once the end has been passed on
+    futures_util::stream::unfold(Some((pending, read, Instant::now())), move |state| {
+        let store = store.clone();
+        let log = log.clone();
+        async move {
+            let (mut pending, mut read, mut updated) = state?;
+            loop {
+                if let Some(entry) = pending.pop_front() {
+                    if entry.starts_with(b"id: ") {
+                        return Some((Ok(entry.into()), Some((pending, read, updated))));
+                    }
+                    if entry == b"failed" {
+                        let err = std::io::Error::other("upstream stream failed");
+                        return Some((Err(err), None));
+                    }
+                    return None;
+                }
+                // The recording replica went away without ending it
+                if updated.elapsed() > SHARED_IDLE {
+                    let err = std::io::Error::other("shared stream abandoned");
+                    return Some((Err(err), None));
+                }
+                tokio::time::sleep(SHARED_POLL_INTERVAL).await;
+                match store.backend.range(&log, read).await {
+                    Ok(entries) if !entries.is_empty() => {
+                        read += entries.len();
+                        updated = Instant::now();
+                        pending.extend(entries);
+                    }
+                    Ok(_) => {}
+                    Err(err) => return Some((Err(std::io::Error::other(err)), None)),
+                }
+            }
+        }
+    })
+}
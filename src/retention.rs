@@ -0,0 +1,245 @@
+// Retention of stored data: a background job drops bodies and records older
+// than configured, and /admin/data-subjects/{user} erases everything stored
+// for an end user named in requests' user field, in every store that keeps
+// it: captures, the usage ledger, feedback, transcripts, the response cache
+// with its shared entries, and the agent job journal.
+
+use crate::error::ProxyError;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::time::{parse_utc, unix_now};
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Response,
+};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// Rewrites a JSONL file without the lines `remove` matches, holding the lock
+// of its append handle, which is reopened on the new file
+pub(crate) fn remove_lines(
+    path: &str,
+    file: &Mutex<File>,
+    remove: impl Fn(&Value) -> bool,
+) -> std::io::Result<usize> {
+    let mut file = file.lock().unwrap();
+    let content = fs::read_to_string(path)?;
+    let mut kept = String::with_capacity(content.len());
+    let mut removed = 0;
+    for line in content.lines() {
+        if serde_json::from_str::<Value>(line).is_ok_and(|value| remove(&value)) {
+            removed += 1;
+        } else {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if removed > 0 {
+        let temporary = format!("{}.tmp", path);
+        fs::write(&temporary, kept)?;
+        fs::rename(&temporary, path)?;
+        *file = OpenOptions::new().create(true).append(true).open(path)?;
+    }
+    Ok(removed)
+}
+
+// Deletes the capture files `remove` matches, given their contents and age
+fn remove_captures(
+    directory: &str,
+    remove: impl Fn(&Value, SystemTime) -> bool,
+) -> std::io::Result<usize> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let modified = fs::metadata(&path)?.modified()?;
+        let capture = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or(Value::Null);
+        if remove(&capture, modified) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[derive(Default)]
+struct Removed {
+    captures: usize,
+    usage_records: usize,
+    feedback: usize,
+    transcripts: usize,
+    // Keys of the cached responses, as an entry may be in several tiers
+    cached_responses: HashSet<String>,
+    agent_jobs: usize,
+}
+
+impl Removed {
+    fn total(&self) -> usize {
+        self.captures
+            + self.usage_records
+            + self.feedback
+            + self.transcripts
+            + self.cached_responses.len()
+            + self.agent_jobs
+    }
+}
+
+// Applies the retention policy once. Shared cache entries need no pass: their
+// TTL is capped at the retention of bodies.
+fn purge(state: &AppState) -> std::io::Result<Removed> {
+    let Some(config) = &state.retention else {
+        return Ok(Removed::default());
+    };
+    let mut removed = Removed::default();
+    if config.bodies_days > 0 {
+        let cutoff = SystemTime::now() - Duration::from_secs(config.bodies_days * 86400);
+        let directory = state.capture.read().unwrap().directory.clone();
+        removed.captures = remove_captures(&directory, |_, modified| modified < cutoff)?;
+        if let Some(cache) = &state.response_cache {
+            removed.cached_responses = cache.purge()?;
+        }
+        let cutoff = unix_now().saturating_sub(config.bodies_days * 86400);
+        if let Some(jobs) = &state.agent_jobs {
+            removed.agent_jobs = jobs.remove(|job| job.settled_before(cutoff))?;
+        }
+    }
+    if config.records_days > 0 {
+        let cutoff = unix_now().saturating_sub(config.records_days * 86400);
+        let expired = |value: &Value| {
+            value["time"]
+                .as_str()
+                .and_then(parse_utc)
+                .is_some_and(|time| time < cutoff)
+        };
+        if let Some(ledger) = &state.usage_ledger {
+            removed.usage_records = ledger.remove(expired)?;
+        }
+        if let Some(feedback) = &state.feedback {
+            removed.feedback = feedback.remove(expired)?;
+        }
+        if let Some(transcripts) = &state.transcripts {
+            removed.transcripts = transcripts.remove(expired)?;
+        }
+    }
+    Ok(removed)
+}
+
+// Runs the retention policy at startup and every interval_secs
+pub(crate) fn start(state: &Arc<AppState>) {
+    let Some(config) = &state.retention else {
+        return;
+    };
+    let period = Duration::from_secs(config.interval_secs.max(1));
+    let state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let purging = state.clone();
+            match tokio::task::spawn_blocking(move || purge(&purging)).await {
+                Ok(Ok(removed)) if removed.total() > 0 => println!(
+                    "🧹 Retention removed {} captures, {} cached responses, {} agent jobs, \
+                     {} usage records, {} feedback entries and {} transcripts",
+                    removed.captures,
+                    removed.cached_responses.len(),
+                    removed.agent_jobs,
+                    removed.usage_records,
+                    removed.feedback,
+                    removed.transcripts
+                ),
+                Ok(Err(err)) => eprintln!("⚠️  Failed to apply retention policy: {}", err),
+                _ => {}
+            }
+            tokio::time::sleep(period).await;
+        }
+    });
+}
+
+// Everything stored locally for requests with the given user field
+fn erase(state: &AppState, user: &str) -> std::io::Result<Removed> {
+    let mut removed = Removed::default();
+    // The rated requests of the user, for feedback stored without details
+    let request_ids = RefCell::new(HashSet::new());
+    // Captures written before the user was recorded still have it in the body
+    let owned = |value: &Value| {
+        let matched = value["user"] == user || value["request"]["body"]["user"] == user;
+        if matched {
+            if let Some(id) = value["request_id"].as_str() {
+                request_ids.borrow_mut().insert(id.to_string());
+            }
+        }
+        matched
+    };
+
+    let directory = state.capture.read().unwrap().directory.clone();
+    removed.captures = remove_captures(&directory, |capture, _| owned(capture))?;
+    if let Some(ledger) = &state.usage_ledger {
+        removed.usage_records = ledger.remove(owned)?;
+    }
+    if let Some(feedback) = &state.feedback {
+        let request_ids = request_ids.borrow();
+        removed.feedback = feedback.remove(|entry| {
+            entry["request"]["user"] == user
+                || entry["request_id"]
+                    .as_str()
+                    .is_some_and(|id| request_ids.contains(id))
+        })?;
+    }
+    if let Some(transcripts) = &state.transcripts {
+        removed.transcripts = transcripts.remove(|example| example["user"] == user)?;
+    }
+    if let Some(cache) = &state.response_cache {
+        removed.cached_responses = cache.remove(|entry| entry.user.as_deref() == Some(user))?;
+    }
+    if let Some(jobs) = &state.agent_jobs {
+        removed.agent_jobs = jobs.remove(|job| job.user() == Some(user))?;
+    }
+    Ok(removed)
+}
+
+// DELETE /admin/data-subjects/{user}
+pub(crate) async fn erase_data_subject_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let erasing = state.clone();
+    let lookup = user.clone();
+    let mut removed = tokio::task::spawn_blocking(move || erase(&erasing, &lookup))
+        .await
+        .map_err(|e| ProxyError::BodyReadError(e.to_string()))?
+        .map_err(|e| ProxyError::BodyReadError(format!("Failed to erase stored data: {}", e)))?;
+    if let Some(cache) = &state.response_cache {
+        let shared = cache.remove_shared(&user).await.map_err(|err| {
+            ProxyError::Unavailable(format!("Failed to erase shared cache entries: {}", err))
+        })?;
+        removed.cached_responses.extend(shared);
+    }
+    println!(
+        "🧹 Erased {} stored items of a data subject",
+        removed.total()
+    );
+    Ok(json_response(&serde_json::json!({
+        "user": user,
+        "captures": removed.captures,
+        "usage_records": removed.usage_records,
+        "feedback": removed.feedback,
+        "transcripts": removed.transcripts,
+        "cached_responses": removed.cached_responses.len(),
+        "agent_jobs": removed.agent_jobs,
+    })))
+}
@@ -0,0 +1,332 @@
+// HTTP routes and the non-proxy handlers
+
+use crate::agents::{agent_handler, tenant_agent_handler};
+use crate::audit::{audit_admin_changes, audit_handler};
+use crate::chaos::{get_chaos_handler, update_chaos_handler};
+use crate::dictionaries;
+use crate::error::{panic_message, ProxyError};
+use crate::extract::extract_field;
+use crate::feedback::feedback_handler;
+use crate::fine_tunes::list_fine_tunes_handler;
+use crate::flags::{get_flags_handler, update_flags_handler};
+use crate::inspector::inspect_handler;
+use crate::jobs::{cancel_job_handler, get_job_handler, list_jobs_handler};
+use crate::key_pool;
+use crate::leader::cluster_handler;
+use crate::localization::localize_errors;
+use crate::modes::{get_modes_handler, update_modes_handler};
+use crate::pacing;
+use crate::probes::list_probes_handler;
+use crate::proxy::proxy_handler;
+use crate::retention::erase_data_subject_handler;
+use crate::rules;
+use crate::state::AppState;
+use crate::status::status_handler;
+use crate::templates::{list_prompts_handler, rollback_prompt_handler, update_prompt_handler};
+use crate::tenant::SignedRequest;
+use crate::usage::{export_usage_handler, usage_rollup_handler};
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::{header::HeaderValue, HeaderMap, HeaderName},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Router,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::cors::CorsLayer;
+
+pub(crate) fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(root))
+        .route("/v3/*path", post(proxy_handler))
+        .route("/v3/*path", get(proxy_handler))
+        .route("/v3/*path", delete(proxy_handler))
+        .route("/t/:tenant/*path", post(proxy_handler))
+        .route("/t/:tenant/*path", get(proxy_handler))
+        .route("/t/:tenant/*path", delete(proxy_handler))
+        .route("/agents/chat/completions", post(agent_handler))
+        .route(
+            "/t/:tenant/agents/chat/completions",
+            post(tenant_agent_handler),
+        )
+        .route("/usage", get(usage_handler))
+        .route("/v1/feedback", post(feedback_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/status", get(status_handler))
+        .route("/admin/capture", get(get_capture_handler))
+        .route("/admin/capture", put(update_capture_handler))
+        .route("/admin/modes", get(get_modes_handler))
+        .route("/admin/modes", put(update_modes_handler))
+        .route("/admin/chaos", get(get_chaos_handler))
+        .route("/admin/chaos", put(update_chaos_handler))
+        .route("/admin/flags", get(get_flags_handler))
+        .route("/admin/flags", put(update_flags_handler))
+        .route("/admin/upstream", get(get_upstream_overrides_handler))
+        .route("/admin/upstream", put(set_upstream_override_handler))
+        .route("/admin/upstream", delete(remove_upstream_override_handler))
+        .route("/admin/inspect", get(inspect_handler))
+        .route("/admin/usage/export", get(export_usage_handler))
+        .route("/admin/usage/rollup", get(usage_rollup_handler))
+        .route(
+            "/admin/data-subjects/:user",
+            delete(erase_data_subject_handler),
+        )
+        .route("/admin/jobs", get(list_jobs_handler))
+        .route("/admin/jobs/:id", get(get_job_handler))
+        .route("/admin/jobs/:id", delete(cancel_job_handler))
+        .route("/admin/cluster", get(cluster_handler))
+        .route("/admin/audit", get(audit_handler))
+        .route("/admin/fine_tunes", get(list_fine_tunes_handler))
+        .route("/admin/probes", get(list_probes_handler))
+        .route("/admin/prompts", get(list_prompts_handler))
+        .route("/admin/prompts/:name", put(update_prompt_handler))
+        .route(
+            "/admin/prompts/:name/rollback",
+            post(rollback_prompt_handler),
+        )
+        .fallback(not_found)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            audit_admin_changes,
+        ))
+        .layer(CatchPanicLayer::custom(panic_handler(state.clone())))
+        .layer(middleware::from_fn(extract_field))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            localize_errors,
+        ))
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+// Last-resort handler: a panic while handling a request is logged, counted and
+// answered with a 500 instead of dropping the connection
+fn panic_handler(
+    state: Arc<AppState>,
+) -> impl Fn(Box<dyn std::any::Any + Send + 'static>) -> Response + Clone {
+    move |payload| {
+        println!(
+            "🚨 Panic while handling request: {}",
+            panic_message(&*payload)
+        );
+        state.metrics.record_panic();
+        ProxyError::Internal("Internal proxy error".to_string()).into_response()
+    }
+}
+
+pub(crate) async fn not_found(req: Request) -> ProxyError {
+    ProxyError::NotFound(format!(
+        "No route for {} {}",
+        req.method(),
+        req.uri().path()
+    ))
+}
+
+pub(crate) async fn root() -> &'static str {
+    "OpenAI API Proxy Server is running!"
+}
+
+// Prometheus scrape endpoint
+pub(crate) async fn metrics_handler(State(state): State<Arc<AppState>>) -> Response {
+    let metrics = state.metrics.render()
+        + &state.flags.render()
+        + &state.buffers.render()
+        + &state.connection_stats.render()
+        + &state.chaos.render()
+        + &rules::render(&state.rules)
+        + &state.prompt_templates.render()
+        + &key_pool::render(state.providers.iter().filter_map(|p| {
+            p.key_pool
+                .as_ref()
+                .map(|pool| (p.config.name.as_str(), pool))
+        }))
+        + &pacing::render(state.providers.iter().filter_map(|p| {
+            p.pacer
+                .as_ref()
+                .map(|pacer| (p.config.name.as_str(), pacer))
+        }))
+        + &state
+            .probes
+            .as_ref()
+            .map(|p| p.render())
+            .unwrap_or_default()
+        + &dictionaries::render(
+            state
+                .dictionaries
+                .iter()
+                .chain(state.tenants.iter().flat_map(|t| t.dictionaries.iter())),
+        );
+    let mut resp = Response::new(Body::from(metrics));
+    resp.headers_mut().insert(
+        "content-type",
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    resp
+}
+
+// Usage accounting for the tenant owning the presented client key
+pub(crate) async fn usage_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    req: Request,
+) -> Result<Response, ProxyError> {
+    let signed = SignedRequest {
+        method: req.method().as_str(),
+        target: req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/usage"),
+        body: &[],
+    };
+    let namespace = state.resolve_namespace(None, &headers, &signed).await?;
+    let tenant = namespace
+        .tenant
+        .ok_or_else(|| ProxyError::Forbidden("No tenants are configured".to_string()))?;
+
+    let usage = tenant.current_usage();
+    let body = serde_json::json!({
+        "tenant": tenant.name,
+        "budget_period": tenant.budget_period,
+        "token_budget": tenant.token_budget,
+        "usage": usage,
+    });
+
+    Ok(json_response(&body))
+}
+
+pub(crate) fn json_response(value: &serde_json::Value) -> Response {
+    let mut response = Response::new(Body::from(value.to_string()));
+    response.headers_mut().insert(
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+pub(crate) async fn get_capture_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let capture = state.capture.read().unwrap().clone();
+    Ok(json_response(&serde_json::json!(capture)))
+}
+
+// Partial update, e.g. {"enabled": true, "sample_percent": 5, "clients": ["chatbot"]}
+pub(crate) async fn update_capture_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let update: serde_json::Value = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid JSON: {}", e)))?;
+
+    let mut capture = state.capture.write().unwrap();
+    let mut current = serde_json::json!(*capture);
+    if let (Some(current), Some(update)) = (current.as_object_mut(), update.as_object()) {
+        // The capture directory can only be set in the config file
+        for (key, value) in update.iter().filter(|(key, _)| *key != "directory") {
+            current.insert(key.clone(), value.clone());
+        }
+    }
+    *capture = serde_json::from_value(current)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid capture settings: {}", e)))?;
+
+    println!(
+        "🔍 Debug capture {} ({}% sampled, {} clients)",
+        if capture.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        capture.sample_percent,
+        capture.clients.len()
+    );
+    Ok(json_response(&serde_json::json!(*capture)))
+}
+
+pub(crate) async fn get_upstream_overrides_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    Ok(json_response(&serde_json::json!({
+        "overrides": state.upstream_overrides.active(),
+    })))
+}
+
+// Overrides are for drills and emergencies, not a config change: a day at most
+const MAX_OVERRIDE_TTL_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub(crate) struct UpstreamOverrideRequest {
+    api_base: String,
+    // Omit to redirect every model
+    model: Option<String>,
+    ttl_secs: u64,
+    reason: Option<String>,
+}
+
+// e.g. {"api_base": "https://backup.example.com", "model": "gpt-4o", "ttl_secs": 900}
+pub(crate) async fn set_upstream_override_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let request: UpstreamOverrideRequest = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid override: {}", e)))?;
+    if reqwest::Url::parse(&request.api_base).is_err() {
+        return Err(ProxyError::InvalidRequest(format!(
+            "Invalid api_base: {}",
+            request.api_base
+        )));
+    }
+    if request.ttl_secs == 0 || request.ttl_secs > MAX_OVERRIDE_TTL_SECS {
+        return Err(ProxyError::InvalidRequest(format!(
+            "ttl_secs must be between 1 and {}",
+            MAX_OVERRIDE_TTL_SECS
+        )));
+    }
+
+    let entry = state.upstream_overrides.set(
+        request.api_base,
+        request.model,
+        request.reason,
+        request.ttl_secs,
+    );
+    println!(
+        "🔀 Upstream for {} overridden to {} until {}",
+        entry.model.as_deref().unwrap_or("all models"),
+        entry.api_base,
+        entry.expires_at
+    );
+    Ok(json_response(&serde_json::json!(entry)))
+}
+
+// DELETE /admin/upstream?model=gpt-4o, or without a model for the global override
+pub(crate) async fn remove_upstream_override_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let model = params.get("model").map(|m| m.as_str());
+    let removed = state.upstream_overrides.remove(model);
+    if removed.is_some() {
+        println!(
+            "🔀 Upstream override for {} removed",
+            model.unwrap_or("all models")
+        );
+    }
+    Ok(json_response(
+        &serde_json::json!({ "removed": removed.is_some() }),
+    ))
+}
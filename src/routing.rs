@@ -0,0 +1,315 @@
+// Routing rules mapping a requested model name to the model actually used
+
+use crate::config::RoutingRule;
+use crate::error::ProxyError;
+use crate::flags::Flag;
+use crate::model_match::{is_pattern, ModelMatcher};
+use crate::models::ModelInfo;
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::time::unix_now;
+use crate::tokens::estimate_request_tokens;
+use axum::http::HeaderMap;
+
+// Checks the rules at startup so bad ones fail fast
+pub(crate) fn validate_rules(rules: &[RoutingRule]) -> Result<(), String> {
+    for rule in rules {
+        let problem = match rule.kind.as_str() {
+            "alias" if rule.target.is_none() => Some("needs a target"),
+            "language" if rule.default.is_none() => Some("needs a default"),
+            "cost" if rule.candidates.is_empty() => Some("needs candidates"),
+            "schedule" if rule.default.is_none() => Some("needs a default"),
+            "schedule" => {
+                if let Some(err) = rule.schedule.iter().find_map(|s| s.window.validate().err()) {
+                    return Err(format!(
+                        "Routing rule for {} (schedule): {}",
+                        rule.model, err
+                    ));
+                }
+                None
+            }
+            "alias" | "language" | "cost" => None,
+            _ => Some("has an unknown type"),
+        };
+        if let Some(problem) = problem {
+            return Err(format!(
+                "Routing rule for {} ({}) {}",
+                rule.model, rule.kind, problem
+            ));
+        }
+    }
+    Ok(())
+}
+
+// The model a request for `requested` should go to, None without a matching
+// rule. A rule for the exact name wins over wildcard and group rules.
+fn route_model(
+    rules: &[RoutingRule],
+    requested: &str,
+    body: &serde_json::Value,
+    models: &[ModelInfo],
+    matcher: &ModelMatcher,
+    degraded: impl Fn(&str) -> bool,
+) -> Option<String> {
+    let rule = rules
+        .iter()
+        .find(|rule| rule.model == requested)
+        .or_else(|| {
+            rules
+                .iter()
+                .find(|rule| is_pattern(&rule.model) && matcher.matches(&rule.model, requested))
+        })?;
+    match rule.kind.as_str() {
+        "alias" => rule.target.clone(),
+        "language" => {
+            let language = latest_user_text(body).and_then(|text| detect_language(&text));
+            language
+                .and_then(|language| rule.languages.get(language))
+                .or(rule.default.as_ref())
+                .cloned()
+        }
+        "cost" => {
+            // Degraded candidates only when all of them are
+            let mut candidates = matcher.expand(&rule.candidates, models);
+            if candidates.iter().any(|c| !degraded(c)) {
+                candidates.retain(|c| !degraded(c));
+            }
+            cheapest_model(&candidates, body, models)
+        }
+        "schedule" => {
+            let now = unix_now();
+            rule.schedule
+                .iter()
+                .find(|entry| entry.window.contains(now))
+                .map(|entry| &entry.target)
+                .or(rule.default.as_ref())
+                .cloned()
+        }
+        _ => None,
+    }
+}
+
+// Settles the model: the one x-proxy-model-override names, or the one a
+// routing rule picks for the requested name
+pub(crate) struct RouteModel;
+
+impl RequestTransform for RouteModel {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let state = request.state;
+        if let Some(model) = &request.overrides.model {
+            println!("🎯 Model overridden to {} by request header", model);
+            request.body["model"] = model.clone().into();
+        }
+        let model_name = request.body["model"].as_str().map(|s| s.to_string());
+        if let Some(model) = &model_name {
+            request.check_allowed(model)?;
+        }
+
+        let rules = match request.overrides.model {
+            Some(_) => &[][..],
+            None if !state.flags.enabled(Flag::RoutingRules) => &[][..],
+            None => &state.routing_rules[..],
+        };
+        let routed = model_name.as_deref().and_then(|requested| {
+            route_model(
+                rules,
+                requested,
+                &request.body,
+                request.namespace.models,
+                &state.model_matcher,
+                |model| state.degraded(model),
+            )
+        });
+        request.model = match routed {
+            Some(target) => {
+                let requested = model_name.unwrap_or_default();
+                println!("🔀 Routed model {} to {}", requested, target);
+                request.log.requested_model = Some(requested);
+                request.body["model"] = target.clone().into();
+                Some(target)
+            }
+            None => model_name,
+        };
+        Ok(())
+    }
+}
+
+// Cheapest candidate with pricing whose context fits the request,
+// falling back to the first candidate
+fn cheapest_model(
+    candidates: &[String],
+    body: &serde_json::Value,
+    models: &[ModelInfo],
+) -> Option<String> {
+    let tokens = estimate_request_tokens(body);
+    candidates
+        .iter()
+        .filter_map(|id| models.iter().find(|m| &m.id == id))
+        .filter(|m| m.context_length.map(|max| tokens <= max).unwrap_or(true))
+        .filter_map(|m| m.pricing.as_ref().map(|p| (m, p.prompt + p.completion)))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(m, _)| m.id.clone())
+        .or_else(|| candidates.first().cloned())
+}
+
+fn latest_user_text(body: &serde_json::Value) -> Option<String> {
+    let message = body["messages"]
+        .as_array()?
+        .iter()
+        .rev()
+        .find(|m| m["role"] == "user")?;
+    match &message["content"] {
+        serde_json::Value::String(text) => Some(text.clone()),
+        serde_json::Value::Array(parts) => Some(
+            parts
+                .iter()
+                .filter_map(|p| p["text"].as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        _ => None,
+    }
+}
+
+// Common words of the Latin-script languages told apart by vocabulary
+const LATIN_STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "is", "are", "of", "to", "what", "how", "you", "with",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "los", "que", "es", "y", "de", "por", "para", "cómo",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "les", "est", "et", "des", "une", "pour", "que", "vous",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ich", "mit", "wie", "ein",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "que", "não", "uma", "para", "com", "você", "é", "do",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "che", "non", "della", "per", "una", "sono", "come", "è", "gli",
+        ],
+    ),
+];
+
+// Dominant language of a text as an ISO 639-1 code, from the script of its
+// letters and, for Latin script, a few common words
+pub(crate) fn detect_language(text: &str) -> Option<&'static str> {
+    let mut counts: Vec<(&'static str, usize)> = Vec::new();
+    let mut latin = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        let script = match c as u32 {
+            0x3040..=0x30ff => "ja",
+            0x4e00..=0x9fff | 0x3400..=0x4dbf => "zh",
+            0xac00..=0xd7af | 0x1100..=0x11ff => "ko",
+            0x0400..=0x04ff => "ru",
+            0x0600..=0x06ff => "ar",
+            0x0590..=0x05ff => "he",
+            0x0900..=0x097f => "hi",
+            0x0e00..=0x0e7f => "th",
+            0x0370..=0x03ff => "el",
+            _ => {
+                latin += 1;
+                continue;
+            }
+        };
+        match counts.iter_mut().find(|(s, _)| *s == script) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((script, 1)),
+        }
+    }
+
+    // Japanese mixes kana and kanji, so any real share of kana decides it
+    let kana = counts
+        .iter()
+        .find(|(s, _)| *s == "ja")
+        .map(|(_, n)| *n)
+        .unwrap_or(0);
+    let total: usize = counts.iter().map(|(_, n)| n).sum();
+    if kana > 0 && kana * 10 >= total {
+        return Some("ja");
+    }
+    let (script, count) = counts
+        .into_iter()
+        .max_by_key(|(_, n)| *n)
+        .unwrap_or(("", 0));
+    // A CJK character carries about as much as a word, so weigh it against letters
+    if count > 0 && count * 3 >= latin {
+        return Some(script);
+    }
+    if latin == 0 {
+        return None;
+    }
+
+    let lowercase = text.to_lowercase();
+    let words: Vec<&str> = lowercase
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .collect();
+    LATIN_STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let hits = words.iter().filter(|w| stopwords.contains(w)).count();
+            (*language, hits)
+        })
+        .max_by_key(|(_, hits)| *hits)
+        .filter(|(_, hits)| *hits > 0)
+        .map(|(language, _)| language)
+        .or(Some("en"))
+}
+
+// Per-request routing headers for clients allowed to steer routing
+#[derive(Debug, Default)]
+pub(crate) struct RequestOverrides {
+    // x-proxy-model-override: this model, skipping routing rules
+    pub(crate) model: Option<String>,
+    // x-proxy-provider: this provider instead of the model's
+    pub(crate) provider: Option<String>,
+    // x-proxy-no-cache: no prompt caching breakpoints
+    pub(crate) no_cache: bool,
+    // x-proxy-no-fallback: no fallback providers
+    pub(crate) no_fallback: bool,
+}
+
+impl RequestOverrides {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let text = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let flag = |name: &str| {
+            text(name).is_some_and(|v| !matches!(v.to_lowercase().as_str(), "0" | "false" | "no"))
+        };
+        Self {
+            model: text("x-proxy-model-override"),
+            provider: text("x-proxy-provider"),
+            no_cache: flag("x-proxy-no-cache"),
+            no_fallback: flag("x-proxy-no-fallback"),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.model.is_none() && self.provider.is_none() && !self.no_cache && !self.no_fallback
+    }
+}
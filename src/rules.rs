@@ -0,0 +1,344 @@
+// Conditional request rewrites from [[rules]]: a rule whose conditions on the
+// model, path, client, headers and body fields all hold sets or removes body
+// fields, adds upstream headers, rejects the request or reroutes it to another
+// model. Rules are evaluated in order, each seeing the body as the ones before
+// left it. Thinking injection for models with enable_thinking is such a rule.
+
+use crate::config::{BodyCondition, RuleAction, RuleConfig};
+use crate::error::ProxyError;
+use crate::flags::Flag;
+use crate::json_path::JsonPath;
+use crate::metrics::escape_label;
+use crate::model_match::ModelMatcher;
+use crate::models::{ModelInfo, ReasoningEffort};
+use crate::request::{OutgoingRequest, RequestTransform};
+use axum::http::HeaderMap;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+enum Action {
+    Set(JsonPath, Value),
+    Remove(JsonPath),
+    Header(String, String),
+    Reject(String),
+    Reroute(String),
+}
+
+enum Test {
+    Equals(Value),
+    Matches(Regex),
+    Exists(bool),
+}
+
+struct Condition {
+    path: JsonPath,
+    test: Test,
+}
+
+impl Condition {
+    fn new(config: &BodyCondition) -> Result<Self, String> {
+        let path = JsonPath::parse(&config.path)?;
+        let test = match (&config.equals, &config.matches, config.exists) {
+            (Some(value), None, None) => Test::Equals(value.clone()),
+            (None, Some(pattern), None) => Test::Matches(
+                Regex::new(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?,
+            ),
+            (None, None, Some(exists)) => Test::Exists(exists),
+            _ => {
+                return Err(format!(
+                    "Condition on {} needs one of equals, matches or exists",
+                    config.path
+                ))
+            }
+        };
+        Ok(Self { path, test })
+    }
+
+    fn holds(&self, body: &Map<String, Value>) -> bool {
+        let value = self.path.get_field(body).filter(|v| !v.is_null());
+        match &self.test {
+            Test::Equals(expected) => value == Some(expected),
+            Test::Matches(regex) => value
+                .and_then(|v| v.as_str())
+                .is_some_and(|v| regex.is_match(v)),
+            Test::Exists(exists) => value.is_some() == *exists,
+        }
+    }
+}
+
+impl Action {
+    fn new(config: &RuleAction) -> Result<Self, String> {
+        // The body stays an object: its fields are set and removed, not it
+        let path = || {
+            let path = JsonPath::parse(config.path.as_deref().ok_or("needs a path")?)?;
+            match path.is_root() {
+                true => Err("cannot change the whole body, only its fields".to_string()),
+                false => Ok(path),
+            }
+        };
+        let missing = |field: &str| format!("needs {}", field);
+        match config.kind.as_str() {
+            "set" => Ok(Action::Set(
+                path()?,
+                config.value.clone().ok_or_else(|| missing("a value"))?,
+            )),
+            "remove" => Ok(Action::Remove(path()?)),
+            "header" => {
+                let header = config.header.as_ref().ok_or_else(|| missing("a header"))?;
+                let value = match &config.value {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(value) => value.to_string(),
+                    None => return Err(missing("a value")),
+                };
+                Ok(Action::Header(header.to_lowercase(), value))
+            }
+            "reject" => {
+                Ok(Action::Reject(config.message.clone().unwrap_or_else(
+                    || "Request rejected by policy".to_string(),
+                )))
+            }
+            "reroute" => Ok(Action::Reroute(
+                config.model.clone().ok_or_else(|| missing("a model"))?,
+            )),
+            other => Err(format!("unknown type {}", other)),
+        }
+    }
+}
+
+pub(crate) struct Rule {
+    name: String,
+    models: Vec<String>,
+    paths: Vec<String>,
+    clients: Vec<String>,
+    headers: Vec<(String, String)>,
+    when: Vec<Condition>,
+    actions: Vec<Action>,
+    matches: AtomicU64,
+}
+
+// What the rules changed outside the body
+#[derive(Default)]
+pub(crate) struct Applied {
+    // Sent upstream with the request
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) rerouted: bool,
+}
+
+impl Rule {
+    pub(crate) fn new(config: &RuleConfig) -> Result<Self, String> {
+        let when = config
+            .when
+            .iter()
+            .map(Condition::new)
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Rule {}: {}", config.name, e))?;
+        let actions = config
+            .actions
+            .iter()
+            .map(|action| {
+                Action::new(action)
+                    .map_err(|e| format!("Rule {}: {} action {}", config.name, action.kind, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name: config.name.clone(),
+            models: config.models.clone(),
+            paths: config.paths.clone(),
+            clients: config.clients.clone(),
+            headers: config
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_lowercase(), value.clone()))
+                .collect(),
+            when,
+            actions,
+            matches: AtomicU64::new(0),
+        })
+    }
+
+    // Thinking parameters for a model with enable_thinking. Providers taking a
+    // budget get these converted by their adapter.
+    pub(crate) fn thinking(model: &ModelInfo) -> Self {
+        let mut thinking = match model.reasoning_effort {
+            ReasoningEffort::None => serde_json::json!({"type": "disabled"}),
+            _ => serde_json::json!({"type": "enabled"}),
+        };
+        if let Some(budget) = model.thinking_budget_tokens {
+            thinking["budget_tokens"] = budget.into();
+        }
+        let field = |name: &str| JsonPath::parse(name).unwrap();
+        Self {
+            name: "thinking".to_string(),
+            models: Vec::new(),
+            paths: Vec::new(),
+            clients: Vec::new(),
+            headers: Vec::new(),
+            when: Vec::new(),
+            actions: vec![
+                Action::Set(field("thinking"), thinking),
+                Action::Set(
+                    field("reasoning_effort"),
+                    model.reasoning_effort.as_str().into(),
+                ),
+            ],
+            matches: AtomicU64::new(0),
+        }
+    }
+
+    fn holds(
+        &self,
+        matcher: &ModelMatcher,
+        request: &RequestFacts,
+        body: &Map<String, Value>,
+    ) -> bool {
+        let model = body
+            .get("model")
+            .and_then(|m| m.as_str())
+            .unwrap_or_default();
+        (self.models.is_empty() || matcher.matches_any(&self.models, model))
+            && (self.paths.is_empty()
+                || self
+                    .paths
+                    .iter()
+                    .any(|p| request.path.ends_with(p.as_str())))
+            && (self.clients.is_empty() || self.clients.iter().any(|c| c == request.client))
+            && self.headers.iter().all(|(name, expected)| {
+                request
+                    .headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v == expected)
+            })
+            && self.when.iter().all(|condition| condition.holds(body))
+    }
+
+    pub(crate) fn apply(
+        &self,
+        obj: &mut Map<String, Value>,
+        applied: &mut Applied,
+    ) -> Result<(), ProxyError> {
+        // Paths are resolved from the body itself
+        let mut body = Value::Object(std::mem::take(obj));
+        let mut result = Ok(());
+        for action in &self.actions {
+            match action {
+                Action::Set(path, value) => {
+                    path.set(&mut body, value.clone());
+                }
+                Action::Remove(path) => {
+                    path.remove(&mut body);
+                }
+                Action::Header(name, value) => {
+                    applied.headers.retain(|(header, _)| header != name);
+                    applied.headers.push((name.clone(), value.clone()));
+                }
+                Action::Reject(message) => {
+                    println!("🚫 Request rejected by rule {}", self.name);
+                    result = Err(ProxyError::InvalidRequest(message.clone()));
+                    break;
+                }
+                Action::Reroute(model) => {
+                    println!("🔀 Rule {} rerouted the request to {}", self.name, model);
+                    body["model"] = model.clone().into();
+                    applied.rerouted = true;
+                }
+            }
+        }
+        if let Value::Object(rewritten) = body {
+            *obj = rewritten;
+        }
+        result
+    }
+}
+
+// What rule conditions can see of a request besides its body
+pub(crate) struct RequestFacts<'a> {
+    pub(crate) path: &'a str,
+    pub(crate) client: &'a str,
+    pub(crate) headers: &'a HeaderMap,
+}
+
+// Applies every rule whose conditions hold, in order
+fn apply_rules(
+    rules: &[Rule],
+    matcher: &ModelMatcher,
+    request: &RequestFacts,
+    obj: &mut Map<String, Value>,
+) -> Result<Applied, ProxyError> {
+    let mut applied = Applied::default();
+    for rule in rules {
+        // Conditions see the body as the rules before left it
+        if rule.holds(matcher, request, obj) {
+            rule.matches.fetch_add(1, Ordering::Relaxed);
+            rule.apply(obj, &mut applied)?;
+        }
+    }
+    Ok(applied)
+}
+
+// Runs [[rules]]; a rerouted request goes to a model the client may use
+pub(crate) struct ApplyRules;
+
+impl RequestTransform for ApplyRules {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let state = request.state;
+        let facts = RequestFacts {
+            path: request.path,
+            client: &request.log.client,
+            headers: request.headers,
+        };
+        let obj = request.body.as_object_mut().unwrap();
+        let applied = apply_rules(&state.rules, &state.model_matcher, &facts, obj)?;
+        request.rule_headers = applied.headers;
+        if applied.rerouted {
+            let model = obj
+                .get("model")
+                .and_then(|m| m.as_str())
+                .map(str::to_string);
+            if let Some(model) = &model {
+                request.check_allowed(model)?;
+            }
+            request.model = model;
+        }
+        Ok(())
+    }
+}
+
+// Adds thinking parameters for models with enable_thinking, in place of a
+// thinking object the client sent
+pub(crate) struct InjectThinking;
+
+impl RequestTransform for InjectThinking {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let Some(model) = request.model_config.filter(|m| m.enable_thinking) else {
+            return Ok(());
+        };
+        if !request.state.flags.enabled(Flag::ThinkingInjection) {
+            return Ok(());
+        }
+        request.client_thinking = false;
+        let obj = request.body.as_object_mut().unwrap();
+        Rule::thinking(model).apply(obj, &mut Applied::default())?;
+        println!(
+            "🧠 Applied deep thinking for model {} (effort: {})",
+            request.model.as_deref().unwrap_or_default(),
+            model.reasoning_effort.as_str()
+        );
+        Ok(())
+    }
+}
+
+pub(crate) fn render(rules: &[Rule]) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP openai_proxy_rule_matches_total Requests a rule was applied to.\n");
+    out.push_str("# TYPE openai_proxy_rule_matches_total counter\n");
+    for rule in rules {
+        out.push_str(&format!(
+            "openai_proxy_rule_matches_total{{rule=\"{}\"}} {}\n",
+            escape_label(&rule.name),
+            rule.matches.load(Ordering::Relaxed)
+        ));
+    }
+    out
+}
@@ -0,0 +1,114 @@
+// Time windows for scheduled routing and maintenance
+
+use crate::time::parse_utc;
+use serde::Deserialize;
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// A recurring window of weekdays and hours at a UTC offset, a one-off period
+// between from and until, or both combined
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct TimeWindow {
+    // "mon" to "sun", every day when empty
+    #[serde(default)]
+    pub(crate) days: Vec<String>,
+    // "09:00-18:00"; a window ending before it starts runs past midnight
+    pub(crate) hours: Option<String>,
+    // Offset the days and hours are given in, e.g. "+08:00"
+    #[serde(default = "default_utc_offset")]
+    pub(crate) utc_offset: String,
+    // UTC timestamps, e.g. "2026-10-20T02:00:00Z"
+    pub(crate) from: Option<String>,
+    pub(crate) until: Option<String>,
+}
+
+pub(crate) fn default_utc_offset() -> String {
+    "+00:00".to_string()
+}
+
+// "+08:00" or "-05:30" in seconds
+fn parse_offset(offset: &str) -> Option<i64> {
+    let (sign, rest) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => (1, offset),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours <= 14 && minutes < 60).then_some(sign * (hours * 3600 + minutes * 60))
+}
+
+// "09:00-18:00" in minutes of the day
+fn parse_hours(hours: &str) -> Option<(u32, u32)> {
+    let minutes = |time: &str| {
+        let (h, m) = time.trim().split_once(':')?;
+        let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+        (h <= 24 && m < 60 && h * 60 + m <= 1440).then_some(h * 60 + m)
+    };
+    let (start, end) = hours.split_once('-')?;
+    Some((minutes(start)?, minutes(end)?))
+}
+
+impl TimeWindow {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if let Some(day) = self
+            .days
+            .iter()
+            .find(|d| !WEEKDAYS.contains(&d.to_lowercase().as_str()))
+        {
+            return Err(format!("unknown day {}", day));
+        }
+        if parse_offset(&self.utc_offset).is_none() {
+            return Err(format!("invalid utc_offset {}", self.utc_offset));
+        }
+        if let Some(hours) = self.hours.as_deref().filter(|h| parse_hours(h).is_none()) {
+            return Err(format!("invalid hours {}", hours));
+        }
+        for time in [&self.from, &self.until].into_iter().flatten() {
+            if parse_utc(time).is_none() {
+                return Err(format!("invalid timestamp {}", time));
+            }
+        }
+        Ok(())
+    }
+
+    fn day_matches(&self, weekday: usize) -> bool {
+        self.days.is_empty()
+            || self
+                .days
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(WEEKDAYS[weekday]))
+    }
+
+    pub(crate) fn contains(&self, unix_secs: u64) -> bool {
+        if let Some(from) = self.from.as_deref().and_then(parse_utc) {
+            if unix_secs < from {
+                return false;
+            }
+        }
+        if let Some(until) = self.until.as_deref().and_then(parse_utc) {
+            if unix_secs >= until {
+                return false;
+            }
+        }
+
+        let offset = parse_offset(&self.utc_offset).unwrap_or(0);
+        let local = unix_secs as i64 + offset;
+        let days = local.div_euclid(86400);
+        let minute = (local.rem_euclid(86400) / 60) as u32;
+        // The epoch was a Thursday
+        let weekday = (days + 3).rem_euclid(7) as usize;
+        let previous_day = (weekday + 6) % 7;
+        match self.hours.as_deref().and_then(parse_hours) {
+            None => self.day_matches(weekday),
+            Some((start, end)) if start <= end => {
+                self.day_matches(weekday) && minute >= start && minute < end
+            }
+            // Past midnight, the window belongs to the day it started on
+            Some((start, end)) => {
+                (self.day_matches(weekday) && minute >= start)
+                    || (self.day_matches(previous_day) && minute < end)
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// Upstream keys and the Vault / AWS Secrets Manager backends
+
+use crate::config::SecretsConfig;
+use crate::time::{format_rotation_suffix, unix_now};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// An upstream API key, optionally sourced (and refreshed) from the secrets backend
+pub(crate) struct UpstreamKey {
+    pub(crate) value: RwLock<String>,
+    // Secret reference as "<path>#<field>"
+    pub(crate) secret: Option<String>,
+    // Last refresh triggered by an upstream 401
+    pub(crate) unauthorized_refresh_at: Mutex<Option<Instant>>,
+}
+
+impl UpstreamKey {
+    pub(crate) fn new(value: String, secret: Option<String>) -> Arc<Self> {
+        Arc::new(Self {
+            value: RwLock::new(value),
+            secret,
+            unauthorized_refresh_at: Mutex::new(None),
+        })
+    }
+
+    pub(crate) fn get(&self) -> String {
+        self.value.read().unwrap().clone()
+    }
+}
+
+pub(crate) struct SecretsBackend {
+    pub(crate) config: SecretsConfig,
+    pub(crate) client: reqwest::Client,
+    pub(crate) keys: Vec<Arc<UpstreamKey>>,
+}
+
+impl SecretsBackend {
+    pub(crate) async fn refresh_all(&self) -> Result<(), String> {
+        for key in &self.keys {
+            self.refresh(key).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn refresh(&self, key: &UpstreamKey) -> Result<(), String> {
+        let Some(reference) = &key.secret else {
+            return Ok(());
+        };
+        let value = self.fetch(reference).await?;
+        let mut current = key.value.write().unwrap();
+        if *current != value {
+            println!("🔑 Loaded upstream key from secret {}", reference);
+            *current = value;
+        }
+        Ok(())
+    }
+
+    // Returns a different key to retry with after the upstream rejected `rejected`
+    pub(crate) async fn refresh_after_unauthorized(
+        &self,
+        key: &UpstreamKey,
+        rejected: &str,
+    ) -> Option<String> {
+        key.secret.as_ref()?;
+
+        // Another request may already have picked up the rotated key
+        let current = key.get();
+        if current != rejected {
+            return Some(current);
+        }
+
+        // Don't hammer the backend when the stored key itself is bad
+        {
+            let mut last = key.unauthorized_refresh_at.lock().unwrap();
+            if last.is_some_and(|at| at.elapsed() < Duration::from_secs(30)) {
+                return None;
+            }
+            *last = Some(Instant::now());
+        }
+
+        if let Err(err) = self.refresh(key).await {
+            eprintln!("⚠️  Secret refresh after 401 failed: {}", err);
+            return None;
+        }
+        let refreshed = key.get();
+        (refreshed != rejected).then_some(refreshed)
+    }
+
+    pub(crate) async fn fetch(&self, reference: &str) -> Result<String, String> {
+        let (path, field) = match reference.split_once('#') {
+            Some((path, field)) => (path, Some(field)),
+            None => (reference, None),
+        };
+        match self.config.backend.as_str() {
+            "vault" => self.fetch_vault(path, field.unwrap_or("api_key")).await,
+            "aws" => self.fetch_aws(path, field).await,
+            other => Err(format!("unknown secrets backend {}", other)),
+        }
+    }
+
+    pub(crate) async fn fetch_vault(&self, path: &str, field: &str) -> Result<String, String> {
+        let address = self
+            .config
+            .vault_address
+            .as_deref()
+            .ok_or("vault_address is not set")?;
+        let token = self
+            .config
+            .vault_token
+            .clone()
+            .or_else(|| std::env::var("VAULT_TOKEN").ok())
+            .ok_or("vault_token is not set")?;
+
+        let url = format!(
+            "{}/v1/{}/data/{}",
+            address.trim_end_matches('/'),
+            self.config.vault_mount,
+            path.trim_start_matches('/')
+        );
+        let mut request = self.client.get(&url).header("X-Vault-Token", token);
+        if let Some(namespace) = &self.config.vault_namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("vault returned {} for {}", response.status(), path));
+        }
+        let json: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+        json.pointer(&format!("/data/data/{}", field))
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+            .ok_or_else(|| format!("field {} not found in vault secret {}", field, path))
+    }
+
+    pub(crate) async fn fetch_aws(
+        &self,
+        secret_id: &str,
+        field: Option<&str>,
+    ) -> Result<String, String> {
+        let config = &self.config;
+        let region = config
+            .aws_region
+            .clone()
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .ok_or("aws_region is not set")?;
+        let access_key = config
+            .aws_access_key_id
+            .clone()
+            .or_else(|| std::env::var("AWS_ACCESS_KEY_ID").ok())
+            .ok_or("aws_access_key_id is not set")?;
+        let secret_key = config
+            .aws_secret_access_key
+            .clone()
+            .or_else(|| std::env::var("AWS_SECRET_ACCESS_KEY").ok())
+            .ok_or("aws_secret_access_key is not set")?;
+        let session_token = config
+            .aws_session_token
+            .clone()
+            .or_else(|| std::env::var("AWS_SESSION_TOKEN").ok());
+
+        let host = format!("secretsmanager.{}.amazonaws.com", region);
+        let body = serde_json::json!({ "SecretId": secret_id }).to_string();
+        let mut headers = vec![
+            (
+                "content-type".to_string(),
+                "application/x-amz-json-1.1".to_string(),
+            ),
+            ("host".to_string(), host.clone()),
+            ("x-amz-date".to_string(), format_amz_date(unix_now())),
+            (
+                "x-amz-target".to_string(),
+                "secretsmanager.GetSecretValue".to_string(),
+            ),
+        ];
+        if let Some(token) = session_token {
+            headers.push(("x-amz-security-token".to_string(), token));
+        }
+        let authorization = aws_sigv4_authorization(
+            &access_key,
+            &secret_key,
+            &region,
+            "secretsmanager",
+            &mut headers,
+            body.as_bytes(),
+        );
+
+        let mut request = self
+            .client
+            .post(format!("https://{}/", host))
+            .header("Authorization", authorization)
+            .body(body);
+        for (name, value) in &headers {
+            if name != "host" {
+                request = request.header(name.as_str(), value.as_str());
+            }
+        }
+
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "secrets manager returned {} for {}",
+                response.status(),
+                secret_id
+            ));
+        }
+        let json: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+        let secret_string = json
+            .get("SecretString")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| format!("secret {} has no SecretString", secret_id))?;
+
+        match field {
+            None => Ok(secret_string.trim().to_string()),
+            Some(field) => serde_json::from_str::<serde_json::Value>(secret_string)
+                .ok()
+                .and_then(|v| v.get(field).and_then(|f| f.as_str()).map(|f| f.to_string()))
+                .ok_or_else(|| format!("field {} not found in secret {}", field, secret_id)),
+        }
+    }
+}
+
+// AWS Signature Version 4 for a POST to "/" without a query string
+pub(crate) fn aws_sigv4_authorization(
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    service: &str,
+    headers: &mut [(String, String)],
+    body: &[u8],
+) -> String {
+    use sha2::Digest;
+
+    headers.sort();
+    let amz_date = headers
+        .iter()
+        .find(|(name, _)| name == "x-amz-date")
+        .map(|(_, value)| value.clone())
+        .unwrap_or_default();
+    let date = &amz_date[..8];
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex_encode(&sha2::Sha256::digest(body))
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex_encode(&sha2::Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let signing_key = [region, service, "aws4_request"].iter().fold(
+        hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes()),
+        |key, part| hmac_sha256(&key, part.as_bytes()),
+    );
+    let signature = hex_encode(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key, scope, signed_headers, signature
+    )
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    use hmac::Mac;
+
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC accepts any key");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub(crate) fn format_amz_date(secs: u64) -> String {
+    format_rotation_suffix(secs).replace('-', "T") + "Z"
+}
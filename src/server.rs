@@ -0,0 +1,194 @@
+// Accept loop applying the [connections] limits
+
+use crate::config::{ConnectionConfig, Settings};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, Sleep};
+use tower::ServiceExt;
+
+// IPv6 listeners are dual-stack unless ipv6_only, whatever the OS default
+pub(crate) async fn bind(settings: &Settings) -> io::Result<TcpListener> {
+    match settings.server_ip() {
+        Some(IpAddr::V6(ip)) => {
+            let addr = SocketAddr::new(IpAddr::V6(ip), settings.server_port);
+            let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?;
+            socket.set_only_v6(settings.connections.ipv6_only)?;
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(1024)?;
+            TcpListener::from_std(socket.into())
+        }
+        Some(ip) => TcpListener::bind((ip, settings.server_port)).await,
+        None => TcpListener::bind((settings.server_host.as_str(), settings.server_port)).await,
+    }
+}
+
+pub(crate) async fn serve_router(
+    listener: TcpListener,
+    app: Router,
+    limits: ConnectionConfig,
+) -> io::Result<()> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_millis(limits.header_read_timeout_ms));
+    if let Some(max) = limits.max_header_bytes {
+        builder.http1().max_buf_size(max);
+        builder
+            .http2()
+            .max_header_list_size(u32::try_from(max).unwrap_or(u32::MAX));
+    }
+    let idle_timeout = limits.idle_timeout_ms.map(Duration::from_millis);
+    let slots = limits.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+
+    loop {
+        let permit = match &slots {
+            Some(slots) => Some(match slots.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    println!("⚠️  Connection limit reached, waiting for a free slot");
+                    slots.clone().acquire_owned().await.expect("never closed")
+                }
+            }),
+            None => None,
+        };
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // e.g. out of file descriptors; back off instead of spinning
+                eprintln!("❌ Failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let io = IdleStream::new(stream, idle_timeout, in_flight.clone());
+        let app = app.clone();
+        let service =
+            hyper::service::service_fn(move |mut req: hyper::Request<hyper::body::Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(peer));
+                let handling = InFlight::start(&in_flight);
+                let response = app.clone().oneshot(req);
+                async move {
+                    let response = response.await;
+                    drop(handling);
+                    response
+                }
+            });
+        let builder = builder.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _ = builder
+                .serve_connection_with_upgrades(TokioIo::new(io), service)
+                .await;
+        });
+    }
+}
+
+// Counts a request being handled on the connection
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// A TCP stream that fails reads once nothing has been read or written for the
+// idle timeout while no request is being handled
+struct IdleStream {
+    inner: TcpStream,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl IdleStream {
+    fn new(inner: TcpStream, timeout: Option<Duration>, in_flight: Arc<AtomicUsize>) -> Self {
+        Self {
+            inner,
+            idle: timeout.map(|t| (t, Box::pin(tokio::time::sleep(t)))),
+            in_flight,
+        }
+    }
+
+    fn touch(&mut self) {
+        if let Some((timeout, timer)) = &mut self.idle {
+            timer.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+}
+
+impl AsyncRead for IdleStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            this.touch();
+            return Poll::Ready(result);
+        }
+        // A slow handler is not an idle connection
+        if this.in_flight.load(Ordering::Relaxed) > 0 {
+            this.touch();
+            return Poll::Pending;
+        }
+        let Some((_, timer)) = &mut this.idle else {
+            return Poll::Pending;
+        };
+        match timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "idle connection timed out",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for IdleStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if result.is_ready() {
+            this.touch();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
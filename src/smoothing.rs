@@ -0,0 +1,126 @@
+// Pacing of streamed tokens to clients: events are held back so a stream
+// delivers at most a configured number of tokens per second
+
+use crate::tokens::estimate_text_tokens;
+use crate::transform::sse_event_end;
+use axum::body::Bytes;
+use futures_util::{ready, Stream};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+pub(crate) struct PacedStream<S> {
+    inner: S,
+    tokens_per_second: f64,
+    burst: f64,
+    // Tokens that may go out right away; negative after a large event
+    allowance: f64,
+    refilled: Instant,
+    pending: Vec<u8>,
+    // Complete events waiting for their turn
+    queue: VecDeque<Bytes>,
+    wait: Option<Pin<Box<Sleep>>>,
+    ended: bool,
+    // An upstream error, passed on after the events before it
+    error: Option<reqwest::Error>,
+}
+
+impl<S> PacedStream<S> {
+    pub(crate) fn new(inner: S, tokens_per_second: f64, burst: f64) -> Self {
+        Self {
+            inner,
+            tokens_per_second: tokens_per_second.max(0.1),
+            burst: burst.max(1.0),
+            allowance: burst.max(1.0),
+            refilled: Instant::now(),
+            pending: Vec::new(),
+            queue: VecDeque::new(),
+            wait: None,
+            ended: false,
+            error: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.allowance = (self.allowance + elapsed * self.tokens_per_second).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+// Estimated tokens of the content and reasoning deltas in one event
+fn event_tokens(event: &[u8]) -> u64 {
+    let text = String::from_utf8_lossy(event);
+    let data: String = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .collect();
+    let Ok(chunk) = serde_json::from_str::<serde_json::Value>(data.trim()) else {
+        return 0;
+    };
+    chunk["choices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|choice| ["content", "reasoning_content"].map(|f| &choice["delta"][f]))
+        .filter_map(|text| text.as_str())
+        .map(estimate_text_tokens)
+        .sum()
+}
+
+impl<S> Stream for PacedStream<S>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+{
+    type Item = reqwest::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(wait) = this.wait.as_mut() {
+                ready!(wait.as_mut().poll(cx));
+                this.wait = None;
+            }
+            if let Some(event) = this.queue.front() {
+                let tokens = event_tokens(event) as f64;
+                this.refill();
+                // An event larger than the burst goes out on a full bucket
+                if tokens == 0.0 || this.allowance >= tokens.min(this.burst) {
+                    this.allowance -= tokens;
+                    return Poll::Ready(this.queue.pop_front().map(Ok));
+                }
+                let missing = tokens.min(this.burst) - this.allowance;
+                let delay = Duration::from_secs_f64(missing / this.tokens_per_second);
+                this.wait = Some(Box::pin(tokio::time::sleep(delay)));
+                continue;
+            }
+            if this.ended {
+                return Poll::Ready(this.error.take().map(Err));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(bytes)) => {
+                    this.pending.extend_from_slice(&bytes);
+                    while let Some((end, separator)) = sse_event_end(&this.pending) {
+                        let event: Vec<u8> = this.pending.drain(..end + separator).collect();
+                        this.queue.push_back(event.into());
+                    }
+                }
+                Some(Err(err)) => {
+                    this.ended = true;
+                    this.error = Some(err);
+                }
+                None => {
+                    this.ended = true;
+                    if !this.pending.is_empty() {
+                        let rest = std::mem::take(&mut this.pending);
+                        this.queue.push_back(rest.into());
+                    }
+                }
+            }
+        }
+    }
+}
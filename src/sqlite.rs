@@ -0,0 +1,310 @@
+// SQLite storage backend, for a single host: the proxy and its restarts, or
+// replicas on one machine, share a database file. Queries take bound
+// parameters; the schema is migrated from migrations/sqlite at startup.
+
+use crate::config::StorageConfig;
+use crate::storage::{Storage, StorageResult};
+use crate::time::unix_now;
+use futures_util::future::BoxFuture;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Expired rows are deleted every this many writes
+const CLEANUP_EVERY: u64 = 100;
+
+pub(crate) struct SqliteStorage {
+    pool: SqlitePool,
+    writes: AtomicU64,
+}
+
+fn sql_error(err: sqlx::Error) -> String {
+    format!("SQLite error: {}", err)
+}
+
+// Unix seconds, as SQLite integers
+fn now() -> i64 {
+    unix_now() as i64
+}
+
+fn expiry(ttl_secs: Option<u64>) -> Option<i64> {
+    ttl_secs.map(|ttl| now() + ttl as i64)
+}
+
+impl SqliteStorage {
+    // sqlite://path/to/file.db, created when missing. Migrates the schema, so
+    // an unwritable path fails startup.
+    pub(crate) async fn open(url: &str, config: &StorageConfig) -> Result<Self, String> {
+        let acquire_timeout = Duration::from_millis(config.acquire_timeout_ms);
+        let options = SqliteConnectOptions::from_str(url)
+            .map_err(|e| format!("Invalid sqlite url {}: {}", url, e))?
+            .create_if_missing(true)
+            // Readers do not wait for writers, and writers wait for each other
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(acquire_timeout);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections.max(1) as u32)
+            .acquire_timeout(acquire_timeout)
+            .connect_with(options)
+            .await
+            .map_err(|e| format!("Failed to open sqlite database {}: {}", url, e))?;
+        sqlx::migrate!("migrations/sqlite")
+            .run(&pool)
+            .await
+            .map_err(|e| format!("SQLite migration failed: {}", e))?;
+        Ok(Self {
+            pool,
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    async fn cleanup(&self) -> StorageResult<()> {
+        if !self
+            .writes
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(CLEANUP_EVERY)
+        {
+            return Ok(());
+        }
+        for table in [
+            "openai_proxy_values",
+            "openai_proxy_counters",
+            "openai_proxy_logs",
+        ] {
+            sqlx::query(&format!("DELETE FROM {} WHERE expires_at <= ?", table))
+                .bind(now())
+                .execute(&self.pool)
+                .await
+                .map_err(sql_error)?;
+        }
+        Ok(())
+    }
+}
+
+// An upsert, so concurrent increments all count; an expired counter starts
+// over
+const HINCR_SQL: &str = "INSERT INTO openai_proxy_counters (key, field, value, expires_at) \
+     VALUES (?, ?, ?, ?) \
+     ON CONFLICT (key, field) DO UPDATE \
+     SET value = CASE WHEN openai_proxy_counters.expires_at <= ? \
+     THEN excluded.value ELSE openai_proxy_counters.value + excluded.value END, \
+     expires_at = excluded.expires_at \
+     RETURNING value";
+
+const HGETALL_SQL: &str = "SELECT field, value FROM openai_proxy_counters \
+     WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)";
+
+fn hash(rows: &[sqlx::sqlite::SqliteRow]) -> StorageResult<BTreeMap<String, i64>> {
+    rows.iter()
+        .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+        .collect::<Result<_, sqlx::Error>>()
+        .map_err(sql_error)
+}
+
+impl Storage for SqliteStorage {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let row = sqlx::query(
+                "SELECT value FROM openai_proxy_values \
+                 WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+            )
+            .bind(key)
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(sql_error)?;
+            row.map(|row| row.try_get(0).map_err(sql_error)).transpose()
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO openai_proxy_values (key, value, expires_at) VALUES (?, ?, ?) \
+                 ON CONFLICT (key) DO UPDATE \
+                 SET value = excluded.value, expires_at = excluded.expires_at",
+            )
+            .bind(key)
+            .bind(value)
+            .bind(expiry(ttl_secs))
+            .execute(&self.pool)
+            .await
+            .map_err(sql_error)?;
+            self.cleanup().await
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            let mut transaction = self.pool.begin().await.map_err(sql_error)?;
+            for table in [
+                "openai_proxy_values",
+                "openai_proxy_counters",
+                "openai_proxy_logs",
+            ] {
+                sqlx::query(&format!("DELETE FROM {} WHERE key = ?", table))
+                    .bind(key)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(sql_error)?;
+            }
+            transaction.commit().await.map_err(sql_error)
+        })
+    }
+
+    fn hincr<'a>(
+        &'a self,
+        key: &'a str,
+        field: &'a str,
+        by: i64,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<i64>> {
+        Box::pin(async move {
+            let row = sqlx::query(HINCR_SQL)
+                .bind(key)
+                .bind(field)
+                .bind(by)
+                .bind(expiry(ttl_secs))
+                .bind(now())
+                .fetch_one(&self.pool)
+                .await
+                .map_err(sql_error)?;
+            row.try_get(0).map_err(sql_error)
+        })
+    }
+
+    fn hgetall<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>> {
+        Box::pin(async move {
+            let rows = sqlx::query(HGETALL_SQL)
+                .bind(key)
+                .bind(now())
+                .fetch_all(&self.pool)
+                .await
+                .map_err(sql_error)?;
+            hash(&rows)
+        })
+    }
+
+    // One transaction; SQLite has one writer at a time, and the first
+    // upsert makes it this one until the commit
+    fn hincr_all<'a>(
+        &'a self,
+        key: &'a str,
+        counts: &'a [(String, i64)],
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>> {
+        Box::pin(async move {
+            let mut transaction = self.pool.begin().await.map_err(sql_error)?;
+            for (field, by) in counts {
+                sqlx::query(HINCR_SQL)
+                    .bind(key)
+                    .bind(field)
+                    .bind(by)
+                    .bind(expiry(ttl_secs))
+                    .bind(now())
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(sql_error)?;
+            }
+            let rows = sqlx::query(HGETALL_SQL)
+                .bind(key)
+                .bind(now())
+                .fetch_all(&mut *transaction)
+                .await
+                .map_err(sql_error)?;
+            transaction.commit().await.map_err(sql_error)?;
+            hash(&rows)
+        })
+    }
+
+    // The whole log takes the new expiry
+    fn append<'a>(
+        &'a self,
+        key: &'a str,
+        entry: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            let expires = expiry(ttl_secs);
+            let mut transaction = self.pool.begin().await.map_err(sql_error)?;
+            sqlx::query("INSERT INTO openai_proxy_logs (key, entry, expires_at) VALUES (?, ?, ?)")
+                .bind(key)
+                .bind(entry)
+                .bind(expires)
+                .execute(&mut *transaction)
+                .await
+                .map_err(sql_error)?;
+            sqlx::query("UPDATE openai_proxy_logs SET expires_at = ? WHERE key = ?")
+                .bind(expires)
+                .bind(key)
+                .execute(&mut *transaction)
+                .await
+                .map_err(sql_error)?;
+            transaction.commit().await.map_err(sql_error)?;
+            self.cleanup().await
+        })
+    }
+
+    fn range<'a>(
+        &'a self,
+        key: &'a str,
+        start: usize,
+    ) -> BoxFuture<'a, StorageResult<Vec<Vec<u8>>>> {
+        Box::pin(async move {
+            let rows = sqlx::query(
+                "SELECT entry FROM openai_proxy_logs \
+                 WHERE key = ? AND (expires_at IS NULL OR expires_at > ?) \
+                 ORDER BY seq LIMIT -1 OFFSET ?",
+            )
+            .bind(key)
+            .bind(now())
+            .bind(start as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(sql_error)?;
+            rows.iter()
+                .map(|row| row.try_get(0))
+                .collect::<Result<_, _>>()
+                .map_err(sql_error)
+        })
+    }
+
+    // One upsert: it only updates a row that is ours or expired
+    fn acquire_lease<'a>(
+        &'a self,
+        key: &'a str,
+        owner: &'a str,
+        ttl_secs: u64,
+    ) -> BoxFuture<'a, StorageResult<bool>> {
+        Box::pin(async move {
+            let row = sqlx::query(
+                "INSERT INTO openai_proxy_values (key, value, expires_at) VALUES (?, ?, ?) \
+                 ON CONFLICT (key) DO UPDATE \
+                 SET value = excluded.value, expires_at = excluded.expires_at \
+                 WHERE openai_proxy_values.value = excluded.value \
+                 OR openai_proxy_values.expires_at <= ? \
+                 RETURNING key",
+            )
+            .bind(key)
+            .bind(owner.as_bytes())
+            .bind(expiry(Some(ttl_secs)))
+            .bind(now())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(sql_error)?;
+            Ok(row.is_some())
+        })
+    }
+}
@@ -0,0 +1,173 @@
+// Local staging for file uploads: the client's body is written to disk first,
+// within a size limit, and the upload is sent upstream from there. A slow
+// client then does not hold an upstream connection, the upload can be retried
+// and fall back like any request, and one the upstream did not take is kept for
+// a while, so the client can have it sent again without uploading it anew.
+
+use crate::config::FileStagingConfig;
+use crate::error::{body_read_error, ProxyError};
+use axum::body::Body;
+use axum::http::{HeaderValue, Method};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+pub(crate) struct StagedUpload {
+    pub(crate) id: String,
+    path: PathBuf,
+    pub(crate) len: u64,
+    pub(crate) content_type: Option<HeaderValue>,
+}
+
+impl StagedUpload {
+    // The file as a request body, opened for each attempt
+    pub(crate) fn body(&self) -> reqwest::Body {
+        match fs::File::open(&self.path) {
+            Ok(file) => tokio::fs::File::from_std(file).into(),
+            Err(err) => {
+                let failed = futures_util::stream::once(async move { Err::<Vec<u8>, _>(err) });
+                reqwest::Body::wrap_stream(failed)
+            }
+        }
+    }
+}
+
+// The file goes with the last reference to the upload
+impl Drop for StagedUpload {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+// Uploads the upstream did not take, by ID
+struct Kept {
+    upload: Arc<StagedUpload>,
+    client: String,
+    until: Instant,
+}
+
+pub(crate) struct FileStaging {
+    config: FileStagingConfig,
+    dir: PathBuf,
+    kept: Mutex<HashMap<String, Kept>>,
+}
+
+impl FileStaging {
+    // Uploads left over from an earlier run cannot be resumed and are removed
+    pub(crate) fn open(config: FileStagingConfig) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.directory);
+        fs::create_dir_all(&dir)?;
+        for file in fs::read_dir(&dir)? {
+            let file = file?;
+            if file.file_name().to_string_lossy().ends_with(".upload") {
+                fs::remove_file(file.path()).ok();
+            }
+        }
+        Ok(Self {
+            config,
+            dir,
+            kept: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub(crate) fn applies(&self, method: &Method, path: &str) -> bool {
+        (method == Method::POST || method == Method::PUT)
+            && self
+                .config
+                .paths
+                .iter()
+                .any(|suffix| path.ends_with(suffix.as_str()))
+    }
+
+    // Writes the body to disk; the file is removed again if that fails or it
+    // grows past max_bytes
+    pub(crate) async fn stage(
+        &self,
+        request_id: &str,
+        body: Body,
+        content_type: Option<HeaderValue>,
+    ) -> Result<Arc<StagedUpload>, ProxyError> {
+        self.purge();
+        let id = format!("upload_{}", request_id.trim_start_matches("req_"));
+        let path = self.dir.join(format!("{}.upload", id));
+        let mut upload = StagedUpload {
+            id,
+            path,
+            len: 0,
+            content_type,
+        };
+        upload.len = self.write(&upload.path, body).await?;
+        println!("📥 Staged upload {} ({} bytes)", upload.id, upload.len);
+        Ok(Arc::new(upload))
+    }
+
+    async fn write(&self, path: &std::path::Path, body: Body) -> Result<u64, ProxyError> {
+        let disk_error =
+            |err: io::Error| ProxyError::Internal(format!("Failed to stage the upload: {}", err));
+        let mut file = tokio::fs::File::create(path).await.map_err(disk_error)?;
+        let mut stream = body.into_data_stream();
+        let mut len = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(body_read_error)?;
+            len += chunk.len() as u64;
+            if len > self.config.max_bytes {
+                return Err(ProxyError::PayloadTooLarge(format!(
+                    "Uploads are limited to {} bytes",
+                    self.config.max_bytes
+                )));
+            }
+            file.write_all(&chunk).await.map_err(disk_error)?;
+        }
+        file.flush().await.map_err(disk_error)?;
+        Ok(len)
+    }
+
+    // A kept upload of the same client, to send again
+    pub(crate) fn resume(&self, id: &str, client: &str) -> Result<Arc<StagedUpload>, ProxyError> {
+        self.purge();
+        let mut kept = self.kept.lock().unwrap();
+        match kept.remove(id) {
+            Some(entry) if entry.client == client => Ok(entry.upload),
+            Some(entry) => {
+                kept.insert(id.to_string(), entry);
+                Err(ProxyError::NotFound(format!("No staged upload {}", id)))
+            }
+            None => Err(ProxyError::NotFound(format!("No staged upload {}", id))),
+        }
+    }
+
+    // Keeps an upload the upstream did not take and returns its ID for the
+    // client; one it took goes once the request is done
+    pub(crate) fn finish(
+        &self,
+        upload: &Arc<StagedUpload>,
+        client: &str,
+        taken: bool,
+    ) -> Option<String> {
+        if taken {
+            return None;
+        }
+        let id = upload.id.clone();
+        println!("📥 Keeping staged upload {} for a retry", id);
+        let entry = Kept {
+            upload: upload.clone(),
+            client: client.to_string(),
+            until: Instant::now() + Duration::from_secs(self.config.keep_secs),
+        };
+        self.kept.lock().unwrap().insert(id.clone(), entry);
+        Some(id)
+    }
+
+    fn purge(&self) {
+        let now = Instant::now();
+        self.kept
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.until > now);
+    }
+}
@@ -0,0 +1,1051 @@
+// Shared application state and request bookkeeping
+
+use crate::access_log::AccessLog;
+use crate::alerts::AlertMonitor;
+use crate::audit::AuditLog;
+use crate::buffers::BufferPool;
+use crate::cache::ResponseCache;
+use crate::chaos::Chaos;
+use crate::config::{
+    CaptureConfig, CompletionRetryConfig, CompositeModelConfig, ConstrainedOutputConfig,
+    DeadlineConfig, GuardrailsConfig, IpFamily, LoopDetectionConfig, ModelCatalogConfig,
+    ModesConfig, ParameterProfile, RequestTagsConfig, RetentionConfig, RetryConfig, RoutingRule,
+    Settings, StreamPacingConfig, StreamSalvageConfig, TraceContextConfig,
+};
+use crate::dictionaries::Dictionary;
+use crate::dns::{ConnectionStats, UpstreamResolver};
+use crate::encryption::{open_upstream_keys, secrets_backend, SealingKey};
+use crate::error::ProxyError;
+use crate::feedback::FeedbackStore;
+use crate::fine_tunes::FineTunes;
+use crate::flags::FeatureFlags;
+use crate::inspector::{Inspector, RequestSummary};
+use crate::integrity::Integrity;
+use crate::jobs::AgentJobs;
+use crate::leader::Leadership;
+use crate::limits::ConcurrencyLimits;
+use crate::localization::MessageCatalog;
+use crate::metrics::{Metrics, StatsdClient};
+use crate::model_match::ModelMatcher;
+use crate::models::ModelInfo;
+use crate::overrides::UpstreamOverrides;
+use crate::postprocess::{build_post_processors, PostProcessor};
+use crate::probes::Probes;
+use crate::providers::Provider;
+use crate::proxy::RequestLog;
+use crate::resume::StreamBuffers;
+use crate::routing::validate_rules;
+use crate::rules::Rule;
+use crate::secrets::{SecretsBackend, UpstreamKey};
+use crate::staging::FileStaging;
+use crate::status::StatusInfo;
+use crate::storage::Store;
+use crate::tags::metric_tags;
+use crate::templates::PromptTemplates;
+use crate::tenant::{bearer_token, Tenant, TenantUsage};
+use crate::time::{format_utc, unix_now};
+use crate::transcripts::TranscriptCollector;
+use crate::transform::{finish_reason_map, StreamStats};
+use crate::usage::{UsageLedger, UsageRecord};
+use axum::http::{HeaderMap, StatusCode};
+use std::collections::HashMap;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) struct AppState {
+    pub(crate) openai_api_key: Arc<UpstreamKey>,
+    pub(crate) openai_api_base: String,
+    pub(crate) client: reqwest::Client,
+    // For remote images, refuses non-public addresses
+    pub(crate) image_client: reqwest::Client,
+    pub(crate) available_models: Vec<ModelInfo>,
+    pub(crate) access_log: Option<AccessLog>,
+    pub(crate) statsd: Option<StatsdClient>,
+    pub(crate) model_catalog: ModelCatalogConfig,
+    pub(crate) model_matcher: ModelMatcher,
+    pub(crate) tenants: Vec<Arc<Tenant>>,
+    pub(crate) secrets: Option<Arc<SecretsBackend>>,
+    pub(crate) key_passthrough: bool,
+    pub(crate) hmac_max_skew_secs: u64,
+    // Nonces of recently accepted signed requests with their expiry
+    pub(crate) hmac_nonces: Mutex<HashMap<String, u64>>,
+    pub(crate) guardrails: GuardrailsConfig,
+    pub(crate) admin_key: Option<String>,
+    // Runtime-adjustable through /admin/capture
+    pub(crate) capture: RwLock<CaptureConfig>,
+    pub(crate) capture_cipher: Option<SealingKey>,
+    // Runtime-adjustable through /admin/modes
+    pub(crate) modes: RwLock<ModesConfig>,
+    pub(crate) chaos: Chaos,
+    pub(crate) status: StatusInfo,
+    pub(crate) flags: FeatureFlags,
+    pub(crate) buffers: BufferPool,
+    pub(crate) connection_stats: Arc<ConnectionStats>,
+    pub(crate) request_counter: AtomicU64,
+    pub(crate) metrics: Metrics,
+    pub(crate) alerts: Option<AlertMonitor>,
+    pub(crate) limits: ConcurrencyLimits,
+    pub(crate) upstream_overrides: UpstreamOverrides,
+    pub(crate) providers: Vec<Provider>,
+    // None when finish_reason normalization is off
+    pub(crate) finish_reasons: Option<Arc<HashMap<String, String>>>,
+    pub(crate) translate_upstream_errors: bool,
+    pub(crate) json_errors: bool,
+    pub(crate) metadata_headers: bool,
+    pub(crate) retries: RetryConfig,
+    pub(crate) completion_retry: Option<CompletionRetryConfig>,
+    pub(crate) composite_models: Vec<CompositeModelConfig>,
+    // Compiled post_process settings by model ID
+    pub(crate) post_processors: HashMap<String, Arc<PostProcessor>>,
+    pub(crate) routing_rules: Vec<RoutingRule>,
+    pub(crate) allow_routing_overrides: bool,
+    pub(crate) allow_dry_run: bool,
+    pub(crate) trace_context: TraceContextConfig,
+    pub(crate) deadlines: DeadlineConfig,
+    pub(crate) constrained_output: ConstrainedOutputConfig,
+    pub(crate) stream_pacing: Option<StreamPacingConfig>,
+    pub(crate) stream_salvage: Option<StreamSalvageConfig>,
+    pub(crate) stream_resumption: Option<StreamBuffers>,
+    pub(crate) loop_detection: Option<LoopDetectionConfig>,
+    // Global deny dictionaries; tenants have their own on top
+    pub(crate) dictionaries: Vec<Arc<Dictionary>>,
+    pub(crate) rules: Vec<Rule>,
+    pub(crate) prompt_templates: PromptTemplates,
+    pub(crate) passthrough_paths: Vec<String>,
+    pub(crate) integrity: Option<Integrity>,
+    pub(crate) file_staging: Option<FileStaging>,
+    pub(crate) fine_tunes: Option<FineTunes>,
+    pub(crate) probes: Option<Probes>,
+    pub(crate) parameter_profiles: Vec<ParameterProfile>,
+    pub(crate) inspector: Inspector,
+    pub(crate) usage_ledger: Option<UsageLedger>,
+    pub(crate) transcripts: Option<Arc<TranscriptCollector>>,
+    pub(crate) feedback: Option<FeedbackStore>,
+    pub(crate) retention: Option<RetentionConfig>,
+    pub(crate) localization: Option<MessageCatalog>,
+    pub(crate) request_tags: RequestTagsConfig,
+    pub(crate) response_cache: Option<ResponseCache>,
+    pub(crate) agent_jobs: Option<AgentJobs>,
+    pub(crate) storage: Option<Arc<Store>>,
+    pub(crate) leadership: Arc<Leadership>,
+    pub(crate) audit: AuditLog,
+}
+
+// Keep JSON bodies structured, everything else as text
+pub(crate) fn capture_body(body: &[u8]) -> serde_json::Value {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(body).to_string()))
+}
+
+pub(crate) struct CaptureRecord<'a> {
+    pub(crate) log: &'a RequestLog,
+    pub(crate) request_headers: &'a HeaderMap,
+    pub(crate) request_body: &'a [u8],
+    pub(crate) forwarded_body: &'a [u8],
+    pub(crate) upstream_url: &'a str,
+    pub(crate) status: StatusCode,
+    pub(crate) response_headers: &'a HeaderMap,
+}
+
+impl AppState {
+    pub(crate) fn next_request_id(&self) -> String {
+        let counter = self.request_counter.fetch_add(1, Ordering::Relaxed);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        format!("req_{:x}{:04x}", millis, counter & 0xffff)
+    }
+
+    // The global dictionaries and those of the tenant
+    pub(crate) fn dictionaries_for(&self, tenant: Option<&Tenant>) -> Vec<Arc<Dictionary>> {
+        self.dictionaries
+            .iter()
+            .chain(tenant.into_iter().flat_map(|t| t.dictionaries.iter()))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn should_capture(&self, client: &str) -> bool {
+        let capture = self.capture.read().unwrap();
+        if !capture.enabled {
+            return false;
+        }
+        if capture.clients.iter().any(|c| c == client) {
+            return true;
+        }
+        if capture.sample_percent <= 0.0 {
+            return false;
+        }
+        let counter = self.request_counter.load(Ordering::Relaxed);
+        let roll = std::collections::hash_map::RandomState::new().hash_one(counter) % 10_000;
+        (roll as f64) < capture.sample_percent * 100.0
+    }
+
+    // Capture file contents; the response body is filled in once it is complete
+    pub(crate) fn capture_document(&self, record: CaptureRecord) -> serde_json::Value {
+        let headers_json = |headers: &HeaderMap| {
+            let mut map = serde_json::Map::new();
+            for (name, value) in headers {
+                let redacted = matches!(
+                    name.as_str(),
+                    "authorization" | "x-proxy-key" | "x-proxy-signature" | "cookie"
+                );
+                let value = if redacted {
+                    "[redacted]".to_string()
+                } else {
+                    value.to_str().unwrap_or_default().to_string()
+                };
+                map.insert(name.to_string(), serde_json::Value::String(value));
+            }
+            serde_json::Value::Object(map)
+        };
+        serde_json::json!({
+            "request_id": record.log.request_id,
+            "timestamp": format_utc(unix_now()),
+            "client": record.log.client,
+            "tenant": record.log.tenant,
+            "user": record.log.user,
+            "tags": record.log.tags,
+            "model": record.log.model,
+            "request": {
+                "method": record.log.method,
+                "path": record.log.path,
+                "headers": headers_json(record.request_headers),
+                "body": capture_body(record.request_body),
+            },
+            "upstream": {
+                "url": record.upstream_url,
+                "body": capture_body(record.forwarded_body),
+            },
+            "response": {
+                "status": record.status.as_u16(),
+                "headers": headers_json(record.response_headers),
+                "body": serde_json::Value::Null,
+            },
+        })
+    }
+
+    pub(crate) fn save_capture(&self, request_id: &str, mut capture: serde_json::Value) {
+        if let Some(cipher) = &self.capture_cipher {
+            // Better no capture than one in the clear
+            if let Err(err) = cipher.seal(&mut capture) {
+                eprintln!("⚠️  Failed to encrypt capture {}: {}", request_id, err);
+                return;
+            }
+        }
+        let directory = self.capture.read().unwrap().directory.clone();
+        let file = Path::new(&directory).join(format!("{}.json", request_id));
+        tokio::task::spawn_blocking(move || {
+            let result = fs::create_dir_all(&directory).and_then(|_| {
+                fs::write(
+                    &file,
+                    serde_json::to_vec_pretty(&capture).unwrap_or_default(),
+                )
+            });
+            if let Err(err) = result {
+                eprintln!("⚠️  Failed to write capture {}: {}", file.display(), err);
+            }
+        });
+    }
+
+    // Whether probes found the model failing
+    pub(crate) fn degraded(&self, model: &str) -> bool {
+        self.probes.as_ref().is_some_and(|p| p.degraded(model))
+    }
+
+    // Counts requests and tokens under the tags that are metric labels
+    pub(crate) fn record_tags(
+        &self,
+        log: &RequestLog,
+        requests: u64,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        for (key, value) in metric_tags(&self.request_tags, &log.tags) {
+            self.metrics
+                .record_tag(key, value, requests, prompt_tokens, completion_tokens);
+        }
+    }
+
+    // Accounts a relayed event stream once it has ended
+    pub(crate) fn finish_stream(
+        &self,
+        log: &RequestLog,
+        stats: StreamStats,
+        capture: Option<serde_json::Value>,
+    ) {
+        let model = log.model.as_deref().unwrap_or("unknown");
+        println!(
+            "📊 Stream finished: model={} ttft={} tokens={} {:.1} tok/s in {}ms",
+            model,
+            stats
+                .ttft_ms
+                .map(|ms| format!("{}ms", ms))
+                .unwrap_or_else(|| "-".to_string()),
+            stats.completion_tokens,
+            stats.tokens_per_second(),
+            stats.duration_ms
+        );
+
+        self.metrics.record_stream(model, &log.provider, &stats);
+        self.record_tags(log, 0, stats.prompt_tokens, stats.completion_tokens);
+        if let Some(version) = &log.prompt_version {
+            self.prompt_templates.record(
+                version,
+                None,
+                stats.prompt_tokens,
+                stats.completion_tokens,
+            );
+        }
+        if let Some(fingerprint) = &log.prompt_fingerprint {
+            self.metrics.record_prompt(
+                fingerprint,
+                None,
+                stats.prompt_tokens,
+                stats.completion_tokens,
+            );
+        }
+        if stats.panicked {
+            self.metrics.record_panic();
+        }
+        if let Some(outcome) = stats.failure {
+            self.metrics
+                .record_stream_failure(model, &log.provider, outcome);
+        }
+        if let Some(ledger) = self
+            .usage_ledger
+            .as_ref()
+            .filter(|_| stats.prompt_tokens + stats.completion_tokens > 0)
+        {
+            ledger.record(&UsageRecord::new(
+                log,
+                stats.prompt_tokens,
+                stats.completion_tokens,
+            ));
+        }
+        self.request_finished(RequestSummary {
+            prompt_tokens: Some(stats.prompt_tokens),
+            completion_tokens: Some(stats.completion_tokens),
+            ..RequestSummary::new(log, StatusCode::OK.as_u16(), stats.duration_ms)
+        });
+        if let Some(alerts) = &self.alerts {
+            alerts.record(
+                log,
+                StatusCode::OK.as_u16(),
+                stats.duration_ms,
+                Some(stats.completion_tokens),
+            );
+        }
+        if let Some(tenant) = log
+            .tenant
+            .as_ref()
+            .and_then(|name| self.tenants.iter().find(|t| &t.name == name))
+        {
+            tenant.record_usage(
+                log.model.as_deref(),
+                stats.prompt_tokens,
+                stats.completion_tokens,
+            );
+            tenant.record_stream(model, &log.provider, &stats);
+        }
+
+        if let Some(statsd) = &self.statsd {
+            let tags = [
+                ("model", model),
+                ("provider", log.provider.as_str()),
+                ("client", log.client.as_str()),
+                ("tenant", log.tenant.as_deref().unwrap_or("none")),
+            ];
+            if let Some(ttft_ms) = stats.ttft_ms {
+                statsd.timing("stream.ttft_ms", ttft_ms, &tags);
+            }
+            statsd.timing("stream.duration_ms", stats.duration_ms, &tags);
+            statsd.gauge("stream.tokens_per_second", stats.tokens_per_second(), &tags);
+        }
+
+        if let Some(mut capture) = capture {
+            capture["response"]["body"] = stats
+                .chunks
+                .iter()
+                .map(|(offset_ms, chunk)| {
+                    serde_json::json!({
+                        "offset_ms": offset_ms,
+                        "data": String::from_utf8_lossy(chunk),
+                    })
+                })
+                .collect();
+            self.save_capture(&log.request_id, capture);
+        }
+    }
+
+    // Feeds the live inspector and the feedback index
+    pub(crate) fn request_finished(&self, summary: RequestSummary) {
+        let summary = Arc::new(summary);
+        if let Some(feedback) = &self.feedback {
+            feedback.remember(summary.clone());
+        }
+        self.inspector.publish(summary);
+    }
+
+    pub(crate) fn check_admin(&self, headers: &HeaderMap) -> Result<(), ProxyError> {
+        let Some(admin_key) = &self.admin_key else {
+            return Err(ProxyError::Forbidden(
+                "Admin API is disabled, set admin_key to enable it".to_string(),
+            ));
+        };
+        match bearer_token(headers) {
+            Some(key) if key == admin_key => Ok(()),
+            _ => Err(ProxyError::Unauthorized("Invalid admin key".to_string())),
+        }
+    }
+}
+
+impl AppState {
+    pub(crate) async fn from_settings(settings: Settings) -> std::io::Result<Self> {
+        let status = StatusInfo::from_settings(&settings);
+        let flags = FeatureFlags::new(&settings.feature_flags).map_err(std::io::Error::other)?;
+        println!("📋 Configuration loaded:");
+        println!("   - Server: {}", settings.server_address());
+        println!("   - API Base: {}", settings.openai_api_base);
+        println!("   - API Version: {}", settings.api_version);
+        match &settings.openai_api_key_secret {
+            Some(secret) => println!("   - API Key: from secret {}", secret),
+            None => println!(
+                "   - API Key: {}***",
+                &settings.openai_api_key.chars().take(10).collect::<String>()
+            ),
+        }
+        println!(
+            "   - Available Models: {} models configured",
+            settings.available_models.len()
+        );
+
+        let storage = match settings.storage {
+            Some(config) => {
+                let store = Store::open(config).await.map_err(std::io::Error::other)?;
+                println!("   - Storage: {}", store.backend.name());
+                Some(Arc::new(store))
+            }
+            None => None,
+        };
+        let leadership = Arc::new(Leadership::new(storage.clone()));
+        leadership.start().await;
+
+        let access_log = match settings.access_log {
+            Some(config) => {
+                let path = config.path.clone();
+                let access_log = AccessLog::open(config).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to open access log {}: {}", path, err),
+                    )
+                })?;
+                println!("   - Access Log: {}", path);
+                Some(access_log)
+            }
+            None => None,
+        };
+
+        let usage_ledger = match settings.usage_ledger {
+            Some(config) => {
+                let path = config.path.clone();
+                let ledger = UsageLedger::open(config).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to open usage ledger {}: {}", path, err),
+                    )
+                })?;
+                println!("   - Usage Ledger: {}", path);
+                Some(ledger)
+            }
+            None => None,
+        };
+
+        let transcripts = match settings.transcripts {
+            Some(config) => {
+                let path = config.path.clone();
+                let collector = TranscriptCollector::open(config).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to open transcript file {}: {}", path, err),
+                    )
+                })?;
+                println!("   - Transcripts: {}", path);
+                Some(Arc::new(collector))
+            }
+            None => None,
+        };
+
+        let feedback = match settings.feedback {
+            Some(config) => {
+                let path = config.path.clone();
+                let store = FeedbackStore::open(config).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to open feedback file {}: {}", path, err),
+                    )
+                })?;
+                println!("   - Feedback: {}", path);
+                Some(store)
+            }
+            None => None,
+        };
+
+        let response_cache = match settings.response_cache {
+            Some(config) => {
+                let disk_path = config.disk.as_ref().map(|d| d.path.clone());
+                let shared = storage.clone().filter(|_| config.shared);
+                let max_age_secs = settings
+                    .retention
+                    .as_ref()
+                    .map(|retention| retention.bodies_days * 86400)
+                    .filter(|secs| *secs > 0);
+                let cache = ResponseCache::open(config, shared, max_age_secs).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Failed to open response cache {}: {}",
+                            disk_path.unwrap_or_default(),
+                            err
+                        ),
+                    )
+                })?;
+                if cache.config.disk.is_none() {
+                    println!(
+                        "   - Response Cache: up to {} entries in memory",
+                        cache.config.max_entries
+                    );
+                }
+                Some(cache)
+            }
+            None => None,
+        };
+
+        let file_staging = match settings.file_staging {
+            Some(config) => {
+                let directory = config.directory.clone();
+                let staging = FileStaging::open(config).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to open upload staging in {}: {}", directory, err),
+                    )
+                })?;
+                println!("   - File Staging: uploads staged in {}", directory);
+                Some(staging)
+            }
+            None => None,
+        };
+
+        let statsd = match settings.statsd {
+            Some(config) => {
+                let address = config.address.clone();
+                let statsd = StatsdClient::connect(config).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to set up statsd emitter for {}: {}", address, err),
+                    )
+                })?;
+                println!("   - Statsd: {}", address);
+                Some(statsd)
+            }
+            None => None,
+        };
+
+        let openai_api_key = UpstreamKey::new(
+            settings.openai_api_key.clone(),
+            settings.openai_api_key_secret.clone(),
+        );
+        let upstream_key_sealing = match &settings.upstream_key_encryption {
+            Some(config) => Some(
+                SealingKey::load(config, secrets_backend(settings.secrets.as_ref()).as_ref())
+                    .await
+                    .map_err(|err| {
+                        std::io::Error::other(format!(
+                            "Failed to load upstream key encryption: {}",
+                            err
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+        let tenants: Vec<Arc<Tenant>> = settings
+            .tenants
+            .into_iter()
+            .map(|mut config| {
+                for client in &mut config.clients {
+                    open_upstream_keys(&config.name, client, upstream_key_sealing.as_ref())
+                        .map_err(std::io::Error::other)?;
+                }
+                // Tenants without their own key share (and refresh) the global one
+                let tenant_key = match (config.openai_api_key, config.openai_api_key_secret) {
+                    (None, None) => openai_api_key.clone(),
+                    (key, secret) => UpstreamKey::new(key.unwrap_or_default(), secret),
+                };
+                let dictionaries = config
+                    .dictionaries
+                    .iter()
+                    .map(|d| Dictionary::new(d, Some(&config.name)).map(Arc::new))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(std::io::Error::other)?;
+                Ok(Arc::new(Tenant {
+                    name: config.name,
+                    clients: config.clients,
+                    openai_api_key: tenant_key,
+                    openai_api_base: config
+                        .openai_api_base
+                        .unwrap_or_else(|| settings.openai_api_base.clone()),
+                    available_models: if config.available_models.is_empty() {
+                        settings.available_models.clone()
+                    } else {
+                        config.available_models
+                    },
+                    token_budget: config.token_budget,
+                    budget_period: config.budget_period,
+                    model_budgets: config.model_budgets,
+                    dictionaries,
+                    usage: Mutex::new(TenantUsage::default()),
+                    storage: storage.clone(),
+                }))
+            })
+            .collect::<std::io::Result<_>>()?;
+        let dictionaries = settings
+            .dictionaries
+            .iter()
+            .map(|d| Dictionary::new(d, None).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(std::io::Error::other)?;
+        let rules = settings
+            .rules
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(std::io::Error::other)?;
+        if !rules.is_empty() {
+            println!("   - Rules: {} request rules", rules.len());
+        }
+        let prompt_templates =
+            PromptTemplates::new(&settings.prompt_templates).map_err(std::io::Error::other)?;
+        if !prompt_templates.is_empty() {
+            println!(
+                "   - Prompt templates: {} templates",
+                prompt_templates.len()
+            );
+        }
+        if !tenants.is_empty() {
+            println!("   - Tenants: {} tenants configured", tenants.len());
+        }
+        // Budgets pick up where earlier runs and the other replicas are
+        if let Some(storage) = storage.as_ref().filter(|_| !tenants.is_empty()) {
+            for tenant in &tenants {
+                if let Err(err) = tenant.sync_usage().await {
+                    eprintln!(
+                        "⚠️  Failed to load usage of tenant {}: {}",
+                        tenant.name, err
+                    );
+                }
+            }
+            let tenants = tenants.clone();
+            let period = Duration::from_secs(storage.config.sync_interval_secs.max(1));
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(period).await;
+                    for tenant in &tenants {
+                        if let Err(err) = tenant.sync_usage().await {
+                            eprintln!(
+                                "⚠️  Failed to sync usage of tenant {}: {}",
+                                tenant.name, err
+                            );
+                        }
+                    }
+                }
+            });
+        }
+
+        let providers: Vec<Provider> = settings
+            .providers
+            .into_iter()
+            .map(|mut config| {
+                let api_key = match (config.api_key.take(), config.api_key_secret.clone()) {
+                    (None, None) => None,
+                    (key, secret) => Some(UpstreamKey::new(key.unwrap_or_default(), secret)),
+                };
+                let pooled = config
+                    .keys
+                    .iter_mut()
+                    .map(|k| {
+                        let key = UpstreamKey::new(
+                            k.api_key.take().unwrap_or_default(),
+                            k.api_key_secret.clone(),
+                        );
+                        (k.name.clone(), key)
+                    })
+                    .collect();
+                Provider::new(config, api_key, pooled)
+            })
+            .collect();
+        for provider in &providers {
+            if let Some(err) = provider
+                .config
+                .maintenance
+                .iter()
+                .find_map(|w| w.validate().err())
+            {
+                return Err(std::io::Error::other(format!(
+                    "Maintenance window of provider {}: {}",
+                    provider.config.name, err
+                )));
+            }
+            println!(
+                "   - Provider {}: {} replicas{}",
+                provider.config.name,
+                provider.bases().len(),
+                provider
+                    .key_pool
+                    .as_ref()
+                    .map(|pool| format!(", {} pooled keys", pool.keys.len()))
+                    .unwrap_or_default()
+            );
+            if provider.config.pacing.is_some() && provider.pacer.is_none() {
+                eprintln!(
+                    "⚠️  Pacing of provider {} needs an rpm, requests are not paced",
+                    provider.config.name
+                );
+            }
+        }
+        let all_models = settings
+            .available_models
+            .iter()
+            .chain(tenants.iter().flat_map(|t| t.available_models.iter()));
+        for model in all_models {
+            for name in model.provider.iter().chain(model.fallbacks.iter()) {
+                if !providers.iter().any(|p| &p.config.name == name) {
+                    return Err(std::io::Error::other(format!(
+                        "Model {} uses unknown provider {}",
+                        model.id, name
+                    )));
+                }
+            }
+        }
+
+        let post_processors = build_post_processors(
+            settings
+                .available_models
+                .iter()
+                .chain(tenants.iter().flat_map(|t| t.available_models.iter())),
+        )
+        .map_err(std::io::Error::other)?;
+
+        validate_rules(&settings.routing.rules).map_err(std::io::Error::other)?;
+        let model_matcher = {
+            let models = settings
+                .available_models
+                .iter()
+                .chain(tenants.iter().flat_map(|t| t.available_models.iter()))
+                .map(|m| &m.id);
+            let rules = settings
+                .routing
+                .rules
+                .iter()
+                .flat_map(|r| std::iter::once(&r.model).chain(&r.candidates));
+            let clients = tenants
+                .iter()
+                .flat_map(|t| &t.clients)
+                .flat_map(|c| c.allowed_models.iter().flatten());
+            let budgets = tenants
+                .iter()
+                .flat_map(|t| &t.model_budgets)
+                .map(|b| &b.models);
+            let catalog = settings
+                .model_catalog
+                .show
+                .iter()
+                .chain(&settings.model_catalog.hide);
+            let transcribed = transcripts.iter().flat_map(|t| &t.config.models);
+            let selectors = models
+                .chain(rules)
+                .chain(clients)
+                .chain(budgets)
+                .chain(catalog)
+                .chain(transcribed)
+                .map(|s| s.as_str());
+            ModelMatcher::new(settings.model_groups, selectors).map_err(std::io::Error::other)?
+        };
+        if !model_matcher.is_empty() {
+            println!(
+                "   - Model Groups: {}",
+                model_matcher.group_names().join(", ")
+            );
+        }
+        if settings
+            .connections
+            .max_header_bytes
+            .is_some_and(|max| max < 8192)
+        {
+            return Err(std::io::Error::other(
+                "connections.max_header_bytes must be at least 8192",
+            ));
+        }
+        if settings.connections.max_connections == Some(0) {
+            return Err(std::io::Error::other(
+                "connections.max_connections must be at least 1",
+            ));
+        }
+        let clients = tenants.iter().flat_map(|t| t.clients.iter());
+        for (client, name) in clients.filter_map(|c| c.profile.as_ref().map(|p| (c, p))) {
+            if !settings.parameter_profiles.iter().any(|p| &p.name == name) {
+                return Err(std::io::Error::other(format!(
+                    "Client {} uses unknown parameter profile {}",
+                    client.name, name
+                )));
+            }
+        }
+
+        let connection_stats = Arc::new(ConnectionStats::default());
+        let upstream = &settings.upstream_connections;
+        let resolver = UpstreamResolver::new(upstream, connection_stats.clone())
+            .map_err(std::io::Error::other)?;
+        let mut client = reqwest::Client::builder()
+            .dns_resolver(resolver.shared())
+            .pool_idle_timeout(Duration::from_secs(upstream.pool_idle_timeout_secs));
+        if let Some(max_idle) = upstream.pool_max_idle_per_host {
+            client = client.pool_max_idle_per_host(max_idle);
+        }
+        let client = client.build().map_err(std::io::Error::other)?;
+        let image_client =
+            crate::guardrails::image_client(&settings.guardrails).map_err(std::io::Error::other)?;
+        let fine_tunes = settings.fine_tunes.map(|config| {
+            println!(
+                "   - Fine-tuning: jobs polled every {}s, {} webhooks",
+                config.poll_interval_secs,
+                config.webhooks.len()
+            );
+            FineTunes::new(config, client.clone())
+        });
+        if let Some(ttl) = upstream.dns.cache_ttl_secs {
+            println!(
+                "   - Upstream DNS: cached for {}s, {} pinned hosts",
+                ttl,
+                upstream.dns.hosts.len()
+            );
+        } else if !upstream.dns.hosts.is_empty() {
+            println!(
+                "   - Upstream DNS: {} pinned hosts",
+                upstream.dns.hosts.len()
+            );
+        }
+        if upstream.ip_family != IpFamily::System {
+            println!("   - Upstream IP family: {:?}", upstream.ip_family);
+        }
+
+        let secrets = match settings.secrets {
+            Some(config) => {
+                let mut keys = vec![openai_api_key.clone()];
+                let tenant_keys = tenants.iter().map(|t| &t.openai_api_key);
+                let provider_keys = providers.iter().flat_map(|p| {
+                    let pooled = p.key_pool.iter().flat_map(|pool| &pool.keys);
+                    p.api_key.iter().chain(pooled.map(|k| &k.key))
+                });
+                for key in tenant_keys.chain(provider_keys) {
+                    if !keys.iter().any(|k| Arc::ptr_eq(k, key)) {
+                        keys.push(key.clone());
+                    }
+                }
+                let backend = Arc::new(SecretsBackend {
+                    config,
+                    client: client.clone(),
+                    keys,
+                });
+                if let Err(err) = backend.refresh_all().await {
+                    return Err(std::io::Error::other(format!(
+                        "Failed to load secrets: {}",
+                        err
+                    )));
+                }
+                println!("   - Secrets Backend: {}", backend.config.backend);
+
+                if backend.config.refresh_interval_secs > 0 {
+                    let refresher = backend.clone();
+                    tokio::spawn(async move {
+                        let period = Duration::from_secs(refresher.config.refresh_interval_secs);
+                        loop {
+                            tokio::time::sleep(period).await;
+                            if let Err(err) = refresher.refresh_all().await {
+                                eprintln!("⚠️  Secret refresh failed: {}", err);
+                            }
+                        }
+                    });
+                }
+                Some(backend)
+            }
+            None => None,
+        };
+
+        let capture_cipher = match &settings.capture_encryption {
+            Some(config) => Some(SealingKey::load(config, secrets.as_deref()).await.map_err(
+                |err| std::io::Error::other(format!("Failed to load capture key: {}", err)),
+            )?),
+            None => None,
+        };
+
+        let agent_jobs = match settings.agent_jobs {
+            Some(config) => {
+                let path = config.journal.clone();
+                let key = match &config.encryption {
+                    Some(key) => Some(SealingKey::load(key, secrets.as_deref()).await.map_err(
+                        |err| {
+                            std::io::Error::other(format!("Failed to load agent job key: {}", err))
+                        },
+                    )?),
+                    None => None,
+                };
+                let jobs = AgentJobs::open(config, key).map_err(|err| {
+                    std::io::Error::new(
+                        err.kind(),
+                        format!("Failed to open agent job journal {}: {}", path, err),
+                    )
+                })?;
+                println!("   - Agent Jobs: journaled to {}", path);
+                Some(jobs)
+            }
+            None => None,
+        };
+
+        let limits =
+            ConcurrencyLimits::new(settings.concurrency, &settings.available_models, &tenants);
+        let shared_limits = storage
+            .as_ref()
+            .and_then(|store| Some((store, store.config.rate_limits.clone()?)));
+        if let Some((store, config)) = shared_limits {
+            let provider_quotas = providers.iter().filter_map(|provider| {
+                let quota = provider.quota.as_ref()?;
+                Some((format!("provider:{}", provider.config.name), quota))
+            });
+            let client_quotas = limits
+                .client_quotas
+                .iter()
+                .map(|(client, quota)| (format!("client:{}", client), quota));
+            let mut shared = 0;
+            for (name, quota) in provider_quotas.chain(client_quotas) {
+                quota.share(store.clone(), name, config.clone());
+                shared += 1;
+            }
+            println!("   - Rate Limits: {} shared through the storage", shared);
+        }
+
+        if settings.modes.maintenance {
+            println!(
+                "   - Maintenance Mode: on, {} clients allowed",
+                settings.modes.allowed_clients.len()
+            );
+        }
+        if settings.modes.read_only {
+            println!("   - Read-Only Mode: on");
+        }
+        if settings.chaos.enabled {
+            println!("   - Fault Injection: on, for testing only");
+        }
+        let disabled = flags.disabled();
+        if !disabled.is_empty() {
+            println!("   - Feature Flags: {} off", disabled.join(", "));
+        }
+
+        let alerts = settings.alerts.map(|config| {
+            println!(
+                "   - Alerts: evaluated over {}s windows",
+                config.window_secs
+            );
+            AlertMonitor::new(config, client.clone(), leadership.clone())
+        });
+
+        Ok(AppState {
+            openai_api_key,
+            openai_api_base: settings.openai_api_base,
+            client,
+            available_models: settings.available_models,
+            access_log,
+            statsd,
+            model_catalog: settings.model_catalog,
+            model_matcher,
+            tenants,
+            secrets,
+            key_passthrough: settings.key_passthrough,
+            hmac_max_skew_secs: settings.hmac_max_skew_secs,
+            hmac_nonces: Mutex::new(HashMap::new()),
+            image_client,
+            guardrails: settings.guardrails,
+            admin_key: settings.admin_key,
+            capture: RwLock::new(settings.debug_capture),
+            capture_cipher,
+            modes: RwLock::new(settings.modes),
+            chaos: Chaos::new(settings.chaos),
+            status,
+            flags,
+            buffers: BufferPool::default(),
+            connection_stats,
+            request_counter: AtomicU64::new(0),
+            metrics: Metrics::default(),
+            alerts,
+            limits,
+            upstream_overrides: UpstreamOverrides::default(),
+            providers,
+            finish_reasons: settings
+                .finish_reasons
+                .normalize
+                .then(|| Arc::new(finish_reason_map(&settings.finish_reasons.map))),
+            translate_upstream_errors: settings.translate_upstream_errors,
+            json_errors: settings.json_errors,
+            metadata_headers: settings.metadata_headers,
+            retries: settings.retries,
+            completion_retry: settings.completion_retry,
+            composite_models: settings.composite_models,
+            post_processors,
+            routing_rules: settings.routing.rules,
+            allow_routing_overrides: settings.routing.allow_overrides,
+            allow_dry_run: settings.allow_dry_run,
+            trace_context: settings.trace_context,
+            deadlines: settings.deadlines,
+            constrained_output: settings.constrained_output,
+            stream_pacing: settings.stream_pacing,
+            stream_salvage: settings.stream_salvage,
+            stream_resumption: settings
+                .stream_resumption
+                .map(|config| StreamBuffers::new(config, storage.clone())),
+            loop_detection: settings.loop_detection,
+            dictionaries,
+            rules,
+            prompt_templates,
+            integrity: settings
+                .integrity
+                .map(|config| Integrity::new(config, &settings.passthrough.paths)),
+            passthrough_paths: settings.passthrough.paths,
+            file_staging,
+            fine_tunes,
+            probes: settings.probes.map(|config| {
+                println!(
+                    "   - Probes: {} models every {}s",
+                    config.models.len(),
+                    config.interval_secs
+                );
+                Probes::new(config)
+            }),
+            parameter_profiles: settings.parameter_profiles,
+            inspector: Inspector::default(),
+            usage_ledger,
+            transcripts,
+            feedback,
+            retention: settings.retention,
+            localization: settings.localization.map(MessageCatalog::new),
+            request_tags: settings.request_tags,
+            response_cache,
+            agent_jobs,
+            audit: AuditLog::new(storage.clone()),
+            storage,
+            leadership,
+        })
+    }
+}
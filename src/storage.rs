@@ -0,0 +1,645 @@
+// Shared state behind one interface, so replicas and restarts see the same
+// usage counters, cached responses, audit trail and stream sessions: in
+// memory, in SQLite, in Redis or, built with the "postgres" feature, in
+// Postgres
+
+use crate::config::StorageConfig;
+#[cfg(feature = "postgres")]
+use crate::postgres::PostgresStorage;
+use crate::sqlite::SqliteStorage;
+use crate::time::unix_now;
+use futures_util::future::BoxFuture;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+pub(crate) type StorageResult<T> = Result<T, String>;
+
+// Keys are namespaced by the caller, e.g. "usage:acme:24300". Values serve the
+// response cache, hashes of integer counters the usage, and logs the audit
+// trail and stream sessions.
+pub(crate) trait Storage: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<Option<Vec<u8>>>>;
+    // Expires after ttl_secs, or never without it
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>>;
+    // Removes a value, hash or log
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<()>>;
+    // Adds to a hash field and returns its new value. With ttl_secs the hash
+    // expires that long after its last update.
+    fn hincr<'a>(
+        &'a self,
+        key: &'a str,
+        field: &'a str,
+        by: i64,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<i64>>;
+    fn hgetall<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>>;
+    // Adds to several fields of a hash in one step and returns the whole hash
+    // after it. Replicas updating the same budget or rate limit take turns,
+    // so each reads the hash with every earlier update in it.
+    fn hincr_all<'a>(
+        &'a self,
+        key: &'a str,
+        counts: &'a [(String, i64)],
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>>;
+    // Adds an entry to the end of a log. With ttl_secs the log expires that
+    // long after its last entry.
+    fn append<'a>(
+        &'a self,
+        key: &'a str,
+        entry: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>>;
+    // The entries of a log from index start on, oldest first
+    fn range<'a>(
+        &'a self,
+        key: &'a str,
+        start: usize,
+    ) -> BoxFuture<'a, StorageResult<Vec<Vec<u8>>>>;
+    // Takes or renews a lease on key for ttl_secs; false while another owner
+    // holds it. get returns the owner.
+    fn acquire_lease<'a>(
+        &'a self,
+        key: &'a str,
+        owner: &'a str,
+        ttl_secs: u64,
+    ) -> BoxFuture<'a, StorageResult<bool>>;
+}
+
+// The configured backend, with the key prefix
+pub(crate) struct Store {
+    pub(crate) config: StorageConfig,
+    pub(crate) backend: Box<dyn Storage>,
+}
+
+impl Store {
+    pub(crate) async fn open(config: StorageConfig) -> Result<Self, String> {
+        let url = |backend: &str| {
+            config
+                .url
+                .as_deref()
+                .ok_or_else(|| format!("The {} storage backend needs a url", backend))
+        };
+        let backend: Box<dyn Storage> =
+            match config.backend.as_str() {
+                "memory" => Box::new(MemoryStorage::default()),
+                "sqlite" => Box::new(SqliteStorage::open(url("sqlite")?, &config).await?),
+                "redis" => Box::new(RedisStorage::new(url("redis")?, &config)?),
+                #[cfg(feature = "postgres")]
+                "postgres" => Box::new(PostgresStorage::open(url("postgres")?, &config).await?),
+                #[cfg(not(feature = "postgres"))]
+                "postgres" => return Err(
+                    "This build has no postgres storage backend, build it with --features postgres"
+                        .to_string(),
+                ),
+                other => {
+                    return Err(format!(
+                        "Unknown storage backend {}, expected memory, sqlite, redis or postgres",
+                        other
+                    ))
+                }
+            };
+        Ok(Self { config, backend })
+    }
+
+    pub(crate) fn key(&self, name: &str) -> String {
+        format!("{}{}", self.config.prefix, name)
+    }
+}
+
+// Entries with their expiry in unix seconds. An expired entry is dropped when
+// it is looked up, and all of them whenever the map has doubled since the
+// last sweep, so keys never read again do not pile up.
+struct Expiring<T> {
+    entries: HashMap<String, (T, Option<u64>)>,
+    swept_len: usize,
+}
+
+impl<T> Default for Expiring<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            swept_len: 0,
+        }
+    }
+}
+
+// Below this many entries the map is not swept
+const MIN_SWEEP_LEN: usize = 64;
+
+impl<T: Default> Expiring<T> {
+    fn get(&mut self, key: &str, now: u64) -> Option<&mut T> {
+        self.expire(key, now);
+        self.entries.get_mut(key).map(|(value, _)| value)
+    }
+
+    // The live entry for key, created empty when missing, expiring at expires
+    fn entry(&mut self, key: &str, now: u64, expires: Option<u64>) -> &mut T {
+        self.sweep(now);
+        self.expire(key, now);
+        let (value, expiry) = self.entries.entry(key.to_string()).or_default();
+        *expiry = expires;
+        value
+    }
+
+    fn insert(&mut self, key: &str, value: T, now: u64, expires: Option<u64>) {
+        self.sweep(now);
+        self.entries.insert(key.to_string(), (value, expires));
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    fn expire(&mut self, key: &str, now: u64) {
+        let expired = self
+            .entries
+            .get(key)
+            .is_some_and(|(_, expires)| expires.is_some_and(|at| at <= now));
+        if expired {
+            self.entries.remove(key);
+        }
+    }
+
+    fn sweep(&mut self, now: u64) {
+        if self.entries.len() < 2 * self.swept_len.max(MIN_SWEEP_LEN) {
+            return;
+        }
+        self.entries
+            .retain(|_, (_, expires)| expires.is_none_or(|at| at > now));
+        self.swept_len = self.entries.len();
+    }
+}
+
+// Process-local, for single instances and tests
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    values: Mutex<Expiring<Vec<u8>>>,
+    hashes: Mutex<Expiring<BTreeMap<String, i64>>>,
+    logs: Mutex<Expiring<Vec<Vec<u8>>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let mut values = self.values.lock().unwrap();
+            Ok(values.get(key, unix_now()).cloned())
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            let now = unix_now();
+            let expires = ttl_secs.map(|ttl| now + ttl);
+            self.values.lock().unwrap().insert(key, value, now, expires);
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            self.values.lock().unwrap().remove(key);
+            self.hashes.lock().unwrap().remove(key);
+            self.logs.lock().unwrap().remove(key);
+            Ok(())
+        })
+    }
+
+    fn hincr<'a>(
+        &'a self,
+        key: &'a str,
+        field: &'a str,
+        by: i64,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<i64>> {
+        Box::pin(async move {
+            let now = unix_now();
+            let mut hashes = self.hashes.lock().unwrap();
+            let hash = hashes.entry(key, now, ttl_secs.map(|ttl| now + ttl));
+            let value = hash.entry(field.to_string()).or_default();
+            *value += by;
+            Ok(*value)
+        })
+    }
+
+    fn hgetall<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>> {
+        Box::pin(async move {
+            let mut hashes = self.hashes.lock().unwrap();
+            Ok(hashes.get(key, unix_now()).cloned().unwrap_or_default())
+        })
+    }
+
+    fn hincr_all<'a>(
+        &'a self,
+        key: &'a str,
+        counts: &'a [(String, i64)],
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>> {
+        Box::pin(async move {
+            let now = unix_now();
+            let mut hashes = self.hashes.lock().unwrap();
+            let hash = hashes.entry(key, now, ttl_secs.map(|ttl| now + ttl));
+            for (field, by) in counts {
+                *hash.entry(field.clone()).or_default() += by;
+            }
+            Ok(hash.clone())
+        })
+    }
+
+    fn append<'a>(
+        &'a self,
+        key: &'a str,
+        entry: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            let now = unix_now();
+            let mut logs = self.logs.lock().unwrap();
+            logs.entry(key, now, ttl_secs.map(|ttl| now + ttl))
+                .push(entry);
+            Ok(())
+        })
+    }
+
+    fn range<'a>(
+        &'a self,
+        key: &'a str,
+        start: usize,
+    ) -> BoxFuture<'a, StorageResult<Vec<Vec<u8>>>> {
+        Box::pin(async move {
+            let mut logs = self.logs.lock().unwrap();
+            Ok(logs
+                .get(key, unix_now())
+                .map(|log| log.iter().skip(start).cloned().collect())
+                .unwrap_or_default())
+        })
+    }
+
+    fn acquire_lease<'a>(
+        &'a self,
+        key: &'a str,
+        owner: &'a str,
+        ttl_secs: u64,
+    ) -> BoxFuture<'a, StorageResult<bool>> {
+        Box::pin(async move {
+            let mut values = self.values.lock().unwrap();
+            let now = unix_now();
+            let held = values
+                .get(key, now)
+                .is_some_and(|value| value != owner.as_bytes());
+            if !held {
+                values.insert(key, owner.into(), now, Some(now + ttl_secs));
+            }
+            Ok(!held)
+        })
+    }
+}
+
+// A RESP client with a small pool of connections; one that fails is dropped
+// and replaced by a new one when needed
+pub(crate) struct RedisStorage {
+    address: String,
+    password: Option<String>,
+    database: u32,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+    // One per connection open at most
+    permits: Semaphore,
+    acquire_timeout: Duration,
+}
+
+// A reply, with errors already turned into Err
+enum Reply {
+    Nil,
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Array(Vec<Reply>),
+}
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Takes a free lease, or renews it when it is ours; 1 when we hold it
+const LEASE_SCRIPT: &str = "\
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'EX', ARGV[2]) then return 1 end \
+if redis.call('GET', KEYS[1]) == ARGV[1] then \
+redis.call('EXPIRE', KEYS[1], ARGV[2]) return 1 end \
+return 0";
+
+// KEYS[1] is the hash, ARGV[1] its TTL or 0, then fields and increments
+const HINCR_ALL_SCRIPT: &str = "\
+for i = 2, #ARGV, 2 do redis.call('HINCRBY', KEYS[1], ARGV[i], ARGV[i + 1]) end \
+if tonumber(ARGV[1]) > 0 then redis.call('EXPIRE', KEYS[1], ARGV[1]) end \
+return redis.call('HGETALL', KEYS[1])";
+
+// Field and value pairs, as HGETALL returns them
+fn hash_reply(reply: Reply) -> BTreeMap<String, i64> {
+    let Reply::Array(items) = reply else {
+        return BTreeMap::new();
+    };
+    let mut fields = BTreeMap::new();
+    let mut items = items.into_iter();
+    while let (Some(Reply::Bytes(field)), Some(Reply::Bytes(value))) = (items.next(), items.next())
+    {
+        let value = String::from_utf8_lossy(&value).parse().unwrap_or(0);
+        fields.insert(String::from_utf8_lossy(&field).into_owned(), value);
+    }
+    fields
+}
+
+impl RedisStorage {
+    // redis://[:password@]host[:port][/database]
+    fn new(url: &str, config: &StorageConfig) -> Result<Self, String> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid redis url: {}", e))?;
+        if parsed.scheme() != "redis" {
+            return Err(format!("Invalid redis url {}: expected redis://", url));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| format!("Invalid redis url {}: no host", url))?;
+        let database = match parsed.path().trim_start_matches('/') {
+            "" => 0,
+            db => db
+                .parse()
+                .map_err(|_| format!("Invalid redis url {}: bad database {}", url, db))?,
+        };
+        Ok(Self {
+            address: format!("{}:{}", host, parsed.port().unwrap_or(6379)),
+            password: parsed.password().map(str::to_string),
+            database,
+            idle: Mutex::new(Vec::new()),
+            permits: Semaphore::new(config.max_connections.max(1)),
+            acquire_timeout: Duration::from_millis(config.acquire_timeout_ms),
+        })
+    }
+
+    async fn connect(&self) -> StorageResult<BufReader<TcpStream>> {
+        let stream = TcpStream::connect(&self.address)
+            .await
+            .map_err(|e| format!("Failed to connect to redis at {}: {}", self.address, e))?;
+        let mut connection = BufReader::new(stream);
+        if let Some(password) = &self.password {
+            round_trip(&mut connection, &[b"AUTH", password.as_bytes()]).await?;
+        }
+        if self.database != 0 {
+            let database = self.database.to_string();
+            round_trip(&mut connection, &[b"SELECT", database.as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn command(&self, args: &[&[u8]]) -> StorageResult<Reply> {
+        let _permit = tokio::time::timeout(self.acquire_timeout, self.permits.acquire())
+            .await
+            .map_err(|_| "Timed out waiting for a redis connection".to_string())?
+            .map_err(|e| e.to_string())?;
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => self.connect().await?,
+        };
+        let reply = tokio::time::timeout(REDIS_TIMEOUT, round_trip(&mut connection, args))
+            .await
+            .unwrap_or_else(|_| Err("Redis command timed out".to_string()));
+        // The connection may be out of step after an error
+        if reply.is_ok() {
+            self.idle.lock().unwrap().push(connection);
+        }
+        reply
+    }
+}
+
+async fn round_trip(connection: &mut BufReader<TcpStream>, args: &[&[u8]]) -> StorageResult<Reply> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    connection
+        .get_mut()
+        .write_all(&request)
+        .await
+        .map_err(|e| format!("Redis write failed: {}", e))?;
+    read_reply(connection).await
+}
+
+fn read_reply(connection: &mut BufReader<TcpStream>) -> BoxFuture<'_, StorageResult<Reply>> {
+    Box::pin(async move {
+        let mut line = String::new();
+        connection
+            .read_line(&mut line)
+            .await
+            .map_err(|e| format!("Redis read failed: {}", e))?;
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(line.len().min(1));
+        let number = || {
+            rest.parse::<i64>()
+                .map_err(|_| format!("Unexpected redis reply: {}", line))
+        };
+        match kind {
+            "+" => Ok(Reply::Bytes(rest.as_bytes().to_vec())),
+            "-" => Err(format!("Redis error: {}", rest)),
+            ":" => Ok(Reply::Integer(number()?)),
+            "$" => {
+                let Ok(len) = usize::try_from(number()?) else {
+                    return Ok(Reply::Nil);
+                };
+                let mut data = vec![0; len + 2];
+                connection
+                    .read_exact(&mut data)
+                    .await
+                    .map_err(|e| format!("Redis read failed: {}", e))?;
+                data.truncate(len);
+                Ok(Reply::Bytes(data))
+            }
+            "*" => {
+                let Ok(len) = usize::try_from(number()?) else {
+                    return Ok(Reply::Nil);
+                };
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(read_reply(connection).await?);
+                }
+                Ok(Reply::Array(items))
+            }
+            _ => Err(format!("Unexpected redis reply: {}", line)),
+        }
+    })
+}
+
+impl Storage for RedisStorage {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            match self.command(&[b"GET", key.as_bytes()]).await? {
+                Reply::Bytes(value) => Ok(Some(value)),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            match ttl_secs {
+                Some(ttl) => {
+                    let ttl = ttl.max(1).to_string();
+                    self.command(&[b"SET", key.as_bytes(), &value, b"EX", ttl.as_bytes()])
+                        .await?
+                }
+                None => self.command(&[b"SET", key.as_bytes(), &value]).await?,
+            };
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            self.command(&[b"DEL", key.as_bytes()]).await?;
+            Ok(())
+        })
+    }
+
+    fn hincr<'a>(
+        &'a self,
+        key: &'a str,
+        field: &'a str,
+        by: i64,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<i64>> {
+        Box::pin(async move {
+            let by = by.to_string();
+            let args: [&[u8]; 4] = [b"HINCRBY", key.as_bytes(), field.as_bytes(), by.as_bytes()];
+            let Reply::Integer(value) = self.command(&args).await? else {
+                return Err("Unexpected reply to HINCRBY".to_string());
+            };
+            if let Some(ttl) = ttl_secs {
+                let ttl = ttl.max(1).to_string();
+                self.command(&[b"EXPIRE", key.as_bytes(), ttl.as_bytes()])
+                    .await?;
+            }
+            Ok(value)
+        })
+    }
+
+    fn hgetall<'a>(&'a self, key: &'a str) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>> {
+        Box::pin(async move {
+            let reply = self.command(&[b"HGETALL", key.as_bytes()]).await?;
+            Ok(hash_reply(reply))
+        })
+    }
+
+    // One script, which Redis runs without interleaving other commands
+    fn hincr_all<'a>(
+        &'a self,
+        key: &'a str,
+        counts: &'a [(String, i64)],
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<BTreeMap<String, i64>>> {
+        Box::pin(async move {
+            let ttl = ttl_secs.map_or(0, |ttl| ttl.max(1)).to_string();
+            let increments: Vec<String> = counts.iter().map(|(_, by)| by.to_string()).collect();
+            let mut args: Vec<&[u8]> = vec![
+                b"EVAL",
+                HINCR_ALL_SCRIPT.as_bytes(),
+                b"1",
+                key.as_bytes(),
+                ttl.as_bytes(),
+            ];
+            for ((field, _), by) in counts.iter().zip(&increments) {
+                args.push(field.as_bytes());
+                args.push(by.as_bytes());
+            }
+            let reply = self.command(&args).await?;
+            Ok(hash_reply(reply))
+        })
+    }
+
+    fn append<'a>(
+        &'a self,
+        key: &'a str,
+        entry: Vec<u8>,
+        ttl_secs: Option<u64>,
+    ) -> BoxFuture<'a, StorageResult<()>> {
+        Box::pin(async move {
+            self.command(&[b"RPUSH", key.as_bytes(), &entry]).await?;
+            if let Some(ttl) = ttl_secs {
+                let ttl = ttl.max(1).to_string();
+                self.command(&[b"EXPIRE", key.as_bytes(), ttl.as_bytes()])
+                    .await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn range<'a>(
+        &'a self,
+        key: &'a str,
+        start: usize,
+    ) -> BoxFuture<'a, StorageResult<Vec<Vec<u8>>>> {
+        Box::pin(async move {
+            let start = start.to_string();
+            let args: [&[u8]; 4] = [b"LRANGE", key.as_bytes(), start.as_bytes(), b"-1"];
+            let Reply::Array(items) = self.command(&args).await? else {
+                return Ok(Vec::new());
+            };
+            Ok(items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Reply::Bytes(entry) => Some(entry),
+                    _ => None,
+                })
+                .collect())
+        })
+    }
+
+    // One script, so a lease that expires and is taken by another owner
+    // between the check and the renewal is not extended
+    fn acquire_lease<'a>(
+        &'a self,
+        key: &'a str,
+        owner: &'a str,
+        ttl_secs: u64,
+    ) -> BoxFuture<'a, StorageResult<bool>> {
+        Box::pin(async move {
+            let ttl = ttl_secs.max(1).to_string();
+            let args: [&[u8]; 6] = [
+                b"EVAL",
+                LEASE_SCRIPT.as_bytes(),
+                b"1",
+                key.as_bytes(),
+                owner.as_bytes(),
+                ttl.as_bytes(),
+            ];
+            match self.command(&args).await? {
+                Reply::Integer(taken) => Ok(taken == 1),
+                _ => Err("Unexpected reply to the lease script".to_string()),
+            }
+        })
+    }
+}
@@ -0,0 +1,119 @@
+// Request labels for cost attribution, from an x-proxy-tags header such as
+// "app=chatbot,env=prod" and the request's metadata field. They are kept in
+// usage records and captures, and the configured keys become metric labels.
+
+use crate::config::{RequestTagsConfig, TagForwarding};
+use crate::error::ProxyError;
+use crate::request::{OutgoingRequest, RequestTransform};
+use axum::http::HeaderMap;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+const MAX_TAGS: usize = 16;
+const MAX_TAG_CHARS: usize = 64;
+
+fn check(key: &str, value: &str) -> Result<(), ProxyError> {
+    let valid = |text: &str| !text.is_empty() && text.chars().count() <= MAX_TAG_CHARS;
+    if valid(key) && valid(value) {
+        Ok(())
+    } else {
+        Err(ProxyError::InvalidRequest(format!(
+            "Invalid tag {}={}, keys and values need 1 to {} characters",
+            key, value, MAX_TAG_CHARS
+        )))
+    }
+}
+
+// The tags of the x-proxy-tags header
+pub(crate) fn header_tags(headers: &HeaderMap) -> Result<BTreeMap<String, String>, ProxyError> {
+    let mut tags = BTreeMap::new();
+    let Some(header) = headers.get("x-proxy-tags") else {
+        return Ok(tags);
+    };
+    let header = header
+        .to_str()
+        .map_err(|_| ProxyError::InvalidRequest("Invalid x-proxy-tags header".to_string()))?;
+    for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').ok_or_else(|| {
+            ProxyError::InvalidRequest(format!("Invalid tag {}, use key=value", pair))
+        })?;
+        let (key, value) = (key.trim(), value.trim());
+        check(key, value)?;
+        tags.insert(key.to_string(), value.to_string());
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(ProxyError::InvalidRequest(format!(
+            "At most {} tags per request",
+            MAX_TAGS
+        )));
+    }
+    Ok(tags)
+}
+
+// Adds the string entries of the request's metadata; the header's win
+fn merge_metadata(tags: &mut BTreeMap<String, String>, obj: &Map<String, Value>) {
+    let Some(metadata) = obj.get("metadata").and_then(|m| m.as_object()) else {
+        return;
+    };
+    for (key, value) in metadata {
+        let Some(value) = value.as_str() else {
+            continue;
+        };
+        if tags.len() < MAX_TAGS && check(key, value).is_ok() {
+            tags.entry(key.clone()).or_insert_with(|| value.to_string());
+        }
+    }
+}
+
+// Passes the tags on upstream, in the metadata field or as the user
+fn forward(
+    config: &RequestTagsConfig,
+    tags: &BTreeMap<String, String>,
+    obj: &mut Map<String, Value>,
+) {
+    if tags.is_empty() {
+        return;
+    }
+    match config.forward {
+        TagForwarding::None => {}
+        TagForwarding::Metadata => {
+            let metadata = obj
+                .entry("metadata")
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let Some(metadata) = metadata.as_object_mut() {
+                for (key, value) in tags {
+                    metadata.insert(key.clone(), value.clone().into());
+                }
+            }
+        }
+        // A user named by the client is kept
+        TagForwarding::User => {
+            if obj.get("user").is_none_or(|u| u.is_null()) {
+                let user: Vec<String> = tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                obj.insert("user".to_string(), user.join(",").into());
+            }
+        }
+    }
+}
+
+// Adds the metadata's tags to the header's and passes them all on
+pub(crate) struct ForwardTags;
+
+impl RequestTransform for ForwardTags {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let obj = request.body.as_object_mut().unwrap();
+        merge_metadata(&mut request.log.tags, obj);
+        forward(&request.state.request_tags, &request.log.tags, obj);
+        Ok(())
+    }
+}
+
+// The tags that are metric labels
+pub(crate) fn metric_tags<'a>(
+    config: &'a RequestTagsConfig,
+    tags: &'a BTreeMap<String, String>,
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    tags.iter()
+        .filter(|(key, _)| config.metric_keys.contains(key))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+}
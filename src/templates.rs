@@ -0,0 +1,445 @@
+// System prompts kept by the proxy in versions. A request names a template in
+// its prompt_template field and gets the stable version, or the version being
+// rolled out for its share of requests, rendered with its variables. Rollouts
+// and rollbacks through /admin/prompts take effect immediately and last until
+// restart.
+
+use crate::config::{PromptRollout, PromptTemplateConfig};
+use crate::error::ProxyError;
+use crate::metrics::escape_label;
+use crate::prompts;
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::router::json_response;
+use crate::state::AppState;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Response,
+};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+struct Version {
+    version: String,
+    text: String,
+    // Of the template text, so filled-in variables keep it
+    fingerprint: Option<String>,
+    // Requests by status
+    requests: Mutex<BTreeMap<u16, u64>>,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+}
+
+struct Serving {
+    stable: String,
+    // The stable version before the last promotion, for rollback
+    previous: Option<String>,
+    rollout: Option<PromptRollout>,
+}
+
+struct Template {
+    name: String,
+    versions: Vec<Version>,
+    serving: RwLock<Serving>,
+}
+
+// The version a request was served, e.g. for its usage record
+pub(crate) struct Served {
+    // "name@version"
+    pub(crate) label: String,
+    pub(crate) fingerprint: Option<String>,
+}
+
+impl Template {
+    fn version(&self, version: &str) -> Option<&Version> {
+        self.versions.iter().find(|v| v.version == version)
+    }
+
+    fn check_version(&self, version: &str) -> Result<(), ProxyError> {
+        match self.version(version) {
+            Some(_) => Ok(()),
+            None => Err(ProxyError::InvalidRequest(format!(
+                "Prompt template {} has no version {}",
+                self.name, version
+            ))),
+        }
+    }
+
+    // Requests falling below the rollout percent get its version. The bucket
+    // comes from the end user when known, so they keep seeing one version.
+    fn pick(&self, bucket_key: &str) -> String {
+        let serving = self.serving.read().unwrap();
+        if let Some(rollout) = &serving.rollout {
+            let digest = Sha256::new()
+                .chain_update(self.name.as_bytes())
+                .chain_update(bucket_key.as_bytes())
+                .finalize();
+            let bucket = u64::from_be_bytes(digest[..8].try_into().unwrap()) % 100;
+            if bucket < rollout.percent as u64 {
+                return rollout.version.clone();
+            }
+        }
+        serving.stable.clone()
+    }
+
+    fn view(&self) -> Value {
+        let serving = self.serving.read().unwrap();
+        let versions: Vec<Value> = self
+            .versions
+            .iter()
+            .map(|v| {
+                let requests = v.requests.lock().unwrap();
+                json!({
+                    "version": v.version,
+                    "fingerprint": v.fingerprint,
+                    "requests": requests.values().sum::<u64>(),
+                    "errors": requests.range(400..).map(|(_, n)| n).sum::<u64>(),
+                    "prompt_tokens": v.prompt_tokens.load(Ordering::Relaxed),
+                    "completion_tokens": v.completion_tokens.load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        json!({
+            "name": self.name,
+            "stable": serving.stable,
+            "previous": serving.previous,
+            "rollout": serving.rollout,
+            "versions": versions,
+        })
+    }
+}
+
+// {{name}} placeholders replaced by the variables, strings without quotes
+fn render(
+    template: &str,
+    text: &str,
+    variables: &Map<String, Value>,
+) -> Result<String, ProxyError> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        match variables.get(name) {
+            Some(Value::String(value)) => out.push_str(value),
+            Some(value) => out.push_str(&value.to_string()),
+            None => {
+                return Err(ProxyError::InvalidRequest(format!(
+                    "Prompt template {} needs the variable {}",
+                    template, name
+                )))
+            }
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+pub(crate) struct PromptTemplates {
+    templates: Vec<Template>,
+}
+
+impl PromptTemplates {
+    pub(crate) fn new(configs: &[PromptTemplateConfig]) -> Result<Self, String> {
+        let mut templates: Vec<Template> = Vec::new();
+        for config in configs {
+            if templates.iter().any(|t| t.name == config.name) {
+                return Err(format!("Prompt template {} is defined twice", config.name));
+            }
+            let mut versions: Vec<Version> = Vec::new();
+            for version in &config.versions {
+                if versions.iter().any(|v| v.version == version.version) {
+                    return Err(format!(
+                        "Prompt template {} has version {} twice",
+                        config.name, version.version
+                    ));
+                }
+                versions.push(Version {
+                    version: version.version.clone(),
+                    text: version.text.clone(),
+                    fingerprint: prompts::fingerprint_text(&version.text),
+                    requests: Mutex::default(),
+                    prompt_tokens: AtomicU64::new(0),
+                    completion_tokens: AtomicU64::new(0),
+                });
+            }
+            let template = Template {
+                name: config.name.clone(),
+                versions,
+                serving: RwLock::new(Serving {
+                    stable: config.stable.clone(),
+                    previous: None,
+                    rollout: config.rollout.clone(),
+                }),
+            };
+            let rollout = config.rollout.as_ref();
+            for version in std::iter::once(&config.stable).chain(rollout.map(|r| &r.version)) {
+                if template.version(version).is_none() {
+                    return Err(format!(
+                        "Prompt template {} has no version {}",
+                        config.name, version
+                    ));
+                }
+            }
+            if rollout.is_some_and(|r| r.percent > 100) {
+                return Err(format!(
+                    "Prompt template {} rolls out to more than 100 percent",
+                    config.name
+                ));
+            }
+            templates.push(template);
+        }
+        Ok(Self { templates })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.templates.len()
+    }
+
+    fn template(&self, name: &str) -> Result<&Template, ProxyError> {
+        self.templates
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| ProxyError::NotFound(format!("Unknown prompt template {}", name)))
+    }
+
+    // Replaces the request's prompt_template field, {"name": .., "version": ..,
+    // "variables": {..}}, by the rendered prompt: a leading system message, or
+    // the instructions of a Responses API request. A version pins the request
+    // to it past any rollout.
+    pub(crate) fn apply(
+        &self,
+        obj: &mut Map<String, Value>,
+        bucket_key: &str,
+    ) -> Result<Option<Served>, ProxyError> {
+        let Some(reference) = obj.remove("prompt_template") else {
+            return Ok(None);
+        };
+        let invalid = || ProxyError::InvalidRequest("Invalid prompt_template field".to_string());
+        let name = reference["name"].as_str().ok_or_else(invalid)?;
+        let template = self
+            .templates
+            .iter()
+            .find(|t| t.name == name)
+            .ok_or_else(|| {
+                ProxyError::InvalidRequest(format!("Unknown prompt template {}", name))
+            })?;
+        let version = match &reference["version"] {
+            Value::Null => template.pick(bucket_key),
+            Value::String(version) => version.clone(),
+            _ => return Err(invalid()),
+        };
+        template.check_version(&version)?;
+        let version = template.version(&version).unwrap();
+        let variables = match &reference["variables"] {
+            Value::Null => Map::new(),
+            Value::Object(variables) => variables.clone(),
+            _ => return Err(invalid()),
+        };
+        let text = render(name, &version.text, &variables)?;
+
+        match obj.get_mut("messages").and_then(|m| m.as_array_mut()) {
+            Some(messages) => {
+                messages.insert(0, json!({"role": "system", "content": text}));
+            }
+            None => {
+                let instructions = match obj.get("instructions").and_then(|i| i.as_str()) {
+                    Some(existing) => format!("{}\n\n{}", text, existing),
+                    None => text,
+                };
+                obj.insert("instructions".to_string(), instructions.into());
+            }
+        }
+        Ok(Some(Served {
+            label: format!("{}@{}", name, version.version),
+            fingerprint: version.fingerprint.clone(),
+        }))
+    }
+
+    // A stream's tokens come without a status, its request was counted before
+    pub(crate) fn record(
+        &self,
+        label: &str,
+        status: Option<u16>,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let Some((name, version)) = label.rsplit_once('@') else {
+            return;
+        };
+        let Some(version) = self
+            .template(name)
+            .ok()
+            .and_then(|template| template.version(version))
+        else {
+            return;
+        };
+        if let Some(status) = status {
+            *version.requests.lock().unwrap().entry(status).or_default() += 1;
+        }
+        version
+            .prompt_tokens
+            .fetch_add(prompt_tokens, Ordering::Relaxed);
+        version
+            .completion_tokens
+            .fetch_add(completion_tokens, Ordering::Relaxed);
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP openai_proxy_prompt_version_requests_total Requests by prompt template version.\n",
+        );
+        out.push_str("# TYPE openai_proxy_prompt_version_requests_total counter\n");
+        let labels = |template: &Template, version: &Version| {
+            format!(
+                "template=\"{}\",version=\"{}\"",
+                escape_label(&template.name),
+                escape_label(&version.version)
+            )
+        };
+        for template in &self.templates {
+            for version in &template.versions {
+                for (status, count) in version.requests.lock().unwrap().iter() {
+                    out.push_str(&format!(
+                        "openai_proxy_prompt_version_requests_total{{{},status=\"{}\"}} {}\n",
+                        labels(template, version),
+                        status,
+                        count
+                    ));
+                }
+            }
+        }
+        out.push_str("# HELP openai_proxy_prompt_version_tokens_total Prompt and completion tokens by prompt template version.\n");
+        out.push_str("# TYPE openai_proxy_prompt_version_tokens_total counter\n");
+        for template in &self.templates {
+            for version in &template.versions {
+                for (kind, tokens) in [
+                    ("prompt", &version.prompt_tokens),
+                    ("completion", &version.completion_tokens),
+                ] {
+                    out.push_str(&format!(
+                        "openai_proxy_prompt_version_tokens_total{{{},kind=\"{}\"}} {}\n",
+                        labels(template, version),
+                        kind,
+                        tokens.load(Ordering::Relaxed)
+                    ));
+                }
+            }
+        }
+        out
+    }
+}
+
+// Renders a prompt_template field, picking the version by the request's end
+// user so one user keeps seeing the same one during a rollout
+pub(crate) struct RenderPromptTemplate;
+
+impl RequestTransform for RenderPromptTemplate {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        let bucket_key = request.body["user"]
+            .as_str()
+            .unwrap_or(&request.log.request_id)
+            .to_string();
+        request.served = request
+            .state
+            .prompt_templates
+            .apply(request.body.as_object_mut().unwrap(), &bucket_key)?;
+        Ok(())
+    }
+}
+
+pub(crate) async fn list_prompts_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let data: Vec<Value> = state
+        .prompt_templates
+        .templates
+        .iter()
+        .map(Template::view)
+        .collect();
+    Ok(json_response(&json!({"object": "list", "data": data})))
+}
+
+// Sets the rollout, e.g. {"version": "v2", "percent": 10}. At 100 percent the
+// version becomes the stable one, at 0 the rollout ends.
+pub(crate) async fn update_prompt_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let template = state.prompt_templates.template(&name)?;
+    let rollout: PromptRollout = serde_json::from_slice(&body)
+        .map_err(|e| ProxyError::InvalidRequest(format!("Invalid rollout: {}", e)))?;
+    template.check_version(&rollout.version)?;
+    if rollout.percent > 100 {
+        return Err(ProxyError::InvalidRequest(
+            "Rollout percent must be between 0 and 100".to_string(),
+        ));
+    }
+
+    {
+        let mut serving = template.serving.write().unwrap();
+        match rollout.percent {
+            0 => serving.rollout = None,
+            100 if rollout.version != serving.stable => {
+                serving.previous = Some(std::mem::replace(
+                    &mut serving.stable,
+                    rollout.version.clone(),
+                ));
+                serving.rollout = None;
+            }
+            100 => serving.rollout = None,
+            _ => serving.rollout = Some(rollout.clone()),
+        }
+    }
+    println!(
+        "📝 Prompt template {} rolls out {} to {}%",
+        name, rollout.version, rollout.percent
+    );
+    Ok(json_response(&template.view()))
+}
+
+// Ends the rollout, or without one goes back to the stable version before the
+// last promotion
+pub(crate) async fn rollback_prompt_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let template = state.prompt_templates.template(&name)?;
+    {
+        let mut serving = template.serving.write().unwrap();
+        if serving.rollout.take().is_none() {
+            let previous = serving.previous.take().ok_or_else(|| {
+                ProxyError::InvalidRequest(format!(
+                    "Prompt template {} has nothing to roll back",
+                    name
+                ))
+            })?;
+            serving.stable = previous;
+        }
+        println!(
+            "↩️  Prompt template {} rolled back to {}",
+            name, serving.stable
+        );
+    }
+    Ok(json_response(&template.view()))
+}
@@ -0,0 +1,394 @@
+// Tenants, client authentication and request namespaces
+
+use crate::config::{ClientConfig, ModelBudget};
+use crate::dictionaries::Dictionary;
+use crate::error::ProxyError;
+use crate::metrics::StreamTotals;
+use crate::model_match::ModelMatcher;
+use crate::models::ModelInfo;
+use crate::secrets::{hex_decode, hex_encode, UpstreamKey};
+use crate::state::AppState;
+use crate::storage::Store;
+use crate::time::{civil_from_days, unix_now};
+use crate::transform::StreamStats;
+use axum::http::HeaderMap;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// The parts of a request covered by an HMAC signature
+pub(crate) struct SignedRequest<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) target: &'a str,
+    pub(crate) body: &'a [u8],
+}
+
+// An isolated namespace with its own client keys, upstream, catalog and budget
+pub(crate) struct Tenant {
+    pub(crate) name: String,
+    pub(crate) clients: Vec<ClientConfig>,
+    pub(crate) openai_api_key: Arc<UpstreamKey>,
+    pub(crate) openai_api_base: String,
+    pub(crate) available_models: Vec<ModelInfo>,
+    pub(crate) token_budget: Option<u64>,
+    pub(crate) budget_period: String,
+    pub(crate) model_budgets: Vec<ModelBudget>,
+    pub(crate) dictionaries: Vec<Arc<Dictionary>>,
+    pub(crate) usage: Mutex<TenantUsage>,
+    // Where the usage counters are shared with other replicas
+    pub(crate) storage: Option<Arc<Store>>,
+}
+
+#[derive(Default, Clone, serde::Serialize)]
+pub(crate) struct TenantUsage {
+    // Budget period the counters belong to, reset when it changes
+    #[serde(skip)]
+    pub(crate) period: u64,
+    pub(crate) requests: u64,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    // Prompt + completion tokens per model, for model_budgets
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) models: BTreeMap<String, u64>,
+    // Stream latency keyed by "provider/model"
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) streams: BTreeMap<String, StreamTotals>,
+}
+
+// Upstream credentials and model catalog a request is served with
+pub(crate) struct Namespace<'a> {
+    pub(crate) tenant: Option<&'a Tenant>,
+    pub(crate) client: Option<&'a ClientConfig>,
+    pub(crate) api_key: &'a UpstreamKey,
+    pub(crate) api_base: &'a str,
+    pub(crate) models: &'a [ModelInfo],
+}
+
+impl AppState {
+    // Pick the namespace for a request: a tenant selected by client key and/or
+    // "/t/{tenant}" path prefix, or the global configuration when no tenants exist
+    pub(crate) async fn resolve_namespace(
+        &self,
+        tenant_prefix: Option<&str>,
+        headers: &HeaderMap,
+        signed: &SignedRequest<'_>,
+    ) -> Result<Namespace<'_>, ProxyError> {
+        if self.tenants.is_empty() {
+            return Ok(Namespace {
+                tenant: None,
+                client: None,
+                api_key: self.openai_api_key.as_ref(),
+                api_base: &self.openai_api_base,
+                models: &self.available_models,
+            });
+        }
+
+        let (tenant, client) = if headers.contains_key("x-proxy-signature") {
+            self.verify_signature(tenant_prefix, headers, signed)
+                .await?
+        } else {
+            let key = client_key(headers)
+                .ok_or_else(|| ProxyError::Unauthorized("Missing client API key".to_string()))?;
+            self.tenants
+                .iter()
+                .find_map(|t| {
+                    t.clients
+                        .iter()
+                        .find(|c| c.key.as_deref() == Some(key))
+                        .map(|c| (t.as_ref(), c))
+                })
+                .ok_or_else(|| ProxyError::Unauthorized("Invalid client API key".to_string()))?
+        };
+
+        // A key never grants access to another tenant's namespace
+        if let Some(prefix) = tenant_prefix {
+            if prefix != tenant.name {
+                return Err(ProxyError::Forbidden(format!(
+                    "Client key does not belong to tenant {}",
+                    prefix
+                )));
+            }
+        }
+
+        Ok(Namespace {
+            tenant: Some(tenant),
+            client: Some(client),
+            api_key: tenant.openai_api_key.as_ref(),
+            api_base: &tenant.openai_api_base,
+            models: &tenant.available_models,
+        })
+    }
+}
+
+impl AppState {
+    // Authenticate an HMAC-signed request:
+    //   x-proxy-client:    client name
+    //   x-proxy-timestamp: Unix seconds
+    //   x-proxy-nonce:     unique per request
+    //   x-proxy-signature: hex HMAC-SHA256 over
+    //                      "{timestamp}\n{nonce}\n{METHOD}\n{path?query}\n{hex sha256(body)}"
+    pub(crate) async fn verify_signature(
+        &self,
+        tenant_prefix: Option<&str>,
+        headers: &HeaderMap,
+        signed: &SignedRequest<'_>,
+    ) -> Result<(&Tenant, &ClientConfig), ProxyError> {
+        use hmac::Mac;
+        use sha2::Digest;
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim())
+                .ok_or_else(|| ProxyError::Unauthorized(format!("Missing {} header", name)))
+        };
+        let client_name = header("x-proxy-client")?;
+        let timestamp = header("x-proxy-timestamp")?;
+        let nonce = header("x-proxy-nonce")?;
+        let signature = header("x-proxy-signature")?;
+
+        let (tenant, client) = self
+            .tenants
+            .iter()
+            .filter(|t| tenant_prefix.map(|p| p == t.name).unwrap_or(true))
+            .find_map(|t| {
+                t.clients
+                    .iter()
+                    .find(|c| c.name == client_name && c.hmac_secret.is_some())
+                    .map(|c| (t.as_ref(), c))
+            })
+            .ok_or_else(|| ProxyError::Unauthorized("Unknown signing client".to_string()))?;
+
+        let now = unix_now();
+        let sent_at: u64 = timestamp
+            .parse()
+            .map_err(|_| ProxyError::Unauthorized("Invalid x-proxy-timestamp".to_string()))?;
+        if now.abs_diff(sent_at) > self.hmac_max_skew_secs {
+            return Err(ProxyError::Unauthorized(
+                "Request timestamp is outside the allowed window".to_string(),
+            ));
+        }
+
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            timestamp,
+            nonce,
+            signed.method,
+            signed.target,
+            hex_encode(&sha2::Sha256::digest(signed.body))
+        );
+        let secret = client.hmac_secret.as_deref().unwrap_or_default();
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts any key");
+        mac.update(string_to_sign.as_bytes());
+        let expected = hex_decode(signature)
+            .ok_or_else(|| ProxyError::Unauthorized("Invalid x-proxy-signature".to_string()))?;
+        mac.verify_slice(&expected)
+            .map_err(|_| ProxyError::Unauthorized("Signature mismatch".to_string()))?;
+
+        // Each nonce is accepted once while its timestamp is within the window,
+        // by any replica sharing the storage
+        let replayed = || ProxyError::Unauthorized("Replayed request nonce".to_string());
+        let nonce_key = format!("{}/{}/{}", tenant.name, client.name, nonce);
+        let expires = sent_at + self.hmac_max_skew_secs + 1;
+        {
+            let mut nonces = self.hmac_nonces.lock().unwrap();
+            nonces.retain(|_, expires| *expires > now);
+            if nonces.contains_key(&nonce_key) {
+                return Err(replayed());
+            }
+            nonces.insert(nonce_key.clone(), expires);
+        }
+        if let Some(store) = &self.storage {
+            // A nonce that cannot be checked is not accepted
+            let seen = store
+                .backend
+                .hincr(
+                    &store.key(&format!("nonce:{}", nonce_key)),
+                    "seen",
+                    1,
+                    Some(expires - now),
+                )
+                .await
+                .map_err(|err| {
+                    eprintln!("⚠️  Failed to record request nonce: {}", err);
+                    ProxyError::Unavailable("Request nonce cannot be checked".to_string())
+                })?;
+            if seen > 1 {
+                return Err(replayed());
+            }
+        }
+
+        Ok((tenant, client))
+    }
+}
+
+impl Tenant {
+    pub(crate) fn current_period(&self) -> u64 {
+        let now = unix_now();
+        match self.budget_period.as_str() {
+            "daily" => now / 86400,
+            "total" => 0,
+            _ => {
+                let (year, month, _) = civil_from_days((now / 86400) as i64);
+                year as u64 * 12 + month as u64
+            }
+        }
+    }
+
+    pub(crate) fn current_usage(&self) -> TenantUsage {
+        let period = self.current_period();
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period != period {
+            *usage = TenantUsage {
+                period,
+                ..TenantUsage::default()
+            };
+        }
+        usage.clone()
+    }
+
+    pub(crate) fn check_budget(&self) -> Result<(), ProxyError> {
+        if let Some(budget) = self.token_budget {
+            let usage = self.current_usage();
+            if usage.prompt_tokens + usage.completion_tokens >= budget {
+                return Err(ProxyError::BudgetExceeded(format!(
+                    "Tenant {} has used its token budget of {}",
+                    self.name, budget
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The first model budget covering the model that is used up, if any
+    pub(crate) fn check_model_budget(
+        &self,
+        matcher: &ModelMatcher,
+        model: &str,
+    ) -> Result<(), ProxyError> {
+        let Some(budget) = self
+            .model_budgets
+            .iter()
+            .find(|b| matcher.matches(&b.models, model))
+        else {
+            return Ok(());
+        };
+        let usage = self.current_usage();
+        let used: u64 = usage
+            .models
+            .iter()
+            .filter(|(id, _)| matcher.matches(&budget.models, id))
+            .map(|(_, tokens)| tokens)
+            .sum();
+        if used >= budget.token_budget {
+            return Err(ProxyError::BudgetExceeded(format!(
+                "Tenant {} has used its token budget of {} for {}",
+                self.name, budget.token_budget, budget.models
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record_usage(
+        &self,
+        model: Option<&str>,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let period = self.current_period();
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period != period {
+            *usage = TenantUsage {
+                period,
+                ..TenantUsage::default()
+            };
+        }
+        usage.requests += 1;
+        usage.prompt_tokens += prompt_tokens;
+        usage.completion_tokens += completion_tokens;
+        if let Some(model) = model {
+            *usage.models.entry(model.to_string()).or_default() +=
+                prompt_tokens + completion_tokens;
+        }
+
+        if let Some(storage) = self.storage.clone() {
+            let key = storage.key(&format!("usage:{}:{}", self.name, period));
+            let mut counts = vec![
+                ("requests".to_string(), 1),
+                ("prompt_tokens".to_string(), prompt_tokens as i64),
+                ("completion_tokens".to_string(), completion_tokens as i64),
+            ];
+            if let Some(model) = model {
+                counts.push((
+                    format!("model:{}", model),
+                    (prompt_tokens + completion_tokens) as i64,
+                ));
+            }
+            // One update, so a sync never reads a request without its tokens
+            tokio::spawn(async move {
+                if let Err(err) = storage.backend.hincr_all(&key, &counts, None).await {
+                    eprintln!("⚠️  Failed to store usage: {}", err);
+                }
+            });
+        }
+    }
+
+    // Replaces the counters with the stored ones, which include every replica's
+    pub(crate) async fn sync_usage(&self) -> Result<(), String> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+        let period = self.current_period();
+        let key = storage.key(&format!("usage:{}:{}", self.name, period));
+        let stored = storage.backend.hgetall(&key).await?;
+        let count = |field: &str| stored.get(field).copied().unwrap_or(0).max(0) as u64;
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period != period {
+            *usage = TenantUsage {
+                period,
+                ..TenantUsage::default()
+            };
+        }
+        usage.requests = count("requests");
+        usage.prompt_tokens = count("prompt_tokens");
+        usage.completion_tokens = count("completion_tokens");
+        usage.models = stored
+            .keys()
+            .filter_map(|field| {
+                let model = field.strip_prefix("model:")?;
+                Some((model.to_string(), count(field)))
+            })
+            .collect();
+        Ok(())
+    }
+
+    pub(crate) fn record_stream(&self, model: &str, provider: &str, stats: &StreamStats) {
+        let period = self.current_period();
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period == period {
+            usage
+                .streams
+                .entry(format!("{}/{}", provider, model))
+                .or_default()
+                .add(stats);
+        }
+    }
+}
+
+// The proxy client key, sent as "x-proxy-key" or as the bearer token
+pub(crate) fn client_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-proxy-key")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim())
+        .or_else(|| bearer_token(headers))
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim())
+}
@@ -0,0 +1,344 @@
+// Test harness: a mock OpenAI upstream and an in-process proxy pointed at it.
+// Also meant for downstream users checking their own config files:
+//
+//     let upstream = MockUpstream::start().await;
+//     let settings = Settings::from_toml(&std::fs::read_to_string("config.toml")?)?;
+//     let proxy = TestProxy::start(settings.with_api_base(&upstream.url())).await?;
+//     let response = reqwest::Client::new()
+//         .post(proxy.url("/v3/chat/completions"))
+//         .json(&serde_json::json!({"model": "gpt-4o", "messages": []}))
+//         .send()
+//         .await?;
+
+use crate::Settings;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Router,
+};
+use futures_util::StreamExt;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+// A request as received by the mock upstream
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    // Path and query, e.g. "/v3/chat/completions"
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl RecordedRequest {
+    // The body parsed as JSON, Null when it is not JSON
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap_or(serde_json::Value::Null)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+// A canned response, served instead of the default behaviour
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // Served after this long, on top of the mock's delay
+    pub delay: Duration,
+    // The connection breaks after the body, as if the upstream died
+    pub cut_off: bool,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json".to_string(),
+            headers: Vec::new(),
+            body: body.to_string(),
+            delay: Duration::ZERO,
+            cut_off: false,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn cut_off(mut self) -> Self {
+        self.cut_off = true;
+        self
+    }
+
+    // An SSE stream with one "data:" event per chunk, followed by [DONE]
+    pub fn stream(chunks: &[serde_json::Value]) -> Self {
+        let mut body: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {}\n\n", chunk))
+            .collect();
+        body.push_str("data: [DONE]\n\n");
+        Self {
+            status: 200,
+            content_type: "text/event-stream".to_string(),
+            headers: Vec::new(),
+            body,
+            delay: Duration::ZERO,
+            cut_off: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    requests: Mutex<Vec<RecordedRequest>>,
+    responses: Mutex<VecDeque<MockResponse>>,
+    delay: Mutex<Duration>,
+}
+
+// Mock OpenAI server on a random local port. By default it answers
+// GET .../models with a fixed list, streaming requests with three content
+// deltas and a usage chunk, and other requests with a chat completion for
+// the requested model.
+pub struct MockUpstream {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    handle: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock upstream");
+        let addr = listener.local_addr().expect("mock upstream address");
+        let state = Arc::new(MockState::default());
+        let app = Router::new()
+            .fallback(mock_handler)
+            .with_state(state.clone());
+        let handle = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    // Base URL to use as openai_api_base
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    // Queue a response for the next request without one
+    pub fn push_response(&self, response: MockResponse) {
+        self.state.responses.lock().unwrap().push_back(response);
+    }
+
+    // Wait this long before answering each request, e.g. to hit timeouts
+    pub fn set_delay(&self, delay: Duration) {
+        *self.state.delay.lock().unwrap() = delay;
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    pub fn last_request(&self) -> Option<RecordedRequest> {
+        self.state.requests.lock().unwrap().last().cloned()
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn mock_handler(State(state): State<Arc<MockState>>, req: Request) -> Response {
+    let method = req.method().to_string();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let headers = req.headers().clone();
+    let body = axum::body::to_bytes(req.into_body(), usize::MAX)
+        .await
+        .unwrap_or_default();
+    let recorded = RecordedRequest {
+        method,
+        path,
+        headers,
+        body,
+    };
+    let request_json = recorded.json();
+    state.requests.lock().unwrap().push(recorded.clone());
+
+    let delay = *state.delay.lock().unwrap();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    let queued = state.responses.lock().unwrap().pop_front();
+    let response = queued.unwrap_or_else(|| default_response(&recorded, &request_json));
+    if !response.delay.is_zero() {
+        tokio::time::sleep(response.delay).await;
+    }
+    let mut builder = Response::builder()
+        .status(StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK))
+        .header("content-type", response.content_type);
+    for (name, value) in &response.headers {
+        builder = builder.header(name, value);
+    }
+    if response.cut_off {
+        // The body reaches the client before the connection breaks
+        let parts = futures_util::stream::iter([Some(response.body), None]).then(|part| async {
+            match part {
+                Some(body) => Ok(Bytes::from(body)),
+                None => {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Err(std::io::Error::other("upstream died"))
+                }
+            }
+        });
+        return builder.body(Body::from_stream(parts)).unwrap();
+    }
+    builder.body(Body::from(response.body)).unwrap()
+}
+
+fn default_response(request: &RecordedRequest, body: &serde_json::Value) -> MockResponse {
+    if request.method == "GET" && request.path.ends_with("/models") {
+        return MockResponse::json(
+            200,
+            serde_json::json!({
+                "object": "list",
+                "data": [
+                    {"id": "gpt-4o", "object": "model", "owned_by": "openai"},
+                    {"id": "gpt-4o-mini", "object": "model", "owned_by": "openai"},
+                ],
+            }),
+        );
+    }
+
+    let model = body["model"].as_str().unwrap_or("gpt-4o");
+    if body["stream"].as_bool() == Some(true) {
+        let mut chunks: Vec<serde_json::Value> = ["Hello", " from", " mock"]
+            .iter()
+            .map(|text| {
+                serde_json::json!({
+                    "id": "chatcmpl-mock",
+                    "object": "chat.completion.chunk",
+                    "model": model,
+                    "choices": [{"index": 0, "delta": {"content": text}, "finish_reason": null}],
+                })
+            })
+            .collect();
+        chunks.push(serde_json::json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{"index": 0, "delta": {}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8},
+        }));
+        return MockResponse::stream(&chunks);
+    }
+
+    MockResponse::json(
+        200,
+        serde_json::json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion",
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "Hello from mock"},
+                "finish_reason": "stop",
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8},
+        }),
+    )
+}
+
+// The proxy served in-process on a random local port
+pub struct TestProxy {
+    addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl TestProxy {
+    pub async fn start(settings: Settings) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let limits = settings.connections.clone();
+        // Startup errors (e.g. unreachable secrets) surface here rather than in the task
+        let app = crate::router(settings).await?;
+        let handle = tokio::spawn(async move {
+            let _ = crate::server::serve_router(listener, app, limits).await;
+        });
+        Ok(Self { addr, handle })
+    }
+
+    // Full URL for a proxy path, e.g. url("/v3/chat/completions")
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+// The x-proxy-* headers of an HMAC-signed request, see verify_signature;
+// `target` is the path and query
+pub fn signed_headers(
+    client: &str,
+    secret: &str,
+    timestamp: u64,
+    nonce: &str,
+    method: &str,
+    target: &str,
+    body: &[u8],
+) -> Vec<(&'static str, String)> {
+    use hmac::Mac;
+    use sha2::Digest;
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}\n{}",
+        timestamp,
+        nonce,
+        method,
+        target,
+        crate::secrets::hex_encode(&sha2::Sha256::digest(body))
+    );
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts any key");
+    mac.update(string_to_sign.as_bytes());
+    vec![
+        ("x-proxy-client", client.to_string()),
+        ("x-proxy-timestamp", timestamp.to_string()),
+        ("x-proxy-nonce", nonce.to_string()),
+        (
+            "x-proxy-signature",
+            crate::secrets::hex_encode(&mac.finalize().into_bytes()),
+        ),
+    ]
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
@@ -0,0 +1,61 @@
+// Local token estimates for rate limiting, without a model-specific tokenizer
+
+// Roughly four characters per token for Latin text. Other scripts, CJK in
+// particular, are counted one token per character, which errs on the high side.
+pub(crate) fn estimate_text_tokens(text: &str) -> u64 {
+    let (ascii, other) = text.chars().fold((0u64, 0u64), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    ascii.div_ceil(4) + other
+}
+
+// Framing tokens OpenAI adds around every chat message
+const TOKENS_PER_MESSAGE: u64 = 4;
+// Flat estimate for an image part, the price of a low-detail image
+const TOKENS_PER_IMAGE: u64 = 85;
+
+fn content_tokens(content: &serde_json::Value) -> u64 {
+    match content {
+        serde_json::Value::String(text) => estimate_text_tokens(text),
+        serde_json::Value::Array(parts) => parts.iter().map(content_tokens).sum(),
+        serde_json::Value::Object(part) => match part.get("text").and_then(|t| t.as_str()) {
+            Some(text) => estimate_text_tokens(text),
+            None if part.contains_key("image_url") => TOKENS_PER_IMAGE,
+            None => 0,
+        },
+        _ => 0,
+    }
+}
+
+// Projected spend of a request: the estimated prompt plus the completion
+// tokens it asks for at most
+pub(crate) fn estimate_request_tokens(body: &serde_json::Value) -> u64 {
+    let mut tokens = 0;
+    if let Some(messages) = body.get("messages").and_then(|m| m.as_array()) {
+        for message in messages {
+            tokens += TOKENS_PER_MESSAGE + content_tokens(&message["content"]);
+            if let Some(calls) = message.get("tool_calls") {
+                tokens += estimate_text_tokens(&calls.to_string());
+            }
+        }
+        tokens += 3;
+    }
+    for field in ["prompt", "input"] {
+        if let Some(value) = body.get(field) {
+            tokens += content_tokens(value);
+        }
+    }
+    if let Some(tools) = body.get("tools") {
+        tokens += estimate_text_tokens(&tools.to_string());
+    }
+    let max_completion = body
+        .get("max_completion_tokens")
+        .or_else(|| body.get("max_tokens"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0);
+    tokens + max_completion
+}
@@ -0,0 +1,88 @@
+// W3C trace context (traceparent/tracestate) continued through the proxy
+
+use axum::http::HeaderMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub(crate) struct TraceContext {
+    pub(crate) trace_id: String,
+    // The proxy's span, the parent of the upstream call
+    pub(crate) span_id: String,
+    pub(crate) flags: String,
+    pub(crate) state: Option<String>,
+}
+
+impl TraceContext {
+    // Continues the caller's trace, or starts one when `start_new` is set.
+    // An invalid traceparent is treated as missing, as the spec asks.
+    pub(crate) fn from_headers(headers: &HeaderMap, start_new: bool) -> Option<Self> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        match header("traceparent").and_then(parse_traceparent) {
+            Some((trace_id, flags)) => Some(Self {
+                trace_id,
+                span_id: random_hex(8),
+                flags,
+                state: header("tracestate")
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty()),
+            }),
+            None if start_new => Some(Self {
+                trace_id: random_hex(16),
+                span_id: random_hex(8),
+                flags: "01".to_string(),
+                state: None,
+            }),
+            None => None,
+        }
+    }
+
+    // Sent upstream, with the proxy's span as the parent
+    pub(crate) fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+fn is_hex_id(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && value.bytes().any(|b| b != b'0')
+}
+
+// Trace ID and flags of "00-<trace-id>-<parent-id>-<flags>"; later versions
+// may append fields
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let (trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+    let valid_version = version.len() == 2
+        && version != "ff"
+        && version
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase());
+    if !valid_version || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    let valid_flags = flags.len() == 2 && u8::from_str_radix(flags, 16).is_ok();
+    (is_hex_id(trace_id, 32) && is_hex_id(parent_id, 16) && valid_flags)
+        .then(|| (trace_id.to_string(), flags.to_lowercase()))
+}
+
+// Not cryptographic, only unique enough for span and trace IDs
+fn random_hex(bytes: usize) -> String {
+    let mut out = String::new();
+    while out.len() < bytes * 2 {
+        let counter = ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let value = RandomState::new().hash_one(counter);
+        if value != 0 {
+            out.push_str(&format!("{:016x}", value));
+        }
+    }
+    out.truncate(bytes * 2);
+    out
+}
@@ -0,0 +1,215 @@
+// Collects consented chat transcripts in OpenAI fine-tuning JSONL. Each
+// example also carries the request's user field and its time, so retention
+// and erasure can find it.
+
+use crate::config::{ClientConfig, TranscriptConfig};
+use crate::error::ProxyError;
+use crate::model_match::ModelMatcher;
+use crate::request::{OutgoingRequest, RequestTransform};
+use crate::retention::remove_lines;
+use crate::time::{format_utc, unix_now};
+use crate::transform::ResponseTransform;
+use axum::http::HeaderMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub(crate) struct TranscriptCollector {
+    pub(crate) config: TranscriptConfig,
+    file: Mutex<File>,
+}
+
+impl TranscriptCollector {
+    pub(crate) fn open(config: TranscriptConfig) -> std::io::Result<Self> {
+        let path = Path::new(&config.path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            config,
+            file: Mutex::new(file),
+        })
+    }
+
+    // Consent of the client and the x-proxy-rating header
+    pub(crate) fn accepts(&self, client: Option<&ClientConfig>, headers: &HeaderMap) -> bool {
+        let consented = client.map(|c| c.transcript_consent).unwrap_or(false);
+        if self.config.require_consent && !consented {
+            return false;
+        }
+        if self.config.ratings.is_empty() {
+            return true;
+        }
+        let rating = headers
+            .get("x-proxy-rating")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim());
+        rating.is_some_and(|rating| self.config.ratings.iter().any(|r| r == rating))
+    }
+
+    pub(crate) fn accepts_model(&self, matcher: &ModelMatcher, model: Option<&str>) -> bool {
+        self.config.models.is_empty()
+            || model.is_some_and(|model| matcher.matches_any(&self.config.models, model))
+    }
+
+    // Drops the examples `remove` matches, returning how many there were
+    pub(crate) fn remove(
+        &self,
+        remove: impl Fn(&serde_json::Value) -> bool,
+    ) -> std::io::Result<usize> {
+        remove_lines(&self.config.path, &self.file, remove)
+    }
+
+    fn record(&self, request: &serde_json::Value, assistant: serde_json::Value) {
+        let Some(messages) = request["messages"].as_array() else {
+            return;
+        };
+        let mut messages = messages.clone();
+        messages.push(assistant);
+        let mut example = serde_json::json!({ "messages": messages });
+        for key in ["tools", "parallel_tool_calls", "user"] {
+            if let Some(value) = request.get(key) {
+                example[key] = value.clone();
+            }
+        }
+        example["time"] = format_utc(unix_now()).into();
+        if let Err(err) = writeln!(self.file.lock().unwrap(), "{}", example) {
+            eprintln!("⚠️  Failed to write transcript: {}", err);
+        }
+    }
+}
+
+// Sees the final response of the first choice, after the other transforms.
+// A stream is written once it has finished, when the pipeline drops it.
+// The client's own chat request goes into the transcript, before any stage
+// rewrites it
+pub(crate) struct KeepClientRequest;
+
+impl RequestTransform for KeepClientRequest {
+    fn apply(&self, request: &mut OutgoingRequest) -> Result<(), ProxyError> {
+        if request.path.ends_with("chat/completions")
+            && request
+                .state
+                .transcripts
+                .as_ref()
+                .is_some_and(|t| t.accepts(request.namespace.client, request.headers))
+        {
+            request.transcript_request = Some(request.body.clone());
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct RecordTranscript {
+    collector: Arc<TranscriptCollector>,
+    request: serde_json::Value,
+    content: String,
+    tool_calls: Vec<serde_json::Value>,
+    finished: bool,
+    recorded: bool,
+}
+
+impl RecordTranscript {
+    pub(crate) fn new(collector: Arc<TranscriptCollector>, request: serde_json::Value) -> Self {
+        Self {
+            collector,
+            request,
+            content: String::new(),
+            tool_calls: Vec::new(),
+            finished: false,
+            recorded: false,
+        }
+    }
+
+    // Tool call deltas carry the id and name once and the arguments in pieces
+    fn merge_tool_calls(&mut self, deltas: &[serde_json::Value]) {
+        for delta in deltas {
+            let index = delta["index"].as_u64().unwrap_or(0) as usize;
+            while self.tool_calls.len() <= index {
+                self.tool_calls.push(serde_json::json!({
+                    "id": "",
+                    "type": "function",
+                    "function": {"name": "", "arguments": ""},
+                }));
+            }
+            let call = &mut self.tool_calls[index];
+            for (target, source) in [
+                ("id", &delta["id"]),
+                ("name", &delta["function"]["name"]),
+                ("arguments", &delta["function"]["arguments"]),
+            ] {
+                let Some(piece) = source.as_str() else {
+                    continue;
+                };
+                let field = if target == "id" {
+                    &mut call["id"]
+                } else {
+                    &mut call["function"][target]
+                };
+                *field = format!("{}{}", field.as_str().unwrap_or(""), piece).into();
+            }
+        }
+    }
+}
+
+fn assistant_message(
+    content: serde_json::Value,
+    tool_calls: &[serde_json::Value],
+) -> serde_json::Value {
+    let mut message = serde_json::json!({"role": "assistant", "content": content});
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = tool_calls.into();
+    }
+    message
+}
+
+impl ResponseTransform for RecordTranscript {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        let Some(choice) = value["choices"].as_array().and_then(|choices| {
+            choices
+                .iter()
+                .find(|c| c["index"].as_u64().unwrap_or(0) == 0)
+        }) else {
+            return;
+        };
+        if choice["message"].is_object() {
+            let message = &choice["message"];
+            let tool_calls = message["tool_calls"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            let assistant = assistant_message(message["content"].clone(), &tool_calls);
+            self.collector.record(&self.request, assistant);
+            self.recorded = true;
+            return;
+        }
+        let delta = &choice["delta"];
+        if let Some(text) = delta["content"].as_str() {
+            self.content.push_str(text);
+        }
+        if let Some(deltas) = delta["tool_calls"].as_array() {
+            let deltas = deltas.clone();
+            self.merge_tool_calls(&deltas);
+        }
+        if choice["finish_reason"].is_string() {
+            self.finished = true;
+        }
+    }
+}
+
+impl Drop for RecordTranscript {
+    fn drop(&mut self) {
+        // Streams cut off before finishing are not recorded
+        if self.finished && !self.recorded {
+            let content = if self.content.is_empty() && !self.tool_calls.is_empty() {
+                serde_json::Value::Null
+            } else {
+                std::mem::take(&mut self.content).into()
+            };
+            let assistant = assistant_message(content, &self.tool_calls);
+            self.collector.record(&self.request, assistant);
+        }
+    }
+}
@@ -0,0 +1,629 @@
+// Response transforms and the SSE stream pipeline
+
+use crate::error::panic_message;
+use crate::loops::LoopDetector;
+use crate::models::{ENGINE_CHOICE_FIELDS, ENGINE_RESPONSE_FIELDS};
+use axum::body::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::{ready, Stream};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+// Token usage from a JSON response or the final chunks of an SSE stream
+pub(crate) fn usage_from_json(value: &serde_json::Value) -> Option<(u64, u64)> {
+    let usage = value.get("usage")?;
+    Some((
+        usage.get("prompt_tokens")?.as_u64()?,
+        usage
+            .get("completion_tokens")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+    ))
+}
+
+pub(crate) fn extract_usage(body: &[u8]) -> Option<(u64, u64)> {
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) {
+        return usage_from_json(&json);
+    }
+
+    std::str::from_utf8(body)
+        .ok()?
+        .lines()
+        .rev()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .filter_map(|data| serde_json::from_str::<serde_json::Value>(data.trim()).ok())
+        .find_map(|chunk| usage_from_json(&chunk))
+}
+
+// Timing and token counts of one relayed event stream
+pub(crate) struct StreamStats {
+    // Milliseconds from sending the upstream request to the first content delta
+    pub(crate) ttft_ms: Option<u64>,
+    pub(crate) duration_ms: u64,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    // Seconds from the first content delta to the end of the stream
+    pub(crate) generation_secs: f64,
+    // Raw chunks with their offset in milliseconds, only kept for debug capture
+    pub(crate) chunks: Vec<(u64, Bytes)>,
+    // A transform panicked and the stream was cut short
+    pub(crate) panicked: bool,
+    // How a failure of the upstream mid-stream was handled: dropped,
+    // error_chunk or spliced
+    pub(crate) failure: Option<&'static str>,
+}
+
+impl StreamStats {
+    pub(crate) fn tokens_per_second(&self) -> f64 {
+        if self.generation_secs > 0.0 {
+            self.completion_tokens as f64 / self.generation_secs
+        } else {
+            0.0
+        }
+    }
+}
+
+// Rewrites applied to response JSON: the whole body of a buffered response,
+// or each event of a stream
+pub(crate) trait ResponseTransform: Send {
+    fn apply(&mut self, value: &mut serde_json::Value);
+
+    // A finish reason to end a stream with in place of the last event
+    fn cut(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+// Puts the client's model name back where the upstream reports a renamed ID
+pub(crate) struct RestoreModelName {
+    pub(crate) exposed: String,
+    pub(crate) upstream: String,
+}
+
+impl ResponseTransform for RestoreModelName {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        if let Some(model) = value.get_mut("model") {
+            if model.as_str() == Some(self.upstream.as_str()) {
+                *model = serde_json::Value::String(self.exposed.clone());
+            }
+        }
+    }
+}
+
+// Removes reasoning traces from messages and deltas
+pub(crate) struct StripReasoning;
+
+impl ResponseTransform for StripReasoning {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        let Some(choices) = value.get_mut("choices").and_then(|c| c.as_array_mut()) else {
+            return;
+        };
+        for choice in choices {
+            for part in ["message", "delta"] {
+                if let Some(part) = choice.get_mut(part).and_then(|p| p.as_object_mut()) {
+                    part.remove("reasoning_content");
+                    part.remove("reasoning");
+                }
+            }
+        }
+    }
+}
+
+// Removes what TGI and vLLM add to completions and chunks
+pub(crate) struct StripEngineFields;
+
+impl ResponseTransform for StripEngineFields {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        let Some(obj) = value.as_object_mut() else {
+            return;
+        };
+        obj.retain(|field, _| !ENGINE_RESPONSE_FIELDS.contains(&field.as_str()));
+        let Some(choices) = obj.get_mut("choices").and_then(|c| c.as_array_mut()) else {
+            return;
+        };
+        for choice in choices.iter_mut().filter_map(|c| c.as_object_mut()) {
+            choice.retain(|field, _| !ENGINE_CHOICE_FIELDS.contains(&field.as_str()));
+        }
+    }
+}
+
+// Values used by other vendors, keyed in lowercase
+const VENDOR_FINISH_REASONS: &[(&str, &str)] = &[
+    ("end_turn", "stop"),
+    ("stop_sequence", "stop"),
+    ("eos", "stop"),
+    ("eos_token", "stop"),
+    ("complete", "stop"),
+    ("max_tokens", "length"),
+    ("max_output_tokens", "length"),
+    ("model_length", "length"),
+    ("tool_use", "tool_calls"),
+    ("safety", "content_filter"),
+    ("recitation", "content_filter"),
+    ("blocklist", "content_filter"),
+    ("prohibited_content", "content_filter"),
+];
+
+const OPENAI_FINISH_REASONS: &[&str] = &["stop", "length", "tool_calls", "content_filter"];
+
+// The built-in mapping with the configured entries on top
+pub(crate) fn finish_reason_map(extra: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut map: HashMap<String, String> = VENDOR_FINISH_REASONS
+        .iter()
+        .map(|(from, to)| (from.to_string(), to.to_string()))
+        .collect();
+    for (from, to) in extra {
+        map.insert(from.to_lowercase(), to.clone());
+    }
+    map
+}
+
+// Rewrites finish_reason in completions and final stream chunks. Unknown
+// values are kept as they are.
+pub(crate) struct NormalizeFinishReason {
+    pub(crate) map: Arc<HashMap<String, String>>,
+}
+
+impl ResponseTransform for NormalizeFinishReason {
+    fn apply(&mut self, value: &mut serde_json::Value) {
+        let Some(choices) = value.get_mut("choices").and_then(|c| c.as_array_mut()) else {
+            return;
+        };
+        for choice in choices {
+            let Some(reason) = choice.get("finish_reason").and_then(|r| r.as_str()) else {
+                continue;
+            };
+            let key = reason.to_lowercase();
+            let normalized = match self.map.get(&key) {
+                Some(mapped) => mapped.clone(),
+                None if OPENAI_FINISH_REASONS.contains(&key.as_str()) => key,
+                None => continue,
+            };
+            choice["finish_reason"] = serde_json::Value::String(normalized);
+        }
+    }
+}
+
+// Applies the transforms to a buffered JSON body, None if nothing changed
+pub(crate) fn transform_json_body(
+    body: &[u8],
+    transforms: &mut [Box<dyn ResponseTransform>],
+) -> Option<axum::body::Bytes> {
+    if transforms.is_empty() {
+        return None;
+    }
+    let mut json: serde_json::Value = serde_json::from_slice(body).ok()?;
+    for transform in transforms.iter_mut() {
+        transform.apply(&mut json);
+    }
+    serde_json::to_vec(&json).ok().map(Into::into)
+}
+
+// Position and length of the blank line ending the first complete SSE event
+pub(crate) fn sse_event_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    let lf = buffer.windows(2).position(|w| w == b"\n\n").map(|i| (i, 2));
+    let crlf = buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| (i, 4));
+    match (lf, crlf) {
+        (Some(a), Some(b)) => Some(if a.0 <= b.0 { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+pub(crate) type UpstreamStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+// Relays an upstream event stream: each SSE event is parsed once, observed for
+// token timing and usage, then run through the response transforms and
+// re-serialized. Without transforms the upstream bytes are passed on as-is.
+// The callback runs exactly once, when the stream ends or the client goes away.
+pub(crate) struct StreamPipeline {
+    pub(crate) inner: UpstreamStream,
+    pub(crate) done: bool,
+    pub(crate) started: Instant,
+    pub(crate) first_token: Option<Instant>,
+    pub(crate) token_events: u64,
+    pub(crate) usage: Option<(u64, u64)>,
+    pub(crate) pending: Vec<u8>,
+    pub(crate) transforms: Vec<Box<dyn ResponseTransform>>,
+    pub(crate) capture: bool,
+    pub(crate) chunks: Vec<(u64, Bytes)>,
+    pub(crate) panicked: bool,
+    pub(crate) on_finish: Option<Box<dyn FnOnce(StreamStats) + Send>>,
+    pub(crate) salvage: Option<Salvage>,
+    // Content of the first choice sent so far, and the last chunk's id,
+    // created and model for the chunks added after a failure
+    pub(crate) sent_text: String,
+    pub(crate) last_chunk: Option<serde_json::Value>,
+    pub(crate) retrying: Option<BoxFuture<'static, Option<String>>>,
+    pub(crate) upstream_error: Option<reqwest::Error>,
+    pub(crate) failure: Option<&'static str>,
+    // Content events after which the stream is cut with finish_reason "length"
+    pub(crate) output_cap: Option<u64>,
+    // Cuts a repeating stream with finish_reason "repetition"
+    pub(crate) loops: Option<LoopDetector>,
+    // Set once the stream was cut short by the proxy
+    pub(crate) cut: bool,
+}
+
+// Fetches the rest of the completion without streaming, given the text sent
+pub(crate) type SalvageRetry = Box<dyn FnOnce(String) -> BoxFuture<'static, Option<String>> + Send>;
+
+// What to send when the upstream fails mid-stream
+pub(crate) struct Salvage {
+    // A final chunk with finish_reason "error", then an error event
+    pub(crate) error_chunk: bool,
+    pub(crate) retry: Option<SalvageRetry>,
+}
+
+impl StreamPipeline {
+    pub(crate) fn new(
+        inner: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+        started: Instant,
+        transforms: Vec<Box<dyn ResponseTransform>>,
+        capture: bool,
+        on_finish: Box<dyn FnOnce(StreamStats) + Send>,
+    ) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            done: false,
+            started,
+            first_token: None,
+            token_events: 0,
+            usage: None,
+            pending: Vec::new(),
+            transforms,
+            capture,
+            chunks: Vec::new(),
+            panicked: false,
+            on_finish: Some(on_finish),
+            salvage: None,
+            sent_text: String::new(),
+            last_chunk: None,
+            retrying: None,
+            upstream_error: None,
+            failure: None,
+            output_cap: None,
+            loops: None,
+            cut: false,
+        }
+    }
+
+    pub(crate) fn with_output_cap(mut self, cap: u64) -> Self {
+        self.output_cap = Some(cap);
+        self
+    }
+
+    pub(crate) fn with_loop_detection(mut self, detector: LoopDetector) -> Self {
+        self.loops = Some(detector);
+        self
+    }
+
+    // The proxy may end the stream before the upstream does
+    fn cuts(&self) -> bool {
+        self.output_cap.is_some() || self.loops.is_some()
+    }
+
+    // Events are re-serialized rather than passed on as they came
+    fn rewrites(&self) -> bool {
+        !self.transforms.is_empty() || self.cuts()
+    }
+
+    // Ends the stream in place of the event at hand
+    fn cut_with(&mut self, finish_reason: &str, out: &mut Vec<u8>) {
+        self.cut = true;
+        let chunk = self.synthetic_chunk(serde_json::json!({}), Some(finish_reason));
+        out.extend_from_slice(chunk.as_bytes());
+        out.extend_from_slice(b"data: [DONE]\n\n");
+    }
+
+    pub(crate) fn with_salvage(mut self, salvage: Salvage) -> Self {
+        self.salvage = Some(salvage);
+        self
+    }
+
+    // Returns the bytes to send on for an upstream chunk
+    pub(crate) fn process(&mut self, chunk: &Bytes) -> Bytes {
+        if self.capture {
+            let offset = self.started.elapsed().as_millis() as u64;
+            self.chunks.push((offset, chunk.clone()));
+        }
+
+        // Events can be split across chunks, only complete ones are parsed
+        self.pending.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        while let Some((end, separator)) = sse_event_end(&self.pending) {
+            let event: Vec<u8> = self.pending.drain(..end + separator).collect();
+            self.process_event(&event[..end], &event[end..], &mut out);
+        }
+
+        if self.rewrites() {
+            out.into()
+        } else {
+            chunk.clone()
+        }
+    }
+
+    // Handles an unterminated event left over when the upstream ends
+    pub(crate) fn flush(&mut self) -> Bytes {
+        let event = std::mem::take(&mut self.pending);
+        let mut out = Vec::new();
+        if !event.is_empty() {
+            self.process_event(&event, b"", &mut out);
+        }
+        out.into()
+    }
+
+    pub(crate) fn process_event(&mut self, event: &[u8], separator: &[u8], out: &mut Vec<u8>) {
+        if self.cut {
+            return;
+        }
+        let text = String::from_utf8_lossy(event);
+        let mut lines = Vec::new();
+        for line in text.split('\n') {
+            let line = line.trim_end_matches('\r');
+            let data = line
+                .strip_prefix("data:")
+                .and_then(|data| serde_json::from_str::<serde_json::Value>(data.trim()).ok());
+            match data {
+                Some(mut value) => {
+                    self.observe(&value);
+                    if self.output_cap.is_some_and(|cap| self.token_events > cap) {
+                        println!(
+                            "✂️  Stream cut at the output cap of {} tokens",
+                            self.output_cap.unwrap_or_default()
+                        );
+                        self.cut_with("length", out);
+                        return;
+                    }
+                    let content = value["choices"][0]["delta"]["content"].as_str();
+                    if let (Some(loops), Some(content)) = (self.loops.as_mut(), content) {
+                        if loops.feed(content) {
+                            println!("🔁 Stream cut after the output started repeating itself");
+                            self.cut_with("repetition", out);
+                            return;
+                        }
+                    }
+                    for transform in self.transforms.iter_mut() {
+                        transform.apply(&mut value);
+                    }
+                    if let Some(reason) = self.transforms.iter().find_map(|t| t.cut()) {
+                        self.cut_with(reason, out);
+                        return;
+                    }
+                    if self.salvage.is_some() || self.cuts() {
+                        self.remember(&value);
+                    }
+                    lines.push(format!("data: {}", value));
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        if self.rewrites() {
+            out.extend_from_slice(lines.join("\n").as_bytes());
+            out.extend_from_slice(separator);
+        }
+    }
+
+    pub(crate) fn observe(&mut self, event: &serde_json::Value) {
+        if let Some(usage) = usage_from_json(event) {
+            self.usage = Some(usage);
+        }
+        let has_content = event["choices"]
+            .as_array()
+            .map(|choices| {
+                choices.iter().any(|choice| {
+                    let delta = &choice["delta"];
+                    [
+                        &delta["content"],
+                        &delta["reasoning_content"],
+                        &choice["text"],
+                    ]
+                    .iter()
+                    .any(|v| v.as_str().is_some_and(|s| !s.is_empty()))
+                        || delta.get("tool_calls").is_some()
+                })
+            })
+            .unwrap_or(false);
+        if has_content {
+            self.first_token.get_or_insert_with(Instant::now);
+            self.token_events += 1;
+        }
+    }
+
+    // What the client has seen, for the chunks that complete a broken stream
+    fn remember(&mut self, chunk: &serde_json::Value) {
+        if chunk.get("choices").is_none() {
+            return;
+        }
+        let choice = chunk["choices"].as_array().and_then(|choices| {
+            choices
+                .iter()
+                .find(|c| c["index"].as_u64().unwrap_or(0) == 0)
+        });
+        if let Some(text) = choice.and_then(|c| c["delta"]["content"].as_str()) {
+            self.sent_text.push_str(text);
+        }
+        self.last_chunk = Some(serde_json::json!({
+            "id": chunk["id"],
+            "object": "chat.completion.chunk",
+            "created": chunk["created"],
+            "model": chunk["model"],
+        }));
+    }
+
+    // A chunk for the first choice, run through the transforms
+    fn synthetic_chunk(&mut self, delta: serde_json::Value, finish_reason: Option<&str>) -> String {
+        let mut chunk = self
+            .last_chunk
+            .clone()
+            .unwrap_or_else(|| serde_json::json!({"object": "chat.completion.chunk"}));
+        chunk["choices"] =
+            serde_json::json!([{"index": 0, "delta": delta, "finish_reason": finish_reason}]);
+        for transform in self.transforms.iter_mut() {
+            transform.apply(&mut chunk);
+        }
+        format!("data: {}\n\n", chunk)
+    }
+
+    // Ends a stream whose upstream failed, as configured
+    fn fail(&mut self, err: reqwest::Error) -> Poll<Option<reqwest::Result<Bytes>>> {
+        self.done = true;
+        let error_chunk = self.salvage.as_ref().is_some_and(|s| s.error_chunk);
+        if !error_chunk {
+            self.failure = Some("dropped");
+            self.finish();
+            return Poll::Ready(Some(Err(err)));
+        }
+        self.failure = Some("error_chunk");
+        self.finish();
+        let mut out = self
+            .synthetic_chunk(serde_json::json!({}), Some("error"))
+            .into_bytes();
+        let message = format!("Upstream stream failed: {}", err);
+        out.extend_from_slice(&error_event(&message, "upstream_error"));
+        Poll::Ready(Some(Ok(out.into())))
+    }
+
+    pub(crate) fn finish(&mut self) {
+        let Some(on_finish) = self.on_finish.take() else {
+            return;
+        };
+        // Without a usage chunk every content delta counts as one token
+        let (prompt_tokens, completion_tokens) = match self.usage {
+            Some((prompt, completion)) if completion > 0 => (prompt, completion),
+            Some((prompt, _)) => (prompt, self.token_events),
+            None => (0, self.token_events),
+        };
+        on_finish(StreamStats {
+            ttft_ms: self
+                .first_token
+                .map(|t| t.duration_since(self.started).as_millis() as u64),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            prompt_tokens,
+            completion_tokens,
+            generation_secs: self
+                .first_token
+                .map(|t| t.elapsed().as_secs_f64())
+                .unwrap_or(0.0),
+            chunks: std::mem::take(&mut self.chunks),
+            panicked: self.panicked,
+            failure: self.failure,
+        });
+    }
+}
+
+impl Stream for StreamPipeline {
+    type Item = reqwest::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            // The rest of the completion, fetched after the upstream failed
+            if let Some(retry) = self.retrying.as_mut() {
+                let rest = ready!(retry.as_mut().poll(cx));
+                self.retrying = None;
+                let err = self
+                    .upstream_error
+                    .take()
+                    .expect("the error that started the retry");
+                let Some(rest) = rest else {
+                    return self.fail(err);
+                };
+                println!("🩹 Spliced a completion into the broken stream");
+                self.done = true;
+                self.failure = Some("spliced");
+                let mut out = String::new();
+                if !rest.is_empty() {
+                    self.observe(&serde_json::json!({"choices": [{"delta": {"content": rest}}]}));
+                    out.push_str(&self.synthetic_chunk(serde_json::json!({"content": rest}), None));
+                }
+                out.push_str(&self.synthetic_chunk(serde_json::json!({}), Some("stop")));
+                out.push_str("data: [DONE]\n\n");
+                self.finish();
+                return Poll::Ready(Some(Ok(out.into())));
+            }
+            match ready!(self.inner.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => {
+                    let processed = panic::catch_unwind(AssertUnwindSafe(|| self.process(&chunk)));
+                    let out = match processed {
+                        Ok(out) => out,
+                        // Only complete events have been sent so far, so the
+                        // error event can follow directly
+                        Err(payload) => {
+                            println!("🚨 Panic in stream transform: {}", panic_message(&*payload));
+                            self.done = true;
+                            self.panicked = true;
+                            self.finish();
+                            return Poll::Ready(Some(Ok(error_event(
+                                "Internal proxy error",
+                                "internal_error",
+                            ))));
+                        }
+                    };
+                    // The upstream is not read any further once cut
+                    if self.cut {
+                        self.done = true;
+                        self.inner = Box::pin(futures_util::stream::empty());
+                        self.finish();
+                        return Poll::Ready(Some(Ok(out)));
+                    }
+                    // Nothing to send until an event is complete
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(out)));
+                    }
+                }
+                // The client's deadline passed: end the stream with an error
+                // event instead of cutting the connection
+                Some(Err(err)) if err.is_timeout() => {
+                    self.done = true;
+                    let unterminated = !self.pending.is_empty();
+                    let mut out = self.flush().to_vec();
+                    if unterminated {
+                        out.extend_from_slice(b"\n\n");
+                    }
+                    self.finish();
+                    out.extend_from_slice(&error_event("Request deadline exceeded", "timeout"));
+                    return Poll::Ready(Some(Ok(out.into())));
+                }
+                Some(Err(err)) => {
+                    println!("💥 Upstream stream failed: {}", err);
+                    // A partial event is of no use to the client
+                    self.pending.clear();
+                    let retry = self.salvage.as_mut().and_then(|s| s.retry.take());
+                    if let Some(retry) = retry {
+                        self.retrying = Some(retry(self.sent_text.clone()));
+                        self.upstream_error = Some(err);
+                        continue;
+                    }
+                    return self.fail(err);
+                }
+                None => {
+                    self.done = true;
+                    let rest = self.flush();
+                    self.finish();
+                    return Poll::Ready((!rest.is_empty()).then_some(Ok(rest)));
+                }
+            }
+        }
+    }
+}
+
+// A final SSE error event in the OpenAI envelope, followed by [DONE]
+fn error_event(message: &str, error_type: &str) -> Bytes {
+    let error = serde_json::json!({"error": {"message": message, "type": error_type}});
+    format!("data: {}\n\ndata: [DONE]\n\n", error).into()
+}
+
+impl Drop for StreamPipeline {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
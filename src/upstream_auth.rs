@@ -0,0 +1,255 @@
+// How requests authenticate with a provider: the key as a bearer token or in
+// a header of its own, an OAuth2 client-credentials or Google service account
+// token, or an HMAC signature over the request
+
+use crate::config::UpstreamAuthConfig;
+use crate::error::ProxyError;
+use crate::secrets::{hex_encode, hmac_sha256};
+use crate::time::{format_utc, unix_now};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+pub(crate) struct UpstreamAuth {
+    config: UpstreamAuthConfig,
+    // OAuth2 access token and when to fetch the next one
+    token: Mutex<Option<(String, Instant)>>,
+    client: reqwest::Client,
+}
+
+// What the signature covers
+struct SignedParts<'a> {
+    method: &'a str,
+    // Path and query of the upstream URL
+    target: String,
+    // None for bodies streamed upstream unread
+    body: Option<&'a [u8]>,
+}
+
+// The headers for a request to `url`; a bearer token without a strategy
+pub(crate) async fn auth_headers(
+    auth: Option<&UpstreamAuth>,
+    key: &str,
+    method: &str,
+    url: &str,
+    body: Option<&[u8]>,
+) -> Result<Vec<(String, String)>, ProxyError> {
+    let Some(auth) = auth else {
+        return Ok(bearer(key));
+    };
+    let target = reqwest::Url::parse(url).map_or(String::new(), |u| match u.query() {
+        Some(query) => format!("{}?{}", u.path(), query),
+        None => u.path().to_string(),
+    });
+    auth.headers(
+        key,
+        &SignedParts {
+            method,
+            target,
+            body,
+        },
+    )
+    .await
+}
+
+impl UpstreamAuth {
+    pub(crate) fn new(config: UpstreamAuthConfig) -> Self {
+        Self {
+            config,
+            token: Mutex::new(None),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // `key` is the provider's key or the caller's own
+    async fn headers(
+        &self,
+        key: &str,
+        parts: &SignedParts<'_>,
+    ) -> Result<Vec<(String, String)>, ProxyError> {
+        Ok(match &self.config {
+            UpstreamAuthConfig::Bearer => bearer(key),
+            UpstreamAuthConfig::Header { name, prefix } => {
+                vec![(name.to_lowercase(), format!("{}{}", prefix, key))]
+            }
+            UpstreamAuthConfig::Oauth2 { .. } | UpstreamAuthConfig::ServiceAccount { .. } => {
+                vec![(
+                    "authorization".to_string(),
+                    format!("Bearer {}", self.access_token(key).await?),
+                )]
+            }
+            UpstreamAuthConfig::Hmac {
+                key_id,
+                header,
+                date_header,
+            } => {
+                let date = format_utc(unix_now());
+                let body_hash = match parts.body {
+                    Some(body) => hex_encode(&Sha256::digest(body)),
+                    None => "UNSIGNED-PAYLOAD".to_string(),
+                };
+                let string_to_sign = format!(
+                    "{}\n{}\n{}\n{}",
+                    date, parts.method, parts.target, body_hash
+                );
+                let signature = hex_encode(&hmac_sha256(key.as_bytes(), string_to_sign.as_bytes()));
+                let value = match key_id {
+                    Some(id) => format!("{}:{}", id, signature),
+                    None => signature,
+                };
+                vec![
+                    (date_header.to_lowercase(), date),
+                    (header.to_lowercase(), value),
+                ]
+            }
+        })
+    }
+
+    // Drops a token the upstream rejected; true when there is one to fetch anew
+    pub(crate) async fn invalidate(&self) -> bool {
+        match self.config {
+            UpstreamAuthConfig::Oauth2 { .. } | UpstreamAuthConfig::ServiceAccount { .. } => {
+                self.token.lock().await.take().is_some()
+            }
+            _ => false,
+        }
+    }
+
+    // Cached until shortly before it expires; one fetch at a time
+    async fn access_token(&self, key: &str) -> Result<String, ProxyError> {
+        let mut token = self.token.lock().await;
+        if let Some((access_token, refresh_at)) = token.as_ref() {
+            if Instant::now() < *refresh_at {
+                return Ok(access_token.clone());
+            }
+        }
+
+        let (token_url, form) = match &self.config {
+            UpstreamAuthConfig::Oauth2 {
+                token_url,
+                client_id,
+                client_secret,
+                scope,
+                audience,
+            } => {
+                let mut form = vec![
+                    ("grant_type", "client_credentials".to_string()),
+                    ("client_id", client_id.clone()),
+                    (
+                        "client_secret",
+                        client_secret.clone().unwrap_or_else(|| key.to_string()),
+                    ),
+                ];
+                if let Some(scope) = scope {
+                    form.push(("scope", scope.clone()));
+                }
+                if let Some(audience) = audience {
+                    form.push(("audience", audience.clone()));
+                }
+                (token_url.clone(), form)
+            }
+            UpstreamAuthConfig::ServiceAccount {
+                credentials_path,
+                scope,
+            } => {
+                let (token_url, assertion) = service_account_assertion(credentials_path, scope)
+                    .map_err(|reason| {
+                        eprintln!("🔐 Service account {}: {}", credentials_path, reason);
+                        ProxyError::RequestError(format!(
+                            "Upstream service account unusable: {}",
+                            reason
+                        ))
+                    })?;
+                let form = vec![
+                    (
+                        "grant_type",
+                        "urn:ietf:params:oauth:grant-type:jwt-bearer".to_string(),
+                    ),
+                    ("assertion", assertion),
+                ];
+                (token_url, form)
+            }
+            _ => unreachable!("access tokens are only fetched for oauth2 and service accounts"),
+        };
+        let failed = |reason: String| {
+            eprintln!("🔐 Token request to {} failed: {}", token_url, reason);
+            ProxyError::RequestError(format!("Upstream token request failed: {}", reason))
+        };
+        let response = self
+            .client
+            .post(&token_url)
+            .form(&form)
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await
+            .map_err(|e| failed(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(failed(response.status().to_string()));
+        }
+        let json: serde_json::Value = response.json().await.map_err(|e| failed(e.to_string()))?;
+        let access_token = json["access_token"]
+            .as_str()
+            .ok_or_else(|| failed("no access_token in the response".to_string()))?
+            .to_string();
+        // Renewed a tenth of its lifetime early, at most a minute
+        let lifetime = json["expires_in"].as_u64().unwrap_or(3600);
+        let refresh_in = lifetime - (lifetime / 10).min(60);
+        println!(
+            "🔐 Fetched an upstream access token, valid for {}s",
+            lifetime
+        );
+        *token = Some((
+            access_token.clone(),
+            Instant::now() + Duration::from_secs(refresh_in),
+        ));
+        Ok(access_token)
+    }
+}
+
+// The token endpoint and an RS256 JWT asking it for `scope`. The key file is
+// read for every token, so a replaced file is picked up.
+fn service_account_assertion(path: &str, scope: &str) -> Result<(String, String), String> {
+    use base64::Engine;
+    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let account: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let field = |name: &str| {
+        account[name]
+            .as_str()
+            .ok_or_else(|| format!("no {} in the key file", name))
+    };
+    let token_url = account["token_uri"]
+        .as_str()
+        .unwrap_or("https://oauth2.googleapis.com/token");
+    let now = unix_now();
+    let encode = |value: &serde_json::Value| {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value.to_string())
+    };
+    let header = serde_json::json!({
+        "alg": "RS256",
+        "typ": "JWT",
+        "kid": account["private_key_id"],
+    });
+    let claims = serde_json::json!({
+        "iss": field("client_email")?,
+        "scope": scope,
+        "aud": token_url,
+        "iat": now,
+        "exp": now + 3600,
+    });
+    let unsigned = format!("{}.{}", encode(&header), encode(&claims));
+    let key = PKey::private_key_from_pem(field("private_key")?.as_bytes())
+        .map_err(|e| format!("invalid private_key: {}", e))?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+    signer
+        .update(unsigned.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let signature = signer.sign_to_vec().map_err(|e| e.to_string())?;
+    let signature = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature);
+    Ok((token_url.to_string(), format!("{}.{}", unsigned, signature)))
+}
+
+fn bearer(key: &str) -> Vec<(String, String)> {
+    vec![("authorization".to_string(), format!("Bearer {}", key))]
+}
@@ -0,0 +1,377 @@
+// Usage ledger with CSV/JSONL export and monthly billing rollups
+
+use crate::config::UsageLedgerConfig;
+use crate::error::ProxyError;
+use crate::models::ModelPricing;
+use crate::proxy::RequestLog;
+use crate::retention::remove_lines;
+use crate::router::json_response;
+use crate::state::AppState;
+use crate::time::{civil_from_days, days_from_civil, format_utc, parse_utc, unix_now};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// One line of the ledger per request with known usage. The cost is fixed
+// when the request is recorded, so later price changes keep history intact.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct UsageRecord {
+    pub(crate) time: String,
+    pub(crate) request_id: String,
+    pub(crate) tenant: Option<String>,
+    pub(crate) client: String,
+    // The end user named in the request, for erasure requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) tags: BTreeMap<String, String>,
+    // Fingerprint of the system prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prompt: Option<String>,
+    // The prompt template version, e.g. "support@v2"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prompt_version: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    // USD, None without pricing for the model
+    pub(crate) cost: Option<f64>,
+}
+
+const CSV_HEADER: &str = "time,request_id,tenant,client,model,prompt_tokens,completion_tokens,cost";
+
+impl UsageRecord {
+    pub(crate) fn new(log: &RequestLog, prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            time: format_utc(unix_now()),
+            request_id: log.request_id.clone(),
+            tenant: log.tenant.clone(),
+            client: log.client.clone(),
+            user: log.user.clone(),
+            tags: log.tags.clone(),
+            prompt: log.prompt_fingerprint.clone(),
+            prompt_version: log.prompt_version.clone(),
+            model: log.model.clone(),
+            prompt_tokens,
+            completion_tokens,
+            cost: log
+                .pricing
+                .as_ref()
+                .map(|p| cost(p, prompt_tokens, completion_tokens)),
+        }
+    }
+
+    fn csv_row(&self) -> String {
+        [
+            csv_field(&self.time),
+            csv_field(&self.request_id),
+            csv_field(self.tenant.as_deref().unwrap_or("")),
+            csv_field(&self.client),
+            csv_field(self.model.as_deref().unwrap_or("")),
+            self.prompt_tokens.to_string(),
+            self.completion_tokens.to_string(),
+            self.cost.map(|c| format!("{:.6}", c)).unwrap_or_default(),
+        ]
+        .join(",")
+    }
+}
+
+pub(crate) fn cost(pricing: &ModelPricing, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+    (prompt_tokens as f64 * pricing.prompt + completion_tokens as f64 * pricing.completion)
+        / 1_000_000.0
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub(crate) struct UsageLedger {
+    pub(crate) config: UsageLedgerConfig,
+    file: Mutex<File>,
+}
+
+impl UsageLedger {
+    pub(crate) fn open(config: UsageLedgerConfig) -> std::io::Result<Self> {
+        let path = Path::new(&config.path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            config,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub(crate) fn record(&self, record: &UsageRecord) {
+        let line = serde_json::to_string(record).unwrap_or_default();
+        if let Err(err) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            eprintln!("⚠️  Failed to write usage ledger: {}", err);
+        }
+    }
+
+    // Drops the lines `remove` matches, returning how many there were
+    pub(crate) fn remove(
+        &self,
+        remove: impl Fn(&serde_json::Value) -> bool,
+    ) -> std::io::Result<usize> {
+        remove_lines(&self.config.path, &self.file, remove)
+    }
+
+    // Records in [from, until) passing the filter, in ledger order
+    fn read(
+        &self,
+        from: u64,
+        until: u64,
+        filter: &UsageFilter,
+    ) -> std::io::Result<Vec<UsageRecord>> {
+        // Hold the lock so no half-written line is read
+        let _guard = self.file.lock().unwrap();
+        let reader = BufReader::new(File::open(&self.config.path)?);
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let Ok(record) = serde_json::from_str::<UsageRecord>(&line?) else {
+                continue;
+            };
+            let at = parse_utc(&record.time).unwrap_or(0);
+            if at >= from && at < until && filter.matches(&record) {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UsageFilter {
+    tenant: Option<String>,
+    client: Option<String>,
+    model: Option<String>,
+    // "key=value", e.g. tag=app=chatbot
+    tag: Option<String>,
+    // A prompt fingerprint
+    prompt: Option<String>,
+    prompt_version: Option<String>,
+}
+
+impl UsageFilter {
+    fn matches(&self, record: &UsageRecord) -> bool {
+        let field = |filter: &Option<String>, value: Option<&str>| {
+            filter.as_deref().map(|f| value == Some(f)).unwrap_or(true)
+        };
+        let tagged = self.tag.as_deref().is_none_or(|tag| {
+            tag.split_once('=')
+                .is_some_and(|(key, value)| record.tags.get(key).is_some_and(|v| v == value))
+        });
+        field(&self.tenant, record.tenant.as_deref())
+            && field(&self.client, Some(&record.client))
+            && field(&self.model, record.model.as_deref())
+            && field(&self.prompt, record.prompt.as_deref())
+            && field(&self.prompt_version, record.prompt_version.as_deref())
+            && tagged
+    }
+}
+
+// e.g. /admin/usage/export?from=2026-10-01&until=2026-10-15&format=csv&tenant=team-a
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExportQuery {
+    from: Option<String>,
+    until: Option<String>,
+    #[serde(default = "default_export_format")]
+    format: String,
+    #[serde(flatten)]
+    filter: UsageFilter,
+}
+
+fn default_export_format() -> String {
+    "jsonl".to_string()
+}
+
+// e.g. /admin/usage/rollup?month=2026-10&format=csv
+#[derive(Debug, Deserialize)]
+pub(crate) struct RollupQuery {
+    month: Option<String>,
+    format: Option<String>,
+    #[serde(flatten)]
+    filter: UsageFilter,
+}
+
+fn ledger(state: &AppState) -> Result<&UsageLedger, ProxyError> {
+    state.usage_ledger.as_ref().ok_or_else(|| {
+        ProxyError::Forbidden("Usage ledger is disabled, set usage_ledger.path".to_string())
+    })
+}
+
+fn parse_time(value: Option<&str>, default: u64) -> Result<u64, ProxyError> {
+    match value {
+        None => Ok(default),
+        Some(value) => parse_utc(value)
+            .ok_or_else(|| ProxyError::InvalidRequest(format!("Invalid time {}", value))),
+    }
+}
+
+fn read_error(err: std::io::Error) -> ProxyError {
+    ProxyError::BodyReadError(format!("Failed to read usage ledger: {}", err))
+}
+
+fn text_response(content_type: &'static str, body: String) -> Response {
+    let mut response = Response::new(Body::from(body));
+    response
+        .headers_mut()
+        .insert("content-type", HeaderValue::from_static(content_type));
+    response
+}
+
+pub(crate) async fn export_usage_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let ledger = ledger(&state)?;
+    let from = parse_time(query.from.as_deref(), 0)?;
+    let until = parse_time(query.until.as_deref(), u64::MAX)?;
+    let records = ledger
+        .read(from, until, &query.filter)
+        .map_err(read_error)?;
+
+    match query.format.as_str() {
+        "csv" => {
+            let mut body = format!("{}\n", CSV_HEADER);
+            for record in &records {
+                body.push_str(&record.csv_row());
+                body.push('\n');
+            }
+            Ok(text_response("text/csv", body))
+        }
+        "jsonl" => {
+            let body: String = records
+                .iter()
+                .map(|r| format!("{}\n", serde_json::to_string(r).unwrap_or_default()))
+                .collect();
+            Ok(text_response("application/x-ndjson", body))
+        }
+        other => Err(ProxyError::InvalidRequest(format!(
+            "Unknown export format {}, use csv or jsonl",
+            other
+        ))),
+    }
+}
+
+#[derive(Debug, Serialize, Default)]
+struct RollupRow {
+    tenant: Option<String>,
+    client: String,
+    model: Option<String>,
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: f64,
+}
+
+// Start and end of a "YYYY-MM" month, the current one by default
+fn month_range(month: Option<&str>) -> Result<(String, u64, u64), ProxyError> {
+    let (year, month) = match month {
+        Some(value) => value
+            .split_once('-')
+            .and_then(|(y, m)| Some((y.parse::<i64>().ok()?, m.parse::<u32>().ok()?)))
+            .filter(|(_, m)| (1..=12).contains(m))
+            .ok_or_else(|| ProxyError::InvalidRequest(format!("Invalid month {}", value)))?,
+        None => {
+            let (year, month, _) = civil_from_days((unix_now() / 86400) as i64);
+            (year, month)
+        }
+    };
+    let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let day_secs = |y, m| (days_from_civil(y, m, 1).max(0) as u64) * 86400;
+    Ok((
+        format!("{:04}-{:02}", year, month),
+        day_secs(year, month),
+        day_secs(next_year, next_month),
+    ))
+}
+
+// Requests, tokens and cost per tenant, client and model for one month
+pub(crate) async fn usage_rollup_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<RollupQuery>,
+) -> Result<Response, ProxyError> {
+    state.check_admin(&headers)?;
+    let ledger = ledger(&state)?;
+    let (month, from, until) = month_range(query.month.as_deref())?;
+    let records = ledger
+        .read(from, until, &query.filter)
+        .map_err(read_error)?;
+
+    let mut rows: BTreeMap<(Option<String>, String, Option<String>), RollupRow> = BTreeMap::new();
+    for record in records {
+        let key = (
+            record.tenant.clone(),
+            record.client.clone(),
+            record.model.clone(),
+        );
+        let row = rows.entry(key).or_insert_with(|| RollupRow {
+            tenant: record.tenant,
+            client: record.client,
+            model: record.model,
+            ..RollupRow::default()
+        });
+        row.requests += 1;
+        row.prompt_tokens += record.prompt_tokens;
+        row.completion_tokens += record.completion_tokens;
+        row.cost += record.cost.unwrap_or(0.0);
+    }
+    let rows: Vec<RollupRow> = rows.into_values().collect();
+
+    if query.format.as_deref() == Some("csv") {
+        let mut body =
+            "month,tenant,client,model,requests,prompt_tokens,completion_tokens,cost\n".to_string();
+        for row in &rows {
+            body.push_str(&format!(
+                "{},{},{},{},{},{},{},{:.6}\n",
+                month,
+                csv_field(row.tenant.as_deref().unwrap_or("")),
+                csv_field(&row.client),
+                csv_field(row.model.as_deref().unwrap_or("")),
+                row.requests,
+                row.prompt_tokens,
+                row.completion_tokens,
+                row.cost
+            ));
+        }
+        return Ok(text_response("text/csv", body));
+    }
+
+    let total_cost: f64 = rows.iter().map(|r| r.cost).sum();
+    let body = serde_json::json!({
+        "month": month,
+        "from": format_utc(from),
+        "until": format_utc(until),
+        "rows": rows,
+        "total": {
+            "requests": rows.iter().map(|r| r.requests).sum::<u64>(),
+            "prompt_tokens": rows.iter().map(|r| r.prompt_tokens).sum::<u64>(),
+            "completion_tokens": rows.iter().map(|r| r.completion_tokens).sum::<u64>(),
+            "cost": total_cost,
+        },
+    });
+    Ok(json_response(&body))
+}
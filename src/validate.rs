@@ -0,0 +1,414 @@
+// Load-time checks of the settings, reported together with file and line
+
+use crate::config::Settings;
+use std::collections::HashMap;
+use std::ops::Range;
+use toml_edit::{ImDocument, Item, TableLike, Value};
+
+// A config file and its text, for pointing problems at lines
+pub(crate) struct Source {
+    pub(crate) name: String,
+    pub(crate) text: String,
+}
+
+struct Problem {
+    // Dotted key path, e.g. "tenants.0.clients.1.key"
+    path: Vec<String>,
+    message: String,
+    // Only reported when the key is in one of the files, so APP_* environment
+    // variables are not flagged
+    file_only: bool,
+}
+
+impl Problem {
+    fn new(path: &[&str], message: String) -> Self {
+        Self {
+            path: path.iter().map(|s| s.to_string()).collect(),
+            message,
+            file_only: false,
+        }
+    }
+}
+
+// Deserializes the settings, then fails with every unknown key and
+// inconsistency found
+pub(crate) fn deserialize_checked(
+    config: config::Config,
+    sources: &[Source],
+) -> Result<Settings, config::ConfigError> {
+    let (config, mut problems) = replace_invalid_efforts(config)?;
+    let settings: Settings = serde_ignored::deserialize(config, |path| {
+        problems.push(Problem {
+            path: path_segments(&path),
+            message: "unknown key".to_string(),
+            file_only: true,
+        })
+    })?;
+    problems.extend(check(&settings));
+
+    let documents: Vec<(&str, ImDocument<&str>)> = sources
+        .iter()
+        .filter_map(|s| Some((s.name.as_str(), ImDocument::parse(s.text.as_str()).ok()?)))
+        .collect();
+    // Sorted by file and line, problems without a location last
+    let mut located = Vec::new();
+    for problem in problems {
+        // The last file setting the key is the one that counts
+        let location = documents
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, (name, document))| {
+                let (span, exact) = locate(document.as_item(), &problem.path)?;
+                let line = document.raw()[..span.start].matches('\n').count() + 1;
+                Some((i, line, name, exact))
+            });
+        let path = problem.path.join(".");
+        match location {
+            Some((_, _, _, false)) | None if problem.file_only => {}
+            Some((i, line, name, _)) => located.push((
+                (i, line),
+                format!("  {}:{}: {}: {}", name, line, path, problem.message),
+            )),
+            None => located.push(((usize::MAX, 0), format!("  {}: {}", path, problem.message))),
+        }
+    }
+    located.sort_by_key(|(position, _)| *position);
+    let lines: Vec<String> = located.into_iter().map(|(_, line)| line).collect();
+
+    if lines.is_empty() {
+        return Ok(settings);
+    }
+    Err(config::ConfigError::Message(format!(
+        "{} problem{} found:\n{}",
+        lines.len(),
+        if lines.len() == 1 { "" } else { "s" },
+        lines.join("\n")
+    )))
+}
+
+// An unknown reasoning_effort would fail deserialization on its own; it is
+// reported here and replaced so the remaining checks still run
+fn replace_invalid_efforts(
+    config: config::Config,
+) -> Result<(config::Config, Vec<Problem>), config::ConfigError> {
+    let count = |key: &str| config.get_array(key).map(|a| a.len()).unwrap_or(0);
+    // Config keys of the model lists, and their problem paths
+    let mut lists = vec![(
+        "available_models".to_string(),
+        vec!["available_models".to_string()],
+    )];
+    lists.extend((0..count("tenants")).map(|t| {
+        let path = vec![
+            "tenants".to_string(),
+            t.to_string(),
+            "available_models".to_string(),
+        ];
+        (format!("tenants[{}].available_models", t), path)
+    }));
+
+    let mut problems = Vec::new();
+    let mut builder = config::Config::builder().add_source(config.clone());
+    for (list, path) in &lists {
+        for i in 0..count(list) {
+            let key = format!("{}[{}].reasoning_effort", list, i);
+            let Ok(effort) = config.get_string(&key) else {
+                continue;
+            };
+            if ["none", "low", "medium", "high"].contains(&effort.as_str()) {
+                continue;
+            }
+            let mut path = path.clone();
+            path.extend([i.to_string(), "reasoning_effort".to_string()]);
+            problems.push(Problem {
+                path,
+                message: format!(
+                    "invalid reasoning_effort {:?}, expected none, low, medium or high",
+                    effort
+                ),
+                file_only: false,
+            });
+            builder = builder.set_override(key, "medium")?;
+        }
+    }
+    if problems.is_empty() {
+        return Ok((config, problems));
+    }
+    Ok((builder.build()?, problems))
+}
+
+fn path_segments(path: &serde_ignored::Path) -> Vec<String> {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => Vec::new(),
+        Path::Seq { parent, index } => {
+            let mut segments = path_segments(parent);
+            segments.push(index.to_string());
+            segments
+        }
+        Path::Map { parent, key } => {
+            let mut segments = path_segments(parent);
+            segments.push(key.clone());
+            segments
+        }
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => path_segments(parent),
+    }
+}
+
+fn check(settings: &Settings) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    check_url(
+        &mut problems,
+        &["openai_api_base"],
+        &settings.openai_api_base,
+    );
+    check_models(
+        &mut problems,
+        "available_models",
+        &settings.available_models,
+    );
+    for (index, tenant) in settings.tenants.iter().enumerate() {
+        let t = index.to_string();
+        if let Some(base) = &tenant.openai_api_base {
+            check_url(&mut problems, &["tenants", &t, "openai_api_base"], base);
+        }
+        let path = format!("tenants.{}.available_models", t);
+        check_models(&mut problems, &path, &tenant.available_models);
+        if let Some(earlier) = settings.tenants[..index]
+            .iter()
+            .position(|other| other.name == tenant.name)
+        {
+            problems.push(Problem::new(
+                &["tenants", &t, "name"],
+                format!(
+                    "tenant {:?} is already defined as tenants.{}",
+                    tenant.name, earlier
+                ),
+            ));
+        }
+    }
+
+    // A client key may only identify one client
+    let mut keys: HashMap<&str, String> = HashMap::new();
+    for (t, tenant) in settings.tenants.iter().enumerate() {
+        for (c, client) in tenant.clients.iter().enumerate() {
+            let Some(key) = client.key.as_deref() else {
+                continue;
+            };
+            let here = format!("tenants.{}.clients.{}", t, c);
+            match keys.get(key) {
+                Some(earlier) => problems.push(Problem::new(
+                    &["tenants", &t.to_string(), "clients", &c.to_string(), "key"],
+                    format!("key is already used by {}", earlier),
+                )),
+                None => {
+                    keys.insert(key, here);
+                }
+            }
+        }
+    }
+
+    for (index, provider) in settings.providers.iter().enumerate() {
+        let p = index.to_string();
+        check_url(
+            &mut problems,
+            &["providers", &p, "api_base"],
+            &provider.api_base,
+        );
+        for (r, replica) in provider.replicas.iter().enumerate() {
+            check_url(
+                &mut problems,
+                &["providers", &p, "replicas", &r.to_string()],
+                replica,
+            );
+        }
+        if let Some(earlier) = settings.providers[..index]
+            .iter()
+            .position(|other| other.name == provider.name)
+        {
+            problems.push(Problem::new(
+                &["providers", &p, "name"],
+                format!(
+                    "provider {:?} is already defined as providers.{}",
+                    provider.name, earlier
+                ),
+            ));
+        }
+    }
+
+    if let Some(log) = &settings.access_log {
+        if !["daily", "hourly", "never"].contains(&log.rotation.as_str()) {
+            problems.push(Problem::new(
+                &["access_log", "rotation"],
+                format!(
+                    "invalid rotation {:?}, expected daily, hourly or never",
+                    log.rotation
+                ),
+            ));
+        }
+    }
+
+    // Two rules or renames claiming the same name would be applied in file order
+    for (i, rule) in settings.routing.rules.iter().enumerate() {
+        if let Some(earlier) = settings.routing.rules[..i]
+            .iter()
+            .position(|other| other.model == rule.model)
+        {
+            problems.push(Problem::new(
+                &["routing", "rules", &i.to_string(), "model"],
+                format!(
+                    "model {:?} already has a rule, routing.rules.{}",
+                    rule.model, earlier
+                ),
+            ));
+        }
+    }
+    let renames = &settings.model_catalog.rename;
+    for (i, rename) in renames.iter().enumerate() {
+        if let Some(earlier) = renames[..i]
+            .iter()
+            .position(|other| other.from == rename.from)
+        {
+            problems.push(Problem::new(
+                &["model_catalog", "rename", &i.to_string(), "from"],
+                format!(
+                    "{:?} is already renamed by model_catalog.rename.{}",
+                    rename.from, earlier
+                ),
+            ));
+        }
+        if let Some(earlier) = renames[..i].iter().position(|other| other.to == rename.to) {
+            problems.push(Problem::new(
+                &["model_catalog", "rename", &i.to_string(), "to"],
+                format!(
+                    "{:?} is already the name of model_catalog.rename.{}",
+                    rename.to, earlier
+                ),
+            ));
+        }
+    }
+
+    problems
+}
+
+fn check_models(problems: &mut Vec<Problem>, path: &str, models: &[crate::models::ModelInfo]) {
+    let path: Vec<&str> = path.split('.').collect();
+    for (i, model) in models.iter().enumerate() {
+        let index = i.to_string();
+        let at = |field: &'static str| {
+            let mut at = path.clone();
+            at.extend([index.as_str(), field]);
+            at
+        };
+        if let Some(earlier) = models[..i].iter().position(|other| other.id == model.id) {
+            problems.push(Problem::new(
+                &at("id"),
+                format!(
+                    "duplicate model ID {:?}, first defined at index {}",
+                    model.id, earlier
+                ),
+            ));
+        }
+        if let Some(options) = &model.engine_options {
+            if options.engine == crate::models::Engine::Tgi && options.guided.grammar.is_some() {
+                problems.push(Problem::new(
+                    &at("engine_options"),
+                    "TGI takes no EBNF grammars, use guided.regex or guided.json".to_string(),
+                ));
+            }
+        }
+        let mut stop = model.stop.clone();
+        stop.sort();
+        stop.dedup();
+        if stop.len() > crate::models::MAX_STOP_SEQUENCES {
+            problems.push(Problem::new(
+                &at("stop"),
+                format!(
+                    "{} stop sequences, upstreams accept at most {}",
+                    stop.len(),
+                    crate::models::MAX_STOP_SEQUENCES
+                ),
+            ));
+        }
+        let budget = model.thinking_budget_tokens;
+        if let (Some(budget), Some(max)) = (budget, model.max_output_tokens) {
+            if budget >= max {
+                problems.push(Problem::new(
+                    &at("thinking_budget_tokens"),
+                    format!(
+                        "thinking budget of {} tokens leaves no room in max_output_tokens = {}",
+                        budget, max
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+fn check_url(problems: &mut Vec<Problem>, path: &[&str], url: &str) {
+    let message = match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => return,
+        Ok(parsed) => format!(
+            "{:?} is not an http(s) URL with a host (scheme {})",
+            url,
+            parsed.scheme()
+        ),
+        Err(err) => format!("{:?} is not a valid URL: {}", url, err),
+    };
+    problems.push(Problem::new(path, message));
+}
+
+// Span of the deepest key found along the path, and whether it was found whole
+fn locate(item: &Item, path: &[String]) -> Option<(Range<usize>, bool)> {
+    let (segment, rest) = path.split_first()?;
+    match item {
+        Item::Table(table) => locate_in_table(table, segment, rest),
+        Item::ArrayOfTables(array) => {
+            let table = array.get(segment.parse().ok()?)?;
+            match rest.split_first() {
+                None => Some((table.span()?, true)),
+                Some((key, rest)) => {
+                    locate_in_table(table, key, rest).or_else(|| Some((table.span()?, false)))
+                }
+            }
+        }
+        Item::Value(value) => locate_in_value(value, segment, rest),
+        Item::None => None,
+    }
+}
+
+fn locate_in_value(value: &Value, segment: &str, rest: &[String]) -> Option<(Range<usize>, bool)> {
+    match value {
+        Value::InlineTable(table) => locate_in_table(table, segment, rest),
+        Value::Array(array) => {
+            let value = array.get(segment.parse().ok()?)?;
+            match rest.split_first() {
+                None => Some((value.span()?, true)),
+                Some((key, rest)) => {
+                    locate_in_value(value, key, rest).or_else(|| Some((value.span()?, false)))
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+// The config crate lowercases keys, so they are matched ignoring case
+fn locate_in_table(
+    table: &dyn TableLike,
+    segment: &str,
+    rest: &[String],
+) -> Option<(Range<usize>, bool)> {
+    let (name, _) = table
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(segment))?;
+    let (key, item) = table.get_key_value(name)?;
+    let span = key.span()?;
+    if rest.is_empty() {
+        return Some((span, true));
+    }
+    locate(item, rest).or(Some((span, false)))
+}
@@ -0,0 +1,415 @@
+// Vertex AI's generateContent API behind the chat completions schema:
+// publisher model URLs, request and response translation, and the SSE stream.
+// The Gemini API takes the same schema under its own model URLs.
+
+use crate::adapters::StreamTranslator;
+use crate::time::unix_now;
+use crate::transform::sse_event_end;
+use axum::body::Bytes;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+// Top-level fields generateContent takes; everything else is dropped
+const REQUEST_FIELDS: &[&str] = &[
+    "contents",
+    "systemInstruction",
+    "tools",
+    "toolConfig",
+    "generationConfig",
+    "safetySettings",
+    "labels",
+    "cachedContent",
+];
+
+// e.g. {api_base}/publishers/google/models/gemini-2.0-flash:streamGenerateContent?alt=sse;
+// a model given as "publisher/model" names its publisher
+pub(crate) fn model_url(api_base: &str, model: &str, stream: bool) -> String {
+    let (publisher, model) = model.split_once('/').unwrap_or(("google", model));
+    let method = match stream {
+        true => "streamGenerateContent?alt=sse",
+        false => "generateContent",
+    };
+    format!(
+        "{}/publishers/{}/models/{}:{}",
+        api_base.trim_end_matches('/'),
+        publisher,
+        model,
+        method
+    )
+}
+
+// e.g. {api_base}/models/gemini-2.0-flash:generateContent on the Gemini API,
+// where api_base is https://generativelanguage.googleapis.com/v1beta
+pub(crate) fn gemini_url(api_base: &str, model: &str, stream: bool) -> String {
+    let model = model.trim_start_matches("models/");
+    let method = match stream {
+        true => "streamGenerateContent?alt=sse",
+        false => "generateContent",
+    };
+    format!(
+        "{}/models/{}:{}",
+        api_base.trim_end_matches('/'),
+        model,
+        method
+    )
+}
+
+// Runs after the Gemini content parts and parameter renames
+pub(crate) fn translate_request(obj: &mut Map<String, Value>) {
+    let messages = match obj.remove("messages") {
+        Some(Value::Array(messages)) => messages,
+        _ => Vec::new(),
+    };
+    let mut system = Vec::new();
+    let mut contents: Vec<Value> = Vec::new();
+    // Function names by tool call id, for the tool messages answering them
+    let mut call_names = HashMap::new();
+    for message in &messages {
+        let mut parts = content_parts(&message["content"]);
+        match message["role"].as_str().unwrap_or("user") {
+            "system" | "developer" => system.append(&mut parts),
+            "assistant" => {
+                for call in message["tool_calls"].as_array().into_iter().flatten() {
+                    let name = call["function"]["name"].as_str().unwrap_or_default();
+                    if let Some(id) = call["id"].as_str() {
+                        call_names.insert(id.to_string(), name.to_string());
+                    }
+                    let args = call["function"]["arguments"]
+                        .as_str()
+                        .and_then(|args| serde_json::from_str::<Value>(args).ok())
+                        .unwrap_or_else(|| json!({}));
+                    parts.push(json!({"functionCall": {"name": name, "args": args}}));
+                }
+                push_content(&mut contents, "model", parts);
+            }
+            "tool" => {
+                let name = message["tool_call_id"]
+                    .as_str()
+                    .and_then(|id| call_names.get(id))
+                    .cloned()
+                    .unwrap_or_default();
+                let text = text_of(&message["content"]);
+                let response = match serde_json::from_str::<Value>(&text) {
+                    Ok(value) if value.is_object() => value,
+                    _ => json!({"content": text}),
+                };
+                let part = json!({"functionResponse": {"name": name, "response": response}});
+                push_content(&mut contents, "user", vec![part]);
+            }
+            _ => push_content(&mut contents, "user", parts),
+        }
+    }
+    obj.insert("contents".to_string(), Value::Array(contents));
+    if !system.is_empty() {
+        obj.insert("systemInstruction".to_string(), json!({"parts": system}));
+    }
+
+    let declarations: Vec<Value> = obj
+        .remove("tools")
+        .and_then(|tools| tools.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|tool| tool.get("function"))
+        .map(|function| {
+            let mut declaration = json!({"name": function["name"]});
+            for field in ["description", "parameters"] {
+                if let Some(value) = function.get(field) {
+                    declaration[field] = schema(value.clone());
+                }
+            }
+            declaration
+        })
+        .collect();
+    if !declarations.is_empty() {
+        obj.insert(
+            "tools".to_string(),
+            json!([{"functionDeclarations": declarations}]),
+        );
+    }
+    let calling = match obj.remove("tool_choice") {
+        Some(Value::String(choice)) => match choice.as_str() {
+            "none" => Some(json!({"mode": "NONE"})),
+            "required" => Some(json!({"mode": "ANY"})),
+            _ => Some(json!({"mode": "AUTO"})),
+        },
+        Some(choice) => choice["function"]["name"]
+            .as_str()
+            .map(|name| json!({"mode": "ANY", "allowedFunctionNames": [name]})),
+        None => None,
+    };
+    if let Some(calling) = calling {
+        obj.insert(
+            "toolConfig".to_string(),
+            json!({"functionCallingConfig": calling}),
+        );
+    }
+
+    if let Some(format) = obj.remove("response_format") {
+        let config = obj.entry("generationConfig").or_insert_with(|| json!({}));
+        match format["type"].as_str() {
+            Some("json_object") => config["responseMimeType"] = "application/json".into(),
+            Some("json_schema") => {
+                config["responseMimeType"] = "application/json".into();
+                if let Some(value) = format["json_schema"].get("schema") {
+                    config["responseSchema"] = schema(value.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    obj.retain(|field, _| REQUEST_FIELDS.contains(&field.as_str()));
+}
+
+// Consecutive turns of one role are merged, Gemini wants them alternating
+fn push_content(contents: &mut Vec<Value>, role: &str, mut parts: Vec<Value>) {
+    if parts.is_empty() {
+        return;
+    }
+    if let Some(last) = contents.last_mut().filter(|c| c["role"] == role) {
+        if let Some(existing) = last["parts"].as_array_mut() {
+            existing.append(&mut parts);
+            return;
+        }
+    }
+    contents.push(json!({"role": role, "parts": parts}));
+}
+
+// Content parts are already in Gemini's schema, apart from remote images
+fn content_parts(content: &Value) -> Vec<Value> {
+    match content {
+        Value::String(text) if !text.is_empty() => vec![json!({"text": text})],
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| match part["type"].as_str() {
+                Some("image_url") => {
+                    let url = part["image_url"]["url"]
+                        .as_str()
+                        .or(part["image_url"].as_str())
+                        .unwrap_or_default();
+                    json!({"fileData": {"fileUri": url, "mimeType": mime_type(url)}})
+                }
+                Some("text") => json!({"text": part["text"]}),
+                _ => part.clone(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().filter_map(|p| p["text"].as_str()).collect(),
+        _ => String::new(),
+    }
+}
+
+fn mime_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    match path.rsplit('.').next().map(|e| e.to_ascii_lowercase()) {
+        Some(ext) if ext == "png" => "image/png",
+        Some(ext) if ext == "webp" => "image/webp",
+        Some(ext) if ext == "gif" => "image/gif",
+        Some(ext) if ext == "pdf" => "application/pdf",
+        _ => "image/jpeg",
+    }
+}
+
+// JSON Schema keywords Vertex rejects are left out
+fn schema(mut value: Value) -> Value {
+    match &mut value {
+        Value::Object(obj) => {
+            obj.remove("additionalProperties");
+            obj.remove("$schema");
+            obj.remove("strict");
+            for item in obj.values_mut() {
+                *item = schema(item.take());
+            }
+        }
+        Value::Array(items) => {
+            for item in items.iter_mut() {
+                *item = schema(item.take());
+            }
+        }
+        _ => {}
+    }
+    value
+}
+
+fn finish_reason(reason: &str, tool_calls: bool) -> &'static str {
+    match reason {
+        "MAX_TOKENS" => "length",
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => "content_filter",
+        _ if tool_calls => "tool_calls",
+        _ => "stop",
+    }
+}
+
+fn usage(response: &Value) -> Option<Value> {
+    let usage = response.get("usageMetadata")?;
+    let prompt = usage["promptTokenCount"].as_u64().unwrap_or(0);
+    let completion = usage["candidatesTokenCount"].as_u64().unwrap_or(0)
+        + usage["thoughtsTokenCount"].as_u64().unwrap_or(0);
+    Some(json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    }))
+}
+
+// Text, thoughts and function calls of one candidate; call ids continue
+// from `calls` so they stay unique within a stream
+fn candidate_parts(id: &str, candidate: &Value, calls: &mut usize) -> (String, String, Vec<Value>) {
+    let (mut text, mut thoughts, mut tool_calls) = (String::new(), String::new(), Vec::new());
+    for part in candidate["content"]["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        if let Some(call) = part.get("functionCall") {
+            let call_id = call["id"]
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("call_{}_{}", id, calls));
+            tool_calls.push(json!({
+                "index": *calls,
+                "id": call_id,
+                "type": "function",
+                "function": {
+                    "name": call["name"],
+                    "arguments": call.get("args").unwrap_or(&json!({})).to_string(),
+                },
+            }));
+            *calls += 1;
+        } else if let Some(part_text) = part["text"].as_str() {
+            match part["thought"].as_bool() {
+                Some(true) => thoughts.push_str(part_text),
+                _ => text.push_str(part_text),
+            }
+        }
+    }
+    (text, thoughts, tool_calls)
+}
+
+// A generateContent response as a chat completion
+pub(crate) fn translate_response(body: &[u8], id: &str, model: &str) -> Option<Bytes> {
+    let response: Value = serde_json::from_slice(body).ok()?;
+    let choices: Vec<Value> = response["candidates"]
+        .as_array()?
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            let mut calls = 0;
+            let (text, thoughts, mut tool_calls) = candidate_parts(id, candidate, &mut calls);
+            let mut message = json!({
+                "role": "assistant",
+                "content": Some(text).filter(|t| !t.is_empty()),
+            });
+            if !thoughts.is_empty() {
+                message["reasoning_content"] = thoughts.into();
+            }
+            let reason = candidate["finishReason"].as_str().unwrap_or("STOP");
+            let reason = finish_reason(reason, !tool_calls.is_empty());
+            if !tool_calls.is_empty() {
+                for call in &mut tool_calls {
+                    call.as_object_mut().unwrap().remove("index");
+                }
+                message["tool_calls"] = tool_calls.into();
+            }
+            json!({
+                "index": candidate["index"].as_u64().unwrap_or(i as u64),
+                "message": message,
+                "finish_reason": reason,
+            })
+        })
+        .collect();
+    let mut completion = json!({
+        "id": format!("chatcmpl-{}", id),
+        "object": "chat.completion",
+        "created": unix_now(),
+        "model": response["modelVersion"].as_str().unwrap_or(model),
+        "choices": choices,
+    });
+    if let Some(usage) = usage(&response) {
+        completion["usage"] = usage;
+    }
+    serde_json::to_vec(&completion).ok().map(Into::into)
+}
+
+// The streamGenerateContent events as chat.completion.chunk events
+pub(crate) struct VertexChunks {
+    id: String,
+    model: String,
+    created: u64,
+    // Tool calls numbered so far, per candidate that has started
+    started: HashMap<u64, usize>,
+}
+
+impl VertexChunks {
+    pub(crate) fn new(id: &str, model: &str) -> Self {
+        Self {
+            id: format!("chatcmpl-{}", id),
+            model: model.to_string(),
+            created: unix_now(),
+            started: HashMap::new(),
+        }
+    }
+}
+
+impl StreamTranslator for VertexChunks {
+    fn event_end(&self, buffer: &[u8]) -> Option<(usize, usize)> {
+        sse_event_end(buffer)
+    }
+
+    fn translate(&mut self, event: &[u8], out: &mut Vec<u8>) {
+        let text = String::from_utf8_lossy(event);
+        let data = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .collect::<String>();
+        let Ok(response) = serde_json::from_str::<Value>(data.trim()) else {
+            return;
+        };
+        let model = response["modelVersion"].as_str().unwrap_or(&self.model);
+        let mut choices = Vec::new();
+        for (i, candidate) in response["candidates"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let index = candidate["index"].as_u64().unwrap_or(i as u64);
+            let first = !self.started.contains_key(&index);
+            let calls = self.started.entry(index).or_default();
+            let (text, thoughts, tool_calls) = candidate_parts(&self.id, candidate, calls);
+            let mut delta = json!({});
+            if first {
+                delta["role"] = "assistant".into();
+            }
+            if !text.is_empty() {
+                delta["content"] = text.into();
+            }
+            if !thoughts.is_empty() {
+                delta["reasoning_content"] = thoughts.into();
+            }
+            let reason = candidate["finishReason"]
+                .as_str()
+                .map(|reason| finish_reason(reason, *calls > 0));
+            if !tool_calls.is_empty() {
+                delta["tool_calls"] = tool_calls.into();
+            }
+            choices.push(json!({"index": index, "delta": delta, "finish_reason": reason}));
+        }
+        let mut chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": model,
+            "choices": choices,
+        });
+        if let Some(usage) = usage(&response) {
+            chunk["usage"] = usage;
+        }
+        out.extend_from_slice(format!("data: {}\n\n", chunk).as_bytes());
+    }
+}
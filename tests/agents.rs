@@ -0,0 +1,410 @@
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn composite_model_runs_tools_until_answer() {
+    let upstream = MockUpstream::start().await;
+    let tool_server = MockUpstream::start().await;
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({
+            "object": "chat.completion",
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "lookup_order", "arguments": "{\"id\":\"42\"}"},
+                    }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8},
+        }),
+    ));
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[composite_models]]
+id = "support-agent"
+model = "gpt-4o"
+system_prompt = "You answer order questions."
+
+[[composite_models.tools]]
+name = "lookup_order"
+description = "Find an order by ID"
+parameters = {{ type = "object", properties = {{ id = {{ type = "string" }} }} }}
+url = "{}/orders"
+"#,
+        tool_server.url()
+    ))
+    .unwrap()
+    .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/agents/chat/completions"))
+        .json(&json!({
+            "model": "support-agent",
+            "messages": [{"role": "user", "content": "Where is order 42?"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-proxy-agent-steps"], "2");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["model"], "support-agent");
+    assert_eq!(body["choices"][0]["message"]["content"], "Hello from mock");
+    assert_eq!(body["usage"]["prompt_tokens"], 10);
+
+    let tool_call = tool_server.last_request().unwrap();
+    assert_eq!(tool_call.path, "/orders");
+    assert_eq!(tool_call.json(), json!({"id": "42"}));
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+    let first = requests[0].json();
+    assert_eq!(first["model"], "gpt-4o");
+    assert_eq!(first["tools"][0]["function"]["name"], "lookup_order");
+    let messages = requests[1].json()["messages"].clone();
+    assert_eq!(messages[0]["role"], "system");
+    assert_eq!(messages[3]["role"], "tool");
+    assert_eq!(messages[3]["tool_call_id"], "call_1");
+}
+
+#[tokio::test]
+async fn unknown_composite_model_is_bad_request() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml("")
+        .unwrap()
+        .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/agents/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(upstream.requests().is_empty());
+}
+
+fn tool_call_completion() -> MockResponse {
+    MockResponse::json(
+        200,
+        json!({
+            "object": "chat.completion",
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "lookup_order", "arguments": "{\"id\":\"42\"}"},
+                    }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+            "usage": {"prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8},
+        }),
+    )
+}
+
+#[tokio::test]
+async fn async_agent_jobs_resume_from_their_journal_and_can_be_cancelled() {
+    let journal =
+        std::env::temp_dir().join(format!("openai_proxy_jobs_{}.jsonl", std::process::id()));
+    std::fs::remove_file(&journal).ok();
+    let tool_server = MockUpstream::start().await;
+    let config = format!(
+        r#"
+admin_key = "adm"
+
+[agent_jobs]
+journal = {:?}
+
+[[composite_models]]
+id = "support-agent"
+model = "gpt-4o"
+
+[[composite_models.tools]]
+name = "lookup_order"
+url = "{}/orders"
+"#,
+        journal.display().to_string(),
+        tool_server.url()
+    );
+    let start = |upstream: &MockUpstream| {
+        let settings = Settings::from_toml(&config)
+            .unwrap()
+            .with_api_base(&upstream.url());
+        TestProxy::start(settings)
+    };
+    let client = reqwest::Client::new();
+    let ask = json!({
+        "model": "support-agent",
+        "messages": [{"role": "user", "content": "Where is order 42?"}],
+    });
+
+    // The first process gets through the tool call, then hangs on the next step
+    let first = MockUpstream::start().await;
+    first.push_response(tool_call_completion());
+    let hang = std::time::Duration::from_secs(60);
+    first.push_response(MockResponse::json(200, json!({})).with_delay(hang));
+    let proxy = start(&first).await.unwrap();
+    let response = client
+        .post(proxy.url("/agents/chat/completions"))
+        .header("x-proxy-async", "1")
+        .json(&ask)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    let job: Value = response.json().await.unwrap();
+    assert_eq!(job["status"], "running");
+    let id = job["id"].as_str().unwrap().to_string();
+    while first.requests().len() < 2 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    // A restarted proxy picks the job up after its last completed step
+    let second = MockUpstream::start().await;
+    let proxy = start(&second).await.unwrap();
+    let job_url = proxy.url(&format!("/admin/jobs/{}", id));
+    let job = loop {
+        let job: Value = client
+            .get(&job_url)
+            .bearer_auth("adm")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if job["status"] != "running" {
+            break job;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    };
+    assert_eq!(job["status"], "completed");
+    assert_eq!(job["steps"], 1);
+    assert_eq!(job["result"]["status"], 200);
+    assert_eq!(
+        job["result"]["body"]["choices"][0]["message"]["content"],
+        "Hello from mock"
+    );
+    assert_eq!(tool_server.requests().len(), 1);
+    let resumed = second.last_request().unwrap().json();
+    assert_eq!(resumed["messages"][2]["role"], "tool");
+
+    // Cancelling stops a running job; finished ones stay as they are
+    second.set_delay(hang);
+    let response = client
+        .post(proxy.url("/agents/chat/completions"))
+        .header("x-proxy-async", "1")
+        .json(&ask)
+        .send()
+        .await
+        .unwrap();
+    let other: Value = response.json().await.unwrap();
+    let other_url = proxy.url(&format!("/admin/jobs/{}", other["id"].as_str().unwrap()));
+    let cancelled: Value = client
+        .delete(&other_url)
+        .bearer_auth("adm")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(cancelled["status"], "cancelled");
+    let response = client
+        .delete(&job_url)
+        .bearer_auth("adm")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let list: Value = client
+        .get(proxy.url("/admin/jobs"))
+        .bearer_auth("adm")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(list["data"].as_array().unwrap().len(), 2);
+    std::fs::remove_file(&journal).ok();
+}
+
+#[tokio::test]
+async fn agent_job_journals_keep_credentials_sealed() {
+    let journal = std::env::temp_dir().join(format!(
+        "openai_proxy_sealed_jobs_{}.jsonl",
+        std::process::id()
+    ));
+    std::fs::remove_file(&journal).ok();
+    let tool_server = MockUpstream::start().await;
+    let config = format!(
+        r#"
+admin_key = "adm"
+key_passthrough = true
+
+[agent_jobs]
+journal = {:?}
+
+[agent_jobs.encryption]
+key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+
+[[composite_models]]
+id = "support-agent"
+model = "gpt-4o"
+
+[[composite_models.tools]]
+name = "lookup_order"
+url = "{}/orders"
+"#,
+        journal.display().to_string(),
+        tool_server.url()
+    );
+    let start = |upstream: &MockUpstream| {
+        let settings = Settings::from_toml(&config)
+            .unwrap()
+            .with_api_base(&upstream.url());
+        TestProxy::start(settings)
+    };
+
+    let first = MockUpstream::start().await;
+    first.push_response(tool_call_completion());
+    let hang = std::time::Duration::from_secs(60);
+    first.push_response(MockResponse::json(200, json!({})).with_delay(hang));
+    let proxy = start(&first).await.unwrap();
+    let response = reqwest::Client::new()
+        .post(proxy.url("/agents/chat/completions"))
+        .header("x-proxy-async", "1")
+        .header("x-proxy-key", "sk-proxy-bot")
+        .bearer_auth("sk-own-upstream")
+        .json(&json!({
+            "model": "support-agent",
+            "messages": [{"role": "user", "content": "Where is order 42?"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    while first.requests().len() < 2 {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        first.last_request().unwrap().header("authorization"),
+        Some("Bearer sk-own-upstream")
+    );
+
+    // Neither the client's key nor its upstream key is in the clear
+    let written = std::fs::read_to_string(&journal).unwrap();
+    assert!(!written.contains("sk-proxy-bot"));
+    assert!(!written.contains("sk-own-upstream"));
+    assert!(written.contains(r#""state":"sealed""#));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&journal).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // The restarted proxy restores both to finish the job
+    let second = MockUpstream::start().await;
+    let _proxy = start(&second).await.unwrap();
+    while second.requests().is_empty() {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        second.last_request().unwrap().header("authorization"),
+        Some("Bearer sk-own-upstream")
+    );
+    std::fs::remove_file(&journal).ok();
+}
+
+#[tokio::test]
+async fn erasing_a_user_stops_and_forgets_their_agent_jobs() {
+    let journal = std::env::temp_dir().join(format!(
+        "openai_proxy_erased_jobs_{}.jsonl",
+        std::process::id()
+    ));
+    std::fs::remove_file(&journal).ok();
+    let upstream = MockUpstream::start().await;
+    upstream.set_delay(std::time::Duration::from_secs(60));
+    let settings = Settings::from_toml(&format!(
+        r#"
+admin_key = "adm"
+
+[agent_jobs]
+journal = {:?}
+
+[[composite_models]]
+id = "support-agent"
+model = "gpt-4o"
+"#,
+        journal.display().to_string()
+    ))
+    .unwrap()
+    .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+    for user in ["alice", "bob"] {
+        let response = client
+            .post(proxy.url("/agents/chat/completions"))
+            .header("x-proxy-async", "1")
+            .json(&json!({
+                "model": "support-agent",
+                "messages": [{"role": "user", "content": "Where is order 42?"}],
+                "user": user,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 202);
+    }
+
+    let erased: Value = client
+        .delete(proxy.url("/admin/data-subjects/alice"))
+        .bearer_auth("adm")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(erased["agent_jobs"], 1);
+    let list: Value = client
+        .get(proxy.url("/admin/jobs"))
+        .bearer_auth("adm")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(list["data"].as_array().unwrap().len(), 1);
+    let written = std::fs::read_to_string(&journal).unwrap();
+    assert!(!written.contains("alice"));
+    assert!(written.contains("bob"));
+    std::fs::remove_file(&journal).ok();
+}
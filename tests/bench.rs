@@ -0,0 +1,50 @@
+use openai_proxy::bench::{self, BenchOptions, BenchTarget};
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::json;
+
+#[tokio::test]
+async fn bench_reports_the_proxy_and_the_upstream_side_by_side() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml("")
+        .unwrap()
+        .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let targets = [
+        BenchTarget {
+            name: "proxy".to_string(),
+            api_base: proxy.url("/v3"),
+            api_key: None,
+        },
+        BenchTarget {
+            name: "upstream".to_string(),
+            api_base: upstream.url(),
+            api_key: Some("sk-test".to_string()),
+        },
+    ];
+    upstream.push_response(MockResponse::json(
+        500,
+        json!({"error": {"message": "boom"}}),
+    ));
+    let options = BenchOptions {
+        requests: 8,
+        concurrency: 4,
+        prompt_chars: 200,
+        ..BenchOptions::default()
+    };
+
+    let reports = bench::run(&targets, &options).await;
+    assert_eq!(reports.len(), 2);
+    for report in &reports {
+        assert_eq!(report.requests, 8);
+        assert!(report.ttft_ms_p50.is_some());
+        assert!(report.tokens_per_second.is_some_and(|tps| tps > 0.0));
+    }
+    // The failed request is counted against whichever target got it
+    let errors: usize = reports.iter().map(|r| r.errors).sum();
+    assert_eq!(errors, 1);
+    assert_eq!(upstream.requests().len(), 16);
+    let sent = upstream.last_request().unwrap().json();
+    assert_eq!(sent["stream"], true);
+    assert_eq!(sent["messages"][0]["content"].as_str().unwrap().len(), 200);
+}
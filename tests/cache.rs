@@ -0,0 +1,274 @@
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+async fn start(upstream: &MockUpstream, config: &str) -> TestProxy {
+    let settings = Settings::from_toml(config)
+        .expect("valid config")
+        .with_api_base(&upstream.url());
+    TestProxy::start(settings).await.expect("proxy starts")
+}
+
+async fn post(proxy: &TestProxy, body: Value) -> (u16, String, Value) {
+    post_with(proxy, body, &[]).await
+}
+
+async fn post_with(
+    proxy: &TestProxy,
+    body: Value,
+    headers: &[(&str, &str)],
+) -> (u16, String, Value) {
+    let mut request = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&body);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request.send().await.unwrap();
+    let status = response.status().as_u16();
+    let cache = response
+        .headers()
+        .get("x-proxy-cache")
+        .map(|v| v.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    (status, cache, response.json().await.unwrap())
+}
+
+fn cache_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("openai_proxy_{}_{}", name, std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    dir
+}
+
+fn entry_sizes(dir: &Path) -> Vec<u64> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|f| f.unwrap().metadata().unwrap().len())
+        .collect()
+}
+
+#[tokio::test]
+async fn disk_cache_survives_restarts_and_evicts_the_least_recently_used() {
+    let upstream = MockUpstream::start().await;
+    let dir = cache_dir("disk_cache");
+    let config = |max_bytes: u64| {
+        format!(
+            r#"
+openai_api_key = "sk-upstream"
+
+[response_cache]
+max_entries = 1
+
+[response_cache.disk]
+path = {:?}
+max_bytes = {}
+compress = true
+"#,
+            dir.display().to_string(),
+            max_bytes
+        )
+    };
+    let ask =
+        |text: &str| json!({"model": "gpt-4o", "messages": [{"role": "user", "content": text}]});
+
+    let proxy = start(&upstream, &config(1 << 20)).await;
+    let (status, cache, first) = post(&proxy, ask("a")).await;
+    assert_eq!((status, cache.as_str()), (200, "miss"));
+    let (_, cache, second) = post(&proxy, ask("a")).await;
+    assert_eq!(cache, "hit");
+    assert_eq!(first, second);
+    assert_eq!(upstream.requests().len(), 1);
+    drop(proxy);
+
+    // A restarted proxy still has the entry, gzipped on disk
+    let sizes = entry_sizes(&dir);
+    assert_eq!(sizes.len(), 1);
+    assert!(sizes[0] < first.to_string().len() as u64);
+    let proxy = start(&upstream, &config(sizes[0] * 3 / 2)).await;
+    assert_eq!(post(&proxy, ask("a")).await.1, "hit");
+    assert_eq!(upstream.requests().len(), 1);
+
+    // Room for one entry only: "b" pushes out "a"
+    assert_eq!(post(&proxy, ask("b")).await.1, "miss");
+    assert_eq!(entry_sizes(&dir).len(), 1);
+    assert_eq!(post(&proxy, ask("a")).await.1, "miss");
+    assert_eq!(upstream.requests().len(), 3);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn cache_keys_ignore_configured_fields_and_honor_client_headers() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[response_cache]
+ignore_fields = ["user", "metadata.trace_id"]
+normalize_whitespace = true
+"#,
+    )
+    .await;
+    let ask = |user: &str, trace: &str, text: &str| {
+        json!({
+            "model": "gpt-4o",
+            "user": user,
+            "metadata": {"trace_id": trace, "app": "docs"},
+            "messages": [{"role": "user", "content": text}]
+        })
+    };
+
+    assert_eq!(post(&proxy, ask("alice", "t1", "Hi there")).await.1, "miss");
+    let (_, cache, _) = post(&proxy, ask("bob", "t2", "  Hi\n  there ")).await;
+    assert_eq!(cache, "hit");
+    assert_eq!(upstream.requests().len(), 1);
+
+    // Fields that are not ignored still tell requests apart
+    let mut other_app = ask("alice", "t1", "Hi there");
+    other_app["metadata"]["app"] = json!("chat");
+    assert_eq!(post(&proxy, other_app).await.1, "miss");
+
+    // A bypass skips the lookup, and its fresh response replaces the entry
+    let bypass = [("x-proxy-cache-bypass", "1")];
+    let (_, cache, _) = post_with(&proxy, ask("alice", "t1", "Hi there"), &bypass).await;
+    assert_eq!(cache, "miss");
+    assert_eq!(upstream.requests().len(), 3);
+    assert_eq!(post(&proxy, ask("alice", "t1", "Hi there")).await.1, "hit");
+
+    // A TTL of 0 keeps the response out of the cache
+    let no_store = [("x-proxy-cache-ttl", "0")];
+    let (_, cache, _) = post_with(&proxy, ask("alice", "t1", "Other"), &no_store).await;
+    assert_eq!(cache, "miss");
+    assert_eq!(post(&proxy, ask("alice", "t1", "Other")).await.1, "miss");
+    assert_eq!(upstream.requests().len(), 5);
+}
+
+#[tokio::test]
+async fn concurrent_misses_share_one_upstream_call() {
+    let upstream = MockUpstream::start().await;
+    upstream.set_delay(std::time::Duration::from_millis(300));
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[response_cache]
+"#,
+    )
+    .await;
+    let ask = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}]});
+
+    let responses = futures_util::future::join_all((0..5).map(|_| post(&proxy, ask.clone()))).await;
+    assert_eq!(upstream.requests().len(), 1);
+    let mut outcomes: Vec<&str> = responses
+        .iter()
+        .map(|(_, cache, _)| cache.as_str())
+        .collect();
+    outcomes.sort();
+    assert_eq!(
+        outcomes,
+        ["coalesced", "coalesced", "coalesced", "coalesced", "miss"]
+    );
+    assert!(responses
+        .iter()
+        .all(|(status, _, body)| *status == 200 && *body == responses[0].2));
+
+    // A failed fetch is not shared; the waiting requests retry upstream
+    upstream.push_response(MockResponse::json(
+        400,
+        json!({"error": {"message": "boom"}}),
+    ));
+    let other = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Other"}]});
+    let responses =
+        futures_util::future::join_all((0..3).map(|_| post(&proxy, other.clone()))).await;
+    let statuses: Vec<u16> = responses.iter().map(|(status, _, _)| *status).collect();
+    assert_eq!(statuses.iter().filter(|s| **s == 400).count(), 1);
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn get_responses_are_cached_and_revalidated_with_their_etag() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[response_cache]
+get_ttl_secs = 60
+"#,
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = |headers: &[(&str, &str)]| {
+        let mut request = client.get(proxy.url("/v3/files/file-abc"));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.send()
+    };
+    let file = json!({"id": "file-abc", "object": "file", "bytes": 120});
+    upstream.push_response(MockResponse::json(200, file.clone()).with_header("etag", "\"v1\""));
+
+    let response = get(&[]).await.unwrap();
+    assert_eq!(response.headers()["x-proxy-cache"], "miss");
+    assert_eq!(response.json::<Value>().await.unwrap(), file);
+    let response = get(&[]).await.unwrap();
+    assert_eq!(response.headers()["x-proxy-cache"], "hit");
+    assert_eq!(response.headers()["etag"], "\"v1\"");
+    assert_eq!(response.json::<Value>().await.unwrap(), file);
+
+    // Clients holding the current version get a 304 from the cache
+    let response = get(&[("if-none-match", "\"v1\"")]).await.unwrap();
+    assert_eq!(response.status(), 304);
+    assert_eq!(upstream.requests().len(), 1);
+
+    // Expired or bypassed entries are revalidated with their ETag, not refetched
+    upstream.push_response(MockResponse::json(304, json!({})));
+    let response = get(&[("x-proxy-cache-bypass", "1")]).await.unwrap();
+    assert_eq!(response.headers()["x-proxy-cache"], "revalidated");
+    assert_eq!(response.json::<Value>().await.unwrap(), file);
+    let revalidation = upstream.last_request().unwrap();
+    assert_eq!(revalidation.header("if-none-match"), Some("\"v1\""));
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn cached_get_responses_are_kept_apart_by_upstream_key() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+key_passthrough = true
+
+[response_cache]
+get_ttl_secs = 60
+"#,
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let list = |key: &str| client.get(proxy.url("/v3/files")).bearer_auth(key).send();
+    let files = |id: &str| json!({"object": "list", "data": [{"id": id, "object": "file"}]});
+    upstream.push_response(MockResponse::json(200, files("file-a")));
+    upstream.push_response(MockResponse::json(200, files("file-b")));
+
+    let response = list("sk-org-a").await.unwrap();
+    assert_eq!(response.headers()["x-proxy-cache"], "miss");
+    assert_eq!(response.json::<Value>().await.unwrap(), files("file-a"));
+    // Another account's key is not answered with the first one's files
+    let response = list("sk-org-b").await.unwrap();
+    assert_eq!(response.headers()["x-proxy-cache"], "miss");
+    assert_eq!(response.json::<Value>().await.unwrap(), files("file-b"));
+    assert_eq!(
+        upstream.last_request().unwrap().header("authorization"),
+        Some("Bearer sk-org-b")
+    );
+
+    let response = list("sk-org-a").await.unwrap();
+    assert_eq!(response.headers()["x-proxy-cache"], "hit");
+    assert_eq!(response.json::<Value>().await.unwrap(), files("file-a"));
+    assert_eq!(upstream.requests().len(), 2);
+}
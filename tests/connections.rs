@@ -0,0 +1,210 @@
+use openai_proxy::testing::{MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn start(upstream: &MockUpstream, config: &str) -> TestProxy {
+    let settings = Settings::from_toml(config)
+        .expect("valid config")
+        .with_api_base(&upstream.url());
+    TestProxy::start(settings).await.expect("proxy starts")
+}
+
+async fn connect(proxy: &TestProxy) -> TcpStream {
+    let addr = proxy.url("").trim_start_matches("http://").to_string();
+    TcpStream::connect(addr).await.unwrap()
+}
+
+#[tokio::test]
+async fn oversized_headers_and_slow_bodies_are_rejected() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[connections]
+max_header_bytes = 8192
+body_read_timeout_ms = 200
+"#,
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .header("x-padding", "a".repeat(16 * 1024))
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 431);
+
+    // The body is announced but never sent
+    let mut stream = connect(&proxy).await;
+    stream
+        .write_all(
+            b"POST /v3/chat/completions HTTP/1.1\r\nhost: proxy\r\ncontent-length: 10\r\n\r\n{",
+        )
+        .await
+        .unwrap();
+    let mut response = vec![0; 1024];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut response))
+        .await
+        .expect("answered before the test timeout")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn slow_headers_are_cut_off_and_connections_capped() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[connections]
+max_connections = 1
+header_read_timeout_ms = 300
+"#,
+    )
+    .await;
+
+    // A slowloris client holds the only slot until its headers time out
+    let mut slow = connect(&proxy).await;
+    slow.write_all(b"GET / HTTP/1.1\r\nhost: proxy\r\n")
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+    let blocked = client
+        .get(proxy.url("/"))
+        .timeout(Duration::from_millis(150))
+        .send()
+        .await;
+    assert!(blocked.is_err());
+
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), slow.read_to_end(&mut rest))
+        .await
+        .expect("closed by the header timeout")
+        .ok();
+
+    let response = client.get(proxy.url("/")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn pinned_hosts_are_reached_over_reused_connections() {
+    let upstream = MockUpstream::start().await;
+    // The mock listens on 127.0.0.1, which the pin maps a made-up host to
+    let base = upstream.url().replace("127.0.0.1", "upstream.internal");
+    let settings = Settings::from_toml(
+        r#"
+[upstream_connections.dns]
+cache_ttl_secs = 60
+hosts = { "upstream.internal" = ["127.0.0.1"] }
+"#,
+    )
+    .unwrap()
+    .with_api_base(&base);
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+
+    for _ in 0..3 {
+        let response = client
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&serde_json::json!({"model": "gpt-4o", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    assert_eq!(upstream.requests().len(), 3);
+
+    let metrics = reqwest::get(proxy.url("/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("openai_proxy_upstream_sends_total{host=\"upstream.internal\"} 3"));
+    assert!(
+        metrics.contains("openai_proxy_upstream_connections_total{host=\"upstream.internal\"} 1")
+    );
+}
+
+#[tokio::test]
+async fn invalid_pinned_address_fails_startup() {
+    let settings = Settings::from_toml(
+        r#"
+[upstream_connections.dns]
+hosts = { "api.openai.com" = ["not-an-ip"] }
+"#,
+    )
+    .unwrap();
+    let err = TestProxy::start(settings).await.err().unwrap();
+    assert!(err.to_string().contains("Invalid IP not-an-ip"));
+}
+
+#[tokio::test]
+async fn prefer_ipv6_falls_back_to_ipv4() {
+    let upstream = MockUpstream::start().await;
+    let base = upstream.url().replace("127.0.0.1", "dual.internal");
+    // Nothing listens on ::1, so the IPv4 fallback has to take over
+    let settings = Settings::from_toml(
+        r#"
+[upstream_connections]
+ip_family = "prefer_ipv6"
+
+[upstream_connections.dns]
+hosts = { "dual.internal" = ["127.0.0.1", "::1"] }
+"#,
+    )
+    .unwrap()
+    .with_api_base(&base);
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&serde_json::json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn bracketed_ipv6_listener_is_dual_stack() {
+    let upstream = MockUpstream::start().await;
+    let port = std::net::TcpListener::bind("[::]:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let settings = Settings::from_toml(&format!(
+        r#"
+server_host = "[::]"
+server_port = {}
+"#,
+        port
+    ))
+    .unwrap()
+    .with_api_base(&upstream.url());
+    tokio::spawn(openai_proxy::serve(settings));
+
+    let client = reqwest::Client::new();
+    for host in ["127.0.0.1", "[::1]"] {
+        let url = format!("http://{}:{}/", host, port);
+        let response = loop {
+            match client.get(&url).send().await {
+                Ok(response) => break response,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        assert_eq!(response.status(), 200);
+    }
+}
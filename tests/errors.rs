@@ -0,0 +1,335 @@
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::{json, Value};
+
+async fn start(api_base: &str, config: &str) -> TestProxy {
+    let settings = Settings::from_toml(config)
+        .expect("valid config")
+        .with_api_base(api_base);
+    TestProxy::start(settings).await.expect("proxy starts")
+}
+
+async fn post(proxy: &TestProxy, body: Value) -> (u16, Value) {
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn upstream_errors_pass_through() {
+    let upstream = MockUpstream::start().await;
+    upstream.push_response(MockResponse::json(
+        503,
+        json!({"error": {"message": "overloaded", "type": "server_error"}}),
+    ));
+    let proxy = start(&upstream.url(), r#"openai_api_key = "sk-upstream""#).await;
+
+    let (status, body) = post(&proxy, json!({"model": "gpt-4o", "messages": []})).await;
+
+    assert_eq!(status, 503);
+    assert_eq!(body["error"]["message"], "overloaded");
+}
+
+#[tokio::test]
+async fn vendor_errors_are_translated() {
+    let upstream = MockUpstream::start().await;
+    let anthropic =
+        json!({"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}});
+    upstream.push_response(MockResponse::json(529, anthropic.clone()));
+    upstream.push_response(MockResponse::json(
+        400,
+        json!({"error": {"code": 400, "message": "Bad field", "status": "INVALID_ARGUMENT"}}),
+    ));
+    let proxy = start(&upstream.url(), r#"openai_api_key = "sk-upstream""#).await;
+    let request = json!({"model": "gpt-4o", "messages": []});
+
+    let (status, body) = post(&proxy, request.clone()).await;
+    assert_eq!(status, 503);
+    assert_eq!(body["error"]["message"], "Overloaded");
+    assert_eq!(body["error"]["type"], "server_error");
+    assert_eq!(body["error"]["code"], "overloaded_error");
+    assert_eq!(body["upstream_error"], anthropic);
+
+    let (status, body) = post(&proxy, request).await;
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(body["error"]["code"], "INVALID_ARGUMENT");
+}
+
+#[tokio::test]
+async fn unreachable_upstream_is_bad_gateway() {
+    // Nothing listens on the discard port
+    let proxy = start("http://127.0.0.1:9", r#"openai_api_key = "sk-upstream""#).await;
+
+    // The plain-text body of the original error format by default
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 502);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    assert!(response.text().await.unwrap().starts_with("Proxy error: "));
+
+    // The OpenAI envelope with json_errors
+    let proxy = start(
+        "http://127.0.0.1:9",
+        "openai_api_key = \"sk-upstream\"\njson_errors = true",
+    )
+    .await;
+    let (status, body) = post(&proxy, json!({"model": "gpt-4o", "messages": []})).await;
+    assert_eq!(status, 502);
+    assert_eq!(body["error"]["type"], "proxy_error");
+}
+
+#[tokio::test]
+async fn missing_client_key_is_unauthorized() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream.url(),
+        r#"
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+"#,
+    )
+    .await;
+
+    let (status, body) = post(&proxy, json!({"model": "gpt-4o", "messages": []})).await;
+
+    assert_eq!(status, 401);
+    assert_eq!(body["error"]["type"], "authentication_error");
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn proxy_errors_are_localized() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream.url(),
+        r#"
+[localization]
+default_language = "de"
+
+[[localization.messages]]
+language = "fr"
+message = "Too many messages"
+text = "Trop de messages"
+
+[guardrails]
+max_messages = 1
+"#,
+    )
+    .await;
+    let messages = json!([{"role": "user", "content": "a"}, {"role": "user", "content": "b"}]);
+    let send = |language: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .header("accept-language", language)
+            .json(&json!({"model": "gpt-4o", "messages": messages}))
+            .send()
+    };
+
+    // Unknown languages fall back to the default, translated by error type
+    let response = send("nl-NL").await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(response.headers()["content-language"], "de");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["message"], "Ungültige Anfrage");
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert_eq!(
+        body["error"]["original_message"],
+        "Too many messages: 2 (maximum is 1)"
+    );
+
+    // A configured message wins over the built-in type-level one
+    let response = send("en;q=0.5, fr-CA").await.unwrap();
+    assert_eq!(response.headers()["content-language"], "fr");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["message"], "Trop de messages");
+
+    let response = send("en").await.unwrap();
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(
+        body["error"]["message"],
+        "Too many messages: 2 (maximum is 1)"
+    );
+    assert!(body["error"]["original_message"].is_null());
+}
+
+#[tokio::test]
+async fn guardrail_violation_is_bad_request() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream.url(),
+        r#"
+[guardrails]
+max_messages = 1
+"#,
+    )
+    .await;
+
+    let messages = json!([{"role": "user", "content": "a"}, {"role": "user", "content": "b"}]);
+    let (status, body) = post(&proxy, json!({"model": "gpt-4o", "messages": messages})).await;
+
+    assert_eq!(status, 400);
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn concurrency_limit_rejects_with_429() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream.url(),
+        r#"
+[concurrency]
+max_in_flight = 0
+mode = "reject"
+"#,
+    )
+    .await;
+
+    let (status, body) = post(&proxy, json!({"model": "gpt-4o", "messages": []})).await;
+
+    assert_eq!(status, 429);
+    assert_eq!(body["error"]["type"], "rate_limit_exceeded");
+}
+
+#[tokio::test]
+async fn client_tpm_rejects_projected_overspend() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream.url(),
+        r#"
+[concurrency]
+mode = "reject"
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+tpm = 100
+"#,
+    )
+    .await;
+    let send = |max_tokens: u64| {
+        reqwest::Client::new()
+            .post(proxy.url("/t/acme/v3/chat/completions"))
+            .bearer_auth("sk-proxy-bot")
+            .json(&json!({
+                "model": "gpt-4o",
+                "max_tokens": max_tokens,
+                "messages": [{"role": "user", "content": "hi"}],
+            }))
+            .send()
+    };
+
+    // Projected at 98 tokens, then settled at the 8 the mock reports
+    assert_eq!(send(90).await.unwrap().status(), 200);
+    assert_eq!(send(90).await.unwrap().status(), 429);
+    assert_eq!(send(50).await.unwrap().status(), 200);
+}
+
+#[tokio::test]
+async fn deadline_exceeded_is_gateway_timeout() {
+    let upstream = MockUpstream::start().await;
+    upstream.set_delay(std::time::Duration::from_millis(1000));
+    let proxy = start(&upstream.url(), "").await;
+
+    let started = std::time::Instant::now();
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .header("x-request-timeout-ms", "300")
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 504);
+    assert!(started.elapsed() < std::time::Duration::from_millis(900));
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "timeout");
+    let forwarded = upstream.last_request().unwrap();
+    assert_eq!(forwarded.header("x-request-timeout-ms"), None);
+}
+
+#[tokio::test]
+async fn deadline_ends_stream_with_error_event() {
+    use futures_util::stream;
+    use std::time::Duration;
+
+    // Sends one chunk, then stalls
+    let app = axum::Router::new().fallback(|| async {
+        let chunks = stream::unfold(0, |i| async move {
+            if i == 1 {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+            let chunk = json!({"choices": [{"index": 0, "delta": {"content": "Hel"}}]});
+            (i < 2).then(|| {
+                (
+                    Ok::<_, std::io::Error>(format!("data: {}\n\n", chunk)),
+                    i + 1,
+                )
+            })
+        });
+        axum::response::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(axum::body::Body::from_stream(chunks))
+            .unwrap()
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api_base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let proxy = start(&api_base, "").await;
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .header("x-request-timeout-ms", "500")
+        .json(&json!({"model": "gpt-4o", "stream": true, "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.text().await.unwrap();
+    assert!(body.starts_with("data: {"), "{}", body);
+    assert!(body.contains(r#""content":"Hel""#));
+    assert!(body.contains(r#""type":"timeout""#));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+}
+
+#[tokio::test]
+async fn unknown_routes_get_json_errors_and_panics_are_counted() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream.url(), r#"openai_api_key = "sk-upstream""#).await;
+    let client = reqwest::Client::new();
+
+    let response = client.get(proxy.url("/v1/models")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "not_found_error");
+    assert_eq!(body["error"]["message"], "No route for GET /v1/models");
+
+    let metrics = client
+        .get(proxy.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("openai_proxy_panics_total{} 0"));
+}
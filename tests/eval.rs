@@ -0,0 +1,63 @@
+use openai_proxy::eval::{self, EvalOptions};
+use openai_proxy::testing::{MockResponse, MockUpstream};
+use openai_proxy::Settings;
+use serde_json::json;
+
+fn answer(content: &str) -> MockResponse {
+    MockResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-eval",
+            "object": "chat.completion",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 4, "completion_tokens": 2, "total_tokens": 6},
+        }),
+    )
+}
+
+#[tokio::test]
+async fn eval_scores_each_model_on_the_dataset() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml("")
+        .unwrap()
+        .with_api_base(&upstream.url());
+    let target = eval::start_local(settings).await.unwrap();
+    let cases = eval::parse_dataset(
+        r#"
+{"id": "capital", "prompt": "Capital of France?", "expected": "Paris"}
+{"id": "sum", "messages": [{"role": "user", "content": "2+2?"}], "pattern": "^\\d+$"}
+{"id": "object", "prompt": "A JSON object please", "json": true}
+"#,
+    )
+    .unwrap();
+    assert!(eval::parse_dataset("{\"id\": 1}")
+        .unwrap_err()
+        .starts_with("Line 1:"));
+
+    // One request at a time, each case to both models in turn
+    for content in [" Paris\n", "Lyon", "4", "4", "{\"a\": 1}", "not json"] {
+        upstream.push_response(answer(content));
+    }
+    let options = EvalOptions {
+        models: vec!["model-a".to_string(), "model-b".to_string()],
+        concurrency: 1,
+        ..EvalOptions::default()
+    };
+    let report = eval::run(&target, &cases, &options).await;
+
+    let a = &report.models[0];
+    assert_eq!((a.model.as_str(), a.passed, a.cases), ("model-a", 3, 3));
+    let b = &report.models[1];
+    assert_eq!((b.passed, b.errors), (1, 0));
+    assert_eq!(b.checks["exact"].passed, 0);
+    assert_eq!(b.checks["regex"].passed, 1);
+    assert_eq!(b.prompt_tokens, 12);
+    assert_eq!(report.differing(), vec!["capital", "object"]);
+
+    // Sent through the proxy, tagged as evaluation traffic
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 6);
+    assert_eq!(requests[0].json()["model"], "model-a");
+    assert_eq!(requests[0].json()["temperature"], 0.0);
+    assert_eq!(requests[2].json()["messages"][0]["content"], "2+2?");
+}
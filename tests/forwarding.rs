@@ -0,0 +1,1775 @@
+use openai_proxy::testing::{signed_headers, MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::{json, Value};
+
+async fn start(upstream: &MockUpstream, config: &str) -> TestProxy {
+    let settings = Settings::from_toml(config)
+        .expect("valid config")
+        .with_api_base(&upstream.url());
+    TestProxy::start(settings).await.expect("proxy starts")
+}
+
+#[tokio::test]
+async fn forwards_chat_completion_with_upstream_key() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, r#"openai_api_key = "sk-upstream""#).await;
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .bearer_auth("sk-client")
+        .header("x-custom", "kept")
+        .header("x-proxy-debug", "dropped")
+        .json(&json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().contains_key("x-request-id"));
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "Hello from mock");
+
+    let request = upstream.last_request().unwrap();
+    assert_eq!(request.path, "/v3/chat/completions");
+    assert_eq!(request.header("authorization"), Some("Bearer sk-upstream"));
+    assert_eq!(request.header("x-custom"), Some("kept"));
+    assert_eq!(request.header("x-proxy-debug"), None);
+    assert_eq!(request.json()["messages"][0]["content"], "hi");
+}
+
+#[tokio::test]
+async fn forwards_query_string() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, r#"openai_api_key = "sk-upstream""#).await;
+
+    reqwest::get(proxy.url("/v3/files?purpose=batch"))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        upstream.last_request().unwrap().path,
+        "/v3/files?purpose=batch"
+    );
+}
+
+#[tokio::test]
+async fn injects_thinking_and_model_parameters() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[available_models]]
+id = "deep"
+object = "model"
+owned_by = "openai"
+enable_thinking = true
+reasoning_effort = "high"
+stop = ["<|end|>"]
+"#,
+    )
+    .await;
+
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "deep", "messages": [], "stop": ["\n\n"]}))
+        .send()
+        .await
+        .unwrap();
+
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(forwarded["thinking"], json!({"type": "enabled"}));
+    assert_eq!(forwarded["reasoning_effort"], "high");
+    assert_eq!(forwarded["stop"], json!(["\n\n", "<|end|>"]));
+}
+
+#[tokio::test]
+async fn stop_sequences_stay_within_the_upstream_limit() {
+    let upstream = MockUpstream::start().await;
+    let config = r#"
+[[available_models]]
+id = "local"
+object = "model"
+owned_by = "me"
+stop = ["<|end|>", "</s>", "<|end|>"]
+"#;
+    let proxy = start(&upstream, config).await;
+
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "local", "messages": [], "stop": ["a", "b", "a", "</s>", "c"]}))
+        .send()
+        .await
+        .unwrap();
+    // Deduplicated, and the client's last ones make room for the configured
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(forwarded["stop"], json!(["a", "b", "</s>", "<|end|>"]));
+
+    let err = Settings::from_toml(&config.replace(
+        r#"stop = ["<|end|>", "</s>", "<|end|>"]"#,
+        r#"stop = ["1", "2", "3", "4", "5"]"#,
+    ))
+    .unwrap_err()
+    .to_string();
+    assert!(
+        err.contains("available_models.0.stop: 5 stop sequences"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn injects_engine_options_and_strips_them_from_responses() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[available_models]]
+id = "llama"
+object = "model"
+owned_by = "self-hosted"
+engine_options = { best_of = 3, repetition_penalty = 1.1, guided = { choice = ["yes", "no"] } }
+
+[[available_models]]
+id = "mistral-tgi"
+object = "model"
+owned_by = "self-hosted"
+engine_options = { engine = "tgi", guided = { choice = ["a.b", "c"] } }
+"#,
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({
+            "object": "chat.completion",
+            "prompt_logprobs": null,
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "yes"},
+                         "finish_reason": "stop", "stop_reason": null}],
+        }),
+    ));
+    let body: Value = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "llama", "messages": [], "best_of": 1}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(body.get("prompt_logprobs").is_none());
+    assert!(body["choices"][0].get("stop_reason").is_none());
+    assert_eq!(body["choices"][0]["message"]["content"], "yes");
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(forwarded["best_of"], 1);
+    assert_eq!(forwarded["repetition_penalty"], 1.1);
+    assert_eq!(forwarded["guided_choice"], json!(["yes", "no"]));
+
+    client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "mistral-tgi", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(
+        forwarded["response_format"],
+        json!({"type": "regex", "value": r"(a\.b|c)"})
+    );
+}
+
+#[tokio::test]
+async fn response_regex_is_guided_or_checked_with_retries() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[available_models]]
+id = "llama"
+object = "model"
+owned_by = "self-hosted"
+engine_options = {}
+"#,
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let completion = |content: &str| {
+        MockResponse::json(
+            200,
+            json!({"choices": [{"index": 0, "finish_reason": "stop",
+                                "message": {"role": "assistant", "content": content}}]}),
+        )
+    };
+
+    // vLLM enforces it itself
+    client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "llama", "messages": [], "response_regex": "[0-9]+"}))
+        .send()
+        .await
+        .unwrap();
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(forwarded["guided_regex"], "[0-9]+");
+    assert!(forwarded.get("response_regex").is_none());
+
+    // Other upstreams are asked again until the completion matches
+    upstream.push_response(completion("Maybe 42?"));
+    upstream.push_response(completion(" 42\n"));
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": [], "response_regex": "[0-9]+"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-proxy-constraint-retries"], "1");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], " 42\n");
+    assert!(upstream
+        .last_request()
+        .unwrap()
+        .json()
+        .get("response_regex")
+        .is_none());
+
+    for _ in 0..3 {
+        upstream.push_response(completion("no"));
+    }
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": [], "response_regex": "[0-9]+"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 502);
+
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": [], "response_grammar": "root ::= \"a\""}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn injects_prompt_caching_breakpoints() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[available_models]]
+id = "claude"
+object = "model"
+owned_by = "anthropic"
+prompt_caching = { min_prefix_chars = 10 }
+"#,
+    )
+    .await;
+
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .header("anthropic-beta", "tools-2024-04-04")
+        .json(&json!({"model": "claude", "messages": [
+            {"role": "system", "content": "You are terse."},
+            {"role": "user", "content": "Summarize this long document"},
+            {"role": "assistant", "content": "Done"},
+            {"role": "user", "content": "Shorter"},
+        ]}))
+        .send()
+        .await
+        .unwrap();
+
+    let request = upstream.last_request().unwrap();
+    assert_eq!(
+        request.header("anthropic-beta"),
+        Some("tools-2024-04-04,prompt-caching-2024-07-31")
+    );
+    let messages = &request.json()["messages"];
+    let breakpoint = json!({"type": "ephemeral"});
+    assert_eq!(messages[0]["content"][0]["text"], "You are terse.");
+    assert_eq!(messages[0]["content"][0]["cache_control"], breakpoint);
+    assert_eq!(messages[2]["content"][0]["cache_control"], breakpoint);
+    assert_eq!(messages[3]["content"], "Shorter");
+
+    // Mentioning the field in text is not a breakpoint
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "claude", "messages": [
+            {"role": "system", "content": "Explain \"cache_control\" in JSON."},
+            {"role": "user", "content": "Go"},
+        ]}))
+        .send()
+        .await
+        .unwrap();
+    let messages = &upstream.last_request().unwrap().json()["messages"];
+    assert_eq!(messages[0]["content"][0]["cache_control"], breakpoint);
+
+    // The client placed its own breakpoint, so nothing is added
+    let placed = json!([
+        {"role": "system", "content": "You are terse."},
+        {"role": "user", "content": [
+            {"type": "text", "text": "Summarize this long document", "cache_control": breakpoint},
+        ]},
+        {"role": "assistant", "content": "Done"},
+        {"role": "user", "content": "Shorter"},
+    ]);
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "claude", "messages": placed}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(upstream.last_request().unwrap().json()["messages"], placed);
+}
+
+#[tokio::test]
+async fn renamed_model_is_mapped_both_ways() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[model_catalog.rename]]
+from = "gpt-4o-2024-08-06"
+to = "gpt-4o"
+"#,
+    )
+    .await;
+
+    let body: Value = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        upstream.last_request().unwrap().json()["model"],
+        "gpt-4o-2024-08-06"
+    );
+    assert_eq!(body["model"], "gpt-4o");
+}
+
+#[tokio::test]
+async fn models_fall_back_to_configured_list() {
+    let upstream = MockUpstream::start().await;
+    upstream.push_response(MockResponse::json(404, json!({"error": "not found"})));
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[available_models]]
+id = "local-llama"
+object = "model"
+owned_by = "local"
+"#,
+    )
+    .await;
+
+    let response = reqwest::get(proxy.url("/v3/models")).await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["data"][0]["id"], "local-llama");
+}
+
+#[tokio::test]
+async fn client_profile_fills_defaults_and_filters_tools() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[parameter_profiles]]
+name = "support"
+temperature = 0.2
+max_tokens = 512
+system_prompt = "You are a support agent."
+allowed_tools = ["lookup_order"]
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "helpdesk"
+key = "sk-proxy-helpdesk"
+profile = "support"
+"#,
+    )
+    .await;
+    let tool = |name: &str| json!({"type": "function", "function": {"name": name}});
+    let send = |body: Value| {
+        reqwest::Client::new()
+            .post(proxy.url("/t/acme/v3/chat/completions"))
+            .bearer_auth("sk-proxy-helpdesk")
+            .json(&body)
+            .send()
+    };
+
+    let response = send(json!({
+        "model": "gpt-4o",
+        "temperature": 0.9,
+        "messages": [{"role": "user", "content": "Where is my order?"}],
+        "tools": [tool("lookup_order"), tool("delete_account")],
+    }))
+    .await
+    .unwrap();
+    assert_eq!(response.status(), 200);
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(forwarded["temperature"], 0.9);
+    assert_eq!(forwarded["max_tokens"], 512);
+    assert_eq!(forwarded["messages"][0]["role"], "system");
+    assert_eq!(forwarded["messages"][1]["content"], "Where is my order?");
+    assert_eq!(forwarded["tools"], json!([tool("lookup_order")]));
+
+    let forced = send(json!({
+        "model": "gpt-4o",
+        "messages": [],
+        "tools": [tool("delete_account")],
+        "tool_choice": {"type": "function", "function": {"name": "delete_account"}},
+    }))
+    .await
+    .unwrap();
+    assert_eq!(forced.status(), 400);
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn rules_rewrite_reroute_and_reject_requests_in_order() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[rules]]
+name = "staging-is-cheap"
+paths = ["chat/completions"]
+headers = { x-env = "staging" }
+actions = [
+  { type = "reroute", model = "gpt-4o-mini" },
+  { type = "header", header = "x-served-by", value = "rules" },
+]
+
+[[rules]]
+name = "mini-defaults"
+models = ["gpt-4o-mini"]
+when = [{ path = "$.temperature", exists = false }]
+actions = [
+  { type = "set", path = "$.temperature", value = 0.1 },
+  { type = "set", path = "$.metadata.tier", value = "cheap" },
+  { type = "remove", path = "$.logit_bias" },
+]
+
+[[rules]]
+name = "no-secrets"
+when = [{ path = "$.messages[-1].content", matches = "(?i)password" }]
+actions = [{ type = "reject", message = "Passwords are not allowed" }]
+"#,
+    )
+    .await;
+    let send = |env: &str, content: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .header("x-env", env)
+            .json(&json!({
+                "model": "gpt-4o",
+                "logit_bias": {"50256": -100},
+                "messages": [{"role": "user", "content": content}],
+            }))
+            .send()
+    };
+
+    let response = send("staging", "hi").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let forwarded = upstream.last_request().unwrap();
+    assert_eq!(forwarded.header("x-served-by"), Some("rules"));
+    let body = forwarded.json();
+    assert_eq!(body["model"], "gpt-4o-mini");
+    assert_eq!(body["temperature"], 0.1);
+    assert_eq!(body["metadata"]["tier"], "cheap");
+    assert!(body.get("logit_bias").is_none());
+
+    send("production", "hi").await.unwrap();
+    let body = upstream.last_request().unwrap().json();
+    assert_eq!(body["model"], "gpt-4o");
+    assert!(body.get("temperature").is_none());
+    assert_eq!(body["logit_bias"]["50256"], -100);
+
+    let response = send("production", "my PASSWORD is 1234").await.unwrap();
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await.unwrap();
+    assert_eq!(error["error"]["message"], "Passwords are not allowed");
+    assert_eq!(upstream.requests().len(), 2);
+
+    let metrics = reqwest::get(proxy.url("/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains(r#"openai_proxy_rule_matches_total{rule="mini-defaults"} 1"#));
+
+    // Actions change fields of the body, never the body itself
+    for action in [
+        r#"{ type = "set", path = "$", value = "replaced" }"#,
+        r#"{ type = "remove", path = "$" }"#,
+    ] {
+        let config = format!("[[rules]]\nname = \"root\"\nactions = [{}]\n", action);
+        let settings = Settings::from_toml(&config)
+            .unwrap()
+            .with_api_base(&upstream.url());
+        let err = TestProxy::start(settings).await.err().unwrap().to_string();
+        assert!(err.contains("cannot change the whole body"), "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn dry_run_returns_the_transformed_request_without_sending_it() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[parameter_profiles]]
+name = "support"
+max_tokens = 512
+system_prompt = "You are a support agent."
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "helpdesk"
+key = "sk-proxy-helpdesk"
+profile = "support"
+dry_run = true
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+"#,
+    )
+    .await;
+    let send = |key: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/t/acme/v3/chat/completions"))
+            .bearer_auth(key)
+            .header("x-proxy-dry-run", "true")
+            .json(&json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}))
+            .send()
+    };
+
+    let response = send("sk-proxy-helpdesk").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let dry_run: Value = response.json().await.unwrap();
+    assert_eq!(dry_run["dry_run"], true);
+    assert_eq!(dry_run["method"], "POST");
+    assert_eq!(
+        dry_run["url"],
+        format!("{}/v3/chat/completions", upstream.url())
+    );
+    assert_eq!(dry_run["headers"]["authorization"], "***");
+    assert_eq!(dry_run["body"]["max_tokens"], 512);
+    assert_eq!(dry_run["body"]["messages"][0]["role"], "system");
+    assert_eq!(dry_run["body"]["messages"][1]["content"], "hi");
+
+    assert_eq!(send("sk-proxy-bot").await.unwrap().status(), 403);
+    assert!(upstream.requests().is_empty());
+}
+
+#[tokio::test]
+async fn feedback_is_stored_with_request_details() {
+    let upstream = MockUpstream::start().await;
+    let path = std::env::temp_dir().join(format!("feedback-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let proxy = start(
+        &upstream,
+        &format!(
+            r#"
+[feedback]
+path = "{}"
+
+[[routing.rules]]
+model = "fast"
+type = "alias"
+target = "gpt-4o-mini"
+"#,
+            path.display()
+        ),
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "fast", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let stored: Value = client
+        .post(proxy.url("/v1/feedback"))
+        .json(&json!({"request_id": request_id, "rating": "down", "comment": "Too vague"}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stored["request_found"], true);
+
+    let invalid = client
+        .post(proxy.url("/v1/feedback"))
+        .json(&json!({"request_id": request_id, "rating": null}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(invalid.status(), 400);
+
+    let lines: Vec<Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["rating"], "down");
+    assert_eq!(lines[0]["comment"], "Too vague");
+    assert_eq!(lines[0]["request"]["model"], "gpt-4o-mini");
+    assert_eq!(lines[0]["request"]["requested_model"], "fast");
+    assert_eq!(lines[0]["request"]["status"], 200);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn continues_w3c_trace_context() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, "").await;
+    let send = |traceparent: &'static str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .header("traceparent", traceparent)
+            .header("tracestate", "vendor=abc")
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+    };
+
+    send("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        .await
+        .unwrap();
+    let request = upstream.last_request().unwrap();
+    let traceparent: Vec<&str> = request.header("traceparent").unwrap().split('-').collect();
+    assert_eq!(traceparent[1], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(traceparent[2].len(), 16);
+    assert_ne!(traceparent[2], "00f067aa0ba902b7");
+    assert_eq!(traceparent[3], "01");
+    assert_eq!(request.header("tracestate"), Some("vendor=abc"));
+
+    // Invalid context is dropped rather than passed on
+    send("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+        .await
+        .unwrap();
+    let request = upstream.last_request().unwrap();
+    assert_eq!(request.header("traceparent"), None);
+    assert_eq!(request.header("tracestate"), None);
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[tokio::test]
+async fn signed_requests_are_checked_and_not_replayed() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "batch-worker"
+hmac_secret = "shared-secret"
+"#,
+    )
+    .await;
+    let body = json!({"model": "gpt-4o", "messages": []}).to_string();
+    let send = |secret: &str, timestamp: u64, nonce: &str| {
+        let mut request = reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .header("content-type", "application/json")
+            .body(body.clone());
+        let headers = signed_headers(
+            "batch-worker",
+            secret,
+            timestamp,
+            nonce,
+            "POST",
+            "/v3/chat/completions",
+            body.as_bytes(),
+        );
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        request.send()
+    };
+
+    assert_eq!(
+        send("shared-secret", unix_now(), "n-1")
+            .await
+            .unwrap()
+            .status(),
+        200
+    );
+    assert_eq!(upstream.requests().len(), 1);
+    // The same nonce is not accepted again
+    assert_eq!(
+        send("shared-secret", unix_now(), "n-1")
+            .await
+            .unwrap()
+            .status(),
+        401
+    );
+    assert_eq!(
+        send("wrong-secret", unix_now(), "n-2")
+            .await
+            .unwrap()
+            .status(),
+        401
+    );
+    // Nor is a timestamp outside the default 300 second window
+    let stale = unix_now() - 301;
+    assert_eq!(
+        send("shared-secret", stale, "n-3").await.unwrap().status(),
+        401
+    );
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn passthrough_paths_stream_the_body_untouched() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[passthrough]
+paths = ["embeddings"]
+
+[[routing.rules]]
+model = "fast"
+type = "alias"
+target = "gpt-4o-mini"
+"#,
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    let body = r#"{"model":  "fast", "input": ["a", "b"]}"#;
+    let response = client
+        .post(proxy.url("/v3/embeddings"))
+        .header("content-type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let request = upstream.last_request().unwrap();
+    assert_eq!(request.body, body.as_bytes());
+    assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+
+    // Other endpoints are still parsed and routed
+    client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "fast", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        upstream.last_request().unwrap().json()["model"],
+        "gpt-4o-mini"
+    );
+}
+
+#[tokio::test]
+async fn passthrough_paths_still_enforce_client_restrictions() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[passthrough]
+paths = ["embeddings"]
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "app"
+key = "sk-proxy-app"
+allowed_models = ["text-embedding-3-small"]
+"#,
+    )
+    .await;
+    let embed = |model: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/embeddings"))
+            .bearer_auth("sk-proxy-app")
+            .json(&json!({"model": model, "input": "a"}))
+            .send()
+    };
+
+    let response = embed("text-embedding-3-large").await.unwrap();
+    assert_eq!(response.status(), 403);
+    assert!(upstream.requests().is_empty());
+
+    assert_eq!(embed("text-embedding-3-small").await.unwrap().status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().json()["model"],
+        "text-embedding-3-small"
+    );
+}
+
+#[tokio::test]
+async fn file_transfers_are_checked_against_their_content_hashes() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[passthrough]
+paths = ["files"]
+
+[integrity]
+"#,
+    )
+    .await;
+    let upload = |md5: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/files"))
+            .header("content-type", "application/jsonl")
+            .header("content-md5", md5)
+            .body("line one\nline two\n")
+            .send()
+    };
+
+    let response = upload("mHkp1hybafDGQGuECqd/2A==").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().body,
+        "line one\nline two\n".as_bytes()
+    );
+    // The response carries hashes of its own
+    assert_eq!(response.headers()["content-md5"].len(), 24);
+    assert_eq!(response.headers()["x-content-sha256"].len(), 64);
+
+    // A corrupted upload is cut off and rejected
+    let response = upload("AAAAAAAAAAAAAAAAAAAAAA==").await.unwrap();
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await.unwrap();
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("Content-MD5"));
+
+    // As is a download that does not match the upstream's hash
+    upstream.push_response(
+        MockResponse::json(200, json!({"id": "file-1"})).with_header(
+            "x-content-sha256",
+            "e9024f1a07d29d52ad3aa5e1a18e94db1f3a9fd32b89e39d47c472cd99071e13",
+        ),
+    );
+    let response = reqwest::Client::new()
+        .get(proxy.url("/v3/files"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 502);
+}
+
+fn completion(content: &str) -> MockResponse {
+    MockResponse::json(
+        200,
+        json!({
+            "id": "chatcmpl-broken",
+            "object": "chat.completion",
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop",
+            }],
+        }),
+    )
+}
+
+#[tokio::test]
+async fn empty_and_invalid_json_completions_are_retried_once() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[completion_retry]
+model = "gpt-4o-mini"
+"#,
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    upstream.push_response(completion(""));
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-proxy-retried"], "empty_completion");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "Hello from mock");
+    assert_eq!(
+        upstream.last_request().unwrap().json()["model"],
+        "gpt-4o-mini"
+    );
+
+    upstream.push_response(completion(r#"{"answer": "#));
+    let json_mode = json!({
+        "model": "gpt-4o",
+        "messages": [],
+        "response_format": {"type": "json_object"},
+    });
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json_mode)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-proxy-retried"], "invalid_json");
+    assert_eq!(upstream.requests().len(), 4);
+
+    // Plain text is fine without JSON mode
+    upstream.push_response(completion(r#"{"answer": "#));
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert!(!response.headers().contains_key("x-proxy-retried"));
+    assert_eq!(upstream.requests().len(), 5);
+}
+
+#[tokio::test]
+async fn overlay_configs_merge_into_the_base() {
+    let upstream = MockUpstream::start().await;
+    let base = r#"
+openai_api_key = "sk-base"
+
+[guardrails]
+max_messages = 2
+"#;
+    let production = r#"
+openai_api_key = "sk-production"
+
+[guardrails]
+max_message_chars = 5
+"#;
+    let settings = Settings::from_toml_layers(&[base, production])
+        .expect("valid config")
+        .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.expect("proxy starts");
+    let send = |messages: Value| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": "gpt-4o", "messages": messages}))
+            .send()
+    };
+
+    let user = |text: &str| json!({"role": "user", "content": text});
+    assert_eq!(send(json!([user("hi")])).await.unwrap().status(), 200);
+    let request = upstream.last_request().unwrap();
+    assert_eq!(
+        request.header("authorization"),
+        Some("Bearer sk-production")
+    );
+
+    // Both the base's and the overlay's limits apply
+    let too_many = json!([user("a"), user("b"), user("c")]);
+    assert_eq!(send(too_many).await.unwrap().status(), 400);
+    assert_eq!(send(json!([user("too long")])).await.unwrap().status(), 400);
+}
+
+#[test]
+fn invalid_configs_list_every_problem_with_its_line() {
+    let base = r#"
+openai_api_key = "sk-upstream"
+openai_api_base = "api.openai.com/v1"
+
+[[available_models]]
+id = "deep"
+object = "model"
+owned_by = "openai"
+reasoning_effort = "extreme"
+
+[[available_models]]
+id = "deep"
+object = "model"
+owned_by = "openai"
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "helpdesk"
+key = "sk-proxy-shared"
+
+[[tenants.clients]]
+name = "billing"
+key = "sk-proxy-shared"
+profle = "support"
+"#;
+    let overlay = r#"
+[guardrails]
+max_mesages = 2
+"#;
+    let err = Settings::from_toml_layers(&[base, overlay])
+        .unwrap_err()
+        .to_string();
+
+    assert!(err.starts_with("6 problems found:"), "{}", err);
+    for expected in [
+        "config.toml:3: openai_api_base: \"api.openai.com/v1\" is not a valid URL",
+        "config.toml:9: available_models.0.reasoning_effort: invalid reasoning_effort \"extreme\"",
+        "config.toml:12: available_models.1.id: duplicate model ID \"deep\"",
+        "config.toml:25: tenants.0.clients.1.key: key is already used by tenants.0.clients.0",
+        "config.toml:26: tenants.0.clients.1.profle: unknown key",
+        "overlay 1:3: guardrails.max_mesages: unknown key",
+    ] {
+        assert!(
+            err.contains(expected),
+            "missing {:?} in:\n{}",
+            expected,
+            err
+        );
+    }
+}
+
+#[tokio::test]
+async fn large_bodies_reuse_buffers_and_report_their_size() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, "").await;
+    let client = reqwest::Client::new();
+    let input = "x".repeat(1 << 20);
+
+    for _ in 0..3 {
+        let response = client
+            .post(proxy.url("/v3/embeddings"))
+            .json(&json!({"model": "text-embedding-3-small", "input": input}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    assert_eq!(
+        upstream.last_request().unwrap().json()["input"]
+            .as_str()
+            .unwrap()
+            .len(),
+        1 << 20
+    );
+
+    let metrics = reqwest::get(proxy.url("/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let value = |name: &str| -> u64 {
+        let line = metrics.lines().find(|line| line.starts_with(name)).unwrap();
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    };
+    // The client's body and the forwarded copy
+    assert!(value("openai_proxy_request_buffered_bytes_max") > 2 << 20);
+    assert!(value("openai_proxy_body_buffers_reused_total") > 0);
+}
+
+#[tokio::test]
+async fn metadata_headers_describe_how_the_request_was_served() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+metadata_headers = true
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+pricing = { prompt = 2.5, completion = 10.0 }
+"#,
+    )
+    .await;
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({"choices": [], "usage": {"prompt_tokens": 1000, "completion_tokens": 100}}),
+    ));
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let headers = response.headers();
+    let upstream_name = headers["x-proxy-upstream"].to_str().unwrap().to_string();
+    assert!(!upstream_name.is_empty());
+    assert_eq!(headers["x-proxy-model-used"], "gpt-4o");
+    assert_eq!(
+        headers["x-proxy-attempts"].to_str().unwrap(),
+        format!("{}:200", upstream_name)
+    );
+    assert_eq!(headers["x-proxy-cache"], "miss");
+    assert_eq!(headers["x-proxy-cost-usd"], "0.003500");
+    assert!(headers["x-proxy-latency-ms"]
+        .to_str()
+        .unwrap()
+        .parse::<u64>()
+        .is_ok());
+}
+
+#[tokio::test]
+async fn tenant_dictionaries_mask_block_and_count_matches() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[dictionaries]]
+name = "profanity"
+words = ["darn"]
+action = "mask"
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+
+[[tenants.dictionaries]]
+name = "codenames"
+category = "confidential"
+patterns = ['project\s+x\b']
+"#,
+    )
+    .await;
+    let send = |content: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .bearer_auth("sk-proxy-bot")
+            .json(&json!({"model": "gpt-4o", "messages": [{"role": "user", "content": content}]}))
+            .send()
+    };
+
+    let response = send("That Darn printer").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let forwarded = upstream.last_request().unwrap().json();
+    assert_eq!(forwarded["messages"][0]["content"], "That **** printer");
+
+    let response = send("Status of Project  X?").await.unwrap();
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await.unwrap();
+    assert!(error["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("confidential"));
+
+    upstream.push_response(completion("It is about project x."));
+    let body: Value = send("hi").await.unwrap().json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "");
+    assert_eq!(body["choices"][0]["finish_reason"], "content_filter");
+
+    let metrics = reqwest::get(proxy.url("/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    for (labels, count) in [
+        (
+            r#"dictionary="profanity",tenant="",category="profanity",action="mask",direction="input""#,
+            1,
+        ),
+        (
+            r#"dictionary="codenames",tenant="acme",category="confidential",action="block",direction="input""#,
+            1,
+        ),
+        (
+            r#"dictionary="codenames",tenant="acme",category="confidential",action="block",direction="output""#,
+            1,
+        ),
+    ] {
+        let line = format!(
+            "openai_proxy_dictionary_matches_total{{{}}} {}",
+            labels, count
+        );
+        assert!(metrics.contains(&line), "{}", line);
+    }
+}
+
+#[tokio::test]
+async fn statsd_metrics_reach_an_ipv6_agent_with_tags() {
+    let upstream = MockUpstream::start().await;
+    let agent = tokio::net::UdpSocket::bind("[::1]:0").await.unwrap();
+    let proxy = start(
+        &upstream,
+        &format!(
+            r#"
+[statsd]
+address = "{}"
+tags = ["env:test"]
+"#,
+            agent.local_addr().unwrap()
+        ),
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut datagram = [0u8; 1024];
+    let len = tokio::time::timeout(std::time::Duration::from_secs(5), agent.recv(&mut datagram))
+        .await
+        .expect("no statsd datagram")
+        .unwrap();
+    let line = std::str::from_utf8(&datagram[..len]).unwrap();
+    let (metric, tags) = line.split_once("|#").unwrap();
+    assert_eq!(metric, "openai_proxy.requests:1|c");
+    let tags: Vec<&str> = tags.split(',').collect();
+    for tag in [
+        "env:test",
+        "model:gpt-4o",
+        "provider:127.0.0.1",
+        "status:200",
+        "client:127.0.0.1",
+    ] {
+        assert!(tags.contains(&tag), "{} not in {:?}", tag, tags);
+    }
+}
+
+#[tokio::test]
+async fn clients_with_their_own_upstream_keys_are_billed_to_them() {
+    let upstream = MockUpstream::start().await;
+    let encryption = r#"
+[upstream_key_encryption]
+key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+"#;
+    let sealed = openai_proxy::encrypt_upstream_key(
+        Settings::from_toml(encryption).unwrap(),
+        "acme",
+        "team",
+        "default",
+        "sk-team-own",
+    )
+    .await
+    .unwrap();
+    assert!(sealed.starts_with("enc:") && !sealed.contains("sk-team-own"));
+
+    // Sealed for "team", so it does not open for another client
+    let copied = format!(
+        r#"{}
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "intruder"
+key = "sk-proxy-intruder"
+upstream_keys = {{ default = "{}" }}
+"#,
+        encryption, sealed
+    );
+    let settings = Settings::from_toml(&copied)
+        .unwrap()
+        .with_api_base(&upstream.url());
+    assert!(TestProxy::start(settings).await.is_err());
+
+    let proxy = start(
+        &upstream,
+        &format!(
+            r#"{}
+[[tenants]]
+name = "acme"
+openai_api_key = "sk-shared"
+
+[[tenants.clients]]
+name = "team"
+key = "sk-proxy-team"
+upstream_keys = {{ default = "{}" }}
+
+[[tenants.clients]]
+name = "other"
+key = "sk-proxy-other"
+
+[[tenants.clients]]
+name = "elsewhere"
+key = "sk-proxy-elsewhere"
+upstream_keys = {{ backup = "sk-elsewhere" }}
+"#,
+            encryption, sealed
+        ),
+    )
+    .await;
+    let send = |key: &'static str| {
+        reqwest::Client::new()
+            .post(proxy.url("/t/acme/v3/chat/completions"))
+            .bearer_auth(key)
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+    };
+
+    assert_eq!(send("sk-proxy-team").await.unwrap().status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().headers["authorization"],
+        "Bearer sk-team-own"
+    );
+    assert_eq!(send("sk-proxy-other").await.unwrap().status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().headers["authorization"],
+        "Bearer sk-shared"
+    );
+
+    // No key for the tenant's upstream, and never the shared one instead
+    assert_eq!(send("sk-proxy-elsewhere").await.unwrap().status(), 403);
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn client_organization_and_project_headers_replace_the_callers() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "search"
+key = "sk-proxy-search"
+openai_organization = "org-acme"
+openai_project = "proj-search"
+
+[[tenants.clients]]
+name = "chat"
+key = "sk-proxy-chat"
+"#,
+    )
+    .await;
+    let send = |key: &'static str| {
+        reqwest::Client::new()
+            .post(proxy.url("/t/acme/v3/chat/completions"))
+            .bearer_auth(key)
+            .header("OpenAI-Project", "proj-mine")
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+    };
+
+    assert_eq!(send("sk-proxy-search").await.unwrap().status(), 200);
+    let request = upstream.last_request().unwrap();
+    assert_eq!(request.headers["openai-organization"], "org-acme");
+    assert_eq!(request.headers["openai-project"], "proj-search");
+    assert_eq!(request.headers.get_all("openai-project").iter().count(), 1);
+
+    assert_eq!(send("sk-proxy-chat").await.unwrap().status(), 200);
+    let request = upstream.last_request().unwrap();
+    assert!(request.headers.get("openai-organization").is_none());
+    assert_eq!(request.headers["openai-project"], "proj-mine");
+}
+
+#[tokio::test]
+async fn user_field_is_populated_from_the_client_or_required() {
+    let upstream = MockUpstream::start().await;
+    let tenants = r#"
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "helpdesk"
+key = "sk-proxy-helpdesk"
+"#;
+    let proxy = start(
+        &upstream,
+        &format!("[guardrails]\nuser_field = \"populate\"\n{}", tenants),
+    )
+    .await;
+    let send = |proxy: &TestProxy, body: Value| {
+        reqwest::Client::new()
+            .post(proxy.url("/t/acme/v3/chat/completions"))
+            .bearer_auth("sk-proxy-helpdesk")
+            .json(&body)
+            .send()
+    };
+
+    let anonymous = json!({"model": "gpt-4o", "messages": []});
+    let named = json!({"model": "gpt-4o", "messages": [], "user": "user-1234"});
+    assert_eq!(send(&proxy, anonymous.clone()).await.unwrap().status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().json()["user"],
+        "acme/helpdesk"
+    );
+    assert_eq!(send(&proxy, named.clone()).await.unwrap().status(), 200);
+    assert_eq!(upstream.last_request().unwrap().json()["user"], "user-1234");
+
+    let strict = start(
+        &upstream,
+        &format!("[guardrails]\nuser_field = \"require\"\n{}", tenants),
+    )
+    .await;
+    let rejected = send(&strict, anonymous).await.unwrap();
+    assert_eq!(rejected.status(), 400);
+    assert_eq!(send(&strict, named).await.unwrap().status(), 200);
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn extract_header_returns_a_response_field_as_text() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, "").await;
+    let send = |path: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .header("x-proxy-extract", path)
+            .json(&json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}))
+            .send()
+    };
+
+    let response = send("choices[0].message.content").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    assert_eq!(response.text().await.unwrap(), "Hello from mock");
+
+    // Other values are sent as JSON
+    let response = send("$.usage").await.unwrap();
+    let usage: Value = response.json().await.unwrap();
+    assert_eq!(usage["total_tokens"], 8);
+
+    let response = send("choices[1].message").await.unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(upstream.requests().len(), 3);
+
+    // A bad path is rejected before anything is sent
+    let response = send("choices[x]").await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn staged_uploads_are_retried_from_disk_and_can_be_sent_again() {
+    let upstream = MockUpstream::start().await;
+    let dir = std::env::temp_dir().join(format!("openai_proxy_staging_{}", std::process::id()));
+    let proxy = start(
+        &upstream,
+        &format!(
+            r#"
+openai_api_key = "sk-upstream"
+
+[retries]
+per_provider = 2
+backoff_ms = 1
+
+[file_staging]
+directory = "{}"
+max_bytes = 64
+"#,
+            dir.display()
+        ),
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let upload = "{\"prompt\": \"a\"}\n{\"prompt\": \"b\"}\n";
+    let unavailable = || MockResponse::json(503, json!({"error": {"message": "busy"}}));
+
+    // The staged copy is sent again when the first attempt fails
+    upstream.push_response(unavailable());
+    let response = client
+        .post(proxy.url("/v3/files"))
+        .header("content-type", "application/jsonl")
+        .body(upload)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|r| r.body == upload.as_bytes()));
+    assert_eq!(requests[1].header("content-length"), Some("32"));
+
+    // An upload the upstream did not take is kept for the client
+    upstream.push_response(unavailable());
+    upstream.push_response(unavailable());
+    let response = client
+        .post(proxy.url("/v3/files"))
+        .header("content-type", "application/jsonl")
+        .body(upload)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+    let id = response.headers()["x-proxy-upload-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    let response = client
+        .post(proxy.url("/v3/files"))
+        .header("x-proxy-upload-id", &id)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let request = upstream.last_request().unwrap();
+    assert_eq!(request.body, upload.as_bytes());
+    assert_eq!(request.header("content-type"), Some("application/jsonl"));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    // Once sent, it is gone
+    let response = client
+        .post(proxy.url("/v3/files"))
+        .header("x-proxy-upload-id", &id)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .post(proxy.url("/v3/files"))
+        .body("x".repeat(65))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 413);
+    assert_eq!(upstream.requests().len(), 5);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn embeddings_are_brought_to_the_configured_dimensions() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[available_models]]
+id = "text-embedding-3-large"
+embedding_dimensions = { dimensions = 2 }
+
+[[available_models]]
+id = "local-embed"
+embedding_dimensions = { dimensions = 2, mode = "truncate" }
+"#,
+    )
+    .await;
+    let embed = |model: &str, format: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/embeddings"))
+            .json(
+                &json!({"model": model, "input": "hi", "dimensions": 8, "encoding_format": format}),
+            )
+            .send()
+    };
+
+    // Upstreams that support it are asked for the length
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({"object": "list", "data": [{"index": 0, "embedding": [0.6, 0.8]}]}),
+    ));
+    let response: Value = embed("text-embedding-3-large", "float")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(upstream.last_request().unwrap().json()["dimensions"], 2);
+    assert_eq!(response["data"][0]["embedding"], json!([0.6, 0.8]));
+
+    // Others are cut and normalized by the proxy
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({"data": [{"index": 0, "embedding": [3.0, 4.0, 5.0, 6.0]}]}),
+    ));
+    let response: Value = embed("local-embed", "float")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(upstream
+        .last_request()
+        .unwrap()
+        .json()
+        .get("dimensions")
+        .is_none());
+    assert_eq!(response["data"][0]["embedding"], json!([0.6, 0.8]));
+
+    // Base64 vectors too
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({"data": [{"index": 0, "embedding": "AABAQAAAgEAAAKBAAADAQA=="}]}),
+    ));
+    let response: Value = embed("local-embed", "base64")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["data"][0]["embedding"], "mpkZP83MTD8=");
+}
+
+#[tokio::test]
+async fn access_log_prunes_only_rotated_files_oldest_first() {
+    let upstream = MockUpstream::start().await;
+    let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    // Written two days ago, so the first request rotates it
+    let current = std::fs::File::create(dir.join("access.log")).unwrap();
+    std::io::Write::write_all(&mut &current, b"old\n").unwrap();
+    let two_days = std::time::Duration::from_secs(2 * 86400);
+    current
+        .set_modified(std::time::SystemTime::now() - two_days)
+        .unwrap();
+    drop(current);
+    for name in [
+        "access.log.20200101-000000.gz",
+        "access.log.20200101-000000-2.gz",
+        "access.log.20200101-000000-10.gz",
+        "access.log.notes",
+    ] {
+        std::fs::write(dir.join(name), b"").unwrap();
+    }
+
+    let proxy = start(
+        &upstream,
+        &format!(
+            "[access_log]\npath = \"{}\"\nmax_files = 2\n",
+            dir.join("access.log").display()
+        ),
+    )
+    .await;
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+
+    let names = || {
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+    for _ in 0..50 {
+        if names().len() == 4 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let names = names();
+    // The counter orders numerically, and files the rotator did not write stay
+    assert_eq!(names.len(), 4, "{:?}", names);
+    assert!(names.contains(&"access.log".to_string()));
+    assert!(names.contains(&"access.log.notes".to_string()));
+    assert!(names.contains(&"access.log.20200101-000000-10.gz".to_string()));
+    assert!(!names.contains(&"access.log.20200101-000000-2.gz".to_string()));
+
+    let invalid = Settings::from_toml("[access_log]\npath = \"a.log\"\nrotation = \"weekly\"\n");
+    let err = invalid.unwrap_err().to_string();
+    assert!(
+        err.contains("access_log.rotation: invalid rotation"),
+        "{}",
+        err
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn environment_values_are_substituted_as_plain_strings() {
+    std::env::set_var("PROXY_TEST_STOP", "end\"\nopenai_api_key = \"sk-injected\"");
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"  # ${PROXY_TEST_UNSET} is not needed here
+# Neither here: ${PROXY_TEST_UNSET}
+
+[[available_models]]
+id = "local"
+object = "model"
+owned_by = "me"
+stop = ["${PROXY_TEST_STOP}", "$${literal}", "${PROXY_TEST_UNSET:-fallback}"]
+"#,
+    )
+    .await;
+
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "local", "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    let forwarded = upstream.last_request().unwrap();
+    assert_eq!(
+        forwarded.json()["stop"],
+        json!([
+            "end\"\nopenai_api_key = \"sk-injected\"",
+            "${literal}",
+            "fallback"
+        ])
+    );
+    assert_eq!(
+        forwarded.header("authorization"),
+        Some("Bearer sk-upstream")
+    );
+
+    let err = Settings::from_toml("[[tenants]]\nname = \"${PROXY_TEST_UNSET}\"\n")
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains(
+            "config.toml: tenants.0.name: environment variable PROXY_TEST_UNSET is not set"
+        ),
+        "{}",
+        err
+    );
+}
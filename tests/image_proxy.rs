@@ -0,0 +1,57 @@
+// Sets HTTP_PROXY for the whole process, so it runs in its own test binary
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::json;
+
+#[tokio::test]
+async fn image_fetches_ignore_system_proxies() {
+    let primary = MockUpstream::start().await;
+    let forward_proxy = MockUpstream::start().await;
+    forward_proxy.push_response(MockResponse {
+        status: 200,
+        content_type: "image/png".to_string(),
+        headers: Vec::new(),
+        body: "PNG".to_string(),
+        delay: std::time::Duration::ZERO,
+        cut_off: false,
+    });
+    std::env::set_var("HTTP_PROXY", forward_proxy.url());
+    std::env::set_var("http_proxy", forward_proxy.url());
+
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+adapter = "gemini"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+"#,
+        primary.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    // Through the forward proxy, localhost would never be resolved and checked
+    let response = reqwest::Client::builder()
+        .no_proxy()
+        .build()
+        .unwrap()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": "http://localhost/cat.png"}},
+            ]}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(forward_proxy.requests().is_empty());
+    assert!(primary.requests().is_empty());
+}
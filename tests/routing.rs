@@ -0,0 +1,1529 @@
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::{json, Value};
+
+async fn start(replicas: &[&MockUpstream], sticky: bool) -> TestProxy {
+    let (first, rest) = replicas.split_first().unwrap();
+    let rest: Vec<String> = rest.iter().map(|r| format!("{:?}", r.url())).collect();
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "pool"
+api_base = "{}"
+replicas = [{}]
+api_key = "sk-pool"
+sticky_routing = {}
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "pool"
+"#,
+        first.url(),
+        rest.join(", "),
+        sticky
+    ))
+    .unwrap();
+    TestProxy::start(settings).await.unwrap()
+}
+
+async fn chat(proxy: &TestProxy, messages: &[Value]) {
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": messages}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn conversation_sticks_to_one_replica() {
+    let a = MockUpstream::start().await;
+    let b = MockUpstream::start().await;
+    let proxy = start(&[&a, &b], true).await;
+
+    // Each turn resends the conversation so far with one more message
+    let mut messages = vec![
+        json!({"role": "system", "content": "You are terse."}),
+        json!({"role": "user", "content": "hi"}),
+    ];
+    for turn in 0..4 {
+        chat(&proxy, &messages).await;
+        messages.push(json!({"role": "assistant", "content": "Hello from mock"}));
+        messages.push(json!({"role": "user", "content": format!("turn {}", turn)}));
+    }
+    let counts = (a.requests().len(), b.requests().len());
+    assert!(counts == (4, 0) || counts == (0, 4), "{:?}", counts);
+    let request = a.last_request().or(b.last_request()).unwrap();
+    assert_eq!(request.header("authorization"), Some("Bearer sk-pool"));
+
+    // Distinct conversations spread over both replicas. Ports are random, so
+    // only require that each replica gets some of them.
+    for n in 0..16 {
+        let system = json!({"role": "system", "content": format!("System prompt {}", n)});
+        chat(&proxy, &[system, json!({"role": "user", "content": "hi"})]).await;
+    }
+    let spread = (a.requests().len() - counts.0, b.requests().len() - counts.1);
+    assert!(spread.0 > 0 && spread.1 > 0, "{:?}", spread);
+}
+
+#[tokio::test]
+async fn round_robin_without_sticky_routing() {
+    let a = MockUpstream::start().await;
+    let b = MockUpstream::start().await;
+    let proxy = start(&[&a, &b], false).await;
+
+    let messages = [json!({"role": "user", "content": "hi"})];
+    for _ in 0..4 {
+        chat(&proxy, &messages).await;
+    }
+    assert_eq!(a.requests().len(), 2);
+    assert_eq!(b.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn unknown_provider_fails_startup() {
+    let settings = Settings::from_toml(
+        r#"
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "missing"
+"#,
+    )
+    .unwrap();
+    assert!(TestProxy::start(settings).await.is_err());
+}
+
+async fn start_with_fallback(
+    primary: &MockUpstream,
+    backup: &MockUpstream,
+    retries: &str,
+) -> TestProxy {
+    let settings = Settings::from_toml(&format!(
+        r#"
+{}
+
+[[providers]]
+name = "primary"
+api_base = "{}"
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+fallbacks = ["backup"]
+"#,
+        retries,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap();
+    TestProxy::start(settings).await.unwrap()
+}
+
+async fn post(proxy: &TestProxy) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap()
+}
+
+fn overloaded() -> MockResponse {
+    MockResponse::json(
+        503,
+        json!({"error": {"message": "overloaded", "type": "server_error"}}),
+    )
+}
+
+#[tokio::test]
+async fn falls_back_to_next_provider() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    primary.push_response(overloaded());
+    let proxy = start_with_fallback(&primary, &backup, "").await;
+
+    let response = post(&proxy).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["x-proxy-attempts"],
+        "primary:503, backup:200"
+    );
+    assert_eq!(backup.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn attempts_stop_at_the_budget() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    for _ in 0..3 {
+        primary.push_response(overloaded());
+    }
+    let retries = "[retries]\nmax_attempts = 2\nper_provider = 3\nbackoff_ms = 10";
+    let proxy = start_with_fallback(&primary, &backup, retries).await;
+
+    let response = post(&proxy).await;
+    assert_eq!(response.status(), 503);
+    assert_eq!(
+        response.headers()["x-proxy-attempts"],
+        "primary:503, primary:503"
+    );
+    assert_eq!(primary.requests().len(), 2);
+    assert!(backup.requests().is_empty());
+}
+
+#[tokio::test]
+async fn provider_quota_reserves_headroom_for_streaming() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "shared"
+api_base = "{}"
+rpm = 2
+interactive_reserve = 0.5
+max_delay_ms = 0
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "shared"
+"#,
+        upstream.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+    let send = |stream: bool| {
+        client
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": "gpt-4o", "stream": stream, "messages": []}))
+            .send()
+    };
+
+    // Batch traffic may only use half of the quota
+    assert_eq!(send(false).await.unwrap().status(), 200);
+    assert_eq!(send(false).await.unwrap().status(), 429);
+    // The reserved half is still open to streaming requests
+    assert_eq!(send(true).await.unwrap().status(), 200);
+    assert_eq!(send(true).await.unwrap().status(), 429);
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn provider_pacing_spaces_out_bursts() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "paced"
+api_base = "{}"
+pacing = {{ rpm = 600, max_queue_ms = 250 }}
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "paced"
+"#,
+        upstream.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+    let send = || {
+        client
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+    };
+
+    // One request per 100ms: the burst of four queues for 0, 100 and 200ms,
+    // and the last would wait longer than max_queue_ms
+    let started = std::time::Instant::now();
+    let responses = futures_util::future::join_all((0..4).map(|_| send())).await;
+    let mut statuses: Vec<u16> = responses
+        .iter()
+        .map(|r| r.as_ref().unwrap().status().as_u16())
+        .collect();
+    statuses.sort();
+    assert_eq!(statuses, [200, 200, 200, 429]);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(upstream.requests().len(), 3);
+
+    let metrics = client
+        .get(proxy.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("openai_proxy_pacing_delayed_total{provider=\"paced\"} 2"));
+    assert!(metrics.contains("openai_proxy_pacing_rejected_total{provider=\"paced\"} 1"));
+}
+
+#[tokio::test]
+async fn pooled_keys_rest_once_their_quota_is_used_up() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "pooled"
+api_base = "{}"
+keys = [
+  {{ name = "tier-1", api_key = "sk-tier-1" }},
+  {{ name = "tier-5", api_key = "sk-tier-5" }},
+]
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "pooled"
+"#,
+        upstream.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+    let send = || async {
+        let response = client
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        upstream
+            .last_request()
+            .unwrap()
+            .header("authorization")
+            .unwrap()
+            .to_string()
+    };
+
+    // Whichever key the first request took has no requests left for a minute
+    upstream.push_response(
+        MockResponse::json(
+            200,
+            json!({"choices": [{"message": {"role": "assistant", "content": "Hi"}}]}),
+        )
+        .with_header("x-ratelimit-remaining-requests", "0")
+        .with_header("x-ratelimit-reset-requests", "1m0s"),
+    );
+    let exhausted = send().await;
+    for _ in 0..5 {
+        assert_ne!(send().await, exhausted);
+    }
+
+    let metrics = client
+        .get(proxy.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let name = exhausted.trim_start_matches("Bearer sk-");
+    assert!(metrics.contains(&format!(
+        "openai_proxy_upstream_key_remaining_requests{{provider=\"pooled\",key=\"{}\"}} 0",
+        name
+    )));
+    assert!(metrics.contains(&format!(
+        "openai_proxy_upstream_key_requests_total{{provider=\"pooled\",key=\"{}\"}} 1",
+        name
+    )));
+}
+
+#[tokio::test]
+async fn pooled_keys_survive_out_of_range_resets() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "pooled"
+api_base = "{}"
+keys = [{{ name = "only", api_key = "sk-only" }}]
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "pooled"
+"#,
+        upstream.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let ok = || {
+        MockResponse::json(
+            200,
+            json!({"choices": [{"message": {"role": "assistant", "content": "Hi"}}]}),
+        )
+    };
+    upstream.push_response(
+        ok().with_header("x-ratelimit-remaining-requests", "0")
+            .with_header("x-ratelimit-reset-requests", "99999999999999h")
+            .with_header("x-ratelimit-remaining-tokens", "0")
+            .with_header("x-ratelimit-reset-tokens", "99999999999999999999999999s"),
+    );
+    upstream.push_response(
+        MockResponse::json(429, json!({"error": {"message": "slow down"}}))
+            .with_header("retry-after", "18446744073709551615"),
+    );
+    upstream.push_response(ok());
+
+    let client = reqwest::Client::new();
+    for expected in [200, 429, 200] {
+        let response = client
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
+}
+
+#[tokio::test]
+async fn routing_rules_pick_model_by_language_alias_and_cost() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml(
+        r#"
+[[routing.rules]]
+model = "auto"
+type = "language"
+languages = { zh = "qwen-max", ja = "claude" }
+default = "gpt-4o"
+
+[[routing.rules]]
+model = "fast"
+type = "alias"
+target = "gpt-4o-mini"
+
+[[routing.rules]]
+model = "cheap"
+type = "cost"
+candidates = ["gpt-4o", "gpt-4o-mini"]
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+pricing = { prompt = 2.5, completion = 10.0 }
+
+[[available_models]]
+id = "gpt-4o-mini"
+object = "model"
+owned_by = "openai"
+pricing = { prompt = 0.15, completion = 0.6 }
+"#,
+    )
+    .unwrap()
+    .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    for (model, text, expected) in [
+        ("auto", "请用中文解释一下量子计算", "qwen-max"),
+        ("auto", "これは日本語の文章です", "claude"),
+        ("auto", "What is the capital of France?", "gpt-4o"),
+        ("fast", "hi", "gpt-4o-mini"),
+        ("cheap", "hi", "gpt-4o-mini"),
+        ("gpt-4o", "请用中文", "gpt-4o"),
+    ] {
+        let response = reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": model, "messages": [{"role": "user", "content": text}]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            upstream.last_request().unwrap().json()["model"],
+            expected,
+            "{}",
+            text
+        );
+    }
+}
+
+#[tokio::test]
+async fn maintenance_shifts_traffic_and_schedules_pick_targets() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[routing.rules]]
+model = "scheduled"
+type = "schedule"
+schedule = [
+  {{ target = "retired", until = "2000-01-01T00:00:00Z" }},
+  {{ target = "gpt-4o", hours = "00:00-24:00", utc_offset = "+08:00" }},
+]
+default = "gpt-4o-mini"
+
+[[providers]]
+name = "primary"
+api_base = "{}"
+maintenance = [{{ days = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"] }}]
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+fallbacks = ["backup"]
+
+[[available_models]]
+id = "gpt-4o-mini"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+"#,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    let send = |model: &'static str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": model, "messages": []}))
+            .send()
+    };
+    assert_eq!(send("scheduled").await.unwrap().status(), 200);
+    assert_eq!(backup.last_request().unwrap().json()["model"], "gpt-4o");
+
+    let response = send("gpt-4o-mini").await.unwrap();
+    assert_eq!(response.status(), 503);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "service_unavailable");
+    assert!(primary.requests().is_empty());
+}
+
+#[tokio::test]
+async fn override_headers_steer_permitted_clients() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+fallbacks = ["backup"]
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "power-user"
+key = "sk-power"
+routing_overrides = true
+
+[[tenants.clients]]
+name = "app"
+key = "sk-app"
+"#,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap()
+    .with_api_base(&primary.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let send = |key: &'static str, header: (&'static str, &'static str)| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .bearer_auth(key)
+            .header(header.0, header.1)
+            .json(&json!({"model": "gpt-4o", "messages": []}))
+            .send()
+    };
+
+    let response = send("sk-power", ("x-proxy-provider", "backup"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(backup.requests().len(), 1);
+
+    let response = send("sk-power", ("x-proxy-model-override", "gpt-4o-mini"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        primary.last_request().unwrap().json()["model"],
+        "gpt-4o-mini"
+    );
+
+    primary.push_response(overloaded());
+    let response = send("sk-power", ("x-proxy-no-fallback", "1"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(backup.requests().len(), 1);
+
+    let response = send("sk-app", ("x-proxy-provider", "backup"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+}
+
+#[tokio::test]
+async fn providers_get_parameters_under_their_own_names() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    primary.push_response(overloaded());
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+adapter = "anthropic"
+renames = {{ top_p = "sampling.top_p" }}
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+adapter = "gemini"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+fallbacks = ["backup"]
+"#,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({
+            "model": "gpt-4o",
+            "messages": [],
+            "max_completion_tokens": 100,
+            "stop": "END",
+            "top_p": 0.9,
+            "user": "u-1",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let sent = primary.last_request().unwrap().json();
+    assert_eq!(sent["max_tokens"], 100);
+    assert_eq!(sent["stop_sequences"], json!(["END"]));
+    assert_eq!(sent["metadata"]["user_id"], "u-1");
+    assert_eq!(sent["sampling"]["top_p"], 0.9);
+    assert!(sent.get("max_completion_tokens").is_none() && sent.get("stop").is_none());
+
+    let sent = backup.last_request().unwrap().json();
+    assert_eq!(sent["generationConfig"]["maxOutputTokens"], 100);
+    assert_eq!(sent["generationConfig"]["stopSequences"], json!(["END"]));
+    assert_eq!(sent["generationConfig"]["topP"], 0.9);
+    // generateContent has no user field
+    assert!(sent.get("user").is_none());
+}
+
+#[tokio::test]
+async fn image_parts_are_converted_per_provider() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    let images = MockUpstream::start().await;
+    primary.push_response(overloaded());
+    backup.push_response(MockResponse::json(
+        200,
+        json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "Both cats"}]},
+                               "finishReason": "STOP"}]}),
+    ));
+    images.push_response(MockResponse {
+        status: 200,
+        content_type: "image/jpeg".to_string(),
+        headers: Vec::new(),
+        body: "JPEG".to_string(),
+        delay: std::time::Duration::ZERO,
+        cut_off: false,
+    });
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+adapter = "anthropic"
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+adapter = "gemini"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+fallbacks = ["backup"]
+
+[guardrails]
+remote_image_hosts = ["127.0.0.1"]
+"#,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let remote = format!("{}/cat.jpg", images.url());
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "Compare"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,UE5H"}},
+                {"type": "image_url", "image_url": {"url": remote}},
+            ]}],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "Both cats");
+
+    let parts = primary.last_request().unwrap().json()["messages"][0]["content"].clone();
+    assert_eq!(parts[0], json!({"type": "text", "text": "Compare"}));
+    assert_eq!(
+        parts[1],
+        json!({"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "UE5H"}})
+    );
+    // Fetched once for the Gemini fallback, so the primary gets it inline too
+    assert_eq!(
+        parts[2],
+        json!({"type": "image", "source": {"type": "base64", "media_type": "image/jpeg", "data": "SlBFRw=="}})
+    );
+
+    let sent = backup.last_request().unwrap();
+    assert_eq!(sent.path, "/models/gpt-4o:generateContent");
+    let contents = sent.json()["contents"].clone();
+    assert_eq!(contents[0]["role"], "user");
+    let parts = contents[0]["parts"].clone();
+    assert_eq!(parts[0], json!({"text": "Compare"}));
+    assert_eq!(
+        parts[1],
+        json!({"inline_data": {"mime_type": "image/png", "data": "UE5H"}})
+    );
+    assert_eq!(
+        parts[2],
+        json!({"inline_data": {"mime_type": "image/jpeg", "data": "SlBFRw=="}})
+    );
+    assert_eq!(images.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn images_on_private_addresses_are_not_fetched() {
+    let primary = MockUpstream::start().await;
+    let images = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+adapter = "gemini"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+provider = "primary"
+"#,
+        primary.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    for url in [
+        format!("{}/cat.jpg", images.url()),
+        "http://169.254.169.254/latest/meta-data".to_string(),
+        "http://[::ffff:10.0.0.1]/cat.jpg".to_string(),
+    ] {
+        let response = reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({
+                "model": "gpt-4o",
+                "messages": [{"role": "user", "content": [
+                    {"type": "image_url", "image_url": {"url": url}},
+                ]}],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{}", url);
+    }
+    assert!(images.requests().is_empty());
+    assert!(primary.requests().is_empty());
+}
+
+#[tokio::test]
+async fn thinking_is_sent_as_effort_or_budget_per_provider() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    let plain = MockUpstream::start().await;
+    primary.push_response(overloaded());
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+adapter = "anthropic"
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+adapter = "gemini"
+
+[[providers]]
+name = "plain"
+api_base = "{}"
+
+[[available_models]]
+id = "deep"
+object = "model"
+owned_by = "openai"
+enable_thinking = true
+reasoning_effort = "high"
+provider = "primary"
+fallbacks = ["backup"]
+
+[[available_models]]
+id = "budgeted"
+object = "model"
+owned_by = "openai"
+enable_thinking = true
+thinking_budget_tokens = 3000
+provider = "plain"
+"#,
+        primary.url(),
+        backup.url(),
+        plain.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let send = |model: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": model, "messages": []}))
+            .send()
+    };
+
+    assert_eq!(send("deep").await.unwrap().status(), 200);
+    let sent = primary.last_request().unwrap().json();
+    assert_eq!(
+        sent["thinking"],
+        json!({"type": "enabled", "budget_tokens": 24576})
+    );
+    assert!(sent.get("reasoning_effort").is_none());
+    let sent = backup.last_request().unwrap().json();
+    assert_eq!(
+        sent["generationConfig"]["thinkingConfig"]["thinkingBudget"],
+        24576
+    );
+    assert!(sent.get("thinking").is_none() && sent.get("reasoning_effort").is_none());
+
+    // OpenAI-compatible upstreams get the level, and the budget when configured
+    assert_eq!(send("budgeted").await.unwrap().status(), 200);
+    let sent = plain.last_request().unwrap().json();
+    assert_eq!(sent["reasoning_effort"], "medium");
+    assert_eq!(
+        sent["thinking"],
+        json!({"type": "enabled", "budget_tokens": 3000})
+    );
+}
+
+#[tokio::test]
+async fn wildcards_and_groups_select_settings_rules_allowlists_and_budgets() {
+    let upstream = MockUpstream::start().await;
+    let settings = Settings::from_toml(
+        r#"
+[model_groups]
+cheap = ["gpt-4o-mini", 're:llama-3\.\d+-8b']
+
+[[available_models]]
+id = "*-reasoning"
+stop = ["<|end|>"]
+
+[[routing.rules]]
+model = "fast-*"
+type = "alias"
+target = "gpt-4o-mini"
+
+[[tenants]]
+name = "acme"
+model_budgets = [{ models = "*-reasoning", token_budget = 8 }]
+
+[[tenants.clients]]
+name = "app"
+key = "sk-proxy-app"
+allowed_models = ["@cheap", "*-reasoning", "fast-*"]
+"#,
+    )
+    .unwrap()
+    .with_api_base(&upstream.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let send = |model: &str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .bearer_auth("sk-proxy-app")
+            .json(&json!({"model": model, "messages": []}))
+            .send()
+    };
+
+    // The wildcard entry's settings apply; its 8 tokens use up the budget
+    assert_eq!(send("deep-reasoning").await.unwrap().status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().json()["stop"],
+        json!(["<|end|>"])
+    );
+    assert_eq!(send("deep-reasoning").await.unwrap().status(), 429);
+
+    assert_eq!(send("llama-3.1-8b").await.unwrap().status(), 200);
+    assert_eq!(send("gpt-4o").await.unwrap().status(), 403);
+    assert_eq!(send("fast-chat").await.unwrap().status(), 200);
+    assert_eq!(
+        upstream.last_request().unwrap().json()["model"],
+        "gpt-4o-mini"
+    );
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn client_thinking_is_converted_for_the_target_provider() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+adapter = "anthropic"
+
+[[available_models]]
+id = "gpt-4o"
+provider = "primary"
+fallbacks = ["backup"]
+"#,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let send = |body: Value| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&body)
+            .send()
+    };
+
+    // Anthropic-style thinking becomes an effort level for the OpenAI upstream
+    // and stays as sent for the Anthropic one
+    primary.push_response(overloaded());
+    let thinking = json!({"type": "enabled", "budget_tokens": 6000});
+    let body = json!({"model": "gpt-4o", "messages": [], "thinking": thinking});
+    assert_eq!(send(body).await.unwrap().status(), 200);
+    let sent = primary.last_request().unwrap().json();
+    assert_eq!(sent["reasoning_effort"], "medium");
+    assert!(sent.get("thinking").is_none());
+    assert_eq!(backup.last_request().unwrap().json()["thinking"], thinking);
+
+    // And the other way round
+    primary.push_response(overloaded());
+    let body = json!({"model": "gpt-4o", "messages": [], "reasoning_effort": "low"});
+    assert_eq!(send(body).await.unwrap().status(), 200);
+    assert_eq!(
+        primary.last_request().unwrap().json()["reasoning_effort"],
+        "low"
+    );
+    let sent = backup.last_request().unwrap().json();
+    assert_eq!(
+        sent["thinking"],
+        json!({"type": "enabled", "budget_tokens": 2048})
+    );
+    assert!(sent.get("reasoning_effort").is_none());
+}
+
+#[tokio::test]
+async fn providers_get_the_beta_headers_they_understand() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    primary.push_response(overloaded());
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "primary"
+api_base = "{}"
+adapter = "anthropic"
+beta_headers = [{{ value = "token-efficient-tools-2025-02-19", fields = ["tools"] }}]
+
+[[providers]]
+name = "backup"
+api_base = "{}"
+
+[[available_models]]
+id = "claude"
+object = "model"
+owned_by = "anthropic"
+provider = "primary"
+fallbacks = ["backup"]
+"#,
+        primary.url(),
+        backup.url()
+    ))
+    .unwrap()
+    .with_api_base(&backup.url());
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .header("anthropic-beta", "tools-2024-04-04")
+        .header("openai-beta", "realtime=v1")
+        .json(&json!({"model": "claude", "messages": [], "tools": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let sent = primary.last_request().unwrap();
+    assert_eq!(
+        sent.header("anthropic-beta"),
+        Some("tools-2024-04-04,token-efficient-tools-2025-02-19")
+    );
+    assert_eq!(sent.header("openai-beta"), None);
+    let sent = backup.last_request().unwrap();
+    assert_eq!(sent.header("openai-beta"), Some("realtime=v1"));
+    assert_eq!(sent.header("anthropic-beta"), None);
+
+    // The Assistants API needs its version flag
+    client
+        .get(proxy.url("/v3/assistants"))
+        .send()
+        .await
+        .unwrap();
+    let sent = backup.last_request().unwrap();
+    assert_eq!(sent.header("openai-beta"), Some("assistants=v2"));
+}
+
+#[tokio::test]
+async fn providers_sign_requests_or_use_oauth2_tokens() {
+    use hmac::Mac;
+    use sha2::Digest;
+
+    let signed = MockUpstream::start().await;
+    let gateway = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "signed"
+api_base = "{}"
+api_key = "hmac-secret"
+auth = {{ type = "hmac", key_id = "proxy" }}
+
+[[providers]]
+name = "gateway"
+api_base = "{}"
+api_key = "client-secret"
+auth = {{ type = "oauth2", token_url = "{}/oauth/token", client_id = "proxy", scope = "llm" }}
+
+[[available_models]]
+id = "signed-model"
+object = "model"
+owned_by = "internal"
+provider = "signed"
+
+[[available_models]]
+id = "gateway-model"
+object = "model"
+owned_by = "internal"
+provider = "gateway"
+"#,
+        signed.url(),
+        gateway.url(),
+        gateway.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+    let send = |model: &str| {
+        client
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": model, "messages": []}))
+            .send()
+    };
+
+    assert_eq!(send("signed-model").await.unwrap().status(), 200);
+    let sent = signed.last_request().unwrap();
+    assert_eq!(sent.header("authorization"), None);
+    let date = sent.header("x-date").unwrap();
+    let string_to_sign = format!(
+        "{}\nPOST\n{}\n{}",
+        date,
+        sent.path,
+        hex(&sha2::Sha256::digest(&sent.body))
+    );
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"hmac-secret").unwrap();
+    mac.update(string_to_sign.as_bytes());
+    let expected = format!("proxy:{}", hex(&mac.finalize().into_bytes()));
+    assert_eq!(sent.header("x-signature"), Some(expected.as_str()));
+
+    // One token for both requests, a new one once it is rejected
+    gateway.push_response(MockResponse::json(
+        200,
+        json!({"access_token": "tok-1", "expires_in": 3600}),
+    ));
+    for _ in 0..2 {
+        assert_eq!(send("gateway-model").await.unwrap().status(), 200);
+    }
+    let requests = gateway.requests();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].path, "/oauth/token");
+    let form = String::from_utf8_lossy(&requests[0].body).to_string();
+    assert!(form.contains("grant_type=client_credentials"));
+    assert!(form.contains("client_secret=client-secret"));
+    assert!(form.contains("scope=llm"));
+    assert_eq!(requests[2].header("authorization"), Some("Bearer tok-1"));
+
+    gateway.push_response(MockResponse::json(
+        401,
+        json!({"error": {"message": "token revoked"}}),
+    ));
+    gateway.push_response(MockResponse::json(
+        200,
+        json!({"access_token": "tok-2", "expires_in": 3600}),
+    ));
+    assert_eq!(send("gateway-model").await.unwrap().status(), 200);
+    let sent = gateway.last_request().unwrap();
+    assert_eq!(sent.header("authorization"), Some("Bearer tok-2"));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[tokio::test]
+async fn vertex_requests_are_translated_with_service_account_tokens() {
+    use base64::Engine;
+
+    let vertex = MockUpstream::start().await;
+    let key = openssl::rsa::Rsa::generate(2048).unwrap();
+    let pem = String::from_utf8(key.private_key_to_pem().unwrap()).unwrap();
+    let credentials = std::env::temp_dir().join(format!("vertex-sa-{}.json", std::process::id()));
+    let account = json!({
+        "type": "service_account",
+        "client_email": "proxy@project.iam.gserviceaccount.com",
+        "private_key_id": "key-1",
+        "private_key": pem,
+        "token_uri": format!("{}/token", vertex.url()),
+    });
+    std::fs::write(&credentials, account.to_string()).unwrap();
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "vertex"
+api_base = "{}/v1/projects/demo/locations/us-central1"
+adapter = "vertex"
+auth = {{ type = "service_account", credentials_path = {:?} }}
+
+[[available_models]]
+id = "gemini-2.0-flash"
+object = "model"
+owned_by = "google"
+provider = "vertex"
+"#,
+        vertex.url(),
+        credentials.display().to_string()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+
+    vertex.push_response(MockResponse::json(
+        200,
+        json!({"access_token": "ya29.token", "expires_in": 3600}),
+    ));
+    vertex.push_response(MockResponse::json(
+        200,
+        json!({
+            "candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}}
+                ]},
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": {"promptTokenCount": 12, "candidatesTokenCount": 5},
+            "modelVersion": "gemini-2.0-flash-001",
+        }),
+    ));
+    let completion: Value = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({
+            "model": "gemini-2.0-flash",
+            "max_tokens": 64,
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Weather in Paris?"},
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}},
+                               "additionalProperties": false},
+            }}],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let choice = &completion["choices"][0];
+    assert_eq!(choice["finish_reason"], "tool_calls");
+    let call = &choice["message"]["tool_calls"][0]["function"];
+    assert_eq!(call["name"], "get_weather");
+    assert_eq!(call["arguments"], r#"{"city":"Paris"}"#);
+    assert_eq!(completion["usage"]["prompt_tokens"], 12);
+
+    let requests = vertex.requests();
+    // The token was asked for with a JWT the service account key signed
+    let form = String::from_utf8_lossy(&requests[0].body).to_string();
+    let assertion = form.split("assertion=").nth(1).unwrap();
+    let parts: Vec<&str> = assertion.split('.').collect();
+    let decode = |part: &str| {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(part)
+            .unwrap()
+    };
+    let claims: Value = serde_json::from_slice(&decode(parts[1])).unwrap();
+    assert_eq!(claims["iss"], "proxy@project.iam.gserviceaccount.com");
+    let public = openssl::pkey::PKey::from_rsa(
+        openssl::rsa::Rsa::public_key_from_pem(&key.public_key_to_pem().unwrap()).unwrap(),
+    )
+    .unwrap();
+    let mut verifier =
+        openssl::sign::Verifier::new(openssl::hash::MessageDigest::sha256(), &public).unwrap();
+    verifier
+        .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+        .unwrap();
+    assert!(verifier.verify(&decode(parts[2])).unwrap());
+
+    let sent = &requests[1];
+    assert_eq!(
+        sent.path,
+        "/v1/projects/demo/locations/us-central1/publishers/google/models/gemini-2.0-flash:generateContent"
+    );
+    assert_eq!(sent.header("authorization"), Some("Bearer ya29.token"));
+    let body = sent.json();
+    assert_eq!(body["systemInstruction"]["parts"][0]["text"], "Be brief.");
+    assert_eq!(body["contents"][0]["role"], "user");
+    assert_eq!(body["generationConfig"]["maxOutputTokens"], 64);
+    let declaration = &body["tools"][0]["functionDeclarations"][0];
+    assert!(declaration["parameters"]
+        .get("additionalProperties")
+        .is_none());
+    assert!(body.get("model").is_none());
+
+    // Streams come back as chat.completion.chunk events
+    vertex.push_response(MockResponse {
+        status: 200,
+        content_type: "text/event-stream".to_string(),
+        headers: Vec::new(),
+        body: [
+            json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "Sunny"}]}}]}),
+            json!({"candidates": [{"content": {"role": "model", "parts": [{"text": " today"}]},
+                                   "finishReason": "STOP"}],
+                   "usageMetadata": {"promptTokenCount": 8, "candidatesTokenCount": 2}}),
+        ]
+        .iter()
+        .map(|event| format!("data: {}\r\n\r\n", event))
+        .collect(),
+        delay: std::time::Duration::ZERO,
+        cut_off: false,
+    });
+    let text = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gemini-2.0-flash", "stream": true,
+                      "messages": [{"role": "user", "content": "Weather?"}]}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(vertex
+        .last_request()
+        .unwrap()
+        .path
+        .ends_with(":streamGenerateContent?alt=sse"));
+    let chunks: Vec<Value> = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter(|data| *data != "[DONE]")
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(chunks[0]["object"], "chat.completion.chunk");
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Sunny");
+    assert_eq!(chunks[1]["choices"][0]["finish_reason"], "stop");
+    assert_eq!(chunks[1]["usage"]["completion_tokens"], 2);
+    assert!(text.trim_end().ends_with("data: [DONE]"));
+    let _ = std::fs::remove_file(credentials);
+}
+
+#[tokio::test]
+async fn mistral_requests_follow_its_quirks() {
+    let mistral = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "mistral"
+api_base = "{}"
+adapter = "mistral"
+
+[[available_models]]
+id = "mistral-large-latest"
+object = "model"
+owned_by = "mistral"
+provider = "mistral"
+"#,
+        mistral.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+
+    mistral.push_response(MockResponse::json(200, json!({"choices": []})));
+    reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({
+            "model": "mistral-large-latest",
+            "max_completion_tokens": 32,
+            "seed": 7,
+            "user": "alice",
+            "safe_prompt": true,
+            "tool_choice": "required",
+            "messages": [
+                {"role": "user", "content": "Weather?"},
+                {"role": "assistant", "tool_calls": [{"id": "call_abc123xyz789", "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{}"}}]},
+                {"role": "tool", "tool_call_id": "call_abc123xyz789", "content": "Sunny"},
+            ],
+        }))
+        .send()
+        .await
+        .unwrap();
+    let body = mistral.last_request().unwrap().json();
+    assert_eq!(body["max_tokens"], 32);
+    assert_eq!(body["random_seed"], 7);
+    assert_eq!(body["safe_prompt"], true);
+    assert_eq!(body["tool_choice"], "any");
+    assert!(body.get("user").is_none());
+    let id = body["messages"][1]["tool_calls"][0]["id"].as_str().unwrap();
+    assert_eq!(id.len(), 9);
+    assert!(id.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_eq!(body["messages"][2]["tool_call_id"], id);
+}
+
+#[tokio::test]
+async fn cohere_chat_is_translated_both_ways() {
+    let cohere = MockUpstream::start().await;
+    let settings = Settings::from_toml(&format!(
+        r#"
+[[providers]]
+name = "cohere"
+api_base = "{}/v1"
+adapter = "cohere"
+
+[[available_models]]
+id = "command-r-plus"
+object = "model"
+owned_by = "cohere"
+provider = "cohere"
+"#,
+        cohere.url()
+    ))
+    .unwrap();
+    let proxy = TestProxy::start(settings).await.unwrap();
+    let client = reqwest::Client::new();
+
+    cohere.push_response(MockResponse::json(
+        200,
+        json!({
+            "text": "",
+            "generation_id": "gen-1",
+            "finish_reason": "COMPLETE",
+            "tool_calls": [{"name": "get_weather", "parameters": {"city": "Paris"}}],
+            "meta": {"billed_units": {"input_tokens": 20, "output_tokens": 6}},
+        }),
+    ));
+    let completion: Value = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({
+            "model": "command-r-plus",
+            "top_p": 0.5,
+            "safe_prompt": true,
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello!"},
+                {"role": "user", "content": "Weather in Paris?"},
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "description": "Current weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}},
+                               "required": ["city"]},
+            }}],
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let choice = &completion["choices"][0];
+    assert_eq!(choice["finish_reason"], "tool_calls");
+    let call = &choice["message"]["tool_calls"][0]["function"];
+    assert_eq!(call["name"], "get_weather");
+    assert_eq!(call["arguments"], r#"{"city":"Paris"}"#);
+    assert_eq!(completion["usage"]["prompt_tokens"], 20);
+
+    let sent = cohere.last_request().unwrap();
+    assert_eq!(sent.path, "/v1/chat");
+    let body = sent.json();
+    assert_eq!(body["preamble"], "Be brief.");
+    assert_eq!(body["message"], "Weather in Paris?");
+    assert_eq!(
+        body["chat_history"][0],
+        json!({"role": "USER", "message": "Hi"})
+    );
+    assert_eq!(body["chat_history"][1]["role"], "CHATBOT");
+    assert_eq!(body["p"], 0.5);
+    assert_eq!(body["safety_mode"], "STRICT");
+    assert_eq!(
+        body["tools"][0]["parameter_definitions"]["city"],
+        json!({"type": "str", "required": true})
+    );
+    assert!(body.get("messages").is_none());
+
+    // The JSON-lines stream comes back as server-sent events
+    cohere.push_response(MockResponse {
+        status: 200,
+        content_type: "application/stream+json".to_string(),
+        headers: Vec::new(),
+        body: [
+            json!({"event_type": "stream-start", "generation_id": "gen-2"}),
+            json!({"event_type": "text-generation", "text": "Sunny"}),
+            json!({"event_type": "stream-end", "finish_reason": "COMPLETE",
+                   "response": {"meta": {"billed_units": {"input_tokens": 5, "output_tokens": 1}}}}),
+        ]
+        .iter()
+        .map(|event| format!("{}\n", event))
+        .collect(),
+        delay: std::time::Duration::ZERO,
+        cut_off: false,
+    });
+    let response = client
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "command-r-plus", "stream": true,
+                      "messages": [{"role": "user", "content": "Weather?"}]}))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/event-stream"));
+    let text = response.text().await.unwrap();
+    let chunks: Vec<Value> = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter(|data| *data != "[DONE]")
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "Sunny");
+    assert_eq!(chunks[2]["choices"][0]["finish_reason"], "stop");
+    assert_eq!(chunks[2]["usage"]["completion_tokens"], 1);
+    assert!(text.trim_end().ends_with("data: [DONE]"));
+}
+
+#[tokio::test]
+async fn failing_probes_degrade_a_model_until_one_succeeds() {
+    let primary = MockUpstream::start().await;
+    let backup = MockUpstream::start().await;
+    primary.push_response(overloaded());
+    primary.push_response(overloaded());
+    let probes = "[probes]\nmodels = [\"gpt-4o\"]\ninterval_secs = 1\nfailures = 2";
+    let proxy = start_with_fallback(&primary, &backup, probes).await;
+    let degraded = || async {
+        let metrics = reqwest::get(proxy.url("/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        metrics.contains("openai_proxy_model_degraded{model=\"gpt-4o\"} 1")
+    };
+    let wait_for = |expected: bool| async move {
+        for _ in 0..100 {
+            if degraded().await == expected {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("degraded never became {}", expected);
+    };
+
+    // Probes go to the model's own provider only
+    wait_for(true).await;
+    let response = post(&proxy).await;
+    assert_eq!(response.headers()["x-proxy-attempts"], "backup:200");
+    assert_eq!(backup.requests().len(), 1);
+    let probe = primary.requests()[0].json();
+    assert_eq!(probe["max_tokens"], 5);
+
+    wait_for(false).await;
+    let response = post(&proxy).await;
+    assert_eq!(response.headers()["x-proxy-attempts"], "primary:200");
+}
@@ -0,0 +1,510 @@
+use openai_proxy::testing::{MockResponse, MockUpstream, TestProxy};
+use openai_proxy::Settings;
+use serde_json::json;
+
+async fn start(upstream: &MockUpstream, config: &str) -> TestProxy {
+    let settings = Settings::from_toml(config)
+        .expect("valid config")
+        .with_api_base(&upstream.url());
+    TestProxy::start(settings).await.expect("proxy starts")
+}
+
+async fn stream(proxy: &TestProxy, model: &str) -> (reqwest::header::HeaderMap, String) {
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": model, "stream": true, "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let headers = response.headers().clone();
+    (headers, response.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn relays_event_stream() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, r#"openai_api_key = "sk-upstream""#).await;
+
+    let (headers, body) = stream(&proxy, "gpt-4o").await;
+
+    assert_eq!(headers["content-type"], "text/event-stream");
+    assert_eq!(body.matches("chat.completion.chunk").count(), 4);
+    assert!(body.contains(r#""content":" mock""#));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+}
+
+#[tokio::test]
+async fn transforms_stream_events() {
+    let upstream = MockUpstream::start().await;
+    upstream.push_response(MockResponse::stream(&[json!({
+        "object": "chat.completion.chunk",
+        "model": "gpt-4o-2024-08-06",
+        "choices": [{"index": 0, "delta": {"content": "Hi", "reasoning_content": "hmm"}}],
+    })]));
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+strip_reasoning = true
+
+[[model_catalog.rename]]
+from = "gpt-4o-2024-08-06"
+to = "gpt-4o"
+"#,
+    )
+    .await;
+
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+
+    assert!(body.contains(r#""model":"gpt-4o""#));
+    assert!(!body.contains("gpt-4o-2024-08-06"));
+    assert!(!body.contains("reasoning_content"));
+    assert!(body.contains(r#""content":"Hi""#));
+}
+
+#[tokio::test]
+async fn records_stream_metrics_and_usage() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+openai_api_key = "sk-upstream"
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+"#,
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .bearer_auth("sk-proxy-bot")
+        .json(&json!({"model": "gpt-4o", "stream": true, "messages": []}))
+        .send()
+        .await
+        .unwrap();
+    response.text().await.unwrap();
+
+    let metrics = reqwest::get(proxy.url("/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains(r#"openai_proxy_streams_total{model="gpt-4o",provider="127.0.0.1"} 1"#)
+    );
+    assert!(metrics.contains(
+        r#"openai_proxy_stream_completion_tokens_total{model="gpt-4o",provider="127.0.0.1"} 3"#
+    ));
+
+    let usage: serde_json::Value = reqwest::Client::new()
+        .get(proxy.url("/usage"))
+        .bearer_auth("sk-proxy-bot")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(usage["usage"]["prompt_tokens"], 5);
+    assert_eq!(usage["usage"]["completion_tokens"], 3);
+    assert_eq!(usage["usage"]["streams"]["127.0.0.1/gpt-4o"]["streams"], 1);
+}
+
+#[tokio::test]
+async fn normalizes_finish_reason() {
+    let upstream = MockUpstream::start().await;
+    upstream.push_response(MockResponse::stream(&[json!({
+        "object": "chat.completion.chunk",
+        "choices": [{"index": 0, "delta": {}, "finish_reason": "end_turn"}],
+    })]));
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({
+            "object": "chat.completion",
+            "choices": [{"index": 0, "message": {"content": "Hi"}, "finish_reason": "MAX_TOKENS"}],
+        }),
+    ));
+    let proxy = start(
+        &upstream,
+        r#"
+[finish_reasons]
+map = { "refusal" = "content_filter" }
+"#,
+    )
+    .await;
+
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+    assert!(body.contains(r#""finish_reason":"stop""#), "{}", body);
+
+    let completion: serde_json::Value = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .json(&json!({"model": "gpt-4o", "messages": []}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(completion["choices"][0]["finish_reason"], "length");
+}
+
+// Concatenated delta contents of an SSE body
+fn streamed_text(body: &str) -> String {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+        .filter_map(|chunk| {
+            chunk["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn post_processing_matches_for_streams_and_completions() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[available_models]]
+id = "rewrite"
+object = "model"
+owned_by = "openai"
+post_process = { replace = [{ pattern = "mock$", with = "proxy" }], max_chars = 12, prefix = "> " }
+
+[[available_models]]
+id = "live"
+object = "model"
+owned_by = "openai"
+post_process = { max_chars = 12, prefix = "> ", suffix = " [end]" }
+"#,
+    )
+    .await;
+
+    for (model, expected) in [
+        ("rewrite", "> Hello from p"),
+        ("live", "> Hello from m [end]"),
+    ] {
+        let (_, body) = stream(&proxy, model).await;
+        assert_eq!(streamed_text(&body), expected);
+
+        let completion: serde_json::Value = reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .json(&json!({"model": model, "messages": []}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(completion["choices"][0]["message"]["content"], expected);
+    }
+}
+
+#[tokio::test]
+async fn collects_consented_transcripts() {
+    let upstream = MockUpstream::start().await;
+    let path = std::env::temp_dir().join(format!("transcripts-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let proxy = start(
+        &upstream,
+        &format!(
+            r#"
+[transcripts]
+path = "{}"
+models = ["gpt-4o"]
+ratings = ["good"]
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "consented"
+key = "sk-consented"
+transcript_consent = true
+
+[[tenants.clients]]
+name = "other"
+key = "sk-other"
+"#,
+            path.display()
+        ),
+    )
+    .await;
+    let send = |key: &'static str, model: &'static str, stream: bool, rating: &'static str| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .bearer_auth(key)
+            .header("x-proxy-rating", rating)
+            .json(&json!({
+                "model": model,
+                "stream": stream,
+                "messages": [{"role": "user", "content": "hi"}],
+            }))
+            .send()
+    };
+
+    for (key, model, stream, rating) in [
+        ("sk-consented", "gpt-4o", false, "good"),
+        ("sk-consented", "gpt-4o", true, "good"),
+        ("sk-consented", "gpt-4o", false, "bad"),
+        ("sk-consented", "gpt-4o-mini", false, "good"),
+        ("sk-other", "gpt-4o", false, "good"),
+    ] {
+        let response = send(key, model, stream, rating).await.unwrap();
+        assert_eq!(response.status(), 200);
+        response.text().await.unwrap();
+    }
+
+    // The stream is recorded once the proxy drops it
+    let mut lines = Vec::new();
+    for _ in 0..50 {
+        lines = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if lines.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    // Each example carries its time, for retention
+    for line in &mut lines {
+        let time = line.as_object_mut().unwrap().remove("time").unwrap();
+        assert!(time.as_str().unwrap().ends_with('Z'));
+    }
+    let expected = json!({"messages": [
+        {"role": "user", "content": "hi"},
+        {"role": "assistant", "content": "Hello from mock"},
+    ]});
+    assert_eq!(lines, vec![expected.clone(), expected]);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn paces_streams_to_the_configured_token_rate() {
+    let upstream = MockUpstream::start().await;
+    let chunk = |text: &str| {
+        json!({"object": "chat.completion.chunk",
+               "choices": [{"index": 0, "delta": {"content": text}}]})
+    };
+    upstream.push_response(MockResponse::stream(&[
+        chunk("abcd"),
+        chunk("efgh"),
+        chunk("ijkl"),
+        chunk("mnop"),
+        chunk("qrst"),
+    ]));
+    let proxy = start(
+        &upstream,
+        r#"
+[stream_pacing]
+max_tokens_per_second = 10
+burst_tokens = 1
+"#,
+    )
+    .await;
+
+    let started = std::time::Instant::now();
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+
+    // One token goes out at once, the other four 100ms apart
+    assert!(started.elapsed() >= std::time::Duration::from_millis(350));
+    assert_eq!(body.matches("chat.completion.chunk").count(), 5);
+    assert!(body.ends_with("data: [DONE]\n\n"));
+}
+
+#[tokio::test]
+async fn broken_streams_end_with_an_error_chunk_or_a_spliced_completion() {
+    let upstream = MockUpstream::start().await;
+    let broken = || {
+        let chunk = |text: &str| {
+            json!({"id": "chatcmpl-mock", "object": "chat.completion.chunk",
+                   "choices": [{"index": 0, "delta": {"content": text}}]})
+        };
+        let mut response = MockResponse::stream(&[chunk("Hello"), chunk(" from")]).cut_off();
+        response.body = response.body.replace("data: [DONE]\n\n", "");
+        response
+    };
+
+    upstream.push_response(broken());
+    let proxy = start(&upstream, "[stream_salvage]").await;
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+    assert_eq!(streamed_text(&body), "Hello from");
+    assert!(body.contains(r#""finish_reason":"error""#));
+    assert!(body.contains(r#""type":"upstream_error""#));
+    let metrics = reqwest::get(proxy.url("/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains(
+        r#"stream_failures_total{model="gpt-4o",provider="127.0.0.1",outcome="error_chunk"} 1"#
+    ));
+
+    // The rest is asked for without streaming, after the text already sent
+    upstream.push_response(broken());
+    upstream.push_response(MockResponse::json(
+        200,
+        json!({"choices": [{"index": 0, "message": {"role": "assistant", "content": " mock"},
+                            "finish_reason": "stop"}]}),
+    ));
+    let proxy = start(&upstream, "[stream_salvage]\nretry = true").await;
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+    assert_eq!(streamed_text(&body), "Hello from mock");
+    assert!(body.contains(r#""finish_reason":"stop""#));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+    let retry = upstream.last_request().unwrap().json();
+    assert_eq!(retry["stream"], false);
+    assert_eq!(
+        retry["messages"],
+        json!([{"role": "assistant", "content": "Hello from"}])
+    );
+}
+
+#[tokio::test]
+async fn reconnecting_clients_resume_after_the_last_event_id() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(&upstream, "[stream_resumption]").await;
+
+    let (headers, body) = stream(&proxy, "gpt-4o").await;
+    let request_id = headers["x-request-id"].to_str().unwrap();
+    // Events are numbered with a random stream id, not the request id
+    let stream_id = body
+        .strip_prefix("id: ")
+        .and_then(|rest| rest.split_once(":0\ndata: "))
+        .unwrap()
+        .0;
+    assert_eq!(stream_id.len(), 32);
+    assert!(!body.contains(request_id));
+
+    let resume = |key: &str, last_event: String| {
+        reqwest::Client::new()
+            .post(proxy.url("/v3/chat/completions"))
+            .bearer_auth(key)
+            .header("last-event-id", last_event)
+            .json(&json!({"model": "gpt-4o", "stream": true, "messages": []}))
+            .send()
+    };
+    let resumed = resume("", format!("{}:1", stream_id)).await.unwrap();
+    assert_eq!(resumed.headers()["content-type"], "text/event-stream");
+    let rest = resumed.text().await.unwrap();
+    assert!(rest.starts_with(&format!("id: {}:2\n", stream_id)));
+    assert_eq!(streamed_text(&rest), " mock");
+    assert!(rest.ends_with("data: [DONE]\n\n"));
+    assert_eq!(upstream.requests().len(), 1);
+
+    // Another client key starts a stream of its own
+    let other = resume("sk-other", format!("{}:1", stream_id))
+        .await
+        .unwrap();
+    assert_eq!(
+        streamed_text(&other.text().await.unwrap()),
+        "Hello from mock"
+    );
+    assert_eq!(upstream.requests().len(), 2);
+
+    // As does another caller without a key that guesses from the request id
+    let guessed = resume("", format!("{}:1", request_id)).await.unwrap();
+    let guessed = guessed.text().await.unwrap();
+    assert_eq!(streamed_text(&guessed), "Hello from mock");
+    assert!(!guessed.contains(stream_id));
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn output_token_cap_limits_requests_and_cuts_overrunning_streams() {
+    let upstream = MockUpstream::start().await;
+    let proxy = start(
+        &upstream,
+        r#"
+[[available_models]]
+id = "gpt-4o"
+object = "model"
+owned_by = "openai"
+output_token_cap = 5
+
+[[tenants]]
+name = "acme"
+
+[[tenants.clients]]
+name = "bot"
+key = "sk-proxy-bot"
+output_token_cap = 2
+"#,
+    )
+    .await;
+
+    // The mock ignores max_tokens and streams three content deltas
+    let body = reqwest::Client::new()
+        .post(proxy.url("/v3/chat/completions"))
+        .bearer_auth("sk-proxy-bot")
+        .json(&json!({"model": "gpt-4o", "stream": true, "max_tokens": 100, "messages": []}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(upstream.last_request().unwrap().json()["max_tokens"], 2);
+    assert_eq!(streamed_text(&body), "Hello from");
+    assert!(body.contains(r#""finish_reason":"length""#));
+    assert!(!body.contains(r#""finish_reason":"stop""#));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+}
+
+#[tokio::test]
+async fn repeating_streams_are_cut_with_a_repetition_finish_reason() {
+    let upstream = MockUpstream::start().await;
+    let chunk = |text: &str| {
+        json!({"object": "chat.completion.chunk",
+               "choices": [{"index": 0, "delta": {"content": text}}]})
+    };
+    let mut chunks = vec![chunk("Once upon "), chunk("a time ")];
+    chunks.extend(std::iter::repeat_n(chunk("and then "), 5));
+    chunks.push(chunk("The end"));
+    upstream.push_response(MockResponse::stream(&chunks));
+    let proxy = start(
+        &upstream,
+        r#"
+[loop_detection]
+ngram_words = 2
+max_repeats = 3
+"#,
+    )
+    .await;
+
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+
+    assert_eq!(streamed_text(&body), "Once upon a time and then and then ");
+    assert!(body.contains(r#""finish_reason":"repetition""#));
+    assert!(body.ends_with("data: [DONE]\n\n"));
+
+    // Text that does not repeat streams as usual
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+    assert_eq!(streamed_text(&body), "Hello from mock");
+
+    // Rows sharing words, but differing between them, are not a loop
+    let rows: Vec<_> = (0..10)
+        .map(|i| chunk(&format!("| and then | {} |\n", i)))
+        .collect();
+    upstream.push_response(MockResponse::stream(&rows));
+    let (_, body) = stream(&proxy, "gpt-4o").await;
+    assert!(streamed_text(&body).ends_with("| and then | 9 |\n"));
+    assert!(!body.contains(r#""finish_reason":"repetition""#));
+}